@@ -11,10 +11,23 @@ contract SP1AggregationVerifierScript is BaseScript {
         // Read deployment inputs
         address verifier = readAddress("VERIFIER");
         bytes32 aggregationProgramVKey = readBytes32("AGGREGATION_PROGRAM_VKEY");
+        bytes32 aggregationProgramVKeyDigest = readBytes32("AGGREGATION_PROGRAM_VKEY_DIGEST");
+        // Same variable as the aggregation service, so that both hash tree nodes alike
+        string memory hashFunction = vm.envOr("MERKLE_HASH_FUNCTION", string("keccak256"));
+        uint8 merkleHashFunction;
+        if (keccak256(bytes(hashFunction)) == keccak256("keccak256")) {
+            merkleHashFunction = 0;
+        } else if (keccak256(bytes(hashFunction)) == keccak256("sha256")) {
+            merkleHashFunction = 1;
+        } else {
+            revert("Unknown MERKLE_HASH_FUNCTION");
+        }
 
         // Deploy the SP1AggregationVerifier contract
         address verifierAddress = address(
-            new SP1AggregationVerifier(verifier, aggregationProgramVKey)
+            new SP1AggregationVerifier(
+                verifier, aggregationProgramVKey, aggregationProgramVKeyDigest, merkleHashFunction
+            )
         );
 
         // Save the deployed address
@@ -4,6 +4,34 @@ pragma solidity ^0.8.20;
 import {ISP1Verifier} from "@sp1-contracts/ISP1Verifier.sol";
 
 contract SP1AggregationVerifier {
+    /// @notice The public values committed by the aggregation program for a batch.
+    /// @dev The layout must match `AggregationPublicValues` in the `aggregation-merkle` crate.
+    ///      `requestIndices` are reported by the worker and not bound to the leaves, so only the
+    ///      leaves of `batchRoot` are proven.
+    struct AggregationPublicValues {
+        bytes32 batchId;
+        uint64 batchIndex;
+        uint64 leafCount;
+        uint64 timestamp;
+        bytes32 batchRoot;
+        bytes32 previousRoot;
+        bytes32 newRoot;
+        bytes32 aggregationVKeyDigest;
+        bytes32 allowlistRoot;
+        uint64[] requestIndices;
+    }
+
+    /// @notice A proof that several leaves are in the same tree, as returned by
+    ///         `GetAggregatedMultiproof`.
+    /// @dev The fields are those of `MultiProof` in the `aggregation-merkle` crate.
+    struct MultiProof {
+        bytes32[] leaves;
+        uint64[] indices;
+        uint64 leafCount;
+        bytes32[] proof;
+        bool[] proofFlags;
+    }
+
     /// @notice The address of the SP1 verifier contract.
     /// @dev This can either be a specific SP1Verifier for a specific version, or the
     ///      SP1VerifierGateway which can be used to verify proofs for any version of SP1.
@@ -11,55 +39,338 @@ contract SP1AggregationVerifier {
     ///      https://github.com/succinctlabs/sp1-contracts/tree/main/contracts/deployments
     address public verifier;
 
-    /// @notice The root of the merkle tree of the aggregation program.
+    /// @notice The root of the accumulator of every batch aggregated so far.
+    /// @dev The accumulator is a Merkle Mountain Range over the batch roots, so a proof for an
+    ///      earlier batch can be extended to the current root. Proofs against earlier roots stay
+    ///      valid through `knownRoots`.
     bytes32 public merkleRoot;
 
+    /// @notice Whether the given root was the root of the accumulator after some relay, so that
+    ///         proofs fetched before later batches were relayed still verify.
+    mapping(bytes32 => bool) public knownRoots;
+
+    /// @notice The number of batches relayed so far, which is the index of the next batch.
+    uint64 public batchCount;
+
+    /// @notice The timestamp of the last batch relayed.
+    uint64 public lastBatchTimestamp;
+
+    /// @notice Whether the batch with the given id was relayed.
+    mapping(bytes32 => bool) public relayedBatches;
+
     /// @notice The verification key for the aggregation program.
     bytes32 public aggregationProgramVKey;
 
-    constructor(address _verifier, bytes32 _aggregationProgramVKey) {
+    /// @notice The digest of the verification key for the aggregation program, as the program
+    ///         sees it: the little-endian bytes of the words of `SP1VerifyingKey::hash_u32`.
+    /// @dev Runs of the aggregation program that merge earlier runs verify them with this key, and
+    ///      the run at the top of the batch commits it.
+    bytes32 public immutable aggregationProgramVKeyDigest;
+
+    /// @notice The version of the Merkle tree format committed by the aggregation program.
+    /// @dev Proofs for batches aggregated with an older version must be checked against the
+    ///      contract deployment that relayed them.
+    uint8 public constant MERKLE_TREE_VERSION = 1;
+
+    /// @notice The tag a digest leaf preimage starts with, so that it can't be mistaken for the
+    ///         preimage of a leaf over the full public values.
+    /// @dev Must match `DIGEST_LEAF_TAG` in the `aggregation-merkle` crate.
+    bytes32 public constant DIGEST_LEAF_TAG = "aggregation-merkle/digest-leaf/1";
+
+    /// @notice The tag the preimage of a leaf bound to its submitter starts with.
+    /// @dev Must match `SUBMITTER_LEAF_TAG` in the `aggregation-merkle` crate.
+    bytes32 public constant SUBMITTER_LEAF_TAG = "aggregation-merkle/submitter/001";
+
+    /// @notice The hash function of the Merkle tree nodes: 0 for Keccak-256, 1 for SHA-256.
+    /// @dev Must match the `MERKLE_HASH_FUNCTION` the aggregation service is configured with.
+    uint8 public immutable merkleHashFunction;
+
+    constructor(
+        address _verifier,
+        bytes32 _aggregationProgramVKey,
+        bytes32 _aggregationProgramVKeyDigest,
+        uint8 _merkleHashFunction
+    ) {
+        require(_merkleHashFunction <= 1, "Unknown merkle hash function");
         verifier = _verifier;
         aggregationProgramVKey = _aggregationProgramVKey;
+        aggregationProgramVKeyDigest = _aggregationProgramVKeyDigest;
+        merkleHashFunction = _merkleHashFunction;
     }
 
     /// @notice Verifies the proof for the aggregation program.
-    /// @param _publicValues The public values for the aggregation program.
+    /// @param _publicValues The ABI-encoded `AggregationPublicValues` of the batch.
     /// @param _proofBytes The proof bytes for the aggregation program.
     function verifyAggregationProof(bytes calldata _publicValues, bytes calldata _proofBytes) public {
         ISP1Verifier(verifier).verifyProof(aggregationProgramVKey, _publicValues, _proofBytes);
-        bytes32 newMerkleRoot = abi.decode(_publicValues, (bytes32));
-        merkleRoot = newMerkleRoot;
+        AggregationPublicValues memory publicValues = abi.decode(_publicValues, (AggregationPublicValues));
+        require(
+            publicValues.aggregationVKeyDigest == aggregationProgramVKeyDigest, "Batch merges proofs of another program"
+        );
+        require(!relayedBatches[publicValues.batchId], "Batch was already relayed");
+        require(publicValues.batchIndex == batchCount, "Batch is out of order");
+        require(publicValues.previousRoot == merkleRoot, "Batch does not extend the current accumulator");
+        require(
+            publicValues.timestamp >= lastBatchTimestamp && publicValues.timestamp <= block.timestamp,
+            "Batch timestamp is out of order"
+        );
+        relayedBatches[publicValues.batchId] = true;
+        batchCount = publicValues.batchIndex + 1;
+        lastBatchTimestamp = publicValues.timestamp;
+        merkleRoot = publicValues.newRoot;
+        knownRoots[publicValues.newRoot] = true;
     }
 
-    /// @notice Verifies a Merkle proof.
-    /// @param leaf The leaf node to verify.
-    /// @param proof The Merkle proof to verify.
-    function verifyMerkleProof(bytes32 leaf, bytes32[] calldata proof) internal view returns (bool) {
-        bytes32 computedHash = leaf;
-        uint256 proofLength = proof.length;
+    /// @notice Hashes the preimage of a node with the Merkle hash function of this contract.
+    /// @param preimage The preimage to hash.
+    function hashNode(bytes memory preimage) internal view returns (bytes32) {
+        return merkleHashFunction == 0 ? keccak256(preimage) : sha256(preimage);
+    }
+
+    /// @notice Hashes a leaf into a leaf node, so that it cannot be mistaken for an internal node.
+    /// @param leaf The leaf to hash.
+    function hashLeaf(bytes32 leaf) internal view returns (bytes32) {
+        return hashNode(abi.encodePacked(bytes1(0x00), leaf));
+    }
+
+    /// @notice Hashes a pair of nodes in sorted order into their parent node.
+    /// @param a The first node.
+    /// @param b The second node.
+    function hashPair(bytes32 a, bytes32 b) internal view returns (bytes32) {
+        return a < b
+            ? hashNode(abi.encodePacked(bytes1(0x01), a, b))
+            : hashNode(abi.encodePacked(bytes1(0x01), b, a));
+    }
+
+    /// @notice Hashes the verification key of a program and the root of the subtree of its proofs
+    ///         into the node of the program in the top tree of a batch.
+    /// @dev The prefix keeps the node from being mistaken for a leaf node or an internal node. Must
+    ///      match `hash_program_node` in the `aggregation-merkle` crate.
+    /// @param vkey The verification key for the program.
+    /// @param subtreeRoot The root of the subtree of the program.
+    function hashProgramNode(bytes32 vkey, bytes32 subtreeRoot) internal view returns (bytes32) {
+        return hashNode(abi.encodePacked(bytes1(0x02), vkey, subtreeRoot));
+    }
+
+    /// @notice Verifies a Merkle proof of a leaf of a program.
+    /// @param programVKey The verification key for the program of the leaf.
+    /// @param leaf The leaf to verify.
+    /// @param subtreeProof The Merkle proof of the leaf against the subtree of the program.
+    /// @param programProof The path of the program node to the aggregation Merkle root.
+    function verifyMerkleProof(
+        bytes32 programVKey,
+        bytes32 leaf,
+        bytes32[] calldata subtreeProof,
+        bytes32[] calldata programProof
+    ) internal view returns (bool) {
+        bytes32 computedHash = hashLeaf(leaf);
+        uint256 proofLength = subtreeProof.length;
 
         for (uint256 i = 0; i < proofLength; i++) {
-            if (computedHash < proof[i]) {
-                computedHash = keccak256(abi.encodePacked(computedHash, proof[i]));
-            } else {
-                computedHash = keccak256(abi.encodePacked(proof[i], computedHash));
-            }
+            computedHash = hashPair(computedHash, subtreeProof[i]);
         }
 
-        return computedHash == merkleRoot;
+        return processProgramProof(programVKey, computedHash, programProof);
     }
 
     /// @notice Verifies the inclusion of the user's proof in the aggregation proof.
     /// @param _programVKey The verification key for the user's program.
     /// @param _publicValues The public values for the user's program.
-    /// @param _merkleProof The Merkle proof for the user's proof against the aggregation Merkle tree.
-    function verifyProof(bytes calldata _programVKey, bytes calldata _publicValues, bytes32[] calldata _merkleProof) public view {
+    /// @param _subtreeProof The Merkle proof for the user's proof against the subtree of its program.
+    /// @param _programProof The path of the program node to the aggregation Merkle root.
+    function verifyProof(
+        bytes calldata _programVKey,
+        bytes calldata _publicValues,
+        bytes32[] calldata _subtreeProof,
+        bytes32[] calldata _programProof
+    ) public view {
+        require(_programVKey.length == 32, "Invalid program verification key");
         // Hash pair (programVk, publicValues) Sha256 digest
         bytes32 pairHash = sha256(abi.encodePacked(_programVKey, _publicValues));
 
         // Verify the Merkle proof
-        bool isProofValid = verifyMerkleProof(pairHash, _merkleProof);
+        bool isProofValid = verifyMerkleProof(bytes32(_programVKey), pairHash, _subtreeProof, _programProof);
         require(isProofValid, "Invalid Merkle proof");
     }
-}
 
+    /// @notice Computes whether the node of a program proves a known aggregation Merkle root.
+    /// @param programVKey The verification key for the program.
+    /// @param subtreeRoot The root of the subtree of the program.
+    /// @param programProof The path of the program node to the aggregation Merkle root.
+    function processProgramProof(bytes32 programVKey, bytes32 subtreeRoot, bytes32[] calldata programProof)
+        internal
+        view
+        returns (bool)
+    {
+        bytes32 computedHash = hashProgramNode(programVKey, subtreeRoot);
+        for (uint256 i = 0; i < programProof.length; i++) {
+            computedHash = hashPair(computedHash, programProof[i]);
+        }
+        return knownRoots[computedHash];
+    }
+
+    /// @notice Verifies that a root is the root of the subtree of the proofs of a program in an
+    ///         aggregated batch, so that the proofs of the program can be checked against it.
+    /// @dev The node of a program in the top tree of a batch is `hashProgramNode` of its
+    ///      verification key and its subtree root, so a subtree root of another program, or a
+    ///      subtree root presented as another node, doesn't prove a known root.
+    /// @param _programVKey The verification key for the program, as in the leaves of its proofs.
+    /// @param _subtreeRoot The root of the subtree of the program.
+    /// @param _programProof The path of the program node to the aggregation Merkle root.
+    function verifySubtreeRoot(bytes32 _programVKey, bytes32 _subtreeRoot, bytes32[] calldata _programProof)
+        public
+        view
+    {
+        require(processProgramProof(_programVKey, _subtreeRoot, _programProof), "Invalid Merkle proof");
+    }
+
+    /// @notice Computes the root of the tree implied by a multiproof of several of its leaves.
+    /// @dev A port of `process_multiproof` in the `aggregation-merkle` crate for the current tree
+    ///      version. Nodes are hashed level by level, left to right, and the last node of a level
+    ///      with an odd number of nodes is promoted to the next level instead of hashed, which is
+    ///      why the multiproof carries the index of each leaf and the leaf count.
+    /// @param _multiProof The multiproof.
+    function processMultiProof(MultiProof memory _multiProof) internal view returns (bytes32) {
+        uint256 count = _multiProof.leaves.length;
+        require(count > 0 && count == _multiProof.indices.length, "Invalid multiproof");
+        bytes32[] memory nodes = new bytes32[](count);
+        uint64[] memory positions = new uint64[](count);
+        for (uint256 i = 0; i < count; i++) {
+            require(i == 0 || _multiProof.indices[i - 1] < _multiProof.indices[i], "Invalid multiproof");
+            nodes[i] = hashLeaf(_multiProof.leaves[i]);
+            positions[i] = _multiProof.indices[i];
+        }
+        require(positions[count - 1] < _multiProof.leafCount, "Invalid multiproof");
+
+        uint256 proofPos = 0;
+        uint256 flagPos = 0;
+        for (uint64 levelSize = _multiProof.leafCount; levelSize > 1; levelSize = (levelSize + 1) / 2) {
+            // A level has no more nodes than the level below, so its nodes overwrite those.
+            uint256 next = 0;
+            uint256 i = 0;
+            while (i < count) {
+                uint64 position = positions[i];
+                bytes32 node = nodes[i];
+                if (position == levelSize - 1 && levelSize % 2 == 1) {
+                    i += 1;
+                } else {
+                    bool siblingIsKnown = i + 1 < count && positions[i + 1] == position ^ 1;
+                    require(
+                        flagPos < _multiProof.proofFlags.length && _multiProof.proofFlags[flagPos] == siblingIsKnown,
+                        "Invalid multiproof"
+                    );
+                    flagPos += 1;
+                    bytes32 sibling;
+                    if (siblingIsKnown) {
+                        sibling = nodes[i + 1];
+                        i += 2;
+                    } else {
+                        require(proofPos < _multiProof.proof.length, "Invalid multiproof");
+                        sibling = _multiProof.proof[proofPos];
+                        proofPos += 1;
+                        i += 1;
+                    }
+                    node = hashPair(node, sibling);
+                }
+                nodes[next] = node;
+                positions[next] = position / 2;
+                next += 1;
+            }
+            count = next;
+        }
+        require(
+            proofPos == _multiProof.proof.length && flagPos == _multiProof.proofFlags.length, "Invalid multiproof"
+        );
+        return nodes[0];
+    }
+
+    /// @notice Verifies the inclusion of several proofs of a program in the aggregation proof at
+    ///         once, for leaves computed as in `verifyProof`, `digestLeaf` or `submitterLeaf`.
+    /// @dev The multiproof is against the subtree of the program, whose root `_programProof`
+    ///      proves as in `verifySubtreeRoot`.
+    /// @param _multiProof The multiproof of the leaves against the subtree of the program.
+    /// @param _programVKey The verification key for the program.
+    /// @param _programProof The path of the program node to the aggregation Merkle root: the
+    ///        `program_proof` of `GetAggregatedMultiproof`, followed by its `accumulator_proof`.
+    function verifyMultiProof(
+        MultiProof memory _multiProof,
+        bytes32 _programVKey,
+        bytes32[] calldata _programProof
+    ) public view {
+        verifySubtreeRoot(_programVKey, processMultiProof(_multiProof), _programProof);
+    }
+
+    /// @notice Computes the leaf of a proof aggregated in the digest leaf format, which commits to
+    ///         the SHA-256 digest of its public values instead of the public values themselves.
+    /// @param _programVKey The verification key for the user's program.
+    /// @param _publicValuesDigest The SHA-256 digest of the public values for the user's program.
+    function digestLeaf(bytes32 _programVKey, bytes32 _publicValuesDigest) public pure returns (bytes32) {
+        return sha256(abi.encodePacked(DIGEST_LEAF_TAG, _programVKey, _publicValuesDigest));
+    }
+
+    /// @notice Verifies the inclusion of the user's proof in the aggregation proof, for a proof
+    ///         aggregated in the digest leaf format.
+    /// @param _programVKey The verification key for the user's program.
+    /// @param _publicValuesDigest The SHA-256 digest of the public values for the user's program.
+    /// @param _subtreeProof The Merkle proof for the user's proof against the subtree of its program.
+    /// @param _programProof The path of the program node to the aggregation Merkle root.
+    function verifyProofDigest(
+        bytes32 _programVKey,
+        bytes32 _publicValuesDigest,
+        bytes32[] calldata _subtreeProof,
+        bytes32[] calldata _programProof
+    ) public view {
+        bool isProofValid = verifyMerkleProof(
+            _programVKey, digestLeaf(_programVKey, _publicValuesDigest), _subtreeProof, _programProof
+        );
+        require(isProofValid, "Invalid Merkle proof");
+    }
+
+    /// @notice Binds the leaf of a proof to the identity of its submitter, as the aggregation
+    ///         service does for requests with a submitter. A leaf without a submitter is unchanged.
+    /// @param _leaf The leaf of the proof, such as a `digestLeaf`.
+    /// @param _submitter The identity of the submitter, such as `abi.encodePacked` of its address.
+    function submitterLeaf(bytes32 _leaf, bytes memory _submitter) public pure returns (bytes32) {
+        if (_submitter.length == 0) {
+            return _leaf;
+        }
+        return sha256(abi.encodePacked(SUBMITTER_LEAF_TAG, _leaf, _submitter));
+    }
+
+    /// @notice Verifies the inclusion of a leaf in the aggregation proof, for leaves computed with
+    ///         `digestLeaf` or `submitterLeaf`.
+    /// @param _programVKey The verification key for the program of the leaf.
+    /// @param _leaf The leaf to verify.
+    /// @param _subtreeProof The Merkle proof for the leaf against the subtree of its program.
+    /// @param _programProof The path of the program node to the aggregation Merkle root.
+    function verifyLeaf(
+        bytes32 _programVKey,
+        bytes32 _leaf,
+        bytes32[] calldata _subtreeProof,
+        bytes32[] calldata _programProof
+    ) public view {
+        bool isProofValid = verifyMerkleProof(_programVKey, _leaf, _subtreeProof, _programProof);
+        require(isProofValid, "Invalid Merkle proof");
+    }
+
+    /// @notice Verifies the inclusion of the user's proof in the aggregation proof, for a proof
+    ///         requested by the given address.
+    /// @param _programVKey The verification key for the user's program.
+    /// @param _publicValues The public values for the user's program.
+    /// @param _submitter The address that requested the aggregation of the proof.
+    /// @param _subtreeProof The Merkle proof for the user's proof against the subtree of its program.
+    /// @param _programProof The path of the program node to the aggregation Merkle root.
+    function verifyProofFrom(
+        bytes calldata _programVKey,
+        bytes calldata _publicValues,
+        address _submitter,
+        bytes32[] calldata _subtreeProof,
+        bytes32[] calldata _programProof
+    ) public view {
+        require(_programVKey.length == 32, "Invalid program verification key");
+        bytes32 pairHash = sha256(abi.encodePacked(_programVKey, _publicValues));
+        verifyLeaf(
+            bytes32(_programVKey), submitterLeaf(pairHash, abi.encodePacked(_submitter)), _subtreeProof, _programProof
+        );
+    }
+}
@@ -0,0 +1,400 @@
+// SPDX-License-Identifier: MIT
+pragma solidity ^0.8.20;
+
+import {Test} from "forge-std/Test.sol";
+import {stdJson} from "forge-std/StdJson.sol";
+import {SP1AggregationVerifier} from "../src/SP1AggregationVerifier.sol";
+import {SP1VerifierGateway} from "@sp1-contracts/SP1VerifierGateway.sol";
+
+contract SP1AggregationVerifierTest is Test {
+    using stdJson for string;
+
+    address verifier;
+    SP1AggregationVerifier public aggregationVerifier;
+    string json;
+
+    bytes32 constant VKEY_DIGEST = keccak256("aggregation program vkey digest");
+    bytes32 constant BATCH_ID = keccak256("first batch");
+    bytes32 constant NEXT_BATCH_ID = keccak256("next batch");
+
+    /// @dev The vkey of the digest leaf test of the `types` crate, and the leaf it computes for
+    ///      the `hello` public values.
+    bytes32 constant DIGEST_LEAF_VKEY = 0x0101010101010101010101010101010101010101010101010101010101010101;
+    bytes32 constant DIGEST_LEAF = 0x10ba3b57768d56e6ce540c6acdc55f55a9ef8b587a6eb82c13cbeef40cdd838c;
+
+    /// @dev The submitter of the submitter leaf test of the `types` crate, and the leaf it computes
+    ///      when binding `DIGEST_LEAF` to it.
+    address constant SUBMITTER = 0x1111111111111111111111111111111111111111;
+    bytes32 constant SUBMITTER_LEAF = 0x994ad3d7300fa6c0f7f633af7fdde99d16a072925aca4a9dc45f4d5615b163a6;
+
+    function setUp() public {
+        string memory root = vm.projectRoot();
+        string memory path = string.concat(root, "/src/fixtures/merkle-fixture.json");
+        json = vm.readFile(path);
+
+        verifier = address(new SP1VerifierGateway(address(1)));
+        // The fixture is hashed with Keccak-256.
+        aggregationVerifier = new SP1AggregationVerifier(verifier, bytes32(0), VKEY_DIGEST, 0);
+
+        // The accumulator of a single batch has the root of that batch.
+        vm.mockCall(verifier, abi.encodeWithSelector(SP1VerifierGateway.verifyProof.selector), abi.encode(true));
+        aggregationVerifier.verifyAggregationProof(
+            publicValues(BATCH_ID, 0, uint64(block.timestamp), bytes32(0), json.readBytes32(".root"), VKEY_DIGEST), ""
+        );
+    }
+
+    /// @dev Encodes the public values the aggregation program commits for a batch.
+    function publicValues(
+        bytes32 batchId,
+        uint64 batchIndex,
+        uint64 timestamp,
+        bytes32 previousRoot,
+        bytes32 newRoot,
+        bytes32 vkeyDigest
+    ) internal pure returns (bytes memory) {
+        uint64[] memory requestIndices = new uint64[](5);
+        for (uint64 i = 0; i < 5; i++) {
+            requestIndices[i] = i;
+        }
+        return abi.encode(
+            SP1AggregationVerifier.AggregationPublicValues({
+                batchId: batchId,
+                batchIndex: batchIndex,
+                leafCount: 5,
+                timestamp: timestamp,
+                batchRoot: keccak256("batch root"),
+                previousRoot: previousRoot,
+                newRoot: newRoot,
+                aggregationVKeyDigest: vkeyDigest,
+                allowlistRoot: bytes32(0),
+                requestIndices: requestIndices
+            })
+        );
+    }
+
+    /// @dev Encodes the public values of the batch after the one relayed in `setUp`.
+    function nextBatch(bytes32 previousRoot, bytes32 vkeyDigest) internal view returns (bytes memory) {
+        return publicValues(
+            NEXT_BATCH_ID, 1, uint64(block.timestamp), previousRoot, keccak256("next accumulator root"), vkeyDigest
+        );
+    }
+
+    function test_MerkleRootFromFixture() public view {
+        assertEq(aggregationVerifier.merkleRoot(), json.readBytes32(".root"));
+        assertTrue(aggregationVerifier.knownRoots(json.readBytes32(".root")));
+        assertFalse(aggregationVerifier.knownRoots(bytes32(0)));
+    }
+
+    function test_ValidMerkleProofs() public view {
+        bytes32[] memory vkeys = json.readBytes32Array(".vkeys");
+        bytes[] memory publicValues = json.readBytesArray(".publicValues");
+
+        for (uint256 i = 0; i < vkeys.length; i++) {
+            (bytes32[] memory subtreeProof, bytes32[] memory programProof) = fixtureProof(i);
+            aggregationVerifier.verifyProof(abi.encodePacked(vkeys[i]), publicValues[i], subtreeProof, programProof);
+        }
+    }
+
+    /// @dev Reads the proof of the leaf at `index` in the fixture: its path through the subtree of
+    ///      its program, and the path of the program node.
+    function fixtureProof(uint256 index)
+        internal
+        view
+        returns (bytes32[] memory subtreeProof, bytes32[] memory programProof)
+    {
+        subtreeProof = json.readBytes32Array(string.concat(".subtreeProofs[", vm.toString(index), "]"));
+        programProof = json.readBytes32Array(string.concat(".programProofs[", vm.toString(index), "]"));
+    }
+
+    function testFail_InvalidMerkleProof() public view {
+        bytes32[] memory vkeys = json.readBytes32Array(".vkeys");
+        bytes[] memory publicValues = json.readBytesArray(".publicValues");
+
+        // The proof for the first leaf does not prove the second one.
+        (bytes32[] memory subtreeProof, bytes32[] memory programProof) = fixtureProof(0);
+        aggregationVerifier.verifyProof(abi.encodePacked(vkeys[1]), publicValues[1], subtreeProof, programProof);
+    }
+
+    function testFail_MerkleProofOfAnotherProgram() public view {
+        bytes32[] memory vkeys = json.readBytes32Array(".vkeys");
+        bytes[] memory publicValues = json.readBytesArray(".publicValues");
+
+        // The last leaf is of another program than the first, so its path can't be presented as
+        // a path through the subtree of the first program.
+        (bytes32[] memory subtreeProof, bytes32[] memory programProof) = fixtureProof(0);
+        uint256 last = vkeys.length - 1;
+        aggregationVerifier.verifyLeaf(
+            vkeys[0], sha256(abi.encodePacked(vkeys[last], publicValues[last])), subtreeProof, programProof
+        );
+    }
+
+    function test_MerkleHashFunction() public view {
+        assertEq(aggregationVerifier.merkleHashFunction(), 0);
+    }
+
+    function testFail_UnknownMerkleHashFunction() public {
+        new SP1AggregationVerifier(verifier, bytes32(0), VKEY_DIGEST, 2);
+    }
+
+    function testFail_MerkleProofWithOtherHashFunction() public {
+        SP1AggregationVerifier sha256Verifier = new SP1AggregationVerifier(verifier, bytes32(0), VKEY_DIGEST, 1);
+        sha256Verifier.verifyAggregationProof(
+            publicValues(BATCH_ID, 0, uint64(block.timestamp), bytes32(0), json.readBytes32(".root"), VKEY_DIGEST), ""
+        );
+
+        bytes32[] memory vkeys = json.readBytes32Array(".vkeys");
+        bytes[] memory publicValues = json.readBytesArray(".publicValues");
+        (bytes32[] memory subtreeProof, bytes32[] memory programProof) = fixtureProof(0);
+        sha256Verifier.verifyProof(abi.encodePacked(vkeys[0]), publicValues[0], subtreeProof, programProof);
+    }
+
+    function testFail_StaleAccumulatorRoot() public {
+        // The batch must extend the accumulator the contract holds, not the empty one.
+        aggregationVerifier.verifyAggregationProof(nextBatch(bytes32(0), VKEY_DIGEST), "");
+    }
+
+    function test_ExtendAccumulator() public {
+        aggregationVerifier.verifyAggregationProof(nextBatch(json.readBytes32(".root"), VKEY_DIGEST), "");
+        assertEq(aggregationVerifier.merkleRoot(), keccak256("next accumulator root"));
+        assertEq(aggregationVerifier.batchCount(), 2);
+        assertTrue(aggregationVerifier.relayedBatches(NEXT_BATCH_ID));
+    }
+
+    function test_ProofsVerifyAfterLaterBatches() public {
+        // Proofs against the root of the first batch were fetched before the next batch was
+        // relayed, and still verify after it.
+        aggregationVerifier.verifyAggregationProof(nextBatch(json.readBytes32(".root"), VKEY_DIGEST), "");
+        assertTrue(aggregationVerifier.knownRoots(json.readBytes32(".root")));
+        test_ValidMerkleProofs();
+    }
+
+    function testFail_MergedProofsOfAnotherProgram() public {
+        // A batch merging runs verified with another vkey could commit any root.
+        aggregationVerifier.verifyAggregationProof(
+            nextBatch(json.readBytes32(".root"), keccak256("other program")), ""
+        );
+    }
+
+    function testFail_ReplayedBatch() public {
+        // The first batch can't be relayed again, even on top of the current accumulator.
+        aggregationVerifier.verifyAggregationProof(
+            publicValues(
+                BATCH_ID, 1, uint64(block.timestamp), json.readBytes32(".root"), keccak256("replayed"), VKEY_DIGEST
+            ),
+            ""
+        );
+    }
+
+    function testFail_OutOfOrderBatch() public {
+        aggregationVerifier.verifyAggregationProof(
+            publicValues(
+                NEXT_BATCH_ID,
+                2,
+                uint64(block.timestamp),
+                json.readBytes32(".root"),
+                keccak256("next accumulator root"),
+                VKEY_DIGEST
+            ),
+            ""
+        );
+    }
+
+    function testFail_BatchFromTheFuture() public {
+        aggregationVerifier.verifyAggregationProof(
+            publicValues(
+                NEXT_BATCH_ID,
+                1,
+                uint64(block.timestamp + 1),
+                json.readBytes32(".root"),
+                keccak256("next accumulator root"),
+                VKEY_DIGEST
+            ),
+            ""
+        );
+    }
+
+    function testFail_BatchOlderThanTheLastOne() public {
+        vm.warp(block.timestamp + 100);
+        aggregationVerifier.verifyAggregationProof(nextBatch(json.readBytes32(".root"), VKEY_DIGEST), "");
+        aggregationVerifier.verifyAggregationProof(
+            publicValues(
+                keccak256("third batch"),
+                2,
+                uint64(block.timestamp - 1),
+                keccak256("next accumulator root"),
+                keccak256("third accumulator root"),
+                VKEY_DIGEST
+            ),
+            ""
+        );
+    }
+
+    /// @dev Deploys a verifier whose accumulator holds a single batch with the given leaf as the
+    ///      only proof of the program with the given vkey.
+    function singleLeafVerifier(bytes32 vkey, bytes32 leaf) internal returns (SP1AggregationVerifier) {
+        return rootVerifier(programNode(vkey, keccak256(abi.encodePacked(bytes1(0x00), leaf))));
+    }
+
+    /// @dev The node of a program in the top tree of a Keccak-256 batch.
+    function programNode(bytes32 vkey, bytes32 subtreeRoot) internal pure returns (bytes32) {
+        return keccak256(abi.encodePacked(bytes1(0x02), vkey, subtreeRoot));
+    }
+
+    /// @dev A Keccak-256 verifier holding the given root.
+    function rootVerifier(bytes32 root) internal returns (SP1AggregationVerifier) {
+        SP1AggregationVerifier leafVerifier = new SP1AggregationVerifier(verifier, bytes32(0), VKEY_DIGEST, 0);
+        leafVerifier.verifyAggregationProof(
+            publicValues(BATCH_ID, 0, uint64(block.timestamp), bytes32(0), root, VKEY_DIGEST), ""
+        );
+        return leafVerifier;
+    }
+
+    /// @dev A verifier holding the root of a batch with `DIGEST_LEAF` as the only proof of
+    ///      `DIGEST_LEAF_VKEY`, with the subtree root of the program.
+    function programVerifier() internal returns (SP1AggregationVerifier, bytes32) {
+        bytes32 subtreeRoot = keccak256(abi.encodePacked(bytes1(0x00), DIGEST_LEAF));
+        return (rootVerifier(programNode(DIGEST_LEAF_VKEY, subtreeRoot)), subtreeRoot);
+    }
+
+    function test_DigestLeafMatchesRust() public view {
+        assertEq(aggregationVerifier.digestLeaf(DIGEST_LEAF_VKEY, sha256("hello")), DIGEST_LEAF);
+    }
+
+    function test_VerifyProofDigest() public {
+        singleLeafVerifier(DIGEST_LEAF_VKEY, DIGEST_LEAF).verifyProofDigest(
+            DIGEST_LEAF_VKEY, sha256("hello"), new bytes32[](0), new bytes32[](0)
+        );
+    }
+
+    function testFail_DigestLeafAsFullLeaf() public {
+        // The digest of the public values can't be presented as the public values themselves.
+        singleLeafVerifier(DIGEST_LEAF_VKEY, DIGEST_LEAF).verifyProof(
+            abi.encodePacked(DIGEST_LEAF_VKEY), abi.encodePacked(sha256("hello")), new bytes32[](0), new bytes32[](0)
+        );
+    }
+
+    function test_SubmitterLeafMatchesRust() public view {
+        assertEq(aggregationVerifier.submitterLeaf(DIGEST_LEAF, abi.encodePacked(SUBMITTER)), SUBMITTER_LEAF);
+        assertEq(aggregationVerifier.submitterLeaf(DIGEST_LEAF, ""), DIGEST_LEAF);
+    }
+
+    function test_VerifyLeafOfSubmitter() public {
+        singleLeafVerifier(DIGEST_LEAF_VKEY, SUBMITTER_LEAF).verifyLeaf(
+            DIGEST_LEAF_VKEY,
+            aggregationVerifier.submitterLeaf(DIGEST_LEAF, abi.encodePacked(SUBMITTER)),
+            new bytes32[](0),
+            new bytes32[](0)
+        );
+    }
+
+    function testFail_LeafOfAnotherSubmitter() public {
+        // Another address can't claim the proof of the submitter.
+        singleLeafVerifier(DIGEST_LEAF_VKEY, SUBMITTER_LEAF).verifyLeaf(
+            DIGEST_LEAF_VKEY,
+            aggregationVerifier.submitterLeaf(DIGEST_LEAF, abi.encodePacked(address(this))),
+            new bytes32[](0),
+            new bytes32[](0)
+        );
+    }
+
+    function test_VerifyProofFrom() public {
+        bytes32[] memory vkeys = json.readBytes32Array(".vkeys");
+        bytes[] memory publicValues = json.readBytesArray(".publicValues");
+        bytes32 leaf = sha256(abi.encodePacked(vkeys[0], publicValues[0]));
+        SP1AggregationVerifier leafVerifier =
+            singleLeafVerifier(vkeys[0], aggregationVerifier.submitterLeaf(leaf, abi.encodePacked(SUBMITTER)));
+        leafVerifier.verifyProofFrom(
+            abi.encodePacked(vkeys[0]), publicValues[0], SUBMITTER, new bytes32[](0), new bytes32[](0)
+        );
+    }
+
+    function test_VerifySubtreeRoot() public {
+        (SP1AggregationVerifier programRootVerifier, bytes32 subtreeRoot) = programVerifier();
+        // The program is the only one of the batch, so its node is the root.
+        bytes32[] memory programProof = new bytes32[](0);
+        programRootVerifier.verifySubtreeRoot(DIGEST_LEAF_VKEY, subtreeRoot, programProof);
+        // The proof through the whole batch is the empty path through the subtree, then the
+        // program proof.
+        programRootVerifier.verifyProofDigest(DIGEST_LEAF_VKEY, sha256("hello"), new bytes32[](0), programProof);
+    }
+
+    function testFail_UntaggedProgramNode() public {
+        // A root whose program node pairs the leaf node of the vkey with the subtree root, as an
+        // internal node, doesn't prove the subtree root.
+        bytes32 subtreeRoot = keccak256(abi.encodePacked(bytes1(0x00), DIGEST_LEAF));
+        bytes32 vkeyNode = keccak256(abi.encodePacked(bytes1(0x00), DIGEST_LEAF_VKEY));
+        bytes32 untaggedNode = subtreeRoot < vkeyNode
+            ? keccak256(abi.encodePacked(bytes1(0x01), subtreeRoot, vkeyNode))
+            : keccak256(abi.encodePacked(bytes1(0x01), vkeyNode, subtreeRoot));
+        rootVerifier(untaggedNode).verifySubtreeRoot(DIGEST_LEAF_VKEY, subtreeRoot, new bytes32[](0));
+    }
+
+    /// @dev Reads the multiproof at `index` in the fixture, with the vkey of its program, the
+    ///      proof of the subtree root of the program, and the root of its batch.
+    function fixtureMultiProof(uint256 index)
+        internal
+        view
+        returns (
+            SP1AggregationVerifier.MultiProof memory multiProof,
+            bytes32 vkey,
+            bytes32[] memory programProof,
+            bytes32 root
+        )
+    {
+        string memory key = string.concat(".multiproofs[", vm.toString(index), "]");
+        uint256[] memory indices = json.readUintArray(string.concat(key, ".indices"));
+        multiProof.indices = new uint64[](indices.length);
+        for (uint256 i = 0; i < indices.length; i++) {
+            multiProof.indices[i] = uint64(indices[i]);
+        }
+        multiProof.leaves = json.readBytes32Array(string.concat(key, ".leaves"));
+        multiProof.leafCount = uint64(json.readUint(string.concat(key, ".leafCount")));
+        multiProof.proof = json.readBytes32Array(string.concat(key, ".proof"));
+        multiProof.proofFlags = json.readBoolArray(string.concat(key, ".proofFlags"));
+        vkey = json.readBytes32(string.concat(key, ".vkey"));
+        programProof = json.readBytes32Array(string.concat(key, ".programProof"));
+        root = json.readBytes32(string.concat(key, ".root"));
+    }
+
+    function test_VerifyMultiProofsFromFixture() public {
+        uint256 multiProofCount = json.readBytes32Array(".multiproofs[*].root").length;
+        assertGt(multiProofCount, 0);
+        for (uint256 i = 0; i < multiProofCount; i++) {
+            (
+                SP1AggregationVerifier.MultiProof memory multiProof,
+                bytes32 vkey,
+                bytes32[] memory programProof,
+                bytes32 root
+            ) = fixtureMultiProof(i);
+            rootVerifier(root).verifyMultiProof(multiProof, vkey, programProof);
+        }
+    }
+
+    function testFail_MultiProofWithWrongFlags() public {
+        (SP1AggregationVerifier.MultiProof memory multiProof, bytes32 vkey, bytes32[] memory programProof, bytes32 root)
+        = fixtureMultiProof(0);
+        multiProof.proofFlags[0] = !multiProof.proofFlags[0];
+        rootVerifier(root).verifyMultiProof(multiProof, vkey, programProof);
+    }
+
+    function testFail_MultiProofOfAnotherLeaf() public {
+        (SP1AggregationVerifier.MultiProof memory multiProof, bytes32 vkey, bytes32[] memory programProof, bytes32 root)
+        = fixtureMultiProof(0);
+        multiProof.leaves[0] = json.readBytes32Array(".leaves")[0];
+        rootVerifier(root).verifyMultiProof(multiProof, vkey, programProof);
+    }
+
+    function testFail_MultiProofWithoutPromotedNode() public {
+        // The last leaf of the first multiproof is promoted, so the multiproof doesn't verify as
+        // a multiproof of a tree with one more leaf.
+        (SP1AggregationVerifier.MultiProof memory multiProof, bytes32 vkey, bytes32[] memory programProof, bytes32 root)
+        = fixtureMultiProof(0);
+        multiProof.leafCount += 1;
+        rootVerifier(root).verifyMultiProof(multiProof, vkey, programProof);
+    }
+
+    function testFail_SubtreeRootOfAnotherProgram() public {
+        (SP1AggregationVerifier programRootVerifier, bytes32 subtreeRoot) = programVerifier();
+        programRootVerifier.verifySubtreeRoot(bytes32(uint256(2)), subtreeRoot, new bytes32[](0));
+    }
+}
@@ -0,0 +1,40 @@
+//! The allowlist of programs whose proofs may be aggregated.
+//!
+//! The allowlist is a set of vkey digests, in the byte encoding of [`crate::words_to_bytes_le`].
+//! Its root is the root of the tree over the sorted digests, so that it does not depend on the
+//! order the programs were registered in. Runs of the aggregation program given an allowlist only
+//! verify proofs of the programs in it, and commit its root.
+
+use alloc::vec::Vec;
+
+use crate::{compute_root, MerkleHasher, TreeVersion};
+
+/// The vkey digests of the programs whose proofs may be aggregated.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Allowlist {
+    /// The vkey digests, sorted and without duplicates.
+    vkeys: Vec<[u8; 32]>,
+}
+
+impl Allowlist {
+    pub fn new(mut vkeys: Vec<[u8; 32]>) -> Self {
+        vkeys.sort_unstable();
+        vkeys.dedup();
+        Self { vkeys }
+    }
+
+    pub fn contains(&self, vkey: &[u8; 32]) -> bool {
+        self.vkeys.binary_search(vkey).is_ok()
+    }
+
+    pub fn vkeys(&self) -> &[[u8; 32]] {
+        &self.vkeys
+    }
+
+    /// The root of the tree over the sorted vkey digests. The root of an empty allowlist is all
+    /// zeroes, like the root committed for a batch without an allowlist.
+    pub fn root<H: MerkleHasher + ?Sized>(&self, version: TreeVersion, hasher: &H) -> [u8; 32] {
+        compute_root(version, hasher, &self.vkeys)
+    }
+}
@@ -0,0 +1,567 @@
+//! Merkle tree primitives shared by the aggregation program, the RPC and the contract fixtures.
+//!
+//! A leaf is `sha256(vkey_hash || public_values)`, where `vkey_hash` is the little-endian byte
+//! encoding of the program's `hash_u32` digest. Programs with large public values can opt into
+//! [`LeafFormat::Digest`] leaves, which only commit the digest of the public values, and a request
+//! can bind the identity of its submitter into its leaf with [`bind_submitter`]. Internal nodes
+//! hash their children in sorted order, so proofs are plain sibling lists that
+//! `SP1AggregationVerifier.verifyMerkleProof` can check. A node without a sibling is promoted to
+//! the next layer unchanged.
+//!
+//! How leaves and internal nodes are hashed depends on the [`TreeVersion`] of the tree. Trees
+//! can also be hashed in positional order instead, so that a [`PositionalProof`] binds the index of
+//! the leaf in the batch.
+//!
+//! Nodes are hashed with a [`MerkleHasher`]. Deployments pick one with [`HashFunction`], which
+//! defaults to Keccak-256 so that EVM contracts can verify proofs cheaply.
+//!
+//! The roots of successive batches are accumulated in a [`mmr::MerkleMountainRange`], so that
+//! proofs for earlier batches stay verifiable against the latest root.
+//!
+//! Batches too large for one run of the aggregation program are aggregated as a tree of runs:
+//! each run commits a [`subtree::SubtreeCommitment`] for part of the batch, and the run above it
+//! merges the subtree roots with [`merge_roots`]. The leaves of each program form a subtree of
+//! their own, and the root of the batch is [`subtree::program_root`] over the tagged nodes of the
+//! programs, which `SP1AggregationVerifier.verifySubtreeRoot` checks.
+//!
+//! With the `sol` feature, `public_values` declares what the top run commits for the verifier
+//! contract.
+//!
+//! Deployments can restrict aggregation to registered programs with an [`allowlist::Allowlist`].
+
+#![no_std]
+
+extern crate alloc;
+
+pub mod allowlist;
+pub mod mmr;
+#[cfg(feature = "sol")]
+pub mod public_values;
+pub mod subtree;
+
+use alloc::vec::Vec;
+use core::{fmt, str::FromStr};
+use sha2::Digest;
+use tiny_keccak::{Hasher, Keccak};
+
+/// The prefix of a leaf node preimage in domain-separated trees.
+pub const LEAF_PREFIX: u8 = 0x00;
+
+/// The prefix of an internal node preimage in domain-separated trees.
+pub const NODE_PREFIX: u8 = 0x01;
+
+/// The prefix of the preimage of the node of a program in the top tree of a batch, in
+/// domain-separated trees. See [`subtree::hash_program_node`].
+pub const PROGRAM_NODE_PREFIX: u8 = 0x02;
+
+/// The format of an aggregation Merkle tree.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(u8)]
+pub enum TreeVersion {
+    /// Leaves are used as leaf nodes as is and internal nodes are `hash(min || max)`.
+    ///
+    /// An internal node can be presented as a leaf, so this version is only kept to verify
+    /// batches aggregated before [`TreeVersion::V1`].
+    V0 = 0,
+    /// Leaf nodes are `hash(0x00 || leaf)` and internal nodes are `hash(0x01 || min || max)`.
+    V1 = 1,
+}
+
+impl TreeVersion {
+    /// The version used for new batches.
+    pub const LATEST: Self = Self::V1;
+
+    /// Hash a leaf into the node stored in the bottom layer of the tree.
+    pub fn hash_leaf_node<H: MerkleHasher + ?Sized>(self, hasher: &H, leaf: &[u8; 32]) -> [u8; 32] {
+        match self {
+            Self::V0 => *leaf,
+            Self::V1 => hasher.hash(&[&[LEAF_PREFIX], leaf.as_slice()]),
+        }
+    }
+
+    /// Hash a pair of nodes in sorted order.
+    pub fn hash_pair<H: MerkleHasher + ?Sized>(
+        self,
+        hasher: &H,
+        a: &[u8; 32],
+        b: &[u8; 32],
+    ) -> [u8; 32] {
+        if a <= b {
+            self.hash_ordered_pair(hasher, a, b)
+        } else {
+            self.hash_ordered_pair(hasher, b, a)
+        }
+    }
+
+    /// Hash a left and a right node in that order.
+    pub fn hash_ordered_pair<H: MerkleHasher + ?Sized>(
+        self,
+        hasher: &H,
+        left: &[u8; 32],
+        right: &[u8; 32],
+    ) -> [u8; 32] {
+        match self {
+            Self::V0 => hasher.hash(&[left.as_slice(), right.as_slice()]),
+            Self::V1 => hasher.hash(&[&[NODE_PREFIX], left.as_slice(), right.as_slice()]),
+        }
+    }
+}
+
+impl TryFrom<u8> for TreeVersion {
+    type Error = u8;
+
+    fn try_from(version: u8) -> Result<Self, Self::Error> {
+        match version {
+            0 => Ok(Self::V0),
+            1 => Ok(Self::V1),
+            _ => Err(version),
+        }
+    }
+}
+
+/// A hash function for the nodes of a Merkle tree.
+pub trait MerkleHasher {
+    /// Hash the concatenation of `chunks`.
+    fn hash(&self, chunks: &[&[u8]]) -> [u8; 32];
+}
+
+/// Keccak-256, which the EVM computes natively.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Keccak256;
+
+impl MerkleHasher for Keccak256 {
+    fn hash(&self, chunks: &[&[u8]]) -> [u8; 32] {
+        let mut hasher = Keccak::v256();
+        let mut output = [0u8; 32];
+        for chunk in chunks {
+            hasher.update(chunk);
+        }
+        hasher.finalize(&mut output);
+        output
+    }
+}
+
+/// SHA-256, which is cheaper than Keccak-256 on most non-EVM targets and in the zkVM.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Sha256;
+
+impl MerkleHasher for Sha256 {
+    fn hash(&self, chunks: &[&[u8]]) -> [u8; 32] {
+        let mut hasher = sha2::Sha256::new();
+        for chunk in chunks {
+            hasher.update(chunk);
+        }
+        hasher.finalize().into()
+    }
+}
+
+/// The hash function a deployment uses for the nodes of its trees.
+///
+/// The RPC, the aggregation program and the verifier contract must all use the same one.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(u8)]
+pub enum HashFunction {
+    /// See [`Keccak256`].
+    #[default]
+    Keccak256 = 0,
+    /// See [`Sha256`].
+    Sha256 = 1,
+}
+
+impl MerkleHasher for HashFunction {
+    fn hash(&self, chunks: &[&[u8]]) -> [u8; 32] {
+        match self {
+            Self::Keccak256 => Keccak256.hash(chunks),
+            Self::Sha256 => Sha256.hash(chunks),
+        }
+    }
+}
+
+impl TryFrom<u8> for HashFunction {
+    type Error = u8;
+
+    fn try_from(hash_function: u8) -> Result<Self, Self::Error> {
+        match hash_function {
+            0 => Ok(Self::Keccak256),
+            1 => Ok(Self::Sha256),
+            _ => Err(hash_function),
+        }
+    }
+}
+
+/// The error returned when parsing an unknown [`HashFunction`] name.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnknownHashFunction;
+
+impl fmt::Display for UnknownHashFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("unknown hash function, expected `keccak256` or `sha256`")
+    }
+}
+
+impl FromStr for HashFunction {
+    type Err = UnknownHashFunction;
+
+    /// Parse the name of a hash function as it appears in configuration.
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "keccak256" => Ok(Self::Keccak256),
+            "sha256" => Ok(Self::Sha256),
+            _ => Err(UnknownHashFunction),
+        }
+    }
+}
+
+/// Convert a vkey digest given as eight `u32` words into its little-endian byte encoding.
+pub fn words_to_bytes_le(words: &[u32; 8]) -> [u8; 32] {
+    let mut bytes = [0u8; 32];
+    for (i, word) in words.iter().enumerate() {
+        bytes[i * 4..(i + 1) * 4].copy_from_slice(&word.to_le_bytes());
+    }
+    bytes
+}
+
+/// Compute the leaf for a proof of the program with the given vkey hash and public values.
+///
+/// Leaves are always SHA-256 digests, whatever [`HashFunction`] the tree uses for its nodes.
+pub fn hash_leaf(vkey_hash: &[u8; 32], public_values: &[u8]) -> [u8; 32] {
+    Sha256.hash(&[vkey_hash.as_slice(), public_values])
+}
+
+/// The tag a [`LeafFormat::Digest`] leaf preimage starts with, in place of a vkey hash, so that it
+/// can't be mistaken for the preimage of a [`LeafFormat::Full`] leaf.
+pub const DIGEST_LEAF_TAG: [u8; 32] = *b"aggregation-merkle/digest-leaf/1";
+
+/// Compute the [`LeafFormat::Digest`] leaf for a proof of the program with the given vkey hash
+/// and SHA-256 digest of its public values.
+pub fn hash_digest_leaf(vkey_hash: &[u8; 32], public_values_digest: &[u8; 32]) -> [u8; 32] {
+    Sha256.hash(&[
+        DIGEST_LEAF_TAG.as_slice(),
+        vkey_hash.as_slice(),
+        public_values_digest.as_slice(),
+    ])
+}
+
+/// The tag the preimage of a leaf bound to a submitter starts with, so that it can't be mistaken
+/// for the preimage of another leaf.
+pub const SUBMITTER_LEAF_TAG: [u8; 32] = *b"aggregation-merkle/submitter/001";
+
+/// Bind the leaf of a request to the identity of its submitter, such as an EVM address or a public
+/// key, so that only the submitter can claim the aggregated proof. A request without a submitter
+/// keeps its leaf.
+pub fn bind_submitter(leaf: &[u8; 32], submitter: &[u8]) -> [u8; 32] {
+    if submitter.is_empty() {
+        return *leaf;
+    }
+    Sha256.hash(&[SUBMITTER_LEAF_TAG.as_slice(), leaf.as_slice(), submitter])
+}
+
+/// What the leaf of a proof commits to besides the vkey hash of its program.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(u8)]
+pub enum LeafFormat {
+    /// The public values themselves, see [`hash_leaf`].
+    #[default]
+    Full = 0,
+    /// The SHA-256 digest of the public values, see [`hash_digest_leaf`]. The aggregation program
+    /// is only given the digest, and verifiers only need the digest to check an inclusion proof.
+    Digest = 1,
+}
+
+impl TryFrom<u8> for LeafFormat {
+    type Error = u8;
+
+    fn try_from(format: u8) -> Result<Self, Self::Error> {
+        match format {
+            0 => Ok(Self::Full),
+            1 => Ok(Self::Digest),
+            _ => Err(format),
+        }
+    }
+}
+
+impl LeafFormat {
+    /// Compute the leaf in this format for a proof of the program with the given vkey hash and
+    /// public values.
+    pub fn hash_leaf(self, vkey_hash: &[u8; 32], public_values: &[u8]) -> [u8; 32] {
+        match self {
+            Self::Full => hash_leaf(vkey_hash, public_values),
+            Self::Digest => hash_digest_leaf(vkey_hash, &Sha256.hash(&[public_values])),
+        }
+    }
+}
+
+/// How the children of an internal node are ordered before they are hashed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(u8)]
+pub enum NodeOrdering {
+    /// The smaller child comes first, so proofs don't need to say which side a sibling is on.
+    #[default]
+    Sorted = 0,
+    /// The left child comes first, so the root commits to the position of every leaf.
+    Positional = 1,
+}
+
+impl TryFrom<u8> for NodeOrdering {
+    type Error = u8;
+
+    fn try_from(ordering: u8) -> Result<Self, Self::Error> {
+        match ordering {
+            0 => Ok(Self::Sorted),
+            1 => Ok(Self::Positional),
+            _ => Err(ordering),
+        }
+    }
+}
+
+impl NodeOrdering {
+    /// Hash a left and a right node according to this ordering.
+    pub fn hash_children<H: MerkleHasher + ?Sized>(
+        self,
+        version: TreeVersion,
+        hasher: &H,
+        left: &[u8; 32],
+        right: &[u8; 32],
+    ) -> [u8; 32] {
+        match self {
+            Self::Sorted => version.hash_pair(hasher, left, right),
+            Self::Positional => version.hash_ordered_pair(hasher, left, right),
+        }
+    }
+}
+
+/// Compute the layer above `layer`, promoting a trailing unpaired node as is.
+pub fn next_layer<H: MerkleHasher + ?Sized>(
+    version: TreeVersion,
+    hasher: &H,
+    layer: &[[u8; 32]],
+) -> Vec<[u8; 32]> {
+    next_layer_with_ordering(version, hasher, NodeOrdering::Sorted, layer)
+}
+
+/// Compute the layer above `layer` with the given node ordering.
+pub fn next_layer_with_ordering<H: MerkleHasher + ?Sized>(
+    version: TreeVersion,
+    hasher: &H,
+    ordering: NodeOrdering,
+    layer: &[[u8; 32]],
+) -> Vec<[u8; 32]> {
+    layer
+        .chunks(2)
+        .map(|chunk| match chunk {
+            [left, right] => ordering.hash_children(version, hasher, left, right),
+            [node] => *node,
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+/// Compute the root of the tree over `leaves`. The root of an empty tree is all zeroes.
+pub fn compute_root<H: MerkleHasher + ?Sized>(
+    version: TreeVersion,
+    hasher: &H,
+    leaves: &[[u8; 32]],
+) -> [u8; 32] {
+    compute_root_with_ordering(version, hasher, NodeOrdering::Sorted, leaves)
+}
+
+/// Compute the root of a tree from the roots of consecutive subtrees of its leaves, or all zeroes
+/// if there are none.
+///
+/// Every subtree but the last must hold the same power-of-two number of leaves, and the last at
+/// most as many, see [`subtree::is_aligned`]. The subtree roots are then a layer of the tree over
+/// all the leaves, so the result is the root [`compute_root`] gives over all of them, and proofs
+/// against it are plain proofs through both levels.
+pub fn merge_roots<H: MerkleHasher + ?Sized>(
+    version: TreeVersion,
+    hasher: &H,
+    roots: &[[u8; 32]],
+) -> [u8; 32] {
+    if roots.is_empty() {
+        return [0; 32];
+    }
+    let mut layer = roots.to_vec();
+    while layer.len() > 1 {
+        layer = next_layer(version, hasher, &layer);
+    }
+    layer[0]
+}
+
+/// Compute the root of the tree over `leaves` with the given node ordering.
+pub fn compute_root_with_ordering<H: MerkleHasher + ?Sized>(
+    version: TreeVersion,
+    hasher: &H,
+    ordering: NodeOrdering,
+    leaves: &[[u8; 32]],
+) -> [u8; 32] {
+    if leaves.is_empty() {
+        return [0; 32];
+    }
+    let mut layer: Vec<[u8; 32]> = leaves
+        .iter()
+        .map(|leaf| version.hash_leaf_node(hasher, leaf))
+        .collect();
+    while layer.len() > 1 {
+        layer = next_layer_with_ordering(version, hasher, ordering, &layer);
+    }
+    layer[0]
+}
+
+/// Compute the root implied by `leaf` and its sibling path.
+pub fn process_proof<H: MerkleHasher + ?Sized>(
+    version: TreeVersion,
+    hasher: &H,
+    leaf: [u8; 32],
+    proof: &[[u8; 32]],
+) -> [u8; 32] {
+    proof
+        .iter()
+        .fold(version.hash_leaf_node(hasher, &leaf), |current, sibling| {
+            version.hash_pair(hasher, &current, sibling)
+        })
+}
+
+/// A Merkle proof that binds the position of the leaf in a tree hashed in positional order.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PositionalProof {
+    /// The index of the leaf in the batch.
+    pub index: u64,
+    /// The number of leaves in the batch, which determines the levels where a node is promoted.
+    pub leaf_count: u64,
+    /// The siblings on the path from the leaf to the root.
+    pub siblings: Vec<[u8; 32]>,
+    /// For each sibling, whether the node on the path is the left child.
+    pub is_left: Vec<bool>,
+}
+
+/// Compute the sides of the path from the leaf at `index` to the root. Each entry says whether
+/// the node on the path is the left child; levels where the node is promoted are skipped.
+pub fn positional_path(index: u64, leaf_count: u64) -> Vec<bool> {
+    let mut path = Vec::new();
+    let mut index = index;
+    let mut level_size = leaf_count;
+    while level_size > 1 {
+        let promoted = index == level_size - 1 && level_size % 2 == 1;
+        if !promoted {
+            path.push(index % 2 == 0);
+        }
+        index /= 2;
+        level_size = level_size.div_ceil(2);
+    }
+    path
+}
+
+/// Compute the root implied by `leaf` and a positional proof, or `None` if the path doesn't match
+/// the claimed index.
+pub fn process_positional_proof<H: MerkleHasher + ?Sized>(
+    version: TreeVersion,
+    hasher: &H,
+    leaf: [u8; 32],
+    proof: &PositionalProof,
+) -> Option<[u8; 32]> {
+    if proof.index >= proof.leaf_count
+        || proof.siblings.len() != proof.is_left.len()
+        || proof.is_left != positional_path(proof.index, proof.leaf_count)
+    {
+        return None;
+    }
+    let root = proof.siblings.iter().zip(proof.is_left.iter()).fold(
+        version.hash_leaf_node(hasher, &leaf),
+        |current, (sibling, &is_left)| {
+            if is_left {
+                version.hash_ordered_pair(hasher, &current, sibling)
+            } else {
+                version.hash_ordered_pair(hasher, sibling, &current)
+            }
+        },
+    );
+    Some(root)
+}
+
+/// A proof that several leaves are in the same tree.
+///
+/// Like the output of OpenZeppelin's `getMultiProof`, it holds the proven leaves in tree order, the
+/// nodes that can't be computed from them, and one flag per hash saying whether its second operand
+/// is the next computed node (`true`) or the next proof node (`false`). Hashes are computed level
+/// by level, left to right, which is the order `multiProofVerify` uses for a complete tree. Since
+/// an unpaired node is promoted instead of hashed, the proof also carries the index of each leaf
+/// and the leaf count, so the verifier knows which nodes skip a level. OpenZeppelin can only verify
+/// V0 proofs of complete trees; `SP1AggregationVerifier.verifyMultiProof` verifies V1 proofs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MultiProof {
+    /// The number of leaves in the tree.
+    pub leaf_count: u64,
+    /// The indices of the proven leaves, in ascending order.
+    pub indices: Vec<u64>,
+    /// The proven leaves, in the same order as `indices`.
+    pub leaves: Vec<[u8; 32]>,
+    /// The sibling nodes that can't be computed from the proven leaves, in the order they are used.
+    pub proof: Vec<[u8; 32]>,
+    /// For each hash, whether the second operand is computed from the proven leaves.
+    pub proof_flags: Vec<bool>,
+}
+
+/// Compute the root implied by a multiproof, or `None` if the proof is malformed.
+pub fn process_multiproof<H: MerkleHasher + ?Sized>(
+    version: TreeVersion,
+    hasher: &H,
+    proof: &MultiProof,
+) -> Option<[u8; 32]> {
+    if proof.leaves.is_empty()
+        || proof.leaves.len() != proof.indices.len()
+        || proof.indices.windows(2).any(|pair| pair[0] >= pair[1])
+        || proof
+            .indices
+            .last()
+            .is_some_and(|&index| index >= proof.leaf_count)
+    {
+        return None;
+    }
+
+    let mut layer: Vec<(u64, [u8; 32])> = proof
+        .indices
+        .iter()
+        .zip(proof.leaves.iter())
+        .map(|(&index, leaf)| (index, version.hash_leaf_node(hasher, leaf)))
+        .collect();
+    let mut proof_nodes = proof.proof.iter();
+    let mut proof_flags = proof.proof_flags.iter();
+    let mut level_size = proof.leaf_count;
+    while level_size > 1 {
+        let mut next = Vec::with_capacity(layer.len());
+        let mut i = 0;
+        while i < layer.len() {
+            let (index, node) = layer[i];
+            if index == level_size - 1 && level_size % 2 == 1 {
+                next.push((index / 2, node));
+                i += 1;
+                continue;
+            }
+            let sibling_is_known = layer.get(i + 1).is_some_and(|&(next, _)| next == index ^ 1);
+            if *proof_flags.next()? != sibling_is_known {
+                return None;
+            }
+            let sibling = if sibling_is_known {
+                i += 2;
+                layer[i - 1].1
+            } else {
+                i += 1;
+                *proof_nodes.next()?
+            };
+            next.push((index / 2, version.hash_pair(hasher, &node, &sibling)));
+        }
+        layer = next;
+        level_size = level_size.div_ceil(2);
+    }
+
+    if proof_nodes.next().is_some() || proof_flags.next().is_some() {
+        return None;
+    }
+    Some(layer[0].1)
+}
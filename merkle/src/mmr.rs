@@ -0,0 +1,135 @@
+//! An append-only Merkle Mountain Range over the roots of aggregated batches.
+//!
+//! The accumulator is a list of perfect trees ("mountains") of decreasing height, one for each set
+//! bit of the number of batches. Appending a batch root adds a mountain of height zero and merges
+//! mountains of equal height, so the nodes of earlier batches never change. The root of the
+//! accumulator bags the peaks from right to left, `hash(p0, hash(p1, ... hash(pn-1, pn)))`.
+//!
+//! Mountains and the bagging use the same sorted internal node hash as the batch trees, so the
+//! siblings from a leaf to its batch root followed by the siblings from the batch root to the
+//! accumulator root form a plain proof that [`crate::process_proof`] can check.
+
+use alloc::vec::Vec;
+
+use crate::{next_layer, MerkleHasher, TreeVersion};
+
+/// The peaks of a Merkle Mountain Range, which is all the state needed to extend it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MerkleMountainRange {
+    /// The number of batch roots appended so far.
+    pub leaf_count: u64,
+    /// The roots of the mountains, from the highest (leftmost) to the lowest.
+    pub peaks: Vec<[u8; 32]>,
+}
+
+impl MerkleMountainRange {
+    /// An empty accumulator, whose root is all zeroes.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Rebuild the accumulator over the given batch roots.
+    pub fn from_leaves<H: MerkleHasher + ?Sized>(
+        version: TreeVersion,
+        hasher: &H,
+        leaves: &[[u8; 32]],
+    ) -> Self {
+        let mut mmr = Self::new();
+        for leaf in leaves {
+            mmr.append(version, hasher, *leaf);
+        }
+        mmr
+    }
+
+    /// Whether there is one peak for each mountain implied by the leaf count.
+    pub fn is_valid(&self) -> bool {
+        self.peaks.len() == self.leaf_count.count_ones() as usize
+    }
+
+    /// Append a batch root, merging the mountains it completes.
+    pub fn append<H: MerkleHasher + ?Sized>(
+        &mut self,
+        version: TreeVersion,
+        hasher: &H,
+        leaf: [u8; 32],
+    ) {
+        let mut node = leaf;
+        for _ in 0..self.leaf_count.trailing_ones() {
+            let left = self
+                .peaks
+                .pop()
+                .expect("one peak per set bit of the leaf count");
+            node = version.hash_pair(hasher, &left, &node);
+        }
+        self.peaks.push(node);
+        self.leaf_count += 1;
+    }
+
+    /// The root of the accumulator. The root of an empty accumulator is all zeroes.
+    pub fn root<H: MerkleHasher + ?Sized>(&self, version: TreeVersion, hasher: &H) -> [u8; 32] {
+        bag_peaks(version, hasher, &self.peaks)
+    }
+}
+
+fn bag_peaks<H: MerkleHasher + ?Sized>(
+    version: TreeVersion,
+    hasher: &H,
+    peaks: &[[u8; 32]],
+) -> [u8; 32] {
+    let Some((last, rest)) = peaks.split_last() else {
+        return [0; 32];
+    };
+    rest.iter()
+        .rev()
+        .fold(*last, |acc, peak| version.hash_pair(hasher, peak, &acc))
+}
+
+/// Compute the siblings from the batch root at `index` to the root of the accumulator over
+/// `leaves`, or `None` if `index` is out of bounds.
+pub fn mmr_proof<H: MerkleHasher + ?Sized>(
+    version: TreeVersion,
+    hasher: &H,
+    leaves: &[[u8; 32]],
+    index: usize,
+) -> Option<Vec<[u8; 32]>> {
+    if index >= leaves.len() {
+        return None;
+    }
+
+    // Split the leaves into mountains, from the highest to the lowest.
+    let mut mountains = Vec::new();
+    let mut start = 0;
+    for height in (0..usize::BITS).rev() {
+        let size = 1usize << height;
+        if leaves.len() & size != 0 {
+            mountains.push(start..start + size);
+            start += size;
+        }
+    }
+    let mountain = mountains
+        .iter()
+        .position(|mountain| mountain.contains(&index))?;
+
+    let mut proof = Vec::new();
+    let mut peaks = Vec::with_capacity(mountains.len());
+    for (i, range) in mountains.iter().enumerate() {
+        let mut layer = leaves[range.clone()].to_vec();
+        let mut position = index.wrapping_sub(range.start);
+        while layer.len() > 1 {
+            if i == mountain {
+                proof.push(layer[position ^ 1]);
+                position /= 2;
+            }
+            layer = next_layer(version, hasher, &layer);
+        }
+        peaks.push(layer[0]);
+    }
+
+    // Bagging hashes the peak with the bag of the lower peaks, then with each higher peak.
+    if mountain + 1 < peaks.len() {
+        proof.push(bag_peaks(version, hasher, &peaks[mountain + 1..]));
+    }
+    proof.extend(peaks[..mountain].iter().rev());
+    Some(proof)
+}
@@ -0,0 +1,55 @@
+//! The public values the aggregation program commits for a batch.
+//!
+//! The run at the top of a batch commits an ABI-encoded [`AggregationPublicValues`], which
+//! `SP1AggregationVerifier.verifyAggregationProof` decodes into the struct of the same name, so
+//! the contract knows which batch a proof is for and where it goes in the accumulator.
+
+use alloc::vec::Vec;
+
+use alloy_sol_types::{sol, SolValue};
+
+sol! {
+    /// What the aggregation program commits for a batch. The layout must match
+    /// `SP1AggregationVerifier.AggregationPublicValues`.
+    #[derive(Debug, PartialEq, Eq)]
+    struct AggregationPublicValues {
+        /// The id the aggregation service gave the batch.
+        bytes32 batchId;
+        /// The index of the batch in the accumulator, which is the number of batches before it.
+        uint64 batchIndex;
+        /// The number of leaves of the batch tree.
+        uint64 leafCount;
+        /// When the batch was aggregated, in seconds since the Unix epoch.
+        uint64 timestamp;
+        /// The root of the batch tree, the top tree over the subtree of each program.
+        bytes32 batchRoot;
+        /// The root of the accumulator before the batch was appended.
+        bytes32 previousRoot;
+        /// The root of the accumulator after the batch was appended.
+        bytes32 newRoot;
+        /// The vkey digest of the aggregation program, in the byte encoding of
+        /// `words_to_bytes_le`, which the runs merged into the batch were verified with.
+        bytes32 aggregationVKeyDigest;
+        /// The root of the allowlist of programs the proofs of the batch were checked against, or
+        /// all zeroes if there was none.
+        bytes32 allowlistRoot;
+        /// For each leaf of the batch tree, the index of its request in the batch as the worker
+        /// reported it, with requests whose proofs failed to verify left out. The program only
+        /// checks that there is one increasing index per leaf, not which request each leaf came
+        /// from, so the leaves are what the proof binds.
+        uint64[] requestIndices;
+    }
+}
+
+impl AggregationPublicValues {
+    /// ABI-encode the public values, as the contract expects them.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.abi_encode()
+    }
+
+    /// Decode public values encoded with [`AggregationPublicValues::to_bytes`], or `None` if
+    /// `bytes` is not a valid encoding.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        Self::abi_decode(bytes, true).ok()
+    }
+}
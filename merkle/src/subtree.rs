@@ -0,0 +1,144 @@
+//! The commitment of an aggregation proof over part of a batch.
+//!
+//! A run of the aggregation program either verifies user proofs or merges the commitments of
+//! earlier runs over consecutive parts of the batch. Each run below the top one commits a
+//! [`SubtreeCommitment`], which the run above it verifies with the vkey of the aggregation program
+//! itself. The commitment carries that vkey, so that a merge only accepts proofs of the same
+//! program, and the top run commits it for the verifier contract to check.
+//!
+//! The tree of a batch has one subtree per program: the leaves of the proofs of each program,
+//! which runs below the top one never mix, and a top tree over the [`hash_program_node`] of each
+//! program in ascending order of vkey hash, see [`program_root`]. A proof of a leaf against the
+//! root of the batch is a proof against the root of its subtree, followed by the path of the
+//! program node through the top tree, see [`process_program_proof`].
+
+use alloc::vec::Vec;
+
+use crate::{merge_roots, HashFunction, MerkleHasher, TreeVersion, PROGRAM_NODE_PREFIX};
+
+/// What an aggregation proof over consecutive leaves of a batch commits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SubtreeCommitment {
+    /// The vkey digest of the aggregation program, in the byte encoding of
+    /// [`crate::words_to_bytes_le`].
+    pub aggregation_vkey: [u8; 32],
+    /// The vkey hash of the program whose proofs are the leaves of the subtree.
+    pub program_vkey: [u8; 32],
+    /// The hash function of the subtree nodes.
+    pub hash_function: HashFunction,
+    /// The number of leaves of the subtree.
+    pub leaf_count: u64,
+    /// The root of the subtree.
+    pub root: [u8; 32],
+    /// The root of the allowlist the proofs of the subtree were checked against, or all zeroes if
+    /// there was none.
+    pub allowlist_root: [u8; 32],
+}
+
+impl SubtreeCommitment {
+    /// The length of the encoding of a commitment.
+    pub const LEN: usize = 32 + 32 + 1 + 8 + 32 + 32;
+
+    /// Encode the commitment as `aggregation_vkey || program_vkey || hash_function || leaf_count
+    /// || root || allowlist_root`, where `hash_function` is one byte and `leaf_count` is a
+    /// big-endian `u64`.
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        let mut bytes = [0u8; Self::LEN];
+        bytes[..32].copy_from_slice(&self.aggregation_vkey);
+        bytes[32..64].copy_from_slice(&self.program_vkey);
+        bytes[64] = self.hash_function as u8;
+        bytes[65..73].copy_from_slice(&self.leaf_count.to_be_bytes());
+        bytes[73..105].copy_from_slice(&self.root);
+        bytes[105..].copy_from_slice(&self.allowlist_root);
+        bytes
+    }
+
+    /// Decode a commitment encoded with [`SubtreeCommitment::to_bytes`], or `None` if `bytes` is
+    /// not a valid encoding.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != Self::LEN {
+            return None;
+        }
+        Some(Self {
+            aggregation_vkey: bytes[..32].try_into().unwrap(),
+            program_vkey: bytes[32..64].try_into().unwrap(),
+            hash_function: HashFunction::try_from(bytes[64]).ok()?,
+            leaf_count: u64::from_be_bytes(bytes[65..73].try_into().unwrap()),
+            root: bytes[73..105].try_into().unwrap(),
+            allowlist_root: bytes[105..].try_into().unwrap(),
+        })
+    }
+}
+
+/// Whether `subtrees` can be merged with [`crate::merge_roots`]: there is at least one, every one
+/// but the last holds the same power-of-two number of leaves, and the last holds at least one leaf
+/// and at most as many.
+pub fn is_aligned(subtrees: &[SubtreeCommitment]) -> bool {
+    let Some((last, rest)) = subtrees.split_last() else {
+        return false;
+    };
+    match rest.first() {
+        None => last.leaf_count > 0,
+        Some(first) => {
+            first.leaf_count.is_power_of_two()
+                && rest
+                    .iter()
+                    .all(|subtree| subtree.leaf_count == first.leaf_count)
+                && (1..=first.leaf_count).contains(&last.leaf_count)
+        }
+    }
+}
+
+/// Hash the vkey hash of a program and the root of the subtree of its leaves into the node of the
+/// program in the top tree of a batch.
+///
+/// The node is `hash(0x02 || vkey_hash || subtree_root)`, which can't be mistaken for a leaf node
+/// or an internal node, so no leaf or subtree root can be presented as the node of a program.
+/// [`TreeVersion::V0`] trees have no prefixes, so their program nodes are
+/// `hash(vkey_hash || subtree_root)`.
+pub fn hash_program_node<H: MerkleHasher + ?Sized>(
+    version: TreeVersion,
+    hasher: &H,
+    vkey_hash: &[u8; 32],
+    subtree_root: &[u8; 32],
+) -> [u8; 32] {
+    match version {
+        TreeVersion::V0 => hasher.hash(&[vkey_hash.as_slice(), subtree_root.as_slice()]),
+        TreeVersion::V1 => hasher.hash(&[
+            &[PROGRAM_NODE_PREFIX],
+            vkey_hash.as_slice(),
+            subtree_root.as_slice(),
+        ]),
+    }
+}
+
+/// Compute the root of a batch from the subtree root of one of its programs and the siblings on
+/// the path of the program node through the top tree.
+pub fn process_program_proof<H: MerkleHasher + ?Sized>(
+    version: TreeVersion,
+    hasher: &H,
+    vkey_hash: &[u8; 32],
+    subtree_root: &[u8; 32],
+    program_proof: &[[u8; 32]],
+) -> [u8; 32] {
+    program_proof.iter().fold(
+        hash_program_node(version, hasher, vkey_hash, subtree_root),
+        |node, sibling| version.hash_pair(hasher, &node, sibling),
+    )
+}
+
+/// Compute the root of a batch from the vkey hash and the subtree root of each of its programs, in
+/// ascending order of vkey hash. The root of a batch without programs is all zeroes.
+pub fn program_root<H: MerkleHasher + ?Sized>(
+    version: TreeVersion,
+    hasher: &H,
+    programs: &[([u8; 32], [u8; 32])],
+) -> [u8; 32] {
+    let nodes = programs
+        .iter()
+        .map(|(vkey_hash, subtree_root)| {
+            hash_program_node(version, hasher, vkey_hash, subtree_root)
+        })
+        .collect::<Vec<_>>();
+    merge_roots(version, hasher, &nodes)
+}
@@ -3,65 +3,265 @@
 #![no_main]
 sp1_zkvm::entrypoint!(main);
 
-// use alloy_merkle_tree::tree::MerkleTree;
+use aggregation_merkle::{
+    allowlist::Allowlist,
+    bind_submitter, compute_root, hash_digest_leaf, hash_leaf, merge_roots,
+    mmr::MerkleMountainRange,
+    public_values::AggregationPublicValues,
+    subtree::{is_aligned, program_root, SubtreeCommitment},
+    words_to_bytes_le, HashFunction, LeafFormat, TreeVersion,
+};
 use sha2::Digest;
 use sha2::Sha256;
 
-pub fn words_to_bytes_le(words: &[u32; 8]) -> [u8; 32] {
-    let mut bytes = [0u8; 32];
-    for i in 0..8 {
-        let word_bytes = words[i].to_le_bytes();
-        bytes[i * 4..(i + 1) * 4].copy_from_slice(&word_bytes);
+/// The digest of the public values of a proof, which are given as is for a full leaf and as
+/// their digest for a digest leaf.
+pub fn public_values_digest(public_values: &[u8], leaf_format: LeafFormat) -> [u8; 32] {
+    match leaf_format {
+        LeafFormat::Full => Sha256::digest(public_values).into(),
+        LeafFormat::Digest => public_values
+            .try_into()
+            .expect("Invalid public values digest"),
     }
-    bytes
 }
 
-/// Encode a list of vkeys and public values into a merkle tree. Returns the root of the tree.
-pub fn create_merkle_tree(vkeys: &[[u32; 8]], public_values: &[Vec<u8>]) -> Vec<u8> {
+/// The vkey hash of a program, the root of the subtree of its leaves and its number of leaves.
+type ProgramSubtree = ([u8; 32], [u8; 32], u64);
+
+/// Group consecutive items by the vkey hash of their program. The programs must be in ascending
+/// order of vkey hash, so that each one has a single subtree.
+fn group_by_program<T>(items: &[T], vkey_hash: impl Fn(&T) -> [u8; 32]) -> Vec<([u8; 32], &[T])> {
+    let programs = items
+        .chunk_by(|a, b| vkey_hash(a) == vkey_hash(b))
+        .map(|program| (vkey_hash(&program[0]), program))
+        .collect::<Vec<_>>();
+    assert!(
+        programs.windows(2).all(|pair| pair[0].0 < pair[1].0),
+        "Programs are not in ascending vkey hash order"
+    );
+    programs
+}
+
+/// Encode a list of vkeys and public values into a merkle tree per program, with each leaf in its
+/// format and bound to its submitter. Returns the subtree of each program.
+pub fn create_merkle_trees(
+    vkeys: &[[u32; 8]],
+    public_values: &[Vec<u8>],
+    leaf_formats: &[LeafFormat],
+    submitters: &[Vec<u8>],
+    hash_function: HashFunction,
+) -> Vec<ProgramSubtree> {
     assert_eq!(vkeys.len(), public_values.len());
-    let mut leaves = Vec::new(); // Initialize the Merkle tree
-    for (vkey, public_value) in vkeys.iter().zip(public_values.iter()) {
-        let vkey_bytes = words_to_bytes_le(vkey);
-        let pair = [public_value.as_slice(), &vkey_bytes].concat();
-        let pair_hash = Sha256::digest(&pair);
-        leaves.push(pair_hash.to_vec());
-    }
-    let mut tree = Vec::new();
-    let mut current_layer = leaves.clone();
-    while current_layer.len() > 1 {
-        let mut next_layer = Vec::new();
-        for chunk in current_layer.chunks(2) {
-            if chunk.len() == 2 {
-                next_layer.push(Sha256::digest(chunk.concat()).to_vec());
-            } else {
-                next_layer.push(Sha256::digest(chunk[0].clone()).to_vec());
-            }
-        }
-        tree.extend(current_layer);
-        current_layer = next_layer;
-    }
-    let root = tree.clone().last().unwrap().clone();
-    root
+    assert_eq!(vkeys.len(), leaf_formats.len());
+    assert_eq!(vkeys.len(), submitters.len());
+    let leaves = (0..vkeys.len())
+        .map(|i| {
+            let vkey_hash = words_to_bytes_le(&vkeys[i]);
+            let leaf = match leaf_formats[i] {
+                LeafFormat::Full => hash_leaf(&vkey_hash, &public_values[i]),
+                LeafFormat::Digest => hash_digest_leaf(
+                    &vkey_hash,
+                    &public_values_digest(&public_values[i], leaf_formats[i]),
+                ),
+            };
+            (vkey_hash, bind_submitter(&leaf, &submitters[i]))
+        })
+        .collect::<Vec<_>>();
+    group_by_program(&leaves, |(vkey_hash, _)| *vkey_hash)
+        .into_iter()
+        .map(|(vkey_hash, program)| {
+            let leaves = program.iter().map(|(_, leaf)| *leaf).collect::<Vec<_>>();
+            (
+                vkey_hash,
+                compute_root(TreeVersion::LATEST, &hash_function, &leaves),
+                leaves.len() as u64,
+            )
+        })
+        .collect()
+}
+
+/// Verify the aggregation proofs of consecutive parts of a batch, which must have been proven with
+/// `aggregation_vkey` and checked against the allowlist with `allowlist_root`, and merge the roots
+/// of the parts of each program. Returns the subtree of each program.
+pub fn merge_subtrees(
+    aggregation_vkey: &[u32; 8],
+    subtrees: &[Vec<u8>],
+    hash_function: HashFunction,
+    allowlist_root: [u8; 32],
+) -> Vec<ProgramSubtree> {
+    let commitments = subtrees
+        .iter()
+        .map(|public_values| {
+            let public_values_digest = Sha256::digest(public_values);
+            sp1_zkvm::lib::verify::verify_sp1_proof(aggregation_vkey, &public_values_digest.into());
+            let commitment =
+                SubtreeCommitment::from_bytes(public_values).expect("Invalid subtree commitment");
+            assert_eq!(
+                commitment.aggregation_vkey,
+                words_to_bytes_le(aggregation_vkey),
+                "Subtree was aggregated by another program"
+            );
+            assert_eq!(
+                commitment.hash_function, hash_function,
+                "Subtree was hashed with another hash function"
+            );
+            assert_eq!(
+                commitment.allowlist_root, allowlist_root,
+                "Subtree was checked against another allowlist"
+            );
+            commitment
+        })
+        .collect::<Vec<_>>();
+    group_by_program(&commitments, |commitment| commitment.program_vkey)
+        .into_iter()
+        .map(|(vkey_hash, program)| {
+            assert!(is_aligned(program), "Subtrees are not aligned");
+            let roots = program
+                .iter()
+                .map(|commitment| commitment.root)
+                .collect::<Vec<_>>();
+            (
+                vkey_hash,
+                merge_roots(TreeVersion::LATEST, &hash_function, &roots),
+                program.iter().map(|commitment| commitment.leaf_count).sum(),
+            )
+        })
+        .collect()
 }
 
 pub fn main() {
     // Read the verification keys.
     let vkeys = sp1_zkvm::io::read::<Vec<[u32; 8]>>();
 
-    // Read the public values.
+    // Read the public values, or their digest for digest leaves.
     let public_values = sp1_zkvm::io::read::<Vec<Vec<u8>>>();
 
-    // Verify the proofs.
-    assert_eq!(vkeys.len(), public_values.len());
-    for i in 0..vkeys.len() {
-        let vkey = &vkeys[i];
-        let public_values = &public_values[i];
-        let public_values_digest = Sha256::digest(public_values);
-        sp1_zkvm::lib::verify::verify_sp1_proof(vkey, &public_values_digest.into());
-        // TODO: maybe have an array of bools to indicate whether verification succeeded or not and include this in the merkle tree?
-    }
+    // Read the format of the leaf of each proof.
+    let leaf_formats = sp1_zkvm::io::read::<Vec<LeafFormat>>();
+
+    // Read the submitter each leaf is bound to, empty if it is unbound.
+    let submitters = sp1_zkvm::io::read::<Vec<Vec<u8>>>();
+
+    // Read the hash function of the tree, which must match the one of the verifier contract.
+    let hash_function =
+        HashFunction::try_from(sp1_zkvm::io::read::<u8>()).expect("Unknown merkle hash function");
+
+    // Read the allowlist of programs, if the batch is restricted to registered programs.
+    let allowlist = sp1_zkvm::io::read::<Option<Allowlist>>();
+    let allowlist_root = allowlist.as_ref().map_or([0; 32], |allowlist| {
+        allowlist.root(TreeVersion::LATEST, &hash_function)
+    });
+
+    // Read the vkey of this program, which the proofs of earlier runs are verified with.
+    let aggregation_vkey = sp1_zkvm::io::read::<[u32; 8]>();
+
+    // Read the public values of earlier runs over consecutive parts of the batch, if this run
+    // merges them instead of verifying user proofs.
+    let subtrees = sp1_zkvm::io::read::<Vec<Vec<u8>>>();
+
+    // Read the accumulator of the batches aggregated so far, if this is the top run of the batch.
+    let accumulator = sp1_zkvm::io::read::<Option<MerkleMountainRange>>();
+
+    let programs = if subtrees.is_empty() {
+        // Verify the proofs.
+        assert_eq!(vkeys.len(), public_values.len());
+        assert_eq!(vkeys.len(), leaf_formats.len());
+        for i in 0..vkeys.len() {
+            let vkey = &vkeys[i];
+            let public_values = &public_values[i];
+            if let Some(allowlist) = &allowlist {
+                assert!(
+                    allowlist.contains(&words_to_bytes_le(vkey)),
+                    "Program is not in the allowlist"
+                );
+            }
+            let public_values_digest = public_values_digest(public_values, leaf_formats[i]);
+            // Verification can't fail without failing the whole run, so the worker verifies
+            // every proof beforehand and leaves out the invalid ones.
+            sp1_zkvm::lib::verify::verify_sp1_proof(vkey, &public_values_digest);
+        }
+
+        // Commit to the verified proofs in a merkle tree per program.
+        create_merkle_trees(
+            &vkeys,
+            &public_values,
+            &leaf_formats,
+            &submitters,
+            hash_function,
+        )
+    } else {
+        assert!(vkeys.is_empty(), "A run either verifies or merges proofs");
+        merge_subtrees(&aggregation_vkey, &subtrees, hash_function, allowlist_root)
+    };
+
+    let Some(mut accumulator) = accumulator else {
+        // Commit to the subtree for the run above this one to merge, which only covers proofs of
+        // one program so that the subtree of each program stays aligned with the runs.
+        let [(program_vkey, root, leaf_count)] = programs[..] else {
+            panic!("A run below the top one covers the proofs of one program");
+        };
+        let commitment = SubtreeCommitment {
+            aggregation_vkey: words_to_bytes_le(&aggregation_vkey),
+            program_vkey,
+            hash_function,
+            leaf_count,
+            root,
+            allowlist_root,
+        };
+        sp1_zkvm::io::commit_slice(&commitment.to_bytes());
+        return;
+    };
+
+    // The root of the batch is the root of the top tree over the subtree of each program.
+    let batch_root = program_root(
+        TreeVersion::LATEST,
+        &hash_function,
+        &programs
+            .iter()
+            .map(|(vkey_hash, root, _)| (*vkey_hash, *root))
+            .collect::<Vec<_>>(),
+    );
+    let leaf_count = programs
+        .iter()
+        .map(|(_, _, leaf_count)| leaf_count)
+        .sum::<u64>();
+
+    // Read the id of the batch, when it was aggregated, and the index in the batch of the request
+    // of each leaf. The indices are only checked to be increasing, one per leaf, since the leaves
+    // don't commit to the requests they came from.
+    let batch_id = sp1_zkvm::io::read::<[u8; 32]>();
+    let timestamp = sp1_zkvm::io::read::<u64>();
+    let request_indices = sp1_zkvm::io::read::<Vec<u64>>();
+    assert_eq!(
+        request_indices.len() as u64,
+        leaf_count,
+        "One request index per leaf"
+    );
+    assert!(
+        request_indices.windows(2).all(|pair| pair[0] < pair[1]),
+        "Request indices are not increasing"
+    );
+
+    // Append the root of the batch to the accumulator.
+    assert!(accumulator.is_valid(), "Invalid accumulator");
+    let batch_index = accumulator.leaf_count;
+    let previous_root = accumulator.root(TreeVersion::LATEST, &hash_function);
+    accumulator.append(TreeVersion::LATEST, &hash_function, batch_root);
+    let new_root = accumulator.root(TreeVersion::LATEST, &hash_function);
 
-    // Commit to the verified proofs in a merkle tree.
-    let commitment = create_merkle_tree(&vkeys, &public_values);
-    sp1_zkvm::io::commit_slice(&commitment);
+    // Commit the ABI-encoded public values for the verifier contract, which also checks the vkey
+    // that the proofs of earlier runs were verified with.
+    let public_values = AggregationPublicValues {
+        batchId: batch_id.into(),
+        batchIndex: batch_index,
+        leafCount: leaf_count,
+        timestamp,
+        batchRoot: batch_root.into(),
+        previousRoot: previous_root.into(),
+        newRoot: new_root.into(),
+        aggregationVKeyDigest: words_to_bytes_le(&aggregation_vkey).into(),
+        allowlistRoot: allowlist_root.into(),
+        requestIndices: request_indices,
+    };
+    sp1_zkvm::io::commit_slice(&public_values.to_bytes());
 }
@@ -0,0 +1,2 @@
+-- Trees written before the version column existed use the V0 format.
+ALTER TABLE merkle_trees ADD COLUMN version BIGINT NOT NULL DEFAULT 0;
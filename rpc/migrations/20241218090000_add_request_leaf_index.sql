@@ -0,0 +1,2 @@
+-- The index of the request's leaf in its batch's merkle tree, set when the batch is processed.
+ALTER TABLE requests ADD COLUMN leaf_index BIGINT NULL;
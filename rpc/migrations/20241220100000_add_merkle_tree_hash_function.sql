@@ -0,0 +1,2 @@
+-- Trees written before the hash function column existed use Keccak-256.
+ALTER TABLE merkle_trees ADD COLUMN hash_function BIGINT NOT NULL DEFAULT 0;
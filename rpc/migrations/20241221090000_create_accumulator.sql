@@ -0,0 +1,6 @@
+-- The roots of the relayed batches, in the order they were appended to the on-chain accumulator.
+CREATE TABLE accumulator (
+    leaf_index BIGINT PRIMARY KEY,
+    batch_id BYTEA NOT NULL UNIQUE,
+    root BYTEA NOT NULL
+);
@@ -0,0 +1,6 @@
+-- The state of every aggregated proof id, which the sparse merkle tree of proof ids commits to.
+CREATE TABLE proof_ids (
+    proof_id BYTEA PRIMARY KEY,
+    state INTEGER NOT NULL,
+    batch_id BYTEA NOT NULL
+);
@@ -0,0 +1,3 @@
+-- Trees written before the encoding column existed store only their concatenated leaves. Later
+-- trees store every node in the encoding version given by this column.
+ALTER TABLE merkle_trees ADD COLUMN encoding BIGINT NOT NULL DEFAULT 0;
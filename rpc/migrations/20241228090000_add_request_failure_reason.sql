@@ -0,0 +1,2 @@
+-- Why a request was not aggregated, set when it is marked as failed.
+ALTER TABLE requests ADD COLUMN failure_reason TEXT NULL;
@@ -0,0 +1,13 @@
+-- The programs whose proofs may be aggregated, with their policy. Once a program is registered,
+-- only the proofs of registered programs that have not been retired are accepted.
+CREATE TABLE programs (
+    vkey_hash BYTEA PRIMARY KEY,
+    tenant TEXT NOT NULL,
+    max_public_values_size BIGINT NOT NULL,
+    fee BIGINT NOT NULL,
+    registered_at BIGINT NOT NULL,
+    retired_at BIGINT NULL
+);
+
+-- The fee charged for aggregating the request, set when its program is registered.
+ALTER TABLE requests ADD COLUMN fee BIGINT NULL;
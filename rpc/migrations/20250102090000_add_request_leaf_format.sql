@@ -0,0 +1,2 @@
+-- What the leaf of the request commits to besides the vkey hash, as a `LeafFormat`.
+ALTER TABLE requests ADD COLUMN leaf_format INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,2 @@
+-- The identity of the submitter bound into the leaf of the request, or empty if it is unbound.
+ALTER TABLE requests ADD COLUMN submitter BYTEA NOT NULL DEFAULT '';
@@ -0,0 +1,12 @@
+-- The batches claimed by workers. A batch that is neither completed nor relayed when its lease
+-- expires is released, and its requests go back to pending.
+CREATE TABLE batch_leases (
+    batch_id BYTEA PRIMARY KEY,
+    worker_id TEXT NOT NULL,
+    expires_at BIGINT NOT NULL,
+    completed_at BIGINT NULL,
+    released_at BIGINT NULL
+);
+
+-- The batch a pending request is claimed for, so that no other worker claims it.
+ALTER TABLE requests ADD COLUMN lease_batch_id BYTEA NULL;
@@ -0,0 +1,2 @@
+-- Proof ids are derived from the request, so that a retried request maps to the existing one.
+CREATE UNIQUE INDEX requests_proof_id_key ON requests (proof_id);
@@ -0,0 +1,3 @@
+-- A batch has a single tree. Of the trees written more than once for a batch, the last one is kept.
+DELETE FROM merkle_trees a USING merkle_trees b WHERE a.batch_id = b.batch_id AND a.ctid < b.ctid;
+ALTER TABLE merkle_trees ADD CONSTRAINT merkle_trees_batch_id_key UNIQUE (batch_id);
@@ -0,0 +1,17 @@
+-- The nodes of the sparse merkle tree of proof ids that are not empty, so that the tree is updated
+-- in place as proof ids are aggregated or revoked. A node at `depth` is on the path given by the
+-- first `depth` bits of `path`, whose other bits are zero.
+CREATE TABLE proof_id_nodes (
+    depth SMALLINT NOT NULL,
+    path BYTEA NOT NULL,
+    node BYTEA NOT NULL,
+    PRIMARY KEY (depth, path)
+);
+-- The successive roots of the tree of proof ids: one after the proof ids of each relayed batch are
+-- aggregated, with the batch, and one after each revocation, with the revoked proof id.
+CREATE TABLE proof_id_roots (
+    root_index BIGINT PRIMARY KEY,
+    root BYTEA NOT NULL,
+    batch_id BYTEA NULL,
+    revoked_proof_id BYTEA NULL
+);
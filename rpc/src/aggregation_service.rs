@@ -1,20 +1,53 @@
 use crate::{db, relay, AggregationRpc};
+use chrono::Utc;
 use rand::Rng;
-use sp1_sdk::SP1ProofWithPublicValues;
-use tonic::{Request, Response, Status};
+use sp1_sdk::{HashableKey, ProverClient, SP1Proof, SP1ProofWithPublicValues, SP1VerifyingKey};
+use std::sync::Arc;
+use tonic::{metadata::MetadataValue, Request, Response, Status};
 use types::{
     aggregation::{
         aggregation_service_server::AggregationService, AggregateProofRequest,
-        AggregateProofResponse, AggregationStatusResponse, GetAggregatedDataRequest,
-        GetAggregatedDataResponse, GetAggregationStatusRequest, GetAggregationStatusResponse,
-        GetBatchRequest, GetBatchResponse, GetVkeyAndPublicValuesRequest,
-        GetVkeyAndPublicValuesResponse, ProcessBatchRequest, ProcessBatchResponse,
-        UpdateBatchStatusRequest, UpdateBatchStatusResponse, VerifyAggregationProofRequest,
-        VerifyAggregationProofResponse, WriteMerkleTreeRequest, WriteMerkleTreeResponse,
+        AggregateProofResponse, AggregationProofRejection, AggregationStatusResponse,
+        ClaimBatchRequest, ClaimBatchResponse, CompleteBatchRequest, CompleteBatchResponse,
+        GetAccumulatorRequest, GetAccumulatorResponse, GetAggregatedDataRequest,
+        GetAggregatedDataResponse, GetAggregatedMultiproofRequest, GetAggregatedMultiproofResponse,
+        GetAggregationStatusRequest, GetAggregationStatusResponse, GetProgramAllowlistRequest,
+        GetProgramAllowlistResponse, GetProofIdProofRequest, GetProofIdProofResponse,
+        GetVkeyAndPublicValuesRequest, GetVkeyAndPublicValuesResponse, LeafFormat,
+        MarkProofsFailedRequest, MarkProofsFailedResponse, ProcessBatchRequest,
+        ProcessBatchResponse, RegisterProgramRequest, RegisterProgramResponse,
+        RetireProgramRequest, RetireProgramResponse, RevokeProofRequest, RevokeProofResponse,
+        SubmissionRejection, VerifyAggregationProofRequest, VerifyAggregationProofResponse,
     },
-    merkle_tree::MerkleTree,
+    merkle_tree::{
+        allowlist::Allowlist, EncodedMerkleTree, EncodedProgramTree, HashFunction, MerkleError,
+        MerkleTree, ProgramProof, ProgramTree, TreeVersion,
+    },
+    mmr::{
+        accumulator_program_proof, accumulator_proof, accumulator_siblings, MerkleMountainRange,
+    },
+    proof_id::{content_proof_id, keyed_proof_id},
+    public_values::AggregationPublicValues,
 };
 
+/// The largest submitter identity a request can bind into its leaf, enough for a public key.
+const MAX_SUBMITTER_SIZE: usize = 64;
+
+/// The largest idempotency key a request can carry.
+const MAX_IDEMPOTENCY_KEY_SIZE: usize = 64;
+
+/// The largest encoded proof a request can carry, well above a compressed proof.
+const MAX_PROOF_SIZE: usize = 16 * 1024 * 1024;
+
+/// The largest encoded vk a request can carry.
+const MAX_VK_SIZE: usize = 1024 * 1024;
+
+/// How long a worker holds a claimed batch by default, enough to prove and relay it.
+const DEFAULT_LEASE_SECONDS: u64 = 2 * 60 * 60;
+
+/// The longest lease a worker can claim a batch for.
+const MAX_LEASE_SECONDS: u64 = 24 * 60 * 60;
+
 #[tonic::async_trait]
 impl AggregationService for AggregationRpc {
     async fn get_aggregated_data(
@@ -35,33 +68,74 @@ impl AggregationService for AggregationRpc {
                 tx_hash: vec![],
                 chain_id: 0,
                 contract_address: vec![],
+                tree_version: 0,
+                encoded_proof: vec![],
+                hash_function: 0,
+                root: vec![],
+                submitter: vec![],
+                subtree_proof: vec![],
+                program_proof: vec![],
+                subtree_root: vec![],
             }));
         }
 
-        let merkle_tree_vec = db::get_merkle_tree(&self.db_pool, proof_id.clone())
-            .await
-            .map_err(|e| Status::internal(e.to_string()))?;
-        let merkle_tree_leaves = merkle_tree_vec
-            .chunks(32)
-            .map(|chunk| chunk.try_into().unwrap())
-            .collect();
-        let merkle_tree = MerkleTree::new(merkle_tree_leaves);
+        // The proof of a leaf is against the accumulator root the contract holds, so the batch of
+        // the proof must have been relayed.
+        let (batch_id, accumulator_index) = self.relayed_batch(proof_id.clone()).await?;
+        let merkle_tree_vec = self.batch_merkle_tree(batch_id).await?;
 
         let proof_leaf = db::get_leaf(&self.db_pool, proof_id.clone())
             .await
             .map_err(|e| Status::internal(e.to_string()))?;
-        // let proof_status = db::get_proof_status(&self.db_pool, proof_id.clone())
-        //     .await
-        //     .map_err(|e| Status::internal(e.to_string()))?;
-        let merkle_proof = merkle_tree
-            .generate_proof(proof_leaf.as_slice().try_into().unwrap())
-            .expect("Failed to generate proof");
-        let merkle_proof_vec = merkle_proof.iter().map(|leaf| leaf.to_vec()).collect();
+        let leaf_index = db::get_leaf_index(&self.db_pool, proof_id.clone())
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        // Prove the leaf against the accumulator root that the contract holds, which stays valid
+        // as later batches are appended.
+        let batch_roots = db::get_accumulator_leaves(&self.db_pool)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        // The runs that aggregated the batch cover aligned subtrees of its tree, so the path
+        // through the tree of the batch is the combined path through every level of runs.
+        let (merkle_proof, program) = if ProgramTree::is_encoding(&merkle_tree_vec) {
+            let merkle_tree =
+                EncodedProgramTree::decode(&merkle_tree_vec).map_err(merkle_status)?;
+            let proof = match leaf_index {
+                Some(leaf_index) => Ok(leaf_index),
+                None => merkle_tree.position(proof_leaf),
+            }
+            .and_then(|leaf_index| merkle_tree.generate_proof_at(leaf_index))
+            .and_then(|proof| accumulator_program_proof(proof, &batch_roots, accumulator_index))
+            .map_err(merkle_status)?;
+            (proof.subtree_proof.clone(), Some(proof))
+        } else {
+            let merkle_tree = EncodedMerkleTree::decode(&merkle_tree_vec).map_err(merkle_status)?;
+            let proof = match leaf_index {
+                Some(leaf_index) => merkle_tree.generate_proof_at(leaf_index),
+                // Batches processed before leaf indices were stored.
+                None => merkle_tree
+                    .position(proof_leaf)
+                    .and_then(|leaf_index| merkle_tree.generate_proof_at(leaf_index)),
+            }
+            .and_then(|proof| accumulator_proof(proof, &batch_roots, accumulator_index))
+            .map_err(merkle_status)?;
+            (proof, None)
+        };
+        let (tree_version, hash_function) = (merkle_proof.version, merkle_proof.hash_function);
+        // The proof of a leaf of a program tree is its path through the subtree of its program,
+        // followed by the path of the program node, which is not a sibling of the subtree root.
+        let program_proof = program
+            .as_ref()
+            .map_or(vec![], |program| to_vecs(&program.program_siblings));
+        let merkle_proof_vec = [to_vecs(&merkle_proof.siblings), program_proof.clone()].concat();
 
         let (tx_hash, chain_id, contract_address) =
             db::get_tx_context(&self.db_pool, proof_id.clone())
                 .await
                 .map_err(|e| Status::internal(e.to_string()))?;
+        let submitter = db::get_submitter(&self.db_pool, proof_id.clone())
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
 
         Ok(Response::new(GetAggregatedDataResponse {
             proof: merkle_proof_vec,
@@ -69,6 +143,152 @@ impl AggregationService for AggregationRpc {
             tx_hash,
             chain_id,
             contract_address,
+            tree_version: tree_version as u32,
+            encoded_proof: merkle_proof.to_bytes(),
+            hash_function: hash_function as u32,
+            root: program
+                .as_ref()
+                .map_or(merkle_proof.root, |program| program.root)
+                .to_vec(),
+            submitter,
+            subtree_proof: program
+                .as_ref()
+                .map_or(vec![], |_| to_vecs(&merkle_proof.siblings)),
+            program_proof,
+            subtree_root: program.map_or(vec![], |program| program.subtree_proof.root.to_vec()),
+        }))
+    }
+
+    async fn get_aggregated_multiproof(
+        &self,
+        request: Request<GetAggregatedMultiproofRequest>,
+    ) -> Result<Response<GetAggregatedMultiproofResponse>, Status> {
+        let req = request.into_inner();
+        let Some(first_proof_id) = req.proof_ids.first().cloned() else {
+            return Err(Status::invalid_argument("no proof ids given"));
+        };
+
+        // if any proof_id is not found (not in db), return empty vectors and status NOT_FOUND
+        let mut response_status = AggregationStatusResponse::NotFound;
+        for proof_id in &req.proof_ids {
+            response_status = db::get_proof_status(&self.db_pool, proof_id.clone())
+                .await
+                .map_err(|e| Status::internal(e.to_string()))?;
+            if response_status == AggregationStatusResponse::NotFound {
+                return Ok(Response::new(GetAggregatedMultiproofResponse {
+                    status: AggregationStatusResponse::NotFound as i32,
+                    ..Default::default()
+                }));
+            }
+        }
+
+        // All proofs must be in the same batch to share a multiproof, which must have been relayed.
+        let (batch_id, accumulator_index) = self.relayed_batch(first_proof_id.clone()).await?;
+        let mut leaves: Vec<[u8; 32]> = Vec::new();
+        let mut leaf_indices = Vec::new();
+        for proof_id in &req.proof_ids {
+            let proof_batch_id = db::get_batch_id(&self.db_pool, proof_id.clone())
+                .await
+                .map_err(|e| Status::internal(e.to_string()))?;
+            if proof_batch_id.as_ref() != Some(&batch_id) {
+                return Err(Status::invalid_argument(
+                    "proofs were not aggregated in the same batch",
+                ));
+            }
+            let leaf = db::get_leaf(&self.db_pool, proof_id.clone())
+                .await
+                .map_err(|e| Status::internal(e.to_string()))?;
+            let leaf_index = db::get_leaf_index(&self.db_pool, proof_id.clone())
+                .await
+                .map_err(|e| Status::internal(e.to_string()))?;
+            leaves.push(leaf);
+            leaf_indices.push(leaf_index);
+        }
+
+        let merkle_tree_vec = self.batch_merkle_tree(batch_id.clone()).await?;
+        let (merkle_tree, program) = if ProgramTree::is_encoding(&merkle_tree_vec) {
+            // The multiproof is against the subtree of the program of the proofs, whose root the
+            // path of the program proves.
+            let program_tree =
+                EncodedProgramTree::decode(&merkle_tree_vec).map_err(merkle_status)?;
+            let mut program: Option<ProgramProof> = None;
+            let mut subtree_indices = Vec::new();
+            for (leaf, leaf_index) in leaves.iter().zip(&leaf_indices) {
+                let proof = match leaf_index {
+                    Some(leaf_index) => Ok(*leaf_index),
+                    None => program_tree.position(*leaf),
+                }
+                .and_then(|leaf_index| program_tree.generate_proof_at(leaf_index))
+                .map_err(merkle_status)?;
+                if program.get_or_insert_with(|| proof.clone()).vkey_hash != proof.vkey_hash {
+                    return Err(Status::invalid_argument(
+                        "proofs are not of the same program",
+                    ));
+                }
+                subtree_indices.push(Some(proof.subtree_proof.index as usize));
+            }
+            let program = program.ok_or_else(|| Status::invalid_argument("no proof ids given"))?;
+            let subtree = program_tree
+                .subtree(program.vkey_hash)
+                .unwrap_or(Err(MerkleError::CorruptedTree))
+                .map_err(merkle_status)?;
+            leaf_indices = subtree_indices;
+            (subtree, Some(program))
+        } else {
+            (
+                MerkleTree::from_bytes(&merkle_tree_vec).map_err(merkle_status)?,
+                None,
+            )
+        };
+        let multiproof = match leaf_indices.into_iter().collect::<Option<Vec<usize>>>() {
+            Some(leaf_indices) => merkle_tree.generate_multiproof_at(&leaf_indices),
+            // Batches processed before leaf indices were stored.
+            None => merkle_tree.generate_multiproof(&leaves),
+        }
+        .map_err(merkle_status)?;
+
+        // The root of the batch is a leaf of the accumulator the contract holds, and the
+        // multiproof continues with the path of the batch root through it.
+        let batch_root = program
+            .as_ref()
+            .map_or(merkle_tree.root, |program| program.root);
+        let batch_roots = db::get_accumulator_leaves(&self.db_pool)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        let (accumulator_path, root) = accumulator_siblings(
+            merkle_tree.version,
+            &merkle_tree.hash_function,
+            batch_root,
+            &batch_roots,
+            accumulator_index,
+        )
+        .map_err(merkle_status)?;
+
+        let (tx_hash, chain_id, contract_address) =
+            db::get_tx_context(&self.db_pool, first_proof_id)
+                .await
+                .map_err(|e| Status::internal(e.to_string()))?;
+
+        Ok(Response::new(GetAggregatedMultiproofResponse {
+            leaves: multiproof.leaves.iter().map(|leaf| leaf.to_vec()).collect(),
+            indices: multiproof.indices,
+            leaf_count: multiproof.leaf_count,
+            proof: multiproof.proof.iter().map(|node| node.to_vec()).collect(),
+            proof_flags: multiproof.proof_flags,
+            status: response_status as i32,
+            tx_hash,
+            chain_id,
+            contract_address,
+            tree_version: merkle_tree.version as u32,
+            hash_function: merkle_tree.hash_function as u32,
+            subtree_root: program
+                .as_ref()
+                .map_or(vec![], |program| program.subtree_proof.root.to_vec()),
+            program_proof: program
+                .as_ref()
+                .map_or(vec![], |program| to_vecs(&program.program_siblings)),
+            accumulator_proof: to_vecs(&accumulator_path),
+            root: root.to_vec(),
         }))
     }
 
@@ -81,8 +301,12 @@ impl AggregationService for AggregationRpc {
         let status = db::get_proof_status(&self.db_pool, proof_id.clone())
             .await
             .map_err(|e| Status::internal(e.to_string()))?;
+        let failure_reason = db::get_failure_reason(&self.db_pool, proof_id)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
         Ok(Response::new(GetAggregationStatusResponse {
             status: status as i32,
+            failure_reason: failure_reason.unwrap_or_default(),
         }))
     }
 
@@ -90,46 +314,174 @@ impl AggregationService for AggregationRpc {
         &self,
         request: Request<AggregateProofRequest>,
     ) -> Result<Response<AggregateProofResponse>, Status> {
-        let proof_id: [u8; 32] = rand::thread_rng().gen();
         let req = request.into_inner();
-        db::create_request(&self.db_pool, proof_id.to_vec(), req.proof, req.vk)
-            .await
-            .map_err(|e| Status::internal(e.to_string()))?;
+        let leaf_format = LeafFormat::try_from(req.leaf_format).map_err(|_| {
+            invalid_submission(
+                SubmissionRejection::UnknownLeafFormat,
+                "unknown leaf format",
+            )
+        })?;
+        if req.submitter.len() > MAX_SUBMITTER_SIZE {
+            return Err(invalid_submission(
+                SubmissionRejection::SubmitterTooLarge,
+                "submitter is too large",
+            ));
+        }
+        if req.idempotency_key.len() > MAX_IDEMPOTENCY_KEY_SIZE {
+            return Err(invalid_submission(
+                SubmissionRejection::IdempotencyKeyTooLarge,
+                "idempotency key is too large",
+            ));
+        }
+        if req.proof.len() > MAX_PROOF_SIZE || req.vk.len() > MAX_VK_SIZE {
+            return Err(invalid_submission(
+                SubmissionRejection::TooLarge,
+                "proof or vk is too large",
+            ));
+        }
+
+        // Batches only verify compressed proofs, and fail as a whole on a proof they can't decode.
+        let proof: SP1ProofWithPublicValues = bincode::deserialize(&req.proof).map_err(|_| {
+            invalid_submission(SubmissionRejection::InvalidProofEncoding, "invalid proof")
+        })?;
+        let vk: SP1VerifyingKey = bincode::deserialize(&req.vk).map_err(|_| {
+            invalid_submission(SubmissionRejection::InvalidVkEncoding, "invalid vk")
+        })?;
+        if !matches!(proof.proof, SP1Proof::Compressed(_)) {
+            return Err(invalid_submission(
+                SubmissionRejection::UnsupportedProofType,
+                "proof is not compressed",
+            ));
+        }
+
+        // A retried request gets the proof id of the request it retries, and nothing is created.
+        let proof_id = if req.idempotency_key.is_empty() {
+            content_proof_id(
+                &vk.hash_bytes(),
+                proof.public_values.as_slice(),
+                &req.proof,
+                leaf_format.into(),
+                &req.submitter,
+            )
+        } else {
+            keyed_proof_id(&req.tenant, &req.idempotency_key)
+        };
+        if let Some(response) = self.existing_request(proof_id, &req, leaf_format).await? {
+            return Ok(response);
+        }
+
+        let fee = self.check_program_policy(&req, &proof, &vk).await?;
+        if self.verify_submissions {
+            if let Err(reason) =
+                verify_off_chain(self.prover_client.clone(), proof, Arc::new(vk)).await?
+            {
+                return Err(invalid_submission(
+                    SubmissionRejection::InvalidProof,
+                    format!("proof does not verify: {}", reason),
+                ));
+            }
+        }
+        let created = db::create_request(
+            &self.db_pool,
+            proof_id.to_vec(),
+            req.proof.clone(),
+            req.vk.clone(),
+            fee,
+            leaf_format.into(),
+            req.submitter.clone(),
+        )
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?;
+        if !created {
+            // The request was submitted concurrently.
+            return self
+                .existing_request(proof_id, &req, leaf_format)
+                .await?
+                .ok_or_else(|| Status::internal("request was neither created nor found"));
+        }
         Ok(Response::new(AggregateProofResponse {
             proof_id: proof_id.to_vec(),
+            status: AggregationStatusResponse::AggregationPending.into(),
+            existing: false,
         }))
     }
 
-    async fn get_batch(
+    async fn claim_batch(
         &self,
-        request: Request<GetBatchRequest>,
-    ) -> Result<Response<GetBatchResponse>, Status> {
+        request: Request<ClaimBatchRequest>,
+    ) -> Result<Response<ClaimBatchResponse>, Status> {
         let req = request.into_inner();
+        let lease_seconds = req.lease_seconds.unwrap_or(DEFAULT_LEASE_SECONDS);
+        if lease_seconds == 0 || lease_seconds > MAX_LEASE_SECONDS {
+            return Err(Status::invalid_argument(format!(
+                "lease must be between 1 and {} seconds",
+                MAX_LEASE_SECONDS
+            )));
+        }
+        let worker_id = if req.worker_id.is_empty() {
+            format!("{:016x}", rand::thread_rng().gen::<u64>())
+        } else {
+            req.worker_id
+        };
+
+        // Release expired leases first, so that their requests can be claimed right away.
+        let now = Utc::now().timestamp_millis();
+        db::release_expired_leases(&self.db_pool, now)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
         let batch_id: [u8; 32] = rand::thread_rng().gen();
-        let proofs = db::get_batch(
+        let lease_expires_at = now + lease_seconds as i64 * 1000;
+        let proofs = db::claim_batch(
             &self.db_pool,
-            req.created_after.unwrap_or(0),
+            batch_id.to_vec(),
+            &worker_id,
             req.batch_size.unwrap_or(32),
+            lease_expires_at,
         )
         .await
         .map_err(|e| Status::internal(e.to_string()))?;
-        Ok(Response::new(GetBatchResponse {
+        Ok(Response::new(ClaimBatchResponse {
             batch_id: batch_id.to_vec(),
             proofs,
+            worker_id,
+            lease_expires_at: lease_expires_at as u64,
         }))
     }
 
+    async fn complete_batch(
+        &self,
+        request: Request<CompleteBatchRequest>,
+    ) -> Result<Response<CompleteBatchResponse>, Status> {
+        let req = request.into_inner();
+        let completed = db::complete_batch(
+            &self.db_pool,
+            req.batch_id,
+            &req.worker_id,
+            Utc::now().timestamp_millis(),
+        )
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?;
+        if !completed {
+            return Err(Status::failed_precondition(
+                "batch is not leased to this worker",
+            ));
+        }
+        Ok(Response::new(CompleteBatchResponse { success: true }))
+    }
+
     async fn get_vkey_and_public_values(
         &self,
         request: Request<GetVkeyAndPublicValuesRequest>,
     ) -> Result<Response<GetVkeyAndPublicValuesResponse>, Status> {
         let req = request.into_inner();
-        let (vkey, public_values) = db::get_vkey_and_public_values(&self.db_pool, req.proof_id)
-            .await
-            .map_err(|e| Status::internal(e.to_string()))?;
+        let (vkey, public_values, leaf_format) =
+            db::get_vkey_and_public_values(&self.db_pool, req.proof_id)
+                .await
+                .map_err(|e| Status::internal(e.to_string()))?;
         Ok(Response::new(GetVkeyAndPublicValuesResponse {
             vkey: vkey.to_vec(),
             public_values: public_values.to_vec(),
+            leaf_format: LeafFormat::from(leaf_format).into(),
         }))
     }
 
@@ -138,37 +490,69 @@ impl AggregationService for AggregationRpc {
         request: Request<ProcessBatchRequest>,
     ) -> Result<Response<ProcessBatchResponse>, Status> {
         let req = request.into_inner();
+        let duplicate_policy = req.duplicate_policy();
         let batch_id = req.batch_id;
-        let leaves = db::process_batch(&self.db_pool, req.proofs, batch_id)
+        self.check_worker_lease(&batch_id, &req.worker_id).await?;
+        let proof_ids = req.proofs.into_iter().map(|proof| proof.proof_id).collect();
+
+        // The leaves and the tree of the batch are stored together, or not at all.
+        let mut tx = self
+            .db_pool
+            .begin()
             .await
             .map_err(|e| Status::internal(e.to_string()))?;
-        Ok(Response::new(ProcessBatchResponse {
-            leaves: leaves.to_vec(),
-        }))
-    }
+        let (leaves, vkey_hashes, proof_ids) =
+            db::process_batch(&mut tx, proof_ids, batch_id.clone(), duplicate_policy)
+                .await
+                .map_err(|e| Status::internal(e.to_string()))?
+                .ok_or_else(|| {
+                    Status::failed_precondition("proofs are not pending requests of the batch")
+                })?;
 
-    async fn write_merkle_tree(
-        &self,
-        request: Request<WriteMerkleTreeRequest>,
-    ) -> Result<Response<WriteMerkleTreeResponse>, Status> {
-        let req = request.into_inner();
-        let merkle_tree = req.tree;
-        let batch_id = req.batch_id;
-        db::write_merkle_tree(&self.db_pool, merkle_tree, batch_id)
+        // The tree is built here from the leaves the server assigned, so that the root a relayed
+        // proof commits to can be checked against it.
+        if !leaves.is_empty() {
+            let merkle_tree = ProgramTree::new(
+                &to_program_leaves(&vkey_hashes, &leaves),
+                TreeVersion::LATEST,
+                self.hash_function,
+            )
+            .map_err(merkle_status)?;
+            let written = db::write_merkle_tree(&mut tx, &merkle_tree, batch_id)
+                .await
+                .map_err(|e| Status::internal(e.to_string()))?;
+            if !written {
+                return Err(Status::failed_precondition(
+                    "batch has already been processed",
+                ));
+            }
+        }
+        tx.commit()
             .await
             .map_err(|e| Status::internal(e.to_string()))?;
-        Ok(Response::new(WriteMerkleTreeResponse { success: true }))
+        Ok(Response::new(ProcessBatchResponse {
+            leaves: leaves.to_vec(),
+            proof_ids,
+            vkey_hashes,
+        }))
     }
 
-    async fn update_batch_status(
+    async fn mark_proofs_failed(
         &self,
-        request: Request<UpdateBatchStatusRequest>,
-    ) -> Result<Response<UpdateBatchStatusResponse>, Status> {
+        request: Request<MarkProofsFailedRequest>,
+    ) -> Result<Response<MarkProofsFailedResponse>, Status> {
         let req = request.into_inner();
-        db::update_batch_status(&self.db_pool, req.batch_id, req.status)
+        self.check_worker_lease(&req.batch_id, &req.worker_id)
+            .await?;
+        let marked = db::mark_proofs_failed(&self.db_pool, req.batch_id, req.proofs)
             .await
             .map_err(|e| Status::internal(e.to_string()))?;
-        Ok(Response::new(UpdateBatchStatusResponse { success: true }))
+        if !marked {
+            return Err(Status::failed_precondition(
+                "proofs are not pending requests of the batch",
+            ));
+        }
+        Ok(Response::new(MarkProofsFailedResponse { success: true }))
     }
 
     async fn verify_aggregation_proof(
@@ -176,16 +560,484 @@ impl AggregationService for AggregationRpc {
         request: Request<VerifyAggregationProofRequest>,
     ) -> Result<Response<VerifyAggregationProofResponse>, Status> {
         let req = request.into_inner();
-        let proof: SP1ProofWithPublicValues = bincode::deserialize(&req.proof).unwrap();
-        let tx_hash = relay::relay_proof(proof)
+        self.check_worker_lease(&req.batch_id, &req.worker_id)
+            .await?;
+        let Some(aggregation_vk) = self.aggregation_vk.clone() else {
+            return Err(Status::failed_precondition(
+                "the vkey of the aggregation program is not configured",
+            ));
+        };
+        let Ok(proof) = bincode::deserialize::<SP1ProofWithPublicValues>(&req.proof) else {
+            return Ok(rejected(
+                AggregationProofRejection::InvalidEncoding,
+                "invalid aggregation proof",
+            ));
+        };
+        let merkle_tree_vec = db::get_batch_merkle_tree(&self.db_pool, req.batch_id.clone())
             .await
             .map_err(|e| Status::internal(e.to_string()))?;
-        db::update_proof_tx_hash(&self.db_pool, req.batch_id, tx_hash.clone())
+        let (batch_root, leaf_count) =
+            batch_root_and_leaf_count(&merkle_tree_vec).map_err(merkle_status)?;
+
+        // The leaf of the batch in the accumulator is reserved until the relay is recorded, so
+        // that a concurrent relay can't record its batch at the same index. The reservation is
+        // rolled back if the proof is rejected or the relay fails.
+        let mut tx = self
+            .db_pool
+            .begin()
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        let batch_roots =
+            match db::reserve_accumulator_leaf(&mut tx, req.batch_id.clone(), batch_root).await {
+                Ok(batch_roots) => batch_roots,
+                Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+                    return Err(Status::aborted(
+                        "the batch or its accumulator index was taken by another relay",
+                    ))
+                }
+                Err(e) => return Err(Status::internal(e.to_string())),
+            };
+        if let Err((rejection, reason)) = check_aggregation_proof(
+            &proof,
+            &req.batch_id,
+            batch_root,
+            leaf_count,
+            &batch_roots,
+            self.hash_function,
+        ) {
+            return Ok(rejected(rejection, reason));
+        }
+
+        // Verify the proof off-chain, so that no gas is spent on a proof the contract would reject.
+        let proof =
+            match verify_off_chain(self.prover_client.clone(), proof, aggregation_vk).await? {
+                Ok(proof) => proof,
+                Err(reason) => {
+                    return Ok(rejected(
+                        AggregationProofRejection::InvalidProof,
+                        format!("aggregation proof does not verify: {}", reason),
+                    ))
+                }
+            };
+
+        let relayed_tx = relay::relay_proof(proof, self.hash_function)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        let tx_hash = relayed_tx.0.clone();
+        db::mark_batch_relayed(&mut tx, req.batch_id.clone(), self.hash_function)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        db::update_proof_tx_hash(&mut tx, req.batch_id, relayed_tx)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        tx.commit()
             .await
             .map_err(|e| Status::internal(e.to_string()))?;
         Ok(Response::new(VerifyAggregationProofResponse {
             verified: true,
             tx_hash,
+            ..Default::default()
+        }))
+    }
+
+    async fn get_accumulator(
+        &self,
+        _request: Request<GetAccumulatorRequest>,
+    ) -> Result<Response<GetAccumulatorResponse>, Status> {
+        let batch_roots = db::get_accumulator_leaves(&self.db_pool)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        let accumulator = MerkleMountainRange::from_leaves(
+            TreeVersion::LATEST,
+            &self.hash_function,
+            &batch_roots,
+        );
+        Ok(Response::new(GetAccumulatorResponse {
+            leaf_count: accumulator.leaf_count,
+            peaks: accumulator.peaks.iter().map(|peak| peak.to_vec()).collect(),
+            root: accumulator
+                .root(TreeVersion::LATEST, &self.hash_function)
+                .to_vec(),
         }))
     }
+
+    async fn get_proof_id_proof(
+        &self,
+        request: Request<GetProofIdProofRequest>,
+    ) -> Result<Response<GetProofIdProofResponse>, Status> {
+        let req = request.into_inner();
+        let key: [u8; 32] = req
+            .proof_id
+            .as_slice()
+            .try_into()
+            .map_err(|_| Status::invalid_argument("proof id must be 32 bytes"))?;
+
+        let (proof, state, batch_id, root) =
+            db::get_proof_id_proof(&self.db_pool, key, self.hash_function)
+                .await
+                .map_err(|e| Status::internal(e.to_string()))?;
+
+        Ok(Response::new(GetProofIdProofResponse {
+            root: root.to_vec(),
+            state: state as i32,
+            batch_id,
+            bitmap: proof.bitmap.to_vec(),
+            siblings: proof.siblings.iter().map(|node| node.to_vec()).collect(),
+            hash_function: self.hash_function as u32,
+        }))
+    }
+
+    async fn revoke_proof(
+        &self,
+        request: Request<RevokeProofRequest>,
+    ) -> Result<Response<RevokeProofResponse>, Status> {
+        self.check_admin(&request)?;
+        let req = request.into_inner();
+        let revoked = db::revoke_proof_id(&self.db_pool, req.proof_id, self.hash_function)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        if !revoked {
+            return Err(Status::failed_precondition(
+                "proof has not been aggregated or is already revoked",
+            ));
+        }
+        Ok(Response::new(RevokeProofResponse { success: true }))
+    }
+
+    async fn register_program(
+        &self,
+        request: Request<RegisterProgramRequest>,
+    ) -> Result<Response<RegisterProgramResponse>, Status> {
+        self.check_admin(&request)?;
+        let req = request.into_inner();
+        let vk: SP1VerifyingKey =
+            bincode::deserialize(&req.vk).map_err(|_| Status::invalid_argument("invalid vk"))?;
+        let vkey_hash = vk.hash_bytes().to_vec();
+        let program = db::Program {
+            tenant: req.tenant,
+            max_public_values_size: req.max_public_values_size,
+            fee: req.fee,
+            retired: false,
+        };
+        db::register_program(&self.db_pool, vkey_hash.clone(), &program)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        Ok(Response::new(RegisterProgramResponse { vkey_hash }))
+    }
+
+    async fn retire_program(
+        &self,
+        request: Request<RetireProgramRequest>,
+    ) -> Result<Response<RetireProgramResponse>, Status> {
+        self.check_admin(&request)?;
+        let req = request.into_inner();
+        let retired = db::retire_program(&self.db_pool, req.vkey_hash)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        Ok(Response::new(RetireProgramResponse { retired }))
+    }
+
+    async fn get_program_allowlist(
+        &self,
+        _request: Request<GetProgramAllowlistRequest>,
+    ) -> Result<Response<GetProgramAllowlistResponse>, Status> {
+        let enforced = db::is_allowlist_enforced(&self.db_pool)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        let allowlist = Allowlist::new(
+            db::get_allowlist(&self.db_pool)
+                .await
+                .map_err(|e| Status::internal(e.to_string()))?,
+        );
+        Ok(Response::new(GetProgramAllowlistResponse {
+            enforced,
+            vkey_hashes: allowlist
+                .vkeys()
+                .iter()
+                .map(|vkey_hash| vkey_hash.to_vec())
+                .collect(),
+            root: allowlist
+                .root(TreeVersion::LATEST, &self.hash_function)
+                .to_vec(),
+        }))
+    }
+}
+
+impl AggregationRpc {
+    /// Check that the request carries the admin token as `authorization: Bearer <token>`. Admin
+    /// RPCs are disabled if no admin token is configured.
+    fn check_admin<T>(&self, request: &Request<T>) -> Result<(), Status> {
+        let Some(admin_token) = &self.admin_token else {
+            return Err(Status::failed_precondition("admin RPCs are disabled"));
+        };
+        let authorized = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value == format!("Bearer {}", admin_token));
+        if !authorized {
+            return Err(Status::unauthenticated("invalid admin token"));
+        }
+        Ok(())
+    }
+
+    /// Check that a batch was claimed with `ClaimBatch` and is still held by `worker_id`, since the
+    /// requests of a batch whose lease expired may already be claimed for another batch, and only
+    /// the worker proving a batch may change it.
+    /// The batch of a proof and its index in the accumulator, or an error if the proof was not
+    /// batched or its batch was not relayed yet.
+    async fn relayed_batch(&self, proof_id: Vec<u8>) -> Result<(Vec<u8>, usize), Status> {
+        let batch_id = db::get_batch_id(&self.db_pool, proof_id)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .ok_or_else(|| Status::failed_precondition("proof has not been batched"))?;
+        let accumulator_index = db::get_accumulator_index(&self.db_pool, batch_id.clone())
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .ok_or_else(|| {
+                Status::failed_precondition("batch of the proof has not been relayed")
+            })?;
+        Ok((batch_id, accumulator_index))
+    }
+
+    /// The encoded tree of a batch, see [`db::get_batch_merkle_tree`].
+    async fn batch_merkle_tree(&self, batch_id: Vec<u8>) -> Result<Vec<u8>, Status> {
+        db::get_batch_merkle_tree(&self.db_pool, batch_id)
+            .await
+            .map_err(|e| match e {
+                sqlx::Error::RowNotFound => Status::data_loss("tree of the batch is missing"),
+                e => Status::internal(e.to_string()),
+            })
+    }
+
+    async fn check_worker_lease(&self, batch_id: &[u8], worker_id: &str) -> Result<(), Status> {
+        let lease = db::get_batch_lease(&self.db_pool, batch_id.to_vec())
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .ok_or_else(|| Status::failed_precondition("batch was not claimed"))?;
+        if !lease.is_active(Utc::now().timestamp_millis()) {
+            return Err(Status::failed_precondition("batch lease has expired"));
+        }
+        if lease.worker_id != worker_id {
+            return Err(Status::failed_precondition(
+                "batch is not leased to this worker",
+            ));
+        }
+        Ok(())
+    }
+
+    /// The response to a request that retries the request with the given proof id, or `None` if
+    /// there is no such request. A request that reuses the proof id of another request is
+    /// rejected.
+    async fn existing_request(
+        &self,
+        proof_id: [u8; 32],
+        request: &AggregateProofRequest,
+        leaf_format: LeafFormat,
+    ) -> Result<Option<Response<AggregateProofResponse>>, Status> {
+        let same = db::is_same_request(
+            &self.db_pool,
+            proof_id.to_vec(),
+            &request.proof,
+            &request.vk,
+            leaf_format.into(),
+            &request.submitter,
+        )
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?;
+        match same {
+            None => Ok(None),
+            Some(false) => Err(Status::already_exists(
+                "another request was submitted with the same proof id",
+            )),
+            Some(true) => {
+                let status = db::get_proof_status(&self.db_pool, proof_id.to_vec())
+                    .await
+                    .map_err(|e| Status::internal(e.to_string()))?;
+                Ok(Some(Response::new(AggregateProofResponse {
+                    proof_id: proof_id.to_vec(),
+                    status: status.into(),
+                    existing: true,
+                })))
+            }
+        }
+    }
+
+    /// Check an aggregation request against the policy of its program, once aggregation is
+    /// restricted to registered programs. Returns the fee of the program.
+    async fn check_program_policy(
+        &self,
+        request: &AggregateProofRequest,
+        proof: &SP1ProofWithPublicValues,
+        vk: &SP1VerifyingKey,
+    ) -> Result<Option<u64>, Status> {
+        let enforced = db::is_allowlist_enforced(&self.db_pool)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        if !enforced {
+            return Ok(None);
+        }
+
+        let program = db::get_program(&self.db_pool, vk.hash_bytes().to_vec())
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .filter(|program| !program.retired)
+            .ok_or_else(|| Status::permission_denied("program is not registered"))?;
+        if !program.tenant.is_empty() && program.tenant != request.tenant {
+            return Err(Status::permission_denied(
+                "program is registered to another tenant",
+            ));
+        }
+        if program.max_public_values_size > 0
+            && proof.public_values.as_slice().len() as u64 > program.max_public_values_size
+        {
+            return Err(invalid_submission(
+                SubmissionRejection::PublicValuesTooLarge,
+                "public values are larger than the program allows",
+            ));
+        }
+        Ok(Some(program.fee))
+    }
+}
+
+/// The metadata key of the `SubmissionRejection` of a rejected aggregation request.
+const REJECTION_METADATA_KEY: &str = "rejection-reason";
+
+/// The `InvalidArgument` status of a rejected aggregation request, which carries the name of its
+/// `SubmissionRejection` in the `rejection-reason` metadata.
+fn invalid_submission(rejection: SubmissionRejection, message: impl Into<String>) -> Status {
+    let mut status = Status::invalid_argument(message);
+    status.metadata_mut().insert(
+        REJECTION_METADATA_KEY,
+        MetadataValue::from_static(rejection.as_str_name()),
+    );
+    status
+}
+
+/// Verify a proof off-chain on a blocking thread, since verification is CPU-bound. Returns the
+/// proof if it verifies, and why it doesn't otherwise.
+async fn verify_off_chain(
+    prover_client: Arc<ProverClient>,
+    proof: SP1ProofWithPublicValues,
+    vk: Arc<SP1VerifyingKey>,
+) -> Result<Result<SP1ProofWithPublicValues, String>, Status> {
+    tokio::task::spawn_blocking(move || {
+        prover_client
+            .verify(&proof, &vk)
+            .map(|()| proof)
+            .map_err(|e| e.to_string())
+    })
+    .await
+    .map_err(|e| Status::internal(e.to_string()))
+}
+
+/// The response to an aggregation proof that is rejected without being relayed.
+fn rejected(
+    rejection: AggregationProofRejection,
+    reason: impl Into<String>,
+) -> Response<VerifyAggregationProofResponse> {
+    Response::new(VerifyAggregationProofResponse {
+        verified: false,
+        tx_hash: vec![],
+        rejection: rejection.into(),
+        rejection_reason: reason.into(),
+    })
+}
+
+/// Check that an aggregation proof can be relayed for the batch with the given root and leaf
+/// count, on top of the accumulator over `batch_roots`, short of verifying the proof. Returns why
+/// it is rejected otherwise.
+pub fn check_aggregation_proof(
+    proof: &SP1ProofWithPublicValues,
+    batch_id: &[u8],
+    batch_root: [u8; 32],
+    leaf_count: usize,
+    batch_roots: &[[u8; 32]],
+    hash_function: HashFunction,
+) -> Result<(), (AggregationProofRejection, &'static str)> {
+    // The verifier contract only verifies proofs wrapped for the EVM.
+    if !matches!(proof.proof, SP1Proof::Plonk(_) | SP1Proof::Groth16(_)) {
+        return Err((
+            AggregationProofRejection::UnsupportedProofType,
+            "aggregation proof is not a Plonk or Groth16 proof",
+        ));
+    }
+    let Some(public_values) = AggregationPublicValues::from_bytes(proof.public_values.as_slice())
+    else {
+        return Err((
+            AggregationProofRejection::InvalidEncoding,
+            "invalid aggregation public values",
+        ));
+    };
+    if public_values.batchId.as_slice() != batch_id || public_values.leafCount != leaf_count as u64
+    {
+        return Err((
+            AggregationProofRejection::WrongBatch,
+            "aggregation proof is for another batch",
+        ));
+    }
+    if public_values.batchRoot != batch_root {
+        return Err((
+            AggregationProofRejection::WrongBatch,
+            "aggregation proof commits to another root than the batch",
+        ));
+    }
+
+    // The proof must append the root of this batch to the accumulator the contract holds. The
+    // contract checks the vkey digest and the timestamp.
+    let mut accumulator =
+        MerkleMountainRange::from_leaves(TreeVersion::LATEST, &hash_function, batch_roots);
+    let previous_root = accumulator.root(TreeVersion::LATEST, &hash_function);
+    accumulator.append(TreeVersion::LATEST, &hash_function, batch_root);
+    let new_root = accumulator.root(TreeVersion::LATEST, &hash_function);
+    if public_values.batchIndex != batch_roots.len() as u64
+        || public_values.previousRoot != previous_root
+        || public_values.newRoot != new_root
+    {
+        return Err((
+            AggregationProofRejection::StaleAccumulator,
+            "aggregation proof does not append the batch to the current accumulator",
+        ));
+    }
+    Ok(())
+}
+
+/// The gRPC status of a failure to generate or check a proof.
+fn merkle_status(error: MerkleError) -> Status {
+    let message = error.to_string();
+    match error {
+        MerkleError::LeafNotFound => Status::not_found(message),
+        MerkleError::IndexOutOfBounds { .. } => Status::out_of_range(message),
+        MerkleError::MalformedProof
+        | MerkleError::InvalidEncoding
+        | MerkleError::UnsortedPrograms => Status::invalid_argument(message),
+        MerkleError::EmptyTree | MerkleError::HashSchemeMismatch | MerkleError::RootMismatch => {
+            Status::failed_precondition(message)
+        }
+        MerkleError::CorruptedTree => Status::data_loss(message),
+    }
+}
+
+/// Pair concatenated 32-byte leaves with the concatenated vkey hashes of their programs.
+fn to_program_leaves(vkey_hashes: &[u8], leaves: &[u8]) -> Vec<([u8; 32], [u8; 32])> {
+    vkey_hashes
+        .chunks(32)
+        .zip(leaves.chunks(32))
+        .map(|(vkey_hash, leaf)| (vkey_hash.try_into().unwrap(), leaf.try_into().unwrap()))
+        .collect()
+}
+
+/// The root and the number of leaves of the encoded tree of a batch.
+fn batch_root_and_leaf_count(merkle_tree: &[u8]) -> Result<([u8; 32], usize), MerkleError> {
+    if ProgramTree::is_encoding(merkle_tree) {
+        let merkle_tree = EncodedProgramTree::decode(merkle_tree)?;
+        return Ok((merkle_tree.root, merkle_tree.leaf_count));
+    }
+    let merkle_tree = EncodedMerkleTree::decode(merkle_tree)?;
+    Ok((merkle_tree.root, merkle_tree.leaf_count))
+}
+
+/// The nodes of a proof as the byte vectors of a response.
+fn to_vecs(nodes: &[[u8; 32]]) -> Vec<Vec<u8>> {
+    nodes.iter().map(|node| node.to_vec()).collect()
 }
@@ -1,20 +1,54 @@
 use chrono::Utc;
 use eyre::Result;
-use sha2::{Digest, Sha256};
+use serde::de::DeserializeOwned;
 use sp1_sdk::{HashableKey, SP1ProofWithPublicValues, SP1VerifyingKey};
-use sqlx::{postgres::PgPool, Row};
-use types::aggregation::{AggregationStatus, AggregationStatusResponse, ProofRequest};
+use sqlx::{
+    postgres::{PgConnection, PgPool, PgRow},
+    Row,
+};
+use std::collections::{BTreeMap, HashMap};
+use types::{
+    aggregation::{
+        AggregationStatus, AggregationStatusResponse, DuplicateLeafPolicy, FailedProof,
+        ProofIdState, ProofRequest,
+    },
+    merkle_tree::{
+        assign_leaves, bind_submitter, HashFunction, LeafFormat, MerkleTree, ProgramTree,
+        TreeVersion,
+    },
+    sparse_merkle_tree::{
+        proof_id_value, NodePosition, SparseMerklePatch, SparseMerkleProof, EMPTY,
+    },
+};
 
+/// A registered program and its policy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Program {
+    /// The tenant owning the program, or empty if any tenant may submit its proofs.
+    pub tenant: String,
+    /// The maximum size of the public values of its proofs in bytes, or 0 for no limit.
+    pub max_public_values_size: u64,
+    /// The fee charged for aggregating one of its proofs.
+    pub fee: u64,
+    pub retired: bool,
+}
+
+/// Store a pending request, unless a request with the same proof id exists. Returns whether the
+/// request was stored.
 pub async fn create_request(
     db_pool: &PgPool,
     proof_id: Vec<u8>,
     proof: Vec<u8>,
     vk: Vec<u8>,
-) -> Result<(), sqlx::Error> {
+    fee: Option<u64>,
+    leaf_format: LeafFormat,
+    submitter: Vec<u8>,
+) -> Result<bool, sqlx::Error> {
     let pending_status = AggregationStatus::Pending;
     let created_at = Utc::now().timestamp_millis();
-    sqlx::query(
-        r#"INSERT INTO requests (proof_id, status, proof, vk, batch_id, created_at, tx_hash, chain_id, contract_address) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)"#,
+    let result = sqlx::query(
+        r#"INSERT INTO requests (proof_id, status, proof, vk, batch_id, created_at, tx_hash, chain_id, contract_address, fee, leaf_format, submitter) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)
+        ON CONFLICT (proof_id) DO NOTHING"#,
     )
     .bind(proof_id)
     .bind(pending_status)
@@ -25,88 +59,520 @@ pub async fn create_request(
     .bind::<Option<Vec<u8>>>(None)
     .bind::<Option<i64>>(None)
     .bind::<Option<Vec<u8>>>(None)
+    .bind(fee.map(|fee| fee as i64))
+    .bind(leaf_format as i32)
+    .bind(submitter)
     .execute(db_pool)
     .await?;
 
+    Ok(result.rows_affected() == 1)
+}
+
+/// Whether the request with the given proof id has the same proof, vk, leaf format and
+/// submitter, or `None` if there is no request with that proof id.
+pub async fn is_same_request(
+    db_pool: &PgPool,
+    proof_id: Vec<u8>,
+    proof: &[u8],
+    vk: &[u8],
+    leaf_format: LeafFormat,
+    submitter: &[u8],
+) -> Result<Option<bool>, sqlx::Error> {
+    let row = sqlx::query(
+        r#"SELECT proof = $2 AND vk = $3 AND leaf_format = $4 AND submitter = $5 AS same
+           FROM requests WHERE proof_id = $1"#,
+    )
+    .bind(proof_id)
+    .bind(proof)
+    .bind(vk)
+    .bind(leaf_format as i32)
+    .bind(submitter)
+    .fetch_optional(db_pool)
+    .await?;
+    Ok(row.map(|row| row.get::<bool, _>("same")))
+}
+
+/// Register a program, or update its policy. Registering a retired program reinstates it.
+pub async fn register_program(
+    db_pool: &PgPool,
+    vkey_hash: Vec<u8>,
+    program: &Program,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"INSERT INTO programs (vkey_hash, tenant, max_public_values_size, fee, registered_at, retired_at)
+        VALUES ($1, $2, $3, $4, $5, NULL)
+        ON CONFLICT (vkey_hash) DO UPDATE SET tenant = $2, max_public_values_size = $3, fee = $4, retired_at = NULL"#,
+    )
+    .bind(vkey_hash)
+    .bind(&program.tenant)
+    .bind(program.max_public_values_size as i64)
+    .bind(program.fee as i64)
+    .bind(Utc::now().timestamp_millis())
+    .execute(db_pool)
+    .await?;
     Ok(())
 }
 
-pub async fn get_batch(
+/// Retire a program. Returns whether the program was registered and not already retired.
+pub async fn retire_program(db_pool: &PgPool, vkey_hash: Vec<u8>) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(
+        r#"UPDATE programs SET retired_at = $1 WHERE vkey_hash = $2 AND retired_at IS NULL"#,
+    )
+    .bind(Utc::now().timestamp_millis())
+    .bind(vkey_hash)
+    .execute(db_pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn get_program(
+    db_pool: &PgPool,
+    vkey_hash: Vec<u8>,
+) -> Result<Option<Program>, sqlx::Error> {
+    let row = sqlx::query(
+        r#"SELECT tenant, max_public_values_size, fee, retired_at FROM programs WHERE vkey_hash = $1"#,
+    )
+    .bind(vkey_hash)
+    .fetch_optional(db_pool)
+    .await?;
+    Ok(row.map(|row| Program {
+        tenant: row.get::<String, _>("tenant"),
+        max_public_values_size: row.get::<i64, _>("max_public_values_size") as u64,
+        fee: row.get::<i64, _>("fee") as u64,
+        retired: row.get::<Option<i64>, _>("retired_at").is_some(),
+    }))
+}
+
+/// Whether aggregation is restricted to registered programs, which it is once a program has been
+/// registered.
+pub async fn is_allowlist_enforced(db_pool: &PgPool) -> Result<bool, sqlx::Error> {
+    let row = sqlx::query(r#"SELECT EXISTS (SELECT 1 FROM programs) AS enforced"#)
+        .fetch_one(db_pool)
+        .await?;
+    Ok(row.get::<bool, _>("enforced"))
+}
+
+/// The vkey hashes of the registered programs that have not been retired.
+pub async fn get_allowlist(db_pool: &PgPool) -> Result<Vec<[u8; 32]>, sqlx::Error> {
+    let rows = sqlx::query(r#"SELECT vkey_hash FROM programs WHERE retired_at IS NULL"#)
+        .fetch_all(db_pool)
+        .await?;
+    rows.iter()
+        .map(|row| {
+            row.get::<&[u8], _>("vkey_hash")
+                .try_into()
+                .map_err(|_| sqlx::Error::Decode("invalid vkey hash".into()))
+        })
+        .collect()
+}
+
+/// The lease of a batch claimed by a worker.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchLease {
+    pub worker_id: String,
+    /// The unix timestamp in milliseconds when the lease expires.
+    pub expires_at: i64,
+    pub completed: bool,
+    pub released: bool,
+}
+
+impl BatchLease {
+    /// Whether the worker still holds the batch at `now`.
+    pub fn is_active(&self, now: i64) -> bool {
+        !self.completed && !self.released && now < self.expires_at
+    }
+}
+
+/// Claim up to `batch_size` of the oldest pending requests for a new batch leased to `worker_id`.
+/// Requests that another worker is claiming concurrently are skipped rather than waited for, so
+/// that no two batches share a request. Claims nothing if there are no pending requests.
+pub async fn claim_batch(
     db_pool: &PgPool,
-    created_after: u64,
+    batch_id: Vec<u8>,
+    worker_id: &str,
     batch_size: u64,
+    expires_at: i64,
 ) -> Result<Vec<ProofRequest>, sqlx::Error> {
     let pending_status = AggregationStatus::Pending as i32;
+    let mut tx = db_pool.begin().await?;
     let requests: Vec<ProofRequest> = sqlx::query_as::<_, ProofRequest>(
-        r#"SELECT proof_id, status, proof, vk, batch_id, created_at
+        r#"SELECT proof_id, status::INTEGER AS status, proof, vk, batch_id, created_at, leaf_format, submitter
            FROM requests
-           WHERE created_at > $1 AND status = $2
+           WHERE status = $1 AND lease_batch_id IS NULL
            ORDER BY created_at ASC
-           LIMIT $3"#,
+           LIMIT $2
+           FOR UPDATE SKIP LOCKED"#,
     )
-    .bind(created_after as i64)
     .bind(pending_status)
     .bind(batch_size as i64)
-    .fetch_all(db_pool)
-    .await
-    .expect("Failed to get batch.");
+    .fetch_all(&mut *tx)
+    .await?;
+    if requests.is_empty() {
+        return Ok(requests);
+    }
+
+    let proof_ids = requests
+        .iter()
+        .map(|request| request.proof_id.clone())
+        .collect::<Vec<_>>();
+    sqlx::query(r#"UPDATE requests SET lease_batch_id = $1 WHERE proof_id = ANY($2)"#)
+        .bind(batch_id.clone())
+        .bind(proof_ids)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query(
+        r#"INSERT INTO batch_leases (batch_id, worker_id, expires_at) VALUES ($1, $2, $3)"#,
+    )
+    .bind(batch_id)
+    .bind(worker_id)
+    .bind(expires_at)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
     Ok(requests)
 }
 
-pub async fn write_merkle_tree(
+/// The lease of a batch, or `None` if the batch was not claimed with a lease.
+pub async fn get_batch_lease(
+    db_pool: &PgPool,
+    batch_id: Vec<u8>,
+) -> Result<Option<BatchLease>, sqlx::Error> {
+    let row = sqlx::query(
+        r#"SELECT worker_id, expires_at, completed_at, released_at FROM batch_leases WHERE batch_id = $1"#,
+    )
+    .bind(batch_id)
+    .fetch_optional(db_pool)
+    .await?;
+    Ok(row.map(|row| BatchLease {
+        worker_id: row.get("worker_id"),
+        expires_at: row.get("expires_at"),
+        completed: row.get::<Option<i64>, _>("completed_at").is_some(),
+        released: row.get::<Option<i64>, _>("released_at").is_some(),
+    }))
+}
+
+/// Complete the lease of a batch held by `worker_id`, and release the requests of the batch unless
+/// it was relayed. Returns whether the worker held the lease.
+pub async fn complete_batch(
     db_pool: &PgPool,
-    merkle_tree: Vec<u8>,
     batch_id: Vec<u8>,
+    worker_id: &str,
+    now: i64,
+) -> Result<bool, sqlx::Error> {
+    let mut tx = db_pool.begin().await?;
+    let result = sqlx::query(
+        r#"UPDATE batch_leases SET completed_at = $1
+           WHERE batch_id = $2 AND worker_id = $3 AND completed_at IS NULL AND released_at IS NULL"#,
+    )
+    .bind(now)
+    .bind(batch_id.clone())
+    .bind(worker_id)
+    .execute(&mut *tx)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Ok(false);
+    }
+
+    release_requests(&mut tx, &[batch_id]).await?;
+    tx.commit().await?;
+    Ok(true)
+}
+
+/// Release the batches whose lease expired before `now` without being completed or relayed, see
+/// [`release_requests`]. Returns the number of batches released.
+pub async fn release_expired_leases(db_pool: &PgPool, now: i64) -> Result<u64, sqlx::Error> {
+    let mut tx = db_pool.begin().await?;
+    let batch_ids = sqlx::query(
+        r#"UPDATE batch_leases SET released_at = $1
+           WHERE expires_at <= $1 AND completed_at IS NULL AND released_at IS NULL
+             AND batch_id NOT IN (SELECT batch_id FROM accumulator)
+           RETURNING batch_id"#,
+    )
+    .bind(now)
+    .fetch_all(&mut *tx)
+    .await?
+    .iter()
+    .map(|row| row.get::<Vec<u8>, _>("batch_id"))
+    .collect::<Vec<_>>();
+    if batch_ids.is_empty() {
+        return Ok(0);
+    }
+
+    release_requests(&mut tx, &batch_ids).await?;
+    tx.commit().await?;
+    Ok(batch_ids.len() as u64)
+}
+
+/// Send the requests of the batches that were not relayed back to pending, and drop their leaves
+/// and merkle trees, so that the requests can be claimed for another batch.
+async fn release_requests(
+    conn: &mut PgConnection,
+    batch_ids: &[Vec<u8>],
 ) -> Result<(), sqlx::Error> {
-    sqlx::query(r#"INSERT INTO merkle_trees (batch_id, tree) VALUES ($1, $2)"#)
+    sqlx::query(
+        r#"UPDATE requests SET status = $1, batch_id = NULL, leaf_index = NULL, lease_batch_id = NULL
+           WHERE lease_batch_id = ANY($2) AND status IN ($1, $3)"#,
+    )
+    .bind(AggregationStatus::Pending as i32)
+    .bind(batch_ids)
+    .bind(AggregationStatus::Processing as i32)
+    .execute(&mut *conn)
+    .await?;
+    sqlx::query(
+        r#"DELETE FROM merkle_trees
+           WHERE batch_id = ANY($1) AND batch_id NOT IN (SELECT batch_id FROM accumulator)"#,
+    )
+    .bind(batch_ids)
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+/// Store every node of the tree of a batch, in the encoding of [`ProgramTree::to_bytes`], unless
+/// the batch already has a tree. Returns whether the tree was stored.
+pub async fn write_merkle_tree(
+    conn: &mut PgConnection,
+    merkle_tree: &ProgramTree,
+    batch_id: Vec<u8>,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(
+        r#"INSERT INTO merkle_trees (batch_id, tree, version, hash_function, encoding) VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (batch_id) DO NOTHING"#,
+    )
+    .bind(batch_id)
+    .bind(merkle_tree.to_bytes())
+    .bind(merkle_tree.version as i64)
+    .bind(merkle_tree.hash_function as i64)
+    .bind(ProgramTree::ENCODING_VERSION as i64)
+    .execute(conn)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// The tree of a batch in the encoding of [`ProgramTree::to_bytes`], or of
+/// [`MerkleTree::to_bytes`] for batches aggregated as a single tree before each program had a
+/// subtree of its own. Trees stored as their leaves before the encoding existed are rebuilt.
+pub async fn get_batch_merkle_tree(
+    db_pool: &PgPool,
+    batch_id: Vec<u8>,
+) -> Result<Vec<u8>, sqlx::Error> {
+    let tree = sqlx::query(
+        r#"SELECT tree, version, hash_function, encoding FROM merkle_trees WHERE batch_id = $1"#,
+    )
+    .bind(batch_id)
+    .fetch_one(db_pool)
+    .await?;
+    if tree.get::<i64, _>("encoding") != 0 {
+        return Ok(tree.get::<&[u8], _>("tree").to_vec());
+    }
+
+    let version = u8::try_from(tree.get::<i64, _>("version"))
+        .ok()
+        .and_then(|version| TreeVersion::try_from(version).ok())
+        .ok_or_else(|| sqlx::Error::Decode("invalid merkle tree version".into()))?;
+    let hash_function = u8::try_from(tree.get::<i64, _>("hash_function"))
+        .ok()
+        .and_then(|hash_function| HashFunction::try_from(hash_function).ok())
+        .ok_or_else(|| sqlx::Error::Decode("invalid merkle tree hash function".into()))?;
+    let leaves = tree
+        .get::<&[u8], _>("tree")
+        .chunks(32)
+        .map(|chunk| chunk.try_into())
+        .collect::<Result<Vec<[u8; 32]>, _>>()
+        .map_err(|_| sqlx::Error::Decode("invalid merkle tree leaves".into()))?;
+    Ok(MerkleTree::with_hash_function(leaves, version, hash_function).to_bytes())
+}
+
+/// The roots of the batches in the accumulator, in the order they were appended.
+pub async fn get_accumulator_leaves(db_pool: &PgPool) -> Result<Vec<[u8; 32]>, sqlx::Error> {
+    let rows = sqlx::query(r#"SELECT root FROM accumulator ORDER BY leaf_index"#)
+        .fetch_all(db_pool)
+        .await?;
+    rows.iter()
+        .map(|row| {
+            row.get::<&[u8], _>("root")
+                .try_into()
+                .map_err(|_| sqlx::Error::Decode("invalid accumulator leaf".into()))
+        })
+        .collect()
+}
+
+/// The index of the batch in the accumulator, or `None` if it hasn't been relayed yet.
+pub async fn get_accumulator_index(
+    db_pool: &PgPool,
+    batch_id: Vec<u8>,
+) -> Result<Option<usize>, sqlx::Error> {
+    let row = sqlx::query(r#"SELECT leaf_index FROM accumulator WHERE batch_id = $1"#)
         .bind(batch_id)
-        .bind(merkle_tree)
-        .execute(db_pool)
+        .fetch_optional(db_pool)
+        .await?;
+    Ok(row.map(|row| row.get::<i64, _>("leaf_index") as usize))
+}
+
+/// Reserve the next leaf of the accumulator for the root of a batch that is about to be relayed,
+/// and return the roots of the batches before it. The lease of the batch is locked, so that it is
+/// not released while the batch is relayed, and the unique constraints of the accumulator make a
+/// concurrent reservation of the same leaf or batch wait for this transaction and then fail, so
+/// that the reservation holds until the transaction commits or rolls back.
+pub async fn reserve_accumulator_leaf(
+    conn: &mut PgConnection,
+    batch_id: Vec<u8>,
+    root: [u8; 32],
+) -> Result<Vec<[u8; 32]>, sqlx::Error> {
+    sqlx::query(r#"SELECT batch_id FROM batch_leases WHERE batch_id = $1 FOR UPDATE"#)
+        .bind(batch_id.clone())
+        .fetch_optional(&mut *conn)
         .await?;
+    let leaf_index = sqlx::query(
+        r#"INSERT INTO accumulator (leaf_index, batch_id, root) SELECT COUNT(*), $1, $2 FROM accumulator
+           RETURNING leaf_index"#,
+    )
+    .bind(batch_id)
+    .bind(root.to_vec())
+    .fetch_one(&mut *conn)
+    .await?
+    .get::<i64, _>("leaf_index");
+    let rows =
+        sqlx::query(r#"SELECT root FROM accumulator WHERE leaf_index < $1 ORDER BY leaf_index"#)
+            .bind(leaf_index)
+            .fetch_all(&mut *conn)
+            .await?;
+    rows.iter()
+        .map(|row| {
+            row.get::<&[u8], _>("root")
+                .try_into()
+                .map_err(|_| sqlx::Error::Decode("invalid accumulator leaf".into()))
+        })
+        .collect()
+}
+
+/// Mark the requests of a relayed batch and their proof ids aggregated, once its root has a leaf
+/// of the accumulator reserved with [`reserve_accumulator_leaf`].
+pub async fn mark_batch_relayed(
+    conn: &mut PgConnection,
+    batch_id: Vec<u8>,
+    hash_function: HashFunction,
+) -> Result<(), sqlx::Error> {
+    let rows = sqlx::query(
+        r#"UPDATE requests SET status = $1 WHERE batch_id = $2 AND status = $3 RETURNING proof_id"#,
+    )
+    .bind(AggregationStatus::Aggregated as i32)
+    .bind(batch_id.clone())
+    .bind(AggregationStatus::Processing as i32)
+    .fetch_all(&mut *conn)
+    .await?;
+    let value = proof_id_value(hash_function, ProofIdState::Aggregated, &batch_id);
+    let mut values = Vec::new();
+    for row in rows {
+        let proof_id = row.get::<Vec<u8>, _>("proof_id");
+        values.push((proof_id_key(&proof_id)?, value));
+        set_proof_id_state(
+            &mut *conn,
+            proof_id,
+            ProofIdState::Aggregated,
+            batch_id.clone(),
+        )
+        .await?;
+    }
+    update_proof_id_tree(&mut *conn, hash_function, &values, Some(batch_id), None).await?;
     Ok(())
 }
-pub async fn get_merkle_tree(db_pool: &PgPool, proof_id: Vec<u8>) -> Result<Vec<u8>, sqlx::Error> {
+
+pub async fn get_batch_id(
+    db_pool: &PgPool,
+    proof_id: Vec<u8>,
+) -> Result<Option<Vec<u8>>, sqlx::Error> {
     let batch_row = sqlx::query(r#"SELECT batch_id FROM requests WHERE proof_id = $1"#)
         .bind(proof_id)
         .fetch_one(db_pool)
         .await?;
-    let batch_id = batch_row.get::<&[u8], _>("batch_id").to_vec();
-    // let batch_row = sqlx::query
+    Ok(batch_row.get::<Option<Vec<u8>>, _>("batch_id"))
+}
 
-    let tree = sqlx::query(r#"SELECT tree FROM merkle_trees WHERE batch_id = $1"#)
-        .bind(batch_id)
+pub async fn get_leaf_index(
+    db_pool: &PgPool,
+    proof_id: Vec<u8>,
+) -> Result<Option<usize>, sqlx::Error> {
+    let leaf_row = sqlx::query(r#"SELECT leaf_index FROM requests WHERE proof_id = $1"#)
+        .bind(proof_id)
         .fetch_one(db_pool)
         .await?;
-    Ok(tree.get::<&[u8], _>("tree").to_vec())
+    Ok(leaf_row
+        .get::<Option<i64>, _>("leaf_index")
+        .map(|index| index as usize))
+}
+
+/// The [`LeafFormat`] of a request row, which is checked when the request is created.
+fn leaf_format(row: &PgRow) -> Result<LeafFormat, sqlx::Error> {
+    u8::try_from(row.get::<i32, _>("leaf_format"))
+        .ok()
+        .and_then(|leaf_format| LeafFormat::try_from(leaf_format).ok())
+        .ok_or_else(|| sqlx::Error::Decode("invalid leaf format".into()))
+}
+
+/// Decode the bincode-encoded proof or vk in `column` of a request row, which is checked when the
+/// request is created.
+fn decode_column<T: DeserializeOwned>(row: &PgRow, column: &str) -> Result<T, sqlx::Error> {
+    bincode::deserialize(row.get::<&[u8], _>(column))
+        .map_err(|e| sqlx::Error::Decode(format!("invalid {} of request: {}", column, e).into()))
+}
+
+pub async fn get_leaf(db_pool: &PgPool, proof_id: Vec<u8>) -> Result<[u8; 32], sqlx::Error> {
+    let (_, leaf) = get_program_and_leaf(db_pool, proof_id).await?;
+    Ok(leaf)
+}
+
+/// The vkey hash of the program of a request and the leaf of its proof.
+async fn get_program_and_leaf(
+    db_pool: &PgPool,
+    proof_id: Vec<u8>,
+) -> Result<([u8; 32], [u8; 32]), sqlx::Error> {
+    let proof_row = sqlx::query(
+        r#"SELECT proof, vk, leaf_format, submitter FROM requests WHERE proof_id = $1"#,
+    )
+    .bind(proof_id)
+    .fetch_one(db_pool)
+    .await?;
+    program_and_leaf(&proof_row)
+}
+
+/// The vkey hash of the program and the leaf of a request row with its proof, vk, leaf format and
+/// submitter.
+fn program_and_leaf(proof_row: &PgRow) -> Result<([u8; 32], [u8; 32]), sqlx::Error> {
+    let proof: SP1ProofWithPublicValues = decode_column(proof_row, "proof")?;
+    let vk: SP1VerifyingKey = decode_column(proof_row, "vk")?;
+    let public_values = proof.public_values;
+    let vkey_hash = vk.hash_bytes();
+    let leaf = leaf_format(proof_row)?.hash_leaf(&vkey_hash, public_values.as_slice());
+    let leaf = bind_submitter(&leaf, proof_row.get::<&[u8], _>("submitter"));
+    Ok((vkey_hash, leaf))
 }
-pub async fn get_leaf(db_pool: &PgPool, proof_id: Vec<u8>) -> Result<Vec<u8>, sqlx::Error> {
-    let proof_row = sqlx::query(r#"SELECT proof, vk FROM requests WHERE proof_id = $1"#)
+
+pub async fn get_submitter(db_pool: &PgPool, proof_id: Vec<u8>) -> Result<Vec<u8>, sqlx::Error> {
+    let submitter_row = sqlx::query(r#"SELECT submitter FROM requests WHERE proof_id = $1"#)
         .bind(proof_id)
         .fetch_one(db_pool)
         .await?;
-    let proof_bytes = proof_row.get::<&[u8], _>("proof").to_vec();
-    let proof: SP1ProofWithPublicValues = bincode::deserialize(&proof_bytes).unwrap();
-    let vk_bytes = proof_row.get::<&[u8], _>("vk").to_vec();
-    let vk: SP1VerifyingKey = bincode::deserialize(&vk_bytes).unwrap();
-    let public_values = proof.public_values;
-    let leaf = Sha256::digest([&vk.hash_bytes(), public_values.as_slice()].concat());
-    Ok(leaf.to_vec())
+    Ok(submitter_row.get::<Vec<u8>, _>("submitter"))
 }
 
 pub async fn get_vkey_and_public_values(
     db_pool: &PgPool,
     proof_id: Vec<u8>,
-) -> Result<(Vec<u8>, Vec<u8>), sqlx::Error> {
-    let proof_row = sqlx::query(r#"SELECT vk, proof FROM requests WHERE proof_id = $1"#)
-        .bind(proof_id)
-        .fetch_one(db_pool)
-        .await?;
-    let proof_bytes = proof_row.get::<&[u8], _>("proof").to_vec();
-    let proof: SP1ProofWithPublicValues = bincode::deserialize(&proof_bytes).unwrap();
-    let vk_bytes = proof_row.get::<&[u8], _>("vk").to_vec();
-    let vk: SP1VerifyingKey = bincode::deserialize(&vk_bytes).unwrap();
+) -> Result<(Vec<u8>, Vec<u8>, LeafFormat), sqlx::Error> {
+    let proof_row =
+        sqlx::query(r#"SELECT vk, proof, leaf_format FROM requests WHERE proof_id = $1"#)
+            .bind(proof_id)
+            .fetch_one(db_pool)
+            .await?;
+    let proof: SP1ProofWithPublicValues = decode_column(&proof_row, "proof")?;
+    let vk: SP1VerifyingKey = decode_column(&proof_row, "vk")?;
     let public_values = proof.public_values;
-    Ok((vk.hash_bytes().to_vec(), public_values.to_vec()))
+    Ok((
+        vk.hash_bytes().to_vec(),
+        public_values.to_vec(),
+        leaf_format(&proof_row)?,
+    ))
 }
 
 pub async fn get_proof_status(
@@ -121,21 +587,26 @@ pub async fn get_proof_status(
     // Check if the proof_row was found
     match proof_row {
         Ok(row) => {
-            let aggregation_status = row.get::<i32, _>("status");
-            let response_status = match aggregation_status {
-                status if status == AggregationStatus::Pending as i32 => {
+            let aggregation_status = row.get::<i64, _>("status");
+            match aggregation_status {
+                // A proof is only aggregated once the proof of its batch is relayed.
+                status
+                    if status == AggregationStatus::Pending as i64
+                        || status == AggregationStatus::Processing as i64 =>
+                {
                     Ok(AggregationStatusResponse::AggregationPending)
                 }
-                status if status == AggregationStatus::Aggregated as i32 => {
+                status if status == AggregationStatus::Aggregated as i64 => {
                     Ok(AggregationStatusResponse::AggregationComplete)
                 }
-                status if status == AggregationStatus::Verified as i32 => {
+                status if status == AggregationStatus::Verified as i64 => {
                     Ok(AggregationStatusResponse::AggregationVerified)
                 }
-                _ => Err("Invalid aggregation status"), // Return an error for unexpected status
+                status if status == AggregationStatus::Failed as i64 => {
+                    Ok(AggregationStatusResponse::AggregationFailed)
+                }
+                _ => Err(sqlx::Error::Decode("invalid aggregation status".into())),
             }
-            .unwrap();
-            Ok(response_status)
         }
         Err(_) => {
             // If proof not found, set response status to NotFound
@@ -144,63 +615,422 @@ pub async fn get_proof_status(
     }
 }
 
+/// Assign the leaves of a batch to its requests, which are aggregated once the proof of the batch
+/// is relayed, see [`record_relay`]. Returns the concatenated leaves, the concatenated vkey hashes
+/// of their programs and the proof ids of the proofs to aggregate, all in leaf order, or `None`
+/// unless every proof id is a distinct pending request claimed for the batch.
+///
+/// Each program has a subtree of its own, so the leaves are grouped by program in ascending order
+/// of vkey hash, and the requests of a program keep their order in the batch.
 pub async fn process_batch(
-    db_pool: &PgPool,
-    proofs: Vec<ProofRequest>,
+    conn: &mut PgConnection,
+    proof_ids: Vec<Vec<u8>>,
     batch_id: Vec<u8>,
-) -> Result<Vec<u8>, sqlx::Error> {
-    let mut leaves = Vec::new();
-    let aggregated_status = AggregationStatus::Aggregated as i32;
-    for request in proofs {
-        sqlx::query(r#"UPDATE requests SET status = $1 WHERE proof_id = $2"#)
-            .bind(aggregated_status)
-            .bind(request.proof_id.clone())
-            .execute(db_pool)
-            .await?;
-        sqlx::query(r#"UPDATE requests SET batch_id = $1 WHERE proof_id = $2"#)
-            .bind(batch_id.clone())
-            .bind(request.proof_id.clone())
-            .execute(db_pool)
-            .await?;
-        let leaf = get_leaf(db_pool, request.proof_id).await?;
-        leaves.push(leaf);
+    duplicate_policy: DuplicateLeafPolicy,
+) -> Result<Option<(Vec<u8>, Vec<u8>, Vec<Vec<u8>>)>, sqlx::Error> {
+    // The requests are locked until the batch is stored, so that they can't be released or
+    // processed for the batch concurrently.
+    let mut rows = sqlx::query(
+        r#"SELECT proof_id, proof, vk, leaf_format, submitter FROM requests
+           WHERE lease_batch_id = $1 AND status = $2 AND proof_id = ANY($3)
+           FOR UPDATE"#,
+    )
+    .bind(batch_id.clone())
+    .bind(AggregationStatus::Pending as i32)
+    .bind(proof_ids.clone())
+    .fetch_all(&mut *conn)
+    .await?
+    .into_iter()
+    .map(|row| (row.get::<Vec<u8>, _>("proof_id"), row))
+    .collect::<HashMap<_, _>>();
+    let mut request_rows = Vec::new();
+    for proof_id in proof_ids {
+        let Some(row) = rows.remove(&proof_id) else {
+            return Ok(None);
+        };
+        request_rows.push((proof_id, row));
+    }
+    let mut requests = Vec::new();
+    for (proof_id, row) in request_rows {
+        let (vkey_hash, leaf) = program_and_leaf(&row)?;
+        requests.push((vkey_hash, leaf, proof_id));
     }
+    requests.sort_by_key(|(vkey_hash, _, _)| *vkey_hash);
+    let request_leaves = requests
+        .iter()
+        .map(|(_, leaf, _)| *leaf)
+        .collect::<Vec<_>>();
+    let proof_ids = requests
+        .iter()
+        .map(|(_, _, proof_id)| proof_id)
+        .collect::<Vec<_>>();
+    let batch = assign_leaves(&request_leaves, duplicate_policy);
 
-    let leaves_vec = leaves
+    let processing_status = AggregationStatus::Processing as i32;
+    let failed_status = AggregationStatus::Failed as i32;
+    for (&proof_id, leaf_index) in proof_ids.iter().zip(batch.indices.iter()) {
+        match leaf_index {
+            Some(leaf_index) => {
+                sqlx::query(
+                    r#"UPDATE requests SET status = $1, batch_id = $2, leaf_index = $3 WHERE proof_id = $4"#,
+                )
+                .bind(processing_status)
+                .bind(batch_id.clone())
+                .bind(*leaf_index as i64)
+                .bind(proof_id.clone())
+                .execute(&mut *conn)
+                .await?;
+            }
+            // Rejected duplicates are not part of the batch.
+            None => {
+                sqlx::query(
+                    r#"UPDATE requests SET status = $1, failure_reason = $2 WHERE proof_id = $3"#,
+                )
+                .bind(failed_status)
+                .bind("duplicate of an earlier proof in the batch")
+                .bind(proof_id.clone())
+                .execute(&mut *conn)
+                .await?;
+            }
+        }
+    }
+
+    let vkey_hashes = batch.sources.iter().flat_map(|&i| requests[i].0).collect();
+    let proof_ids = batch
+        .sources
         .iter()
-        .map(|l| l.to_vec())
-        .collect::<Vec<Vec<u8>>>()
-        .concat();
-    Ok(leaves_vec)
+        .map(|&i| proof_ids[i].clone())
+        .collect();
+    Ok(Some((batch.leaves.concat(), vkey_hashes, proof_ids)))
 }
 
-pub async fn update_batch_status(
-    db_pool: &PgPool,
+pub async fn set_proof_id_state(
+    conn: &mut PgConnection,
+    proof_id: Vec<u8>,
+    state: ProofIdState,
     batch_id: Vec<u8>,
-    status: i32,
 ) -> Result<(), sqlx::Error> {
-    // find all proof_ids in batch
-    let rows = sqlx::query(r#"SELECT proof_id FROM requests WHERE batch_id = $1"#)
-        .bind(batch_id)
-        .fetch_all(db_pool)
+    sqlx::query(
+        r#"INSERT INTO proof_ids (proof_id, state, batch_id) VALUES ($1, $2, $3)
+        ON CONFLICT (proof_id) DO UPDATE SET state = $2, batch_id = $3"#,
+    )
+    .bind(proof_id)
+    .bind(state as i32)
+    .bind(batch_id)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+/// The state and batch id of every proof id in the sparse merkle tree of proof ids.
+pub async fn get_proof_id_states(
+    conn: &mut PgConnection,
+) -> Result<Vec<(Vec<u8>, ProofIdState, Vec<u8>)>, sqlx::Error> {
+    let rows = sqlx::query(r#"SELECT proof_id, state, batch_id FROM proof_ids"#)
+        .fetch_all(conn)
+        .await?;
+    rows.iter()
+        .map(|row| {
+            Ok((
+                row.get::<&[u8], _>("proof_id").to_vec(),
+                proof_id_state(row)?,
+                row.get::<&[u8], _>("batch_id").to_vec(),
+            ))
+        })
+        .collect()
+}
+
+fn proof_id_state(row: &PgRow) -> Result<ProofIdState, sqlx::Error> {
+    ProofIdState::try_from(row.get::<i32, _>("state"))
+        .map_err(|_| sqlx::Error::Decode("invalid proof id state".into()))
+}
+
+/// The key of a proof id in the sparse merkle tree of proof ids.
+fn proof_id_key(proof_id: &[u8]) -> Result<[u8; 32], sqlx::Error> {
+    proof_id
+        .try_into()
+        .map_err(|_| sqlx::Error::Decode("invalid proof id".into()))
+}
+
+/// Mark an aggregated proof id as revoked. Returns whether the proof id was aggregated and not
+/// already revoked.
+pub async fn revoke_proof_id(
+    db_pool: &PgPool,
+    proof_id: Vec<u8>,
+    hash_function: HashFunction,
+) -> Result<bool, sqlx::Error> {
+    let mut tx = db_pool.begin().await?;
+    let row = sqlx::query(
+        r#"UPDATE proof_ids SET state = $1 WHERE proof_id = $2 AND state = $3 RETURNING batch_id"#,
+    )
+    .bind(ProofIdState::Revoked as i32)
+    .bind(proof_id.clone())
+    .bind(ProofIdState::Aggregated as i32)
+    .fetch_optional(&mut *tx)
+    .await?;
+    let Some(row) = row else {
+        return Ok(false);
+    };
+    let value = proof_id_value(
+        hash_function,
+        ProofIdState::Revoked,
+        row.get::<&[u8], _>("batch_id"),
+    );
+    update_proof_id_tree(
+        &mut tx,
+        hash_function,
+        &[(proof_id_key(&proof_id)?, value)],
+        None,
+        Some(proof_id),
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(true)
+}
+
+/// Set the values of proof ids in the stored sparse merkle tree of proof ids, and store its new
+/// root with the batch whose proof ids were aggregated or the proof id that was revoked. Updates
+/// of the tree are serialized, so that each one starts from the last root. Returns the new root.
+async fn update_proof_id_tree(
+    conn: &mut PgConnection,
+    hash_function: HashFunction,
+    values: &[([u8; 32], [u8; 32])],
+    batch_id: Option<Vec<u8>>,
+    revoked_proof_id: Option<Vec<u8>>,
+) -> Result<[u8; 32], sqlx::Error> {
+    // Proofs are read without the lock, from a snapshot of the tree and its root.
+    sqlx::query(r#"LOCK TABLE proof_id_roots IN EXCLUSIVE MODE"#)
+        .execute(&mut *conn)
+        .await?;
+    let keys = values.iter().map(|(key, _)| *key).collect::<Vec<_>>();
+    let nodes = get_proof_id_nodes(&mut *conn, &SparseMerklePatch::positions(&keys)).await?;
+    let mut patch = SparseMerklePatch::new(hash_function, nodes);
+    for (key, value) in values {
+        patch.update(*key, Some(*value));
+    }
+
+    let (stored, removed): (Vec<_>, Vec<_>) = patch.changes().partition(|(_, node)| *node != EMPTY);
+    sqlx::query(
+        r#"INSERT INTO proof_id_nodes (depth, path, node)
+           SELECT * FROM UNNEST($1::SMALLINT[], $2::BYTEA[], $3::BYTEA[])
+           ON CONFLICT (depth, path) DO UPDATE SET node = EXCLUDED.node"#,
+    )
+    .bind(
+        stored
+            .iter()
+            .map(|(position, _)| position.depth as i16)
+            .collect::<Vec<_>>(),
+    )
+    .bind(
+        stored
+            .iter()
+            .map(|(position, _)| position.path.to_vec())
+            .collect::<Vec<_>>(),
+    )
+    .bind(
+        stored
+            .iter()
+            .map(|(_, node)| node.to_vec())
+            .collect::<Vec<_>>(),
+    )
+    .execute(&mut *conn)
+    .await?;
+    sqlx::query(
+        r#"DELETE FROM proof_id_nodes
+           WHERE (depth, path) IN (SELECT * FROM UNNEST($1::SMALLINT[], $2::BYTEA[]))"#,
+    )
+    .bind(
+        removed
+            .iter()
+            .map(|(position, _)| position.depth as i16)
+            .collect::<Vec<_>>(),
+    )
+    .bind(
+        removed
+            .iter()
+            .map(|(position, _)| position.path.to_vec())
+            .collect::<Vec<_>>(),
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    let root = patch.root();
+    sqlx::query(
+        r#"INSERT INTO proof_id_roots (root_index, root, batch_id, revoked_proof_id)
+           SELECT COUNT(*), $1, $2, $3 FROM proof_id_roots"#,
+    )
+    .bind(root.to_vec())
+    .bind(batch_id)
+    .bind(revoked_proof_id)
+    .execute(&mut *conn)
+    .await?;
+    Ok(root)
+}
+
+/// The stored nodes of the tree of proof ids at `positions`. Empty nodes are not stored.
+async fn get_proof_id_nodes(
+    conn: &mut PgConnection,
+    positions: &[NodePosition],
+) -> Result<Vec<(NodePosition, [u8; 32])>, sqlx::Error> {
+    let rows = sqlx::query(
+        r#"SELECT proof_id_nodes.depth, proof_id_nodes.path, node FROM proof_id_nodes
+           JOIN UNNEST($1::SMALLINT[], $2::BYTEA[]) AS positions (depth, path)
+             ON proof_id_nodes.depth = positions.depth AND proof_id_nodes.path = positions.path"#,
+    )
+    .bind(
+        positions
+            .iter()
+            .map(|position| position.depth as i16)
+            .collect::<Vec<_>>(),
+    )
+    .bind(
+        positions
+            .iter()
+            .map(|position| position.path.to_vec())
+            .collect::<Vec<_>>(),
+    )
+    .fetch_all(conn)
+    .await?;
+    rows.iter()
+        .map(|row| {
+            let position = NodePosition {
+                depth: row.get::<i16, _>("depth") as usize,
+                path: row.get::<&[u8], _>("path").try_into().map_err(|_| {
+                    sqlx::Error::Decode("invalid node position of the proof id tree".into())
+                })?,
+            };
+            let node = row
+                .get::<&[u8], _>("node")
+                .try_into()
+                .map_err(|_| sqlx::Error::Decode("invalid node of the proof id tree".into()))?;
+            Ok((position, node))
+        })
+        .collect()
+}
+
+/// The proof of the value of `proof_id` in the stored tree of proof ids, with its state, its batch
+/// id, and the last root of the tree, which the proof is against.
+pub async fn get_proof_id_proof(
+    db_pool: &PgPool,
+    proof_id: [u8; 32],
+    hash_function: HashFunction,
+) -> Result<(SparseMerkleProof, ProofIdState, Vec<u8>, [u8; 32]), sqlx::Error> {
+    // The proof and the root are read from the same snapshot of the tree.
+    let mut tx = db_pool.begin().await?;
+    sqlx::query(r#"SET TRANSACTION ISOLATION LEVEL REPEATABLE READ"#)
+        .execute(&mut *tx)
         .await?;
-    let proof_ids = rows
+    let row = sqlx::query(r#"SELECT state, batch_id FROM proof_ids WHERE proof_id = $1"#)
+        .bind(proof_id.to_vec())
+        .fetch_optional(&mut *tx)
+        .await?;
+    let (state, batch_id) = match row {
+        Some(row) => (proof_id_state(&row)?, row.get::<Vec<u8>, _>("batch_id")),
+        None => (ProofIdState::Absent, vec![]),
+    };
+    let root =
+        match sqlx::query(r#"SELECT root FROM proof_id_roots ORDER BY root_index DESC LIMIT 1"#)
+            .fetch_optional(&mut *tx)
+            .await?
+        {
+            Some(row) => row
+                .get::<&[u8], _>("root")
+                .try_into()
+                .map_err(|_| sqlx::Error::Decode("invalid root of the proof id tree".into()))?,
+            None => EMPTY,
+        };
+    let positions = NodePosition::siblings(&proof_id);
+    let nodes = get_proof_id_nodes(&mut tx, &positions)
+        .await?
+        .into_iter()
+        .collect::<BTreeMap<_, _>>();
+    tx.commit().await?;
+
+    let siblings = positions
         .iter()
-        .map(|r| r.get::<&[u8], _>("proof_id").to_vec())
-        .collect::<Vec<Vec<u8>>>();
-    // update status for each proof_id
-    for proof_id in proof_ids {
-        sqlx::query(r#"UPDATE requests SET status = $1 WHERE proof_id = $2"#)
-            .bind(status)
-            .bind(proof_id)
-            .execute(db_pool)
-            .await
-            .expect("Failed to update requests.");
+        .map(|position| nodes.get(position).copied().unwrap_or(EMPTY))
+        .collect();
+    let value =
+        (state != ProofIdState::Absent).then(|| proof_id_value(hash_function, state, &batch_id));
+    let proof = SparseMerkleProof::from_siblings(proof_id, value, siblings, hash_function);
+    Ok((proof, state, batch_id, root))
+}
+
+/// Build the stored tree of proof ids from the proof ids aggregated before the tree was stored.
+/// Does nothing once the tree has a root.
+pub async fn backfill_proof_id_tree(
+    db_pool: &PgPool,
+    hash_function: HashFunction,
+) -> Result<(), sqlx::Error> {
+    let mut tx = db_pool.begin().await?;
+    sqlx::query(r#"LOCK TABLE proof_id_roots IN EXCLUSIVE MODE"#)
+        .execute(&mut *tx)
+        .await?;
+    let has_root = sqlx::query(r#"SELECT 1 FROM proof_id_roots LIMIT 1"#)
+        .fetch_optional(&mut *tx)
+        .await?
+        .is_some();
+    let proof_id_states = get_proof_id_states(&mut tx).await?;
+    if has_root || proof_id_states.is_empty() {
+        return Ok(());
     }
 
+    let values = proof_id_states
+        .iter()
+        .map(|(proof_id, state, batch_id)| {
+            Ok((
+                proof_id_key(proof_id)?,
+                proof_id_value(hash_function, *state, batch_id),
+            ))
+        })
+        .collect::<Result<Vec<_>, sqlx::Error>>()?;
+    update_proof_id_tree(&mut tx, hash_function, &values, None, None).await?;
+    tx.commit().await?;
     Ok(())
 }
 
+/// Mark requests of a batch that can't be aggregated as failed, with the reason for each one.
+/// Either every request is marked, or none is if any of them is not a pending or processing
+/// request claimed for the batch. Returns whether they were marked.
+pub async fn mark_proofs_failed(
+    db_pool: &PgPool,
+    batch_id: Vec<u8>,
+    failed_proofs: Vec<FailedProof>,
+) -> Result<bool, sqlx::Error> {
+    let mut tx = db_pool.begin().await?;
+    for failed_proof in failed_proofs {
+        let result = sqlx::query(
+            r#"UPDATE requests SET status = $1, failure_reason = $2
+               WHERE proof_id = $3 AND lease_batch_id = $4 AND status IN ($5, $6)"#,
+        )
+        .bind(AggregationStatus::Failed as i32)
+        .bind(failed_proof.reason)
+        .bind(failed_proof.proof_id)
+        .bind(batch_id.clone())
+        .bind(AggregationStatus::Pending as i32)
+        .bind(AggregationStatus::Processing as i32)
+        .execute(&mut *tx)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+    }
+    tx.commit().await?;
+    Ok(true)
+}
+
+/// Why the request was not aggregated, if it failed.
+pub async fn get_failure_reason(
+    db_pool: &PgPool,
+    proof_id: Vec<u8>,
+) -> Result<Option<String>, sqlx::Error> {
+    let row = sqlx::query(r#"SELECT failure_reason FROM requests WHERE proof_id = $1"#)
+        .bind(proof_id)
+        .fetch_optional(db_pool)
+        .await?;
+    Ok(row.and_then(|row| row.get::<Option<String>, _>("failure_reason")))
+}
+
 pub async fn get_tx_context(
     db_pool: &PgPool,
     proof_id: Vec<u8>,
@@ -212,25 +1042,29 @@ pub async fn get_tx_context(
     .fetch_one(db_pool)
     .await?;
 
-    let tx_hash = tx_row.get::<&[u8], _>("tx_hash").to_vec();
-    let chain_id = tx_row.get::<i64, _>("chain_id");
-    let contract_address = tx_row.get::<&[u8], _>("contract_address").to_vec();
-    Ok((tx_hash, chain_id as u64, contract_address))
+    // The context is only set once the transaction of the batch is recorded.
+    let tx_hash = tx_row.try_get::<Option<Vec<u8>>, _>("tx_hash")?;
+    let chain_id = tx_row.try_get::<Option<i64>, _>("chain_id")?;
+    let contract_address = tx_row.try_get::<Option<Vec<u8>>, _>("contract_address")?;
+    Ok((
+        tx_hash.unwrap_or_default(),
+        chain_id.unwrap_or_default() as u64,
+        contract_address.unwrap_or_default(),
+    ))
 }
 
+/// Record the transaction that relayed a batch, as returned by [`crate::relay::relay_proof`].
 pub async fn update_proof_tx_hash(
-    db_pool: &PgPool,
+    conn: &mut PgConnection,
     batch_id: Vec<u8>,
-    tx_hash: Vec<u8>,
+    (tx_hash, chain_id, contract_address): (Vec<u8>, u64, Vec<u8>),
 ) -> Result<(), sqlx::Error> {
-    let contract_address = std::env::var("CONTRACT_ADDRESS").unwrap();
-    let chain_id = std::env::var("CHAIN_ID").unwrap();
     sqlx::query(r#"UPDATE requests SET tx_hash = $1, contract_address = $2, chain_id = $3 WHERE batch_id = $4"#)
         .bind(tx_hash)
         .bind(contract_address)
-        .bind(chain_id)
+        .bind(chain_id as i64)
         .bind(batch_id)
-        .execute(db_pool)
+        .execute(conn)
         .await?;
     Ok(())
 }
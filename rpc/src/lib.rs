@@ -2,21 +2,47 @@ use sqlx::postgres::{PgPool, PgPoolOptions};
 mod aggregation_service;
 mod db;
 mod relay;
+#[cfg(test)]
 mod tests;
+use chrono::Utc;
 use eyre::Result;
+use sp1_sdk::{ProverClient, SP1VerifyingKey};
 use std::{
     net::TcpListener,
-    sync::atomic::{AtomicU16, Ordering},
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 use tonic::transport::Server;
 use tracing::error;
-use types::aggregation::aggregation_service_server::AggregationServiceServer;
+use types::{
+    aggregation::aggregation_service_server::AggregationServiceServer,
+    merkle_tree::{hash_function_from_env, HashFunction, HASH_FUNCTION_ENV},
+};
 
 static PORT_COUNTER: AtomicU16 = AtomicU16::new(50052);
 
-#[derive(Debug, Clone)]
+/// How often batches whose lease expired are released back to pending.
+const LEASE_RELEASE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone)]
 pub struct AggregationRpc {
     pub db_pool: PgPool,
+    /// The hash function of the trees of new batches, which the guest and the verifier contract
+    /// must use too.
+    pub hash_function: HashFunction,
+    /// The token admin RPCs must be called with, or `None` to disable them.
+    pub admin_token: Option<String>,
+    /// The vkey of the aggregation program, which aggregation proofs are verified with before
+    /// they are relayed, or `None` to refuse to relay them.
+    pub aggregation_vk: Option<Arc<SP1VerifyingKey>>,
+    /// The client proofs are verified off-chain with, which is built once since building it sets
+    /// up the prover.
+    pub prover_client: Arc<ProverClient>,
+    /// Whether aggregation requests are verified when they are submitted, besides being decoded.
+    pub verify_submissions: bool,
 }
 
 pub async fn new_db(database_url: &str) -> Result<PgPool> {
@@ -28,10 +54,33 @@ pub async fn new_db(database_url: &str) -> Result<PgPool> {
     Ok(db_pool)
 }
 
+impl AggregationRpc {
+    /// Configure the service from the environment, see `.env.example`.
+    pub fn from_env(db_pool: PgPool) -> Result<Self> {
+        Ok(Self {
+            db_pool,
+            hash_function: hash_function_from_env()
+                .map_err(|e| eyre::eyre!("invalid {}: {}", HASH_FUNCTION_ENV, e))?,
+            admin_token: std::env::var("ADMIN_TOKEN")
+                .ok()
+                .filter(|token| !token.is_empty()),
+            aggregation_vk: aggregation_vk_from_env()?,
+            prover_client: Arc::new(ProverClient::new()),
+            verify_submissions: std::env::var("VERIFY_SUBMITTED_PROOFS")
+                .is_ok_and(|value| value == "true"),
+        })
+    }
+}
+
 pub async fn start(db_pool: PgPool, addr: String) -> Result<()> {
-    let grpc_addr = addr.parse()?;
-    let aggregation_rpc = AggregationRpc { db_pool };
+    serve(AggregationRpc::from_env(db_pool)?, addr).await
+}
 
+/// Serve the service on `addr` with the given configuration.
+pub async fn serve(aggregation_rpc: AggregationRpc, addr: String) -> Result<()> {
+    let grpc_addr = addr.parse()?;
+    // Proof ids aggregated before the tree of proof ids was stored are added to it once.
+    db::backfill_proof_id_tree(&aggregation_rpc.db_pool, aggregation_rpc.hash_function).await?;
     println!("Starting RPC server on {}", addr);
 
     let aggregation_server = AggregationServiceServer::new(aggregation_rpc.clone())
@@ -39,6 +88,7 @@ pub async fn start(db_pool: PgPool, addr: String) -> Result<()> {
         .max_encoding_message_size(1024 * 1024 * 1024);
 
     let rpc_server = Server::builder().add_service(aggregation_server);
+    let db_pool = aggregation_rpc.db_pool.clone();
 
     tokio::select! {
         result = rpc_server.serve(grpc_addr) => {
@@ -49,12 +99,45 @@ pub async fn start(db_pool: PgPool, addr: String) -> Result<()> {
         _ = tokio::signal::ctrl_c() => {
             println!("Ctrl-C received, shutting down...");
         }
+        _ = release_expired_leases(db_pool) => {}
     }
 
     Ok(())
 }
 
-pub async fn start_test_rpc_server(db_pool: PgPool) -> eyre::Result<String> {
+/// Read the vkey of the aggregation program from the file at `AGGREGATION_VKEY_PATH`, as written
+/// by `export-aggregation-vkey`.
+fn aggregation_vk_from_env() -> Result<Option<Arc<SP1VerifyingKey>>> {
+    let Some(path) = std::env::var("AGGREGATION_VKEY_PATH")
+        .ok()
+        .filter(|path| !path.is_empty())
+    else {
+        return Ok(None);
+    };
+    let bytes = std::fs::read(&path)
+        .map_err(|e| eyre::eyre!("failed to read aggregation vkey from {}: {}", path, e))?;
+    let vk = bincode::deserialize(&bytes)
+        .map_err(|e| eyre::eyre!("invalid aggregation vkey in {}: {}", path, e))?;
+    Ok(Some(Arc::new(vk)))
+}
+
+/// Release the batches whose lease expired, so that their requests are aggregated even if no
+/// worker claims a batch for a while.
+async fn release_expired_leases(db_pool: PgPool) {
+    let mut interval = tokio::time::interval(LEASE_RELEASE_INTERVAL);
+    loop {
+        interval.tick().await;
+        match db::release_expired_leases(&db_pool, Utc::now().timestamp_millis()).await {
+            Ok(0) => {}
+            Ok(released) => println!("Released {} batches with an expired lease", released),
+            Err(e) => error!("Failed to release expired batch leases: {}", e),
+        }
+    }
+}
+
+/// Start the service in the background with the given configuration, which tests pass explicitly
+/// rather than through the environment since they run in parallel.
+pub async fn start_test_rpc_server(aggregation_rpc: AggregationRpc) -> eyre::Result<String> {
     let port = loop {
         let port = PORT_COUNTER.fetch_add(1, Ordering::SeqCst);
         if TcpListener::bind(("127.0.0.1", port)).is_ok() {
@@ -65,7 +148,7 @@ pub async fn start_test_rpc_server(db_pool: PgPool) -> eyre::Result<String> {
     let grpc_addr = addr.clone();
 
     tokio::spawn(async move {
-        if let Err(e) = start(db_pool, grpc_addr).await {
+        if let Err(e) = serve(aggregation_rpc, grpc_addr).await {
             eprintln!("error starting server: {:?}", e);
         }
     });
@@ -1,35 +1,79 @@
 use std::time::Duration;
 
 use alloy_network::EthereumWallet;
-use alloy_provider::ProviderBuilder;
-use alloy_rpc_types_eth::TransactionReceipt;
+use alloy_provider::{Provider, ProviderBuilder};
 use alloy_signer_local::PrivateKeySigner;
 use alloy_sol_types::sol;
-use clap::Parser;
 use sp1_sdk::SP1ProofWithPublicValues;
+use types::{merkle_tree::HashFunction, public_values::AggregationPublicValues};
 
 sol!(
     #[sol(rpc)]
     "../contracts/src/SP1AggregationVerifier.sol"
 );
 
-pub async fn relay_proof(proof: SP1ProofWithPublicValues) -> Result<Vec<u8>, eyre::Error> {
+/// Relay an aggregation proof to the verifier contract at `CONTRACT_ADDRESS`. Returns the hash of
+/// the transaction, the chain id and the address of the contract.
+pub async fn relay_proof(
+    proof: SP1ProofWithPublicValues,
+    hash_function: HashFunction,
+) -> Result<(Vec<u8>, u64, Vec<u8>), eyre::Error> {
     dotenv::dotenv().ok();
-    let rpc_url = std::env::var("RPC_URL")
-        .unwrap()
+    let rpc_url = env_var("RPC_URL")?
         .parse()
-        .expect("Invalid RPC URL");
-    let address = std::env::var("CONTRACT_ADDRESS").unwrap().parse()?;
-    let signer: PrivateKeySigner = std::env::var("PRIVATE_KEY")
-        .unwrap()
+        .map_err(|e| eyre::eyre!("invalid RPC_URL: {}", e))?;
+    let address = env_var("CONTRACT_ADDRESS")?
         .parse()
-        .expect("Invalid private key");
+        .map_err(|e| eyre::eyre!("invalid CONTRACT_ADDRESS: {}", e))?;
+    let signer: PrivateKeySigner = env_var("PRIVATE_KEY")?
+        .parse()
+        .map_err(|e| eyre::eyre!("invalid PRIVATE_KEY: {}", e))?;
     let wallet = EthereumWallet::from(signer);
     let client = ProviderBuilder::new()
         .with_recommended_fillers()
         .wallet(wallet)
         .on_http(rpc_url);
+    let chain_id = client.get_chain_id().await?;
     let contract = SP1AggregationVerifier::new(address, client);
+
+    // Proofs against the relayed root only verify if the contract hashes nodes like the guest.
+    let contract_hash_function = contract
+        .merkleHashFunction()
+        .call()
+        .await?
+        .merkleHashFunction;
+    if contract_hash_function != hash_function as u8 {
+        return Err(eyre::eyre!(
+            "contract uses merkle hash function {}, but the batch was hashed with {:?}",
+            contract_hash_function,
+            hash_function
+        ));
+    }
+
+    // Don't pay for a transaction the contract would revert.
+    let public_values = AggregationPublicValues::from_bytes(proof.public_values.as_slice())
+        .ok_or_else(|| eyre::eyre!("invalid aggregation public values"))?;
+    if contract
+        .relayedBatches(public_values.batchId)
+        .call()
+        .await?
+        ._0
+    {
+        return Err(eyre::eyre!(
+            "batch {} was already relayed",
+            public_values.batchId
+        ));
+    }
+    let batch_count = contract.batchCount().call().await?.batchCount;
+    if public_values.batchIndex != batch_count {
+        return Err(eyre::eyre!(
+            "batch {} is out of order: it is batch {}, but the contract expects batch {}",
+            public_values.batchId,
+            public_values.batchIndex,
+            batch_count
+        ));
+    }
+
     let tx =
         contract.verifyAggregationProof(proof.public_values.to_vec().into(), proof.bytes().into());
     let pending_tx = tx.send().await?;
@@ -39,5 +83,13 @@ pub async fn relay_proof(proof: SP1ProofWithPublicValues) -> Result<Vec<u8>, eyr
         .get_receipt()
         .await?;
 
-    Ok(receipt.transaction_hash.to_vec())
+    Ok((
+        receipt.transaction_hash.to_vec(),
+        chain_id,
+        address.to_vec(),
+    ))
+}
+
+fn env_var(name: &str) -> Result<String, eyre::Error> {
+    std::env::var(name).map_err(|_| eyre::eyre!("{} is not set", name))
 }
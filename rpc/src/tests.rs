@@ -1,18 +1,38 @@
 use dotenv::dotenv;
 use eyre::Result;
 // use rpc::start_rpc_server;
-use crate::start_test_rpc_server;
+use crate::{
+    aggregation_service::check_aggregation_proof, db, start_test_rpc_server, AggregationRpc,
+};
+use sp1_sdk::{
+    PlonkBn254Proof, ProverClient, SP1Proof, SP1ProofWithPublicValues, SP1PublicValues, SP1Stdin,
+};
 use sqlx::postgres::PgPool;
+use std::sync::{Arc, LazyLock};
+use tonic::{Code, Request};
 use types::aggregation::{
-    aggregation_service_client::AggregationServiceClient, AggregateProofRequest, AggregationStatus,
-    AggregationStatusResponse, GetAggregatedDataRequest, GetAggregationStatusRequest,
-    GetBatchRequest, ProcessBatchRequest, UpdateBatchStatusRequest, WriteMerkleTreeRequest,
+    aggregation_service_client::AggregationServiceClient, AggregateProofRequest,
+    AggregationProofRejection, AggregationStatusResponse, ClaimBatchRequest, CompleteBatchRequest,
+    DuplicateLeafPolicy, FailedProof, GetAccumulatorRequest, GetAggregatedDataRequest,
+    GetAggregatedMultiproofRequest, GetAggregationStatusRequest, GetProgramAllowlistRequest,
+    GetProofIdProofRequest, LeafFormat, MarkProofsFailedRequest, ProcessBatchRequest, ProofIdState,
+    RegisterProgramRequest, RetireProgramRequest, RevokeProofRequest, SubmissionRejection,
+    VerifyAggregationProofRequest,
+};
+use types::merkle_tree::{
+    allowlist::Allowlist, process_multiproof, subtree::process_program_proof, verify, HashFunction,
+    MerkleProof, MultiProof, ProgramTree, TreeVersion,
+};
+use types::mmr::MerkleMountainRange;
+use types::public_values::AggregationPublicValues;
+use types::sparse_merkle_tree::{
+    proof_id_value, verify_sparse, SparseMerkleProof, SparseMerkleTree,
 };
 
 #[sqlx::test(migrations = "./migrations")]
 async fn test_aggregate_proof(db_pool: PgPool) -> Result<()> {
     dotenv().ok();
-    let rpc_addr = start_test_rpc_server(db_pool).await?;
+    let rpc_addr = start_test_rpc_server(test_rpc(db_pool.clone())).await?;
     println!("Connecting to RPC server at {}", rpc_addr);
     let mut network_client = AggregationServiceClient::connect(format!("https://{}", rpc_addr))
         .await
@@ -20,21 +40,9 @@ async fn test_aggregate_proof(db_pool: PgPool) -> Result<()> {
         .max_decoding_message_size(1024 * 1024 * 1024);
     println!("Connected to RPC server");
 
-    // test proof and vk (Vec<u8>)
-    let proof: Vec<u8> = vec![1, 2, 3, 4, 5];
-    let vk: Vec<u8> = vec![6, 7, 8, 9, 10];
-
-    let test_request = AggregateProofRequest { proof, vk };
-    let test_response = network_client
-        .aggregate_proof(test_request)
-        .await?
-        .into_inner();
-    println!("Test response: {:?}", test_response);
-
+    let proof_id = create_test_request(&db_pool, vec![1, 2, 3, 4, 5]).await?;
     let status = network_client
-        .get_aggregation_status(GetAggregationStatusRequest {
-            proof_id: test_response.proof_id,
-        })
+        .get_aggregation_status(GetAggregationStatusRequest { proof_id })
         .await?
         .into_inner();
     assert_eq!(
@@ -42,13 +50,61 @@ async fn test_aggregate_proof(db_pool: PgPool) -> Result<()> {
         AggregationStatusResponse::AggregationPending as i32
     );
 
+    // Requests that can't be aggregated are rejected when they are submitted, with the reason.
+    let request = AggregateProofRequest {
+        proof: vec![1, 2, 3],
+        vk: vec![4, 5, 6],
+        tenant: String::new(),
+        leaf_format: LeafFormat::Full.into(),
+        submitter: vec![],
+        idempotency_key: vec![],
+    };
+    let rejections = [
+        (
+            AggregateProofRequest {
+                leaf_format: 2,
+                ..request.clone()
+            },
+            SubmissionRejection::UnknownLeafFormat,
+        ),
+        (
+            AggregateProofRequest {
+                submitter: vec![0; 65],
+                ..request.clone()
+            },
+            SubmissionRejection::SubmitterTooLarge,
+        ),
+        (
+            AggregateProofRequest {
+                proof: vec![0; 16 * 1024 * 1024 + 1],
+                ..request.clone()
+            },
+            SubmissionRejection::TooLarge,
+        ),
+        (request, SubmissionRejection::InvalidProofEncoding),
+    ];
+    for (request, rejection) in rejections {
+        let status = network_client.aggregate_proof(request).await.unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert_eq!(
+            status.metadata().get("rejection-reason").unwrap(),
+            rejection.as_str_name()
+        );
+    }
+
     Ok(())
 }
 
 #[sqlx::test(migrations = "./migrations")]
+#[ignore = "needs compressed proofs recorded by generate-compressed at RECORD_PROOFS_PATH"]
 async fn test_e2e(db_pool: PgPool) -> Result<()> {
     dotenv().ok();
-    let rpc_addr = start_test_rpc_server(db_pool).await?;
+    let recorded = recorded_proofs();
+    let rpc_addr = start_test_rpc_server(AggregationRpc {
+        admin_token: Some(TEST_ADMIN_TOKEN.to_string()),
+        ..test_rpc(db_pool.clone())
+    })
+    .await?;
     println!("Connecting to RPC server at {}", rpc_addr);
     let mut network_client = AggregationServiceClient::connect(format!("https://{}", rpc_addr))
         .await
@@ -56,18 +112,39 @@ async fn test_e2e(db_pool: PgPool) -> Result<()> {
         .max_decoding_message_size(1024 * 1024 * 1024);
     println!("Connected to RPC server");
 
-    let current_timestamp = chrono::Utc::now().timestamp_millis();
+    // A real proof is still rejected with a vk that doesn't decode.
+    let status = network_client
+        .aggregate_proof(AggregateProofRequest {
+            proof: recorded[0].0.clone(),
+            vk: vec![4, 5, 6],
+            tenant: String::new(),
+            leaf_format: LeafFormat::Full.into(),
+            submitter: vec![],
+            idempotency_key: vec![],
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(
+        status.metadata().get("rejection-reason").unwrap(),
+        SubmissionRejection::InvalidVkEncoding.as_str_name()
+    );
 
-    // generate five requests and then get the aggregated data
+    // submit five recorded proofs and then get the aggregated data, with an idempotency key each
+    // so that a proof recorded once can be submitted several times
     let mut proof_ids = Vec::new();
-    for _ in 0..5 {
-        // generate a random proof and vk
-        let proof: Vec<u8> = (0..5).map(|_| rand::random::<u8>()).collect();
-        let vk: Vec<u8> = (0..5).map(|_| rand::random::<u8>()).collect();
-        let test_request = AggregateProofRequest {
+    let submission = |i: u8| {
+        let (proof, vk) = &recorded[i as usize % recorded.len()];
+        AggregateProofRequest {
             proof: proof.clone(),
             vk: vk.clone(),
-        };
+            tenant: String::new(),
+            leaf_format: LeafFormat::Full.into(),
+            submitter: vec![],
+            idempotency_key: vec![i],
+        }
+    };
+    for i in 0..5 {
+        let test_request = submission(i);
         let test_response = network_client
             .aggregate_proof(test_request)
             .await?
@@ -76,12 +153,15 @@ async fn test_e2e(db_pool: PgPool) -> Result<()> {
         proof_ids.push(test_response.proof_id);
     }
 
-    let batch_request = GetBatchRequest {
-        created_after: Some(current_timestamp as u64),
+    let batch_request = ClaimBatchRequest {
+        worker_id: "worker".to_string(),
         batch_size: Some(5),
+        lease_seconds: None,
     };
-    let batch_response = network_client.get_batch(batch_request).await?.into_inner();
-    // println!("Batch response: {:?}", batch_response);
+    let batch_response = network_client
+        .claim_batch(batch_request)
+        .await?
+        .into_inner();
     let requests = batch_response.proofs;
     assert_eq!(requests.len(), 5);
     let batch_id = batch_response.batch_id;
@@ -89,6 +169,8 @@ async fn test_e2e(db_pool: PgPool) -> Result<()> {
     let process_batch_request = ProcessBatchRequest {
         proofs: requests,
         batch_id: batch_id.clone(),
+        duplicate_policy: DuplicateLeafPolicy::KeepDuplicates as i32,
+        worker_id: batch_response.worker_id.clone(),
     };
     let process_batch_response = network_client
         .process_batch(process_batch_request)
@@ -96,20 +178,88 @@ async fn test_e2e(db_pool: PgPool) -> Result<()> {
         .into_inner();
     let leaves = process_batch_response.leaves;
     println!("Leaves: {:?}", leaves);
+    assert_eq!(process_batch_response.vkey_hashes.len(), leaves.len());
+    let batch_tree = ProgramTree::new(
+        &leaves
+            .chunks(32)
+            .zip(process_batch_response.vkey_hashes.chunks(32))
+            .map(|(leaf, vkey_hash)| (vkey_hash.try_into().unwrap(), leaf.try_into().unwrap()))
+            .collect::<Vec<_>>(),
+        TreeVersion::LATEST,
+        HashFunction::Keccak256,
+    )?;
+    let batch_root = batch_tree.root;
 
-    let merkle_tree_request = WriteMerkleTreeRequest {
-        tree: leaves,
-        batch_id: batch_id.clone(),
-    };
+    // ProcessBatch stores the tree of the batch, built from the leaves it returned.
+    assert_eq!(
+        db::get_batch_merkle_tree(&db_pool, batch_id.clone()).await?,
+        batch_tree.to_bytes()
+    );
 
-    network_client
-        .write_merkle_tree(merkle_tree_request)
+    // A retried request gets the proof id and status of the request it retries, which is still
+    // pending until the batch is relayed, and a request that reuses its idempotency key for
+    // another submission is rejected.
+    let retry = network_client
+        .aggregate_proof(submission(0))
         .await?
         .into_inner();
+    assert_eq!(retry.proof_id, proof_ids[0]);
+    assert!(retry.existing);
+    assert_eq!(
+        retry.status(),
+        AggregationStatusResponse::AggregationPending
+    );
+    let status = network_client
+        .aggregate_proof(AggregateProofRequest {
+            submitter: vec![1; 20],
+            ..submission(0)
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::AlreadyExists);
 
+    // The proofs of the batch are aggregated once it is relayed, and only then proven against the
+    // accumulator.
     let aggregated_data_request = GetAggregatedDataRequest {
         proof_id: proof_ids[0].clone(),
     };
+    let status = network_client
+        .get_aggregated_data(aggregated_data_request.clone())
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::FailedPrecondition);
+    let status = network_client
+        .get_aggregated_multiproof(GetAggregatedMultiproofRequest {
+            proof_ids: vec![proof_ids[0].clone()],
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::FailedPrecondition);
+    let proof_id: [u8; 32] = proof_ids[0].as_slice().try_into().unwrap();
+    let unrelayed_response = network_client
+        .get_proof_id_proof(GetProofIdProofRequest {
+            proof_id: proof_ids[0].clone(),
+        })
+        .await?
+        .into_inner();
+    assert_eq!(unrelayed_response.state(), ProofIdState::Absent);
+    record_relay(
+        &db_pool,
+        batch_id.clone(),
+        batch_root,
+        HashFunction::Keccak256,
+    )
+    .await?;
+    let status = network_client
+        .get_aggregation_status(GetAggregationStatusRequest {
+            proof_id: proof_ids[0].clone(),
+        })
+        .await?
+        .into_inner();
+    assert_eq!(
+        status.status,
+        AggregationStatusResponse::AggregationComplete as i32
+    );
 
     let aggregated_data_response = network_client
         .get_aggregated_data(aggregated_data_request)
@@ -118,5 +268,868 @@ async fn test_e2e(db_pool: PgPool) -> Result<()> {
     let merkle_proof = aggregated_data_response.proof;
     println!("Merkle proof: {:?}", merkle_proof);
 
+    // The encoded proof is against the subtree of the program of the proof, whose node the
+    // program proof proves against the accumulator root.
+    let encoded_proof = MerkleProof::from_bytes(&aggregated_data_response.encoded_proof)
+        .expect("Failed to decode proof");
+    assert_eq!(
+        encoded_proof.siblings,
+        to_nodes(&aggregated_data_response.subtree_proof)
+    );
+    assert_eq!(
+        encoded_proof.root.to_vec(),
+        aggregated_data_response.subtree_root
+    );
+    assert!(verify(&encoded_proof, encoded_proof.root).is_ok());
+    assert_eq!(
+        [
+            aggregated_data_response.subtree_proof.clone(),
+            aggregated_data_response.program_proof.clone()
+        ]
+        .concat(),
+        merkle_proof
+    );
+    let accumulator = network_client
+        .get_accumulator(GetAccumulatorRequest {})
+        .await?
+        .into_inner();
+    assert_eq!(aggregated_data_response.root, accumulator.root);
+    let (vkey_hash, _) = batch_tree
+        .programs
+        .iter()
+        .find(|(_, subtree)| subtree.root == encoded_proof.root)
+        .expect("the subtree root is of a program of the batch");
+    assert_eq!(
+        process_program_proof(
+            TreeVersion::LATEST,
+            &HashFunction::Keccak256,
+            vkey_hash,
+            &encoded_proof.root,
+            &to_nodes(&aggregated_data_response.program_proof)
+        )
+        .to_vec(),
+        accumulator.root
+    );
+
+    // Once a later batch is relayed, a multiproof continues from the root of the batch through
+    // the accumulator.
+    let later_root = [0xff; 32];
+    record_relay(
+        &db_pool,
+        vec![0xff; 32],
+        later_root,
+        HashFunction::Keccak256,
+    )
+    .await?;
+    let multiproof_response = network_client
+        .get_aggregated_multiproof(GetAggregatedMultiproofRequest {
+            proof_ids: vec![proof_ids[0].clone()],
+        })
+        .await?
+        .into_inner();
+    let multiproof = MultiProof {
+        leaf_count: multiproof_response.leaf_count,
+        indices: multiproof_response.indices.clone(),
+        leaves: to_nodes(&multiproof_response.leaves),
+        proof: to_nodes(&multiproof_response.proof),
+        proof_flags: multiproof_response.proof_flags.clone(),
+    };
+    let subtree_root =
+        process_multiproof(TreeVersion::LATEST, &HashFunction::Keccak256, &multiproof).unwrap();
+    assert_eq!(subtree_root.to_vec(), multiproof_response.subtree_root);
+    assert_eq!(
+        multiproof_response.accumulator_proof,
+        vec![later_root.to_vec()]
+    );
+    let root = process_program_proof(
+        TreeVersion::LATEST,
+        &HashFunction::Keccak256,
+        vkey_hash,
+        &subtree_root,
+        &to_nodes(
+            &[
+                multiproof_response.program_proof.clone(),
+                multiproof_response.accumulator_proof.clone(),
+            ]
+            .concat(),
+        ),
+    );
+    assert_eq!(root.to_vec(), multiproof_response.root);
+
+    // The aggregated proof id is in the tree of proof ids until it is revoked.
+    let proof_id_response = network_client
+        .get_proof_id_proof(GetProofIdProofRequest {
+            proof_id: proof_ids[0].clone(),
+        })
+        .await?
+        .into_inner();
+    assert_eq!(proof_id_response.state(), ProofIdState::Aggregated);
+    assert_eq!(proof_id_response.batch_id, batch_id);
+    let proof = SparseMerkleProof::from_response(proof_id, &proof_id_response).unwrap();
+    assert!(verify_sparse(
+        &proof,
+        proof_id_response.root.as_slice().try_into().unwrap()
+    )
+    .is_ok());
+
+    network_client
+        .revoke_proof(admin_request(RevokeProofRequest {
+            proof_id: proof_ids[0].clone(),
+        }))
+        .await?;
+    let revoked_response = network_client
+        .get_proof_id_proof(GetProofIdProofRequest {
+            proof_id: proof_ids[0].clone(),
+        })
+        .await?
+        .into_inner();
+    assert_eq!(revoked_response.state(), ProofIdState::Revoked);
+    assert_ne!(revoked_response.root, proof_id_response.root);
+    let proof = SparseMerkleProof::from_response(proof_id, &revoked_response).unwrap();
+    assert!(verify_sparse(&proof, revoked_response.root.as_slice().try_into().unwrap()).is_ok());
+
+    // A proof id that was never aggregated gets a proof of absence.
+    let absent_response = network_client
+        .get_proof_id_proof(GetProofIdProofRequest {
+            proof_id: vec![0; 32],
+        })
+        .await?
+        .into_inner();
+    assert_eq!(absent_response.state(), ProofIdState::Absent);
+    let proof = SparseMerkleProof::from_response([0; 32], &absent_response).unwrap();
+    assert_eq!(proof.value, None);
+    assert!(verify_sparse(&proof, absent_response.root.as_slice().try_into().unwrap()).is_ok());
+
+    // Without an idempotency key, the proof id is derived from the proof, so resubmitting the
+    // same proof returns the same request.
+    let request = AggregateProofRequest {
+        idempotency_key: vec![],
+        ..submission(0)
+    };
+    let first = network_client
+        .aggregate_proof(request.clone())
+        .await?
+        .into_inner();
+    assert!(!first.existing);
+    assert_eq!(
+        first.status(),
+        AggregationStatusResponse::AggregationPending
+    );
+    let second = network_client
+        .aggregate_proof(request.clone())
+        .await?
+        .into_inner();
+    assert_eq!(second.proof_id, first.proof_id);
+    assert!(second.existing);
+
+    // The same proof bound to a submitter, or in another leaf format, is another request rather
+    // than a retry of the first one.
+    for request in [
+        AggregateProofRequest {
+            submitter: vec![1; 20],
+            ..request.clone()
+        },
+        AggregateProofRequest {
+            leaf_format: LeafFormat::Digest.into(),
+            ..request
+        },
+    ] {
+        let response = network_client.aggregate_proof(request).await?.into_inner();
+        assert_ne!(response.proof_id, first.proof_id);
+        assert!(!response.existing);
+    }
+
+    Ok(())
+}
+
+#[sqlx::test(migrations = "./migrations")]
+async fn test_aggregated_data_before_relay(db_pool: PgPool) -> Result<()> {
+    dotenv().ok();
+    let rpc_addr = start_test_rpc_server(test_rpc(db_pool.clone())).await?;
+    let mut network_client = AggregationServiceClient::connect(format!("https://{}", rpc_addr))
+        .await
+        .unwrap();
+
+    // An unknown proof is reported as not found.
+    let response = network_client
+        .get_aggregated_data(GetAggregatedDataRequest {
+            proof_id: vec![0; 32],
+        })
+        .await?
+        .into_inner();
+    assert_eq!(response.status, AggregationStatusResponse::NotFound as i32);
+
+    // A proof has no merkle proof until its batch is relayed, whether it was batched or not.
+    let proof_id = create_test_request(&db_pool, vec![1, 2, 3]).await?;
+    for batch_id in [None, Some(vec![1u8; 32])] {
+        sqlx::query(r#"UPDATE requests SET batch_id = $1 WHERE proof_id = $2"#)
+            .bind(batch_id)
+            .bind(proof_id.clone())
+            .execute(&db_pool)
+            .await?;
+        let status = network_client
+            .get_aggregated_data(GetAggregatedDataRequest {
+                proof_id: proof_id.clone(),
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::FailedPrecondition);
+        let status = network_client
+            .get_aggregated_multiproof(GetAggregatedMultiproofRequest {
+                proof_ids: vec![proof_id.clone()],
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::FailedPrecondition);
+    }
+
+    Ok(())
+}
+
+#[sqlx::test(migrations = "./migrations")]
+async fn test_mark_proofs_failed(db_pool: PgPool) -> Result<()> {
+    dotenv().ok();
+    let rpc_addr = start_test_rpc_server(test_rpc(db_pool.clone())).await?;
+    let mut network_client = AggregationServiceClient::connect(format!("https://{}", rpc_addr))
+        .await
+        .unwrap();
+
+    let proof_id = create_test_request(&db_pool, vec![1, 2, 3]).await?;
+    let batch = network_client
+        .claim_batch(ClaimBatchRequest {
+            worker_id: "worker".to_string(),
+            batch_size: None,
+            lease_seconds: None,
+        })
+        .await?
+        .into_inner();
+    let mark_failed = |proof_id: &[u8], worker_id: &str| MarkProofsFailedRequest {
+        proofs: vec![FailedProof {
+            proof_id: proof_id.to_vec(),
+            reason: "proof does not verify".to_string(),
+        }],
+        batch_id: batch.batch_id.clone(),
+        worker_id: worker_id.to_string(),
+    };
+
+    // Only the worker holding the lease of a batch can fail its proofs, and only proofs claimed
+    // for the batch.
+    let status = network_client
+        .mark_proofs_failed(mark_failed(&proof_id, "another-worker"))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::FailedPrecondition);
+    let other_proof_id = create_test_request(&db_pool, vec![4, 5, 6]).await?;
+    let status = network_client
+        .mark_proofs_failed(mark_failed(&other_proof_id, "worker"))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::FailedPrecondition);
+    network_client
+        .mark_proofs_failed(mark_failed(&proof_id, "worker"))
+        .await?;
+    // A proof that already failed can't fail again.
+    let status = network_client
+        .mark_proofs_failed(mark_failed(&proof_id, "worker"))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::FailedPrecondition);
+    network_client
+        .complete_batch(CompleteBatchRequest {
+            batch_id: batch.batch_id.clone(),
+            worker_id: "worker".to_string(),
+        })
+        .await?;
+
+    // A failed proof is reported with its reason and no longer batched.
+    let status = network_client
+        .get_aggregation_status(GetAggregationStatusRequest { proof_id })
+        .await?
+        .into_inner();
+    assert_eq!(
+        status.status,
+        AggregationStatusResponse::AggregationFailed as i32
+    );
+    assert_eq!(status.failure_reason, "proof does not verify");
+    let batch = network_client
+        .claim_batch(ClaimBatchRequest {
+            worker_id: String::new(),
+            batch_size: None,
+            lease_seconds: None,
+        })
+        .await?
+        .into_inner();
+    let claimed = batch
+        .proofs
+        .iter()
+        .map(|proof| proof.proof_id.clone())
+        .collect::<Vec<_>>();
+    assert_eq!(claimed, vec![other_proof_id]);
+
+    Ok(())
+}
+
+#[sqlx::test(migrations = "./migrations")]
+async fn test_program_allowlist(db_pool: PgPool) -> Result<()> {
+    dotenv().ok();
+    let rpc_addr = start_test_rpc_server(AggregationRpc {
+        admin_token: Some(TEST_ADMIN_TOKEN.to_string()),
+        ..test_rpc(db_pool.clone())
+    })
+    .await?;
+    let mut network_client = AggregationServiceClient::connect(format!("https://{}", rpc_addr))
+        .await
+        .unwrap();
+
+    // Any program may be aggregated until one is registered.
+    let allowlist = network_client
+        .get_program_allowlist(GetProgramAllowlistRequest {})
+        .await?
+        .into_inner();
+    assert!(!allowlist.enforced);
+
+    // Registering a program is an admin RPC.
+    let register = RegisterProgramRequest {
+        vk: vec![1, 2, 3],
+        tenant: String::new(),
+        max_public_values_size: 0,
+        fee: 0,
+    };
+    let status = network_client
+        .register_program(register.clone())
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::Unauthenticated);
+    let status = network_client
+        .register_program(admin_request(register))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+
+    let vkey_hash = [7u8; 32];
+    db::register_program(
+        &db_pool,
+        vkey_hash.to_vec(),
+        &db::Program {
+            tenant: String::new(),
+            max_public_values_size: 0,
+            fee: 0,
+            retired: false,
+        },
+    )
+    .await?;
+    let allowlist = network_client
+        .get_program_allowlist(GetProgramAllowlistRequest {})
+        .await?
+        .into_inner();
+    assert!(allowlist.enforced);
+    assert_eq!(allowlist.vkey_hashes, vec![vkey_hash.to_vec()]);
+    assert_eq!(
+        allowlist.root,
+        Allowlist::new(vec![vkey_hash])
+            .root(TreeVersion::LATEST, &HashFunction::Keccak256)
+            .to_vec()
+    );
+
+    // Once the allowlist is enforced, requests must name a registered program.
+    let status = network_client
+        .aggregate_proof(AggregateProofRequest {
+            proof: vec![1, 2, 3],
+            vk: vec![4, 5, 6],
+            tenant: String::new(),
+            leaf_format: LeafFormat::Full.into(),
+            submitter: vec![],
+            idempotency_key: vec![],
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+
+    let retire = || {
+        admin_request(RetireProgramRequest {
+            vkey_hash: vkey_hash.to_vec(),
+        })
+    };
+    assert!(
+        network_client
+            .retire_program(retire())
+            .await?
+            .into_inner()
+            .retired
+    );
+    assert!(
+        !network_client
+            .retire_program(retire())
+            .await?
+            .into_inner()
+            .retired
+    );
+
+    Ok(())
+}
+
+#[sqlx::test(migrations = "./migrations")]
+async fn test_revoke_proof_requires_admin(db_pool: PgPool) -> Result<()> {
+    dotenv().ok();
+    let rpc_addr = start_test_rpc_server(AggregationRpc {
+        admin_token: Some(TEST_ADMIN_TOKEN.to_string()),
+        ..test_rpc(db_pool.clone())
+    })
+    .await?;
+    let mut network_client = AggregationServiceClient::connect(format!("https://{}", rpc_addr))
+        .await
+        .unwrap();
+
+    let proof_id = create_test_request(&db_pool, vec![1, 2, 3]).await?;
+    let revoke = RevokeProofRequest { proof_id };
+    let status = network_client
+        .revoke_proof(revoke.clone())
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::Unauthenticated);
+    let mut request = Request::new(revoke.clone());
+    request
+        .metadata_mut()
+        .insert("authorization", "Bearer another-token".parse()?);
+    let status = network_client.revoke_proof(request).await.unwrap_err();
+    assert_eq!(status.code(), Code::Unauthenticated);
+
+    // An admin still can't revoke a proof that hasn't been aggregated.
+    let status = network_client
+        .revoke_proof(admin_request(revoke))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::FailedPrecondition);
+
+    Ok(())
+}
+
+#[sqlx::test(migrations = "./migrations")]
+async fn test_empty_accumulator(db_pool: PgPool) -> Result<()> {
+    dotenv().ok();
+    let rpc_addr = start_test_rpc_server(test_rpc(db_pool)).await?;
+    let mut network_client = AggregationServiceClient::connect(format!("https://{}", rpc_addr))
+        .await
+        .unwrap();
+
+    // The contract starts with a zero root, which the first batch must extend.
+    let accumulator = network_client
+        .get_accumulator(GetAccumulatorRequest {})
+        .await?
+        .into_inner();
+    assert_eq!(accumulator.leaf_count, 0);
+    assert!(accumulator.peaks.is_empty());
+    assert_eq!(accumulator.root, vec![0; 32]);
+
+    Ok(())
+}
+
+#[sqlx::test(migrations = "./migrations")]
+async fn test_claim_batch(db_pool: PgPool) -> Result<()> {
+    dotenv().ok();
+    let rpc_addr = start_test_rpc_server(test_rpc(db_pool.clone())).await?;
+    let mut network_client = AggregationServiceClient::connect(format!("https://{}", rpc_addr))
+        .await
+        .unwrap();
+
+    let mut proof_ids = Vec::new();
+    for i in 0..3 {
+        proof_ids.push(create_test_request(&db_pool, vec![i]).await?);
+        // Requests are claimed in the order they were created, to the millisecond.
+        tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+    }
+    let claim = |worker_id: &str, batch_size| ClaimBatchRequest {
+        worker_id: worker_id.to_string(),
+        batch_size: Some(batch_size),
+        lease_seconds: None,
+    };
+
+    // Workers claim disjoint batches, oldest requests first.
+    let first = network_client
+        .claim_batch(claim("worker-1", 2))
+        .await?
+        .into_inner();
+    assert_eq!(first.worker_id, "worker-1");
+    assert!(first.lease_expires_at > chrono::Utc::now().timestamp_millis() as u64);
+    let second = network_client
+        .claim_batch(claim("worker-2", 32))
+        .await?
+        .into_inner();
+    let claimed = |batch: &types::aggregation::ClaimBatchResponse| {
+        batch
+            .proofs
+            .iter()
+            .map(|proof| proof.proof_id.clone())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(claimed(&first), proof_ids[..2]);
+    assert_eq!(claimed(&second), proof_ids[2..]);
+    assert!(network_client
+        .claim_batch(claim("worker-3", 32))
+        .await?
+        .into_inner()
+        .proofs
+        .is_empty());
+
+    // A batch is only processed by the worker holding its lease, with distinct pending requests
+    // claimed for it.
+    for (proofs, worker_id) in [
+        (second.proofs.clone(), "worker-1"),
+        (
+            [first.proofs.clone(), second.proofs.clone()].concat(),
+            "worker-2",
+        ),
+        (
+            [second.proofs.clone(), second.proofs.clone()].concat(),
+            "worker-2",
+        ),
+    ] {
+        let status = network_client
+            .process_batch(ProcessBatchRequest {
+                proofs,
+                batch_id: second.batch_id.clone(),
+                duplicate_policy: DuplicateLeafPolicy::KeepDuplicates as i32,
+                worker_id: worker_id.to_string(),
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::FailedPrecondition);
+    }
+
+    // Only the worker holding a lease can relay its batch, and batches that were never claimed
+    // can't be relayed at all.
+    let status = network_client
+        .verify_aggregation_proof(VerifyAggregationProofRequest {
+            proof: vec![1, 2, 3],
+            batch_id: first.batch_id.clone(),
+            worker_id: "worker-2".to_string(),
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::FailedPrecondition);
+    let status = network_client
+        .verify_aggregation_proof(VerifyAggregationProofRequest {
+            proof: vec![1, 2, 3],
+            batch_id: vec![0; 32],
+            worker_id: "worker-1".to_string(),
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::FailedPrecondition);
+
+    // Only the worker holding a lease can complete it, which releases the requests it never
+    // processed.
+    let status = network_client
+        .complete_batch(CompleteBatchRequest {
+            batch_id: first.batch_id.clone(),
+            worker_id: "worker-2".to_string(),
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::FailedPrecondition);
+    network_client
+        .complete_batch(CompleteBatchRequest {
+            batch_id: first.batch_id.clone(),
+            worker_id: "worker-1".to_string(),
+        })
+        .await?;
+
+    // Once the lease of the second batch expires, its request goes back to pending too and the
+    // batch can no longer be processed.
+    let released = db::release_expired_leases(&db_pool, i64::MAX).await?;
+    assert_eq!(released, 1);
+    let status = network_client
+        .process_batch(ProcessBatchRequest {
+            proofs: second.proofs.clone(),
+            batch_id: second.batch_id.clone(),
+            duplicate_policy: DuplicateLeafPolicy::KeepDuplicates as i32,
+            worker_id: "worker-2".to_string(),
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::FailedPrecondition);
+    let third = network_client
+        .claim_batch(claim("worker-3", 32))
+        .await?
+        .into_inner();
+    assert_eq!(claimed(&third), proof_ids);
+
+    Ok(())
+}
+
+#[sqlx::test(migrations = "./migrations")]
+async fn test_write_merkle_tree_once(db_pool: PgPool) -> Result<()> {
+    let tree = |leaf| {
+        ProgramTree::new(
+            &[([1; 32], [leaf; 32])],
+            TreeVersion::LATEST,
+            HashFunction::Keccak256,
+        )
+        .unwrap()
+    };
+    let batch_id = vec![0; 32];
+    let mut conn = db_pool.acquire().await?;
+
+    // A batch keeps the first tree written for it.
+    assert!(db::write_merkle_tree(&mut conn, &tree(2), batch_id.clone()).await?);
+    assert!(!db::write_merkle_tree(&mut conn, &tree(3), batch_id.clone()).await?);
+    assert_eq!(
+        db::get_batch_merkle_tree(&db_pool, batch_id).await?,
+        tree(2).to_bytes()
+    );
+
+    Ok(())
+}
+
+#[sqlx::test(migrations = "./migrations")]
+async fn test_reserve_accumulator_leaf(db_pool: PgPool) -> Result<()> {
+    let is_unique_violation =
+        |e: &sqlx::Error| matches!(e, sqlx::Error::Database(e) if e.is_unique_violation());
+
+    // A relay that is rolled back frees its leaf.
+    let mut tx = db_pool.begin().await?;
+    assert!(db::reserve_accumulator_leaf(&mut tx, vec![1], [1; 32])
+        .await?
+        .is_empty());
+    tx.rollback().await?;
+
+    // A concurrent relay waits for the leaf to be recorded or freed, and then can't take it.
+    let mut tx = db_pool.begin().await?;
+    assert!(db::reserve_accumulator_leaf(&mut tx, vec![2], [2; 32])
+        .await?
+        .is_empty());
+    let concurrent = tokio::spawn({
+        let db_pool = db_pool.clone();
+        async move {
+            let mut tx = db_pool.begin().await?;
+            db::reserve_accumulator_leaf(&mut tx, vec![3], [3; 32]).await
+        }
+    });
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    assert!(!concurrent.is_finished());
+    tx.commit().await?;
+    assert!(is_unique_violation(&concurrent.await?.unwrap_err()));
+
+    // The next relay extends the recorded accumulator, and a batch is relayed once.
+    let mut tx = db_pool.begin().await?;
+    assert_eq!(
+        db::reserve_accumulator_leaf(&mut tx, vec![3], [3; 32]).await?,
+        vec![[2; 32]]
+    );
+    tx.commit().await?;
+    let mut tx = db_pool.begin().await?;
+    let e = db::reserve_accumulator_leaf(&mut tx, vec![2], [2; 32])
+        .await
+        .unwrap_err();
+    assert!(is_unique_violation(&e));
+    assert_eq!(
+        db::get_accumulator_leaves(&db_pool).await?,
+        vec![[2; 32], [3; 32]]
+    );
+
+    Ok(())
+}
+
+#[sqlx::test(migrations = "./migrations")]
+async fn test_backfill_proof_id_tree(db_pool: PgPool) -> Result<()> {
+    let hash_function = HashFunction::Keccak256;
+    let mut tree = SparseMerkleTree::new(hash_function);
+    let mut conn = db_pool.acquire().await?;
+    for i in 0..3 {
+        db::set_proof_id_state(&mut conn, vec![i; 32], ProofIdState::Aggregated, vec![i]).await?;
+        tree.insert(
+            [i; 32],
+            proof_id_value(hash_function, ProofIdState::Aggregated, &[i]),
+        );
+    }
+
+    // Proof ids aggregated before the tree was stored are added to it.
+    db::backfill_proof_id_tree(&db_pool, hash_function).await?;
+    for i in 0..4 {
+        let (proof, _, _, root) = db::get_proof_id_proof(&db_pool, [i; 32], hash_function).await?;
+        assert_eq!(root, tree.root());
+        assert_eq!(proof, tree.generate_proof([i; 32]));
+    }
+
+    Ok(())
+}
+
+#[sqlx::test(migrations = "./migrations")]
+async fn test_verify_aggregation_proof_without_vkey(db_pool: PgPool) -> Result<()> {
+    dotenv().ok();
+    let rpc_addr = start_test_rpc_server(AggregationRpc {
+        aggregation_vk: None,
+        ..test_rpc(db_pool.clone())
+    })
+    .await?;
+    let mut network_client = AggregationServiceClient::connect(format!("https://{}", rpc_addr))
+        .await
+        .unwrap();
+    create_test_request(&db_pool, vec![1, 2, 3]).await?;
+    let batch = network_client
+        .claim_batch(ClaimBatchRequest {
+            worker_id: "worker".to_string(),
+            batch_size: None,
+            lease_seconds: None,
+        })
+        .await?
+        .into_inner();
+
+    // Without the vkey of the aggregation program, no proof can be checked before it is relayed.
+    let status = network_client
+        .verify_aggregation_proof(VerifyAggregationProofRequest {
+            proof: vec![1, 2, 3],
+            batch_id: batch.batch_id,
+            worker_id: batch.worker_id,
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::FailedPrecondition);
+    assert_eq!(
+        status.message(),
+        "the vkey of the aggregation program is not configured"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_check_aggregation_proof() {
+    let (batch_id, batch_root, leaf_count) = ([1; 32], [2; 32], 3);
+    let batch_roots = vec![[3; 32]];
+    let hash_function = HashFunction::Keccak256;
+    let mut accumulator =
+        MerkleMountainRange::from_leaves(TreeVersion::LATEST, &hash_function, &batch_roots);
+    let previous_root = accumulator.root(TreeVersion::LATEST, &hash_function);
+    accumulator.append(TreeVersion::LATEST, &hash_function, batch_root);
+    let public_values = AggregationPublicValues {
+        batchId: batch_id.into(),
+        batchIndex: 1,
+        leafCount: leaf_count as u64,
+        timestamp: 0,
+        batchRoot: batch_root.into(),
+        previousRoot: previous_root.into(),
+        newRoot: accumulator.root(TreeVersion::LATEST, &hash_function).into(),
+        aggregationVKeyDigest: [4; 32].into(),
+        allowlistRoot: [0; 32].into(),
+        requestIndices: vec![0, 1, 2],
+    };
+    let aggregation_proof = |proof: SP1Proof| SP1ProofWithPublicValues {
+        proof,
+        stdin: SP1Stdin::new(),
+        public_values: SP1PublicValues::from(&public_values.to_bytes()),
+        sp1_version: String::new(),
+    };
+    let check = |proof: &SP1ProofWithPublicValues, batch_roots: &[[u8; 32]]| {
+        check_aggregation_proof(
+            proof,
+            &batch_id,
+            batch_root,
+            leaf_count,
+            batch_roots,
+            hash_function,
+        )
+        .map_err(|(rejection, _)| rejection)
+    };
+
+    // The worker wraps the top run of a batch in Plonk, which the verifier contract verifies.
+    let plonk = aggregation_proof(SP1Proof::Plonk(PlonkBn254Proof {
+        public_inputs: [String::new(), String::new()],
+        encoded_proof: String::new(),
+        raw_proof: String::new(),
+        plonk_vkey_hash: [0; 32],
+    }));
+    assert_eq!(check(&plonk, &batch_roots), Ok(()));
+
+    // A proof of the runs below the top one can't be relayed.
+    assert_eq!(
+        check(&aggregation_proof(SP1Proof::Core(vec![])), &batch_roots),
+        Err(AggregationProofRejection::UnsupportedProofType)
+    );
+
+    // Once another batch is relayed, the proof no longer extends the accumulator.
+    assert_eq!(
+        check(&plonk, &[[3; 32], [5; 32]]),
+        Err(AggregationProofRejection::StaleAccumulator)
+    );
+}
+
+/// The prover client of the test services, which is shared since building it sets up the prover.
+static PROVER_CLIENT: LazyLock<Arc<ProverClient>> = LazyLock::new(|| Arc::new(ProverClient::new()));
+
+/// The configuration of the service in tests, which doesn't depend on the environment so that
+/// tests running in parallel don't affect each other. Admin RPCs, relaying and the verification
+/// of submitted proofs are disabled.
+fn test_rpc(db_pool: PgPool) -> AggregationRpc {
+    AggregationRpc {
+        db_pool,
+        hash_function: HashFunction::Keccak256,
+        admin_token: None,
+        aggregation_vk: None,
+        prover_client: PROVER_CLIENT.clone(),
+        verify_submissions: false,
+    }
+}
+
+/// The admin token of the service in tests that enable admin RPCs.
+const TEST_ADMIN_TOKEN: &str = "test-admin-token";
+
+/// A request to an admin RPC, authorized with [`TEST_ADMIN_TOKEN`].
+fn admin_request<T>(message: T) -> Request<T> {
+    let mut request = Request::new(message);
+    request.metadata_mut().insert(
+        "authorization",
+        format!("Bearer {}", TEST_ADMIN_TOKEN).parse().unwrap(),
+    );
+    request
+}
+
+/// The nodes of a response as arrays.
+fn to_nodes(nodes: &[Vec<u8>]) -> Vec<[u8; 32]> {
+    nodes
+        .iter()
+        .map(|node| node.as_slice().try_into().unwrap())
+        .collect()
+}
+
+/// Proofs recorded by `generate-compressed` with `RECORD_PROOFS_PATH` set, as pairs of the
+/// encoded proof and vk, since requests are only accepted with real compressed proofs.
+fn recorded_proofs() -> Vec<(Vec<u8>, Vec<u8>)> {
+    let path = std::env::var("RECORD_PROOFS_PATH").expect("RECORD_PROOFS_PATH is not set");
+    let bytes = std::fs::read(path).expect("failed to read recorded proofs");
+    let recorded: Vec<(Vec<u8>, Vec<u8>)> =
+        bincode::deserialize(&bytes).expect("invalid recorded proofs");
+    assert!(!recorded.is_empty(), "no recorded proofs");
+    recorded
+}
+
+/// Store a pending request without the checks of `AggregateProof`, for tests that never decode
+/// its proof. Returns its proof id.
+async fn create_test_request(db_pool: &PgPool, proof: Vec<u8>) -> Result<Vec<u8>> {
+    let proof_id: [u8; 32] = rand::random();
+    db::create_request(
+        db_pool,
+        proof_id.to_vec(),
+        proof,
+        vec![4, 5, 6],
+        None,
+        types::merkle_tree::LeafFormat::Full,
+        vec![],
+    )
+    .await?;
+    Ok(proof_id.to_vec())
+}
+
+/// Record the relay of a batch, as `VerifyAggregationProof` does once the relay succeeds.
+async fn record_relay(
+    db_pool: &PgPool,
+    batch_id: Vec<u8>,
+    root: [u8; 32],
+    hash_function: HashFunction,
+) -> Result<()> {
+    let mut tx = db_pool.begin().await?;
+    db::reserve_accumulator_leaf(&mut tx, batch_id.clone(), root).await?;
+    db::mark_batch_relayed(&mut tx, batch_id, hash_function).await?;
+    tx.commit().await?;
     Ok(())
 }
@@ -0,0 +1,20 @@
+use script::AGGREGATION_ELF;
+use sp1_sdk::{HashableKey, ProverClient};
+
+/// Write the vkey of the aggregation program, which the aggregation service verifies proofs with
+/// before relaying them, see `AGGREGATION_VKEY_PATH`.
+///
+/// Usage: `export-aggregation-vkey [path]`, which writes `aggregation-vkey.bin` by default.
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let path = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "aggregation-vkey.bin".to_string());
+    let (_, aggregation_vk) = ProverClient::new().setup(AGGREGATION_ELF);
+    std::fs::write(&path, bincode::serialize(&aggregation_vk)?)?;
+    println!(
+        "Wrote aggregation program vkey {} to {}",
+        aggregation_vk.bytes32(),
+        path
+    );
+    Ok(())
+}
@@ -1,6 +1,6 @@
 use sp1_sdk::{ProverClient, SP1Stdin};
 use types::aggregation::{
-    aggregation_service_client::AggregationServiceClient, AggregateProofRequest,
+    aggregation_service_client::AggregationServiceClient, AggregateProofRequest, LeafFormat,
 };
 
 const FIBONACCI_ELF: &[u8] =
@@ -23,6 +23,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     // sp1_sdk::utils::setup_logger();
     let n_values = vec![10, 20, 30, 40, 50];
+    let mut recorded = Vec::new();
     for n in n_values {
         println!("Generating proof for {}", n);
         let mut stdin = SP1Stdin::new();
@@ -33,15 +34,26 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             .run()
             .expect("proving failed");
         let proof_serialized = bincode::serialize(&proof).unwrap();
+        recorded.push((proof_serialized.clone(), vk_serialized.clone()));
         println!("Sending proof request to RPC server");
         let response = network
             .aggregate_proof(AggregateProofRequest {
                 proof: proof_serialized,
                 vk: vk_serialized.clone(),
+                tenant: String::new(),
+                leaf_format: LeafFormat::Full.into(),
+                submitter: vec![],
+                idempotency_key: vec![],
             })
             .await?
             .into_inner();
         println!("Proof response: {:?}", response);
     }
+
+    // Record the proofs for `profile-aggregation` to build batches from.
+    if let Ok(path) = std::env::var("RECORD_PROOFS_PATH") {
+        std::fs::write(&path, bincode::serialize(&recorded).unwrap())?;
+        println!("Recorded {} proofs to {}", recorded.len(), path);
+    }
     Ok(())
 }
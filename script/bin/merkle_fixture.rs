@@ -0,0 +1,156 @@
+use std::path::PathBuf;
+
+use serde::Serialize;
+use types::merkle_tree::{hash_leaf, HashFunction, ProgramTree, TreeVersion};
+
+/// The number of leaves in the fixture. Odd, so that the fixture covers a promoted node.
+const NUM_LEAVES: usize = 5;
+
+/// The number of leaves of the first program of the fixture, the rest being of a second one, so
+/// that every leaf has a non-empty path through its subtree and through the top tree.
+const FIRST_PROGRAM_LEAVES: usize = 3;
+
+/// The leaf counts of the program subtrees the multiproofs of the fixture are against. Mostly
+/// odd, so that the multiproofs cover promoted nodes at several levels.
+const MULTIPROOF_LEAF_COUNTS: [usize; 4] = [3, 5, 6, 7];
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct MerkleFixture {
+    vkeys: Vec<String>,
+    public_values: Vec<String>,
+    leaves: Vec<String>,
+    root: String,
+    subtree_proofs: Vec<Vec<String>>,
+    program_proofs: Vec<Vec<String>>,
+    multiproofs: Vec<MultiproofFixture>,
+}
+
+/// A multiproof of some proofs of a program, against the subtree of the program in a batch where
+/// another program has a proof too, as returned by `GetAggregatedMultiproof`.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct MultiproofFixture {
+    vkey: String,
+    leaves: Vec<String>,
+    indices: Vec<u64>,
+    leaf_count: u64,
+    proof: Vec<String>,
+    proof_flags: Vec<bool>,
+    program_proof: Vec<String>,
+    root: String,
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    format!("0x{}", hex::encode(bytes))
+}
+
+/// Multiproofs of two subsets of the proofs of a program with `leaf_count` proofs. None of them
+/// has an empty array, which the contract tests can't read from the fixture.
+fn multiproofs(leaf_count: usize) -> Vec<MultiproofFixture> {
+    let (vkey, other_vkey) = ([1u8; 32], [2u8; 32]);
+    let mut leaves: Vec<([u8; 32], [u8; 32])> = (0..leaf_count)
+        .map(|i| (vkey, hash_leaf(&vkey, &[leaf_count as u8, i as u8])))
+        .collect();
+    leaves.push((other_vkey, hash_leaf(&other_vkey, &[])));
+    let tree = ProgramTree::new(&leaves, TreeVersion::LATEST, HashFunction::Keccak256)
+        .expect("leaves are grouped by program");
+    let (_, subtree) = &tree.programs[0];
+    // The program comes first, so its leaves have the same index in the batch and in its subtree.
+    let program_proof = tree.generate_proof_at(0).expect("leaf is in the tree");
+
+    [
+        vec![1, leaf_count - 1],
+        (0..leaf_count).step_by(2).collect(),
+    ]
+    .iter()
+    .map(|indices| {
+        let multiproof = subtree
+            .generate_multiproof_at(indices)
+            .expect("indices are in the subtree");
+        assert!(!multiproof.proof.is_empty());
+        MultiproofFixture {
+            vkey: to_hex(&vkey),
+            leaves: multiproof.leaves.iter().map(|leaf| to_hex(leaf)).collect(),
+            indices: multiproof.indices,
+            leaf_count: multiproof.leaf_count,
+            proof: multiproof.proof.iter().map(|node| to_hex(node)).collect(),
+            proof_flags: multiproof.proof_flags,
+            program_proof: program_proof
+                .program_siblings
+                .iter()
+                .map(|node| to_hex(node))
+                .collect(),
+            root: to_hex(&tree.root),
+        }
+    })
+    .collect()
+}
+
+/// Generate the merkle tree fixture shared by the `types` conformance tests and the contract tests.
+fn main() {
+    let mut vkeys = Vec::new();
+    let mut public_values = Vec::new();
+    for i in 0..NUM_LEAVES {
+        let program = usize::from(i >= FIRST_PROGRAM_LEAVES);
+        let vkey: [u8; 32] = std::array::from_fn(|j| (program * 32 + j) as u8);
+        let public_value: Vec<u8> = (0..i * 13).map(|j| (j * 7 + i) as u8).collect();
+        vkeys.push(vkey);
+        public_values.push(public_value);
+    }
+
+    let leaves: Vec<[u8; 32]> = vkeys
+        .iter()
+        .zip(public_values.iter())
+        .map(|(vkey, public_value)| hash_leaf(vkey, public_value))
+        .collect();
+    let program_leaves: Vec<([u8; 32], [u8; 32])> =
+        vkeys.iter().copied().zip(leaves.iter().copied()).collect();
+    let tree = ProgramTree::new(
+        &program_leaves,
+        TreeVersion::LATEST,
+        HashFunction::Keccak256,
+    )
+    .expect("leaves are grouped by program");
+    let (subtree_proofs, program_proofs) = (0..NUM_LEAVES)
+        .map(|index| {
+            let proof = tree.generate_proof_at(index).expect("leaf is in the tree");
+            assert!(!proof.subtree_proof.siblings.is_empty() && !proof.program_siblings.is_empty());
+            (
+                proof
+                    .subtree_proof
+                    .siblings
+                    .iter()
+                    .map(|node| to_hex(node))
+                    .collect(),
+                proof
+                    .program_siblings
+                    .iter()
+                    .map(|node| to_hex(node))
+                    .collect(),
+            )
+        })
+        .unzip();
+
+    let fixture = MerkleFixture {
+        vkeys: vkeys.iter().map(|vkey| to_hex(vkey)).collect(),
+        public_values: public_values.iter().map(|pv| to_hex(pv)).collect(),
+        leaves: leaves.iter().map(|leaf| to_hex(leaf)).collect(),
+        root: to_hex(&tree.root),
+        subtree_proofs,
+        program_proofs,
+        multiproofs: MULTIPROOF_LEAF_COUNTS
+            .iter()
+            .flat_map(|&leaf_count| multiproofs(leaf_count))
+            .collect(),
+    };
+
+    let fixture_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../contracts/src/fixtures");
+    std::fs::create_dir_all(&fixture_path).expect("failed to create fixture path");
+    std::fs::write(
+        fixture_path.join("merkle-fixture.json"),
+        serde_json::to_string_pretty(&fixture).unwrap(),
+    )
+    .expect("failed to write fixture");
+    println!("Wrote merkle fixture with root {}", fixture.root);
+}
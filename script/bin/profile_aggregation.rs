@@ -0,0 +1,112 @@
+use script::{aggregation_stdin, sort_by_program, BatchHeader, AGGREGATION_ELF};
+use sp1_sdk::{ExecutionReport, ProverClient};
+use types::{
+    aggregation::ProofRequest,
+    merkle_tree::{hash_function_from_env, HASH_FUNCTION_ENV},
+    mmr::MerkleMountainRange,
+};
+
+/// The number of cycles SP1 proves in one shard, unless overridden with `SHARD_SIZE`.
+const DEFAULT_SHARD_SIZE: u64 = 1 << 22;
+
+/// Execute the aggregation program on batches of recorded proofs without proving them, and
+/// report how many cycles and syscalls each batch takes and what proving it would cost.
+///
+/// Usage: `profile-aggregation <recorded proofs> [batch sizes...]`, where the recorded proofs
+/// are written by `generate-compressed` with `RECORD_PROOFS_PATH` set. Without batch sizes, the
+/// recorded batch is profiled as it is. Batches of other sizes are synthesized from the recorded
+/// proofs, reusing them as needed.
+///
+/// Each batch is executed as a single run of the aggregation program, so that the cycles per
+/// proof tell how large a run can be. The estimated cost is the number of shards the run would
+/// be proven in, and its price if `PROVING_COST_PER_MCYCLE` is set.
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    dotenv::dotenv().ok();
+
+    let mut args = std::env::args().skip(1);
+    let Some(path) = args.next() else {
+        eprintln!("Usage: profile-aggregation <recorded proofs> [batch sizes...]");
+        std::process::exit(1);
+    };
+    let recorded: Vec<(Vec<u8>, Vec<u8>)> = bincode::deserialize(&std::fs::read(&path)?)?;
+    if recorded.is_empty() {
+        return Err(format!("No proofs recorded in {}", path).into());
+    }
+    let mut batch_sizes = args
+        .map(|size| size.parse::<usize>())
+        .collect::<Result<Vec<_>, _>>()?;
+    if batch_sizes.is_empty() {
+        batch_sizes.push(recorded.len());
+    }
+    let shard_size = std::env::var("SHARD_SIZE")
+        .ok()
+        .and_then(|size| size.parse().ok())
+        .unwrap_or(DEFAULT_SHARD_SIZE);
+    let cost_per_mcycle = std::env::var("PROVING_COST_PER_MCYCLE")
+        .ok()
+        .and_then(|cost| cost.parse::<f64>().ok());
+
+    // Executing runs on the CPU whatever prover is configured.
+    let client = ProverClient::local();
+    let (_, aggregation_vk) = client.setup(AGGREGATION_ELF);
+    let hash_function =
+        hash_function_from_env().map_err(|e| format!("invalid {}: {}", HASH_FUNCTION_ENV, e))?;
+
+    for batch_size in batch_sizes {
+        let mut proofs = recorded
+            .iter()
+            .cycle()
+            .take(batch_size)
+            .enumerate()
+            .map(|(index, (proof, vk))| ProofRequest {
+                proof_id: (index as u64).to_be_bytes().to_vec(),
+                proof: proof.clone(),
+                vk: vk.clone(),
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        sort_by_program(&mut proofs);
+        let header = BatchHeader {
+            accumulator: MerkleMountainRange::default(),
+            batch_id: [0; 32],
+            timestamp: 0,
+            request_indices: (0..batch_size as u64).collect(),
+        };
+        let stdin = aggregation_stdin(
+            &proofs,
+            &[],
+            hash_function,
+            &aggregation_vk,
+            None,
+            Some(&header),
+        );
+        let (_, report) = client.execute(AGGREGATION_ELF, stdin).run()?;
+        print_report(batch_size, &report, shard_size, cost_per_mcycle);
+    }
+    Ok(())
+}
+
+fn print_report(
+    batch_size: usize,
+    report: &ExecutionReport,
+    shard_size: u64,
+    cost_per_mcycle: Option<f64>,
+) {
+    let cycles = report.total_instruction_count();
+    println!("Batch of {} proofs", batch_size);
+    println!("  cycles: {}", cycles);
+    println!("  cycles per proof: {}", cycles / batch_size.max(1) as u64);
+    println!("  syscalls: {}", report.total_syscall_count());
+    for (syscall, count) in report.syscall_counts.iter() {
+        if *count > 0 {
+            println!("    {:?}: {}", syscall, count);
+        }
+    }
+    println!("  estimated shards: {}", cycles.div_ceil(shard_size));
+    if let Some(cost_per_mcycle) = cost_per_mcycle {
+        println!(
+            "  estimated proving cost: {:.4}",
+            cycles as f64 / 1_000_000.0 * cost_per_mcycle
+        );
+    }
+}
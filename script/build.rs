@@ -0,0 +1,5 @@
+use sp1_build::build_program;
+
+fn main() {
+    build_program("../programs/aggregation-program");
+}
@@ -0,0 +1,100 @@
+//! The input of the aggregation program, shared by the aggregation service and the tools that
+//! run the program outside of it.
+
+use sp1_sdk::{HashableKey, SP1Proof, SP1ProofWithPublicValues, SP1Stdin, SP1VerifyingKey};
+use types::{
+    aggregation::ProofRequest,
+    merkle_tree::{allowlist::Allowlist, HashFunction, LeafFormat, MerkleHasher, Sha256},
+    mmr::MerkleMountainRange,
+};
+
+pub const AGGREGATION_ELF: &[u8] =
+    include_bytes!("../../programs/aggregation-program/elf/riscv32im-succinct-zkvm-elf");
+
+/// What the top run of a batch commits besides the root of the batch.
+pub struct BatchHeader {
+    /// The accumulator of the batches relayed so far, which the batch is appended to.
+    pub accumulator: MerkleMountainRange,
+    pub batch_id: [u8; 32],
+    /// When the batch was aggregated, in seconds since the Unix epoch.
+    pub timestamp: u64,
+    /// For each leaf, the index of its request in the batch, once ordered with
+    /// [`sort_by_program`]. The program commits them as given, without binding them to the leaves.
+    pub request_indices: Vec<u64>,
+}
+
+/// Order the proofs of a batch by the vkey hash of their program, as the leaves of the batch are,
+/// keeping the order of the proofs of each program. Proofs with an invalid vk come first.
+pub fn sort_by_program(proofs: &mut [ProofRequest]) {
+    proofs.sort_by_cached_key(|request| {
+        bincode::deserialize::<SP1VerifyingKey>(&request.vk)
+            .ok()
+            .map(|vk| vk.hash_bytes())
+    });
+}
+
+/// The input of one run of the aggregation program, which either verifies `proofs` or merges
+/// `subtrees`, the compressed proofs of earlier runs. Only the top run of a batch is given the
+/// header of the batch to commit.
+pub fn aggregation_stdin(
+    proofs: &[ProofRequest],
+    subtrees: &[SP1ProofWithPublicValues],
+    hash_function: HashFunction,
+    aggregation_vk: &SP1VerifyingKey,
+    allowlist: Option<&Allowlist>,
+    header: Option<&BatchHeader>,
+) -> SP1Stdin {
+    let mut stdin = SP1Stdin::new();
+    let mut vkeys = Vec::new();
+    let mut public_values = Vec::new();
+    let mut leaf_formats = Vec::new();
+    let mut submitters = Vec::new();
+    for request in proofs {
+        let proof_deserialized: SP1ProofWithPublicValues =
+            bincode::deserialize(&request.proof).unwrap();
+        let vk_deserialized: SP1VerifyingKey = bincode::deserialize(&request.vk).unwrap();
+
+        vkeys.push(vk_deserialized.hash_u32());
+        // The program only needs the digest of the public values of a digest leaf.
+        let leaf_format = LeafFormat::from(request.leaf_format());
+        public_values.push(match leaf_format {
+            LeafFormat::Full => proof_deserialized.public_values.to_vec(),
+            LeafFormat::Digest => Sha256
+                .hash(&[proof_deserialized.public_values.as_slice()])
+                .to_vec(),
+        });
+        leaf_formats.push(leaf_format);
+        submitters.push(request.submitter.clone());
+
+        let SP1Proof::Compressed(proof) = proof_deserialized.proof else {
+            panic!("Proof is not compressed");
+        };
+        stdin.write_proof(*proof, vk_deserialized.vk);
+    }
+    for subtree in subtrees {
+        let SP1Proof::Compressed(proof) = subtree.proof.clone() else {
+            panic!("Subtree proof is not compressed");
+        };
+        stdin.write_proof(*proof, aggregation_vk.vk.clone());
+    }
+    stdin.write::<Vec<[u32; 8]>>(&vkeys);
+    stdin.write::<Vec<Vec<u8>>>(&public_values);
+    stdin.write::<Vec<LeafFormat>>(&leaf_formats);
+    stdin.write::<Vec<Vec<u8>>>(&submitters);
+    stdin.write::<u8>(&(hash_function as u8));
+    stdin.write::<Option<Allowlist>>(&allowlist.cloned());
+    stdin.write::<[u32; 8]>(&aggregation_vk.hash_u32());
+    stdin.write::<Vec<Vec<u8>>>(
+        &subtrees
+            .iter()
+            .map(|subtree| subtree.public_values.to_vec())
+            .collect(),
+    );
+    stdin.write::<Option<MerkleMountainRange>>(&header.map(|header| header.accumulator.clone()));
+    if let Some(header) = header {
+        stdin.write::<[u8; 32]>(&header.batch_id);
+        stdin.write::<u64>(&header.timestamp);
+        stdin.write::<Vec<u64>>(&header.request_indices);
+    }
+    stdin
+}
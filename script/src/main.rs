@@ -1,15 +1,69 @@
 use chrono::Utc;
-use sp1_sdk::{
-    HashableKey, ProverClient, SP1Proof, SP1ProofWithPublicValues, SP1Stdin, SP1VerifyingKey,
-};
-use types::aggregation::{
-    aggregation_service_client::AggregationServiceClient, AggregationStatus, GetBatchRequest,
-    ProcessBatchRequest, UpdateBatchStatusRequest, VerifyAggregationProofRequest,
-    WriteMerkleTreeRequest,
+use script::{aggregation_stdin, sort_by_program, BatchHeader, AGGREGATION_ELF};
+use sp1_sdk::{HashableKey, ProverClient, SP1Proof, SP1ProofWithPublicValues, SP1VerifyingKey};
+use types::{
+    aggregation::{
+        aggregation_service_client::AggregationServiceClient, AggregationProofRejection,
+        ClaimBatchRequest, CompleteBatchRequest, DuplicateLeafPolicy, FailedProof,
+        GetAccumulatorRequest, GetProgramAllowlistRequest, MarkProofsFailedRequest,
+        ProcessBatchRequest, ProofRequest, VerifyAggregationProofRequest,
+    },
+    merkle_tree::{
+        allowlist::Allowlist, hash_function_from_env, words_to_bytes_le, HASH_FUNCTION_ENV,
+    },
+    mmr::MerkleMountainRange,
 };
 
-const AGGREGATION_ELF: &[u8] =
-    include_bytes!("../../programs/aggregation-program/elf/riscv32im-succinct-zkvm-elf");
+/// The most user proofs one run of the aggregation program verifies. The proofs of each program
+/// in larger batches are split into runs over this many consecutive proofs, whose roots are then
+/// nodes of the subtree of the program because it is a power of two.
+const LEAF_RUN_SIZE: usize = 4;
+
+/// The most earlier runs one run of the aggregation program merges. A power of two as well, so
+/// that every level of runs stays aligned with the tree of the batch.
+const MERGE_RUN_SIZE: usize = 4;
+
+/// Check that the proof of a request is a compressed proof of an allowed program that verifies
+/// with its vkey, as the aggregation program requires. Returns why it can't be aggregated
+/// otherwise.
+fn verify_request(
+    client: &ProverClient,
+    request: &ProofRequest,
+    allowlist: Option<&Allowlist>,
+) -> Result<(), String> {
+    let proof: SP1ProofWithPublicValues =
+        bincode::deserialize(&request.proof).map_err(|e| format!("invalid proof: {}", e))?;
+    let vk: SP1VerifyingKey =
+        bincode::deserialize(&request.vk).map_err(|e| format!("invalid vk: {}", e))?;
+    if allowlist.is_some_and(|allowlist| !allowlist.contains(&vk.hash_bytes())) {
+        return Err("program is not registered".to_string());
+    }
+    if !matches!(proof.proof, SP1Proof::Compressed(_)) {
+        return Err("proof is not compressed".to_string());
+    }
+    client
+        .verify(&proof, &vk)
+        .map_err(|e| format!("proof does not verify: {}", e))
+}
+
+/// Fail every proof of `proofs`, claimed for the batch of `lease`, for `reason`.
+fn fail_proofs(
+    lease: &CompleteBatchRequest,
+    proofs: &[ProofRequest],
+    reason: &str,
+) -> MarkProofsFailedRequest {
+    MarkProofsFailedRequest {
+        proofs: proofs
+            .iter()
+            .map(|request| FailedProof {
+                proof_id: request.proof_id.clone(),
+                reason: reason.to_string(),
+            })
+            .collect(),
+        batch_id: lease.batch_id.clone(),
+        worker_id: lease.worker_id.clone(),
+    }
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -18,12 +72,23 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let client = ProverClient::new();
     let (aggregation_pk, aggregation_vk) = client.setup(AGGREGATION_ELF);
+    // The verifier contract is deployed with this digest, see `AGGREGATION_PROGRAM_VKEY_DIGEST`.
+    println!(
+        "Aggregation program vkey digest: 0x{}",
+        hex::encode(words_to_bytes_le(&aggregation_vk.hash_u32()))
+    );
+    // The RPC and the verifier contract must be configured with the same hash function.
+    let hash_function =
+        hash_function_from_env().map_err(|e| format!("invalid {}: {}", HASH_FUNCTION_ENV, e))?;
     let rpc_addr = std::env::var("RPC_GRPC_ADDR").unwrap();
     println!("Connecting to RPC server at {}", rpc_addr);
     let mut network_client = AggregationServiceClient::connect(format!("https://{}", rpc_addr))
         .await
         .unwrap()
         .max_decoding_message_size(1024 * 1024 * 1024);
+    // Workers running concurrently each claim batches of their own, leased under this id. The
+    // server assigns one to each batch if it is not set.
+    let worker_id = std::env::var("WORKER_ID").unwrap_or_default();
 
     let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(1800)); // run every 30 minutes
     loop {
@@ -32,96 +97,255 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         println!("Current timestamp: {}", current_timestamp);
         println!("Looping every 30 minutes");
 
-        let mut stdin = SP1Stdin::new();
-
-        // Get a batch of proofs.
-        println!("Getting batch of proofs");
+        // Claim a batch of proofs, which must be aggregated and relayed before its lease expires.
+        println!("Claiming batch of proofs");
         let batch_response = network_client
-            .get_batch(GetBatchRequest {
-                created_after: None,
+            .claim_batch(ClaimBatchRequest {
+                worker_id: worker_id.clone(),
                 batch_size: Some(5),
+                lease_seconds: None,
             })
             .await?
             .into_inner();
 
         let batch_id = batch_response.batch_id;
-        let proofs = batch_response.proofs;
+        let lease = CompleteBatchRequest {
+            batch_id: batch_id.clone(),
+            worker_id: batch_response.worker_id,
+        };
+        let mut batch_proofs = batch_response.proofs;
+        // The leaves of each program form a subtree of their own, so order the batch like its
+        // leaves for the request index of each leaf to stay increasing.
+        sort_by_program(&mut batch_proofs);
 
         println!("Batch ID: {:?}", batch_id);
-        println!("Number of proofs in batch: {}", proofs.len());
+        println!("Number of proofs in batch: {}", batch_proofs.len());
+        println!("Lease expires at: {}", batch_response.lease_expires_at);
+
+        // Restrict the batch to registered programs, if the service is.
+        let allowlist_response = network_client
+            .get_program_allowlist(GetProgramAllowlistRequest {})
+            .await?
+            .into_inner();
+        let allowlist = allowlist_response.enforced.then(|| {
+            Allowlist::new(
+                allowlist_response
+                    .vkey_hashes
+                    .iter()
+                    .map(|vkey_hash| vkey_hash.as_slice().try_into().unwrap())
+                    .collect(),
+            )
+        });
 
-        // Process the batch and get the leaves of the merkle tree.
+        // A proof that fails to verify in the aggregation program fails the whole batch, so leave
+        // out the proofs that don't verify here.
+        let mut failed_proofs = Vec::new();
+        let proofs = batch_proofs
+            .iter()
+            .filter(
+                |request| match verify_request(&client, request, allowlist.as_ref()) {
+                    Ok(()) => true,
+                    Err(reason) => {
+                        println!("Proof {:?} failed: {}", request.proof_id, reason);
+                        failed_proofs.push(FailedProof {
+                            proof_id: request.proof_id.clone(),
+                            reason,
+                        });
+                        false
+                    }
+                },
+            )
+            .cloned()
+            .collect::<Vec<_>>();
+        if !failed_proofs.is_empty() {
+            network_client
+                .mark_proofs_failed(MarkProofsFailedRequest {
+                    proofs: failed_proofs,
+                    batch_id: lease.batch_id.clone(),
+                    worker_id: lease.worker_id.clone(),
+                })
+                .await?;
+        }
+        if proofs.is_empty() {
+            println!("No proofs to aggregate");
+            // An empty claim leases no batch.
+            if !batch_proofs.is_empty() {
+                network_client.complete_batch(lease).await?;
+            }
+            continue;
+        }
+
+        // Process the batch, which also stores its merkle tree, and get the programs of its leaves.
         println!("Processing batch");
         let process_batch_response = network_client
             .process_batch(ProcessBatchRequest {
                 batch_id: batch_id.clone(),
                 proofs: proofs.clone(),
+                duplicate_policy: DuplicateLeafPolicy::Deduplicate as i32,
+                worker_id: lease.worker_id.clone(),
             })
             .await?
             .into_inner();
 
-        let leaves = process_batch_response.leaves;
+        let vkey_hashes = process_batch_response.vkey_hashes;
 
-        // Write the merkle tree to the database
-        println!("Writing merkle tree to database");
-        network_client
-            .write_merkle_tree(WriteMerkleTreeRequest {
-                batch_id: batch_id.clone(),
-                tree: leaves,
+        // Only aggregate the proofs that got a leaf of their own, in leaf order.
+        let request_indices = process_batch_response
+            .proof_ids
+            .iter()
+            .map(|proof_id| {
+                batch_proofs
+                    .iter()
+                    .position(|request| &request.proof_id == proof_id)
+                    .expect("Processed proof is not in the batch")
             })
+            .collect::<Vec<_>>();
+        let proofs = request_indices
+            .iter()
+            .map(|&index| batch_proofs[index].clone())
+            .collect::<Vec<_>>();
+
+        // The batch extends the accumulator of the batches relayed so far.
+        let accumulator_response = network_client
+            .get_accumulator(GetAccumulatorRequest {})
             .await?
             .into_inner();
+        let accumulator = MerkleMountainRange {
+            leaf_count: accumulator_response.leaf_count,
+            peaks: accumulator_response
+                .peaks
+                .iter()
+                .map(|peak| peak.as_slice().try_into().unwrap())
+                .collect(),
+        };
+
+        // The contract rejects batches relayed out of order, or with a timestamp in its future.
+        let header = BatchHeader {
+            accumulator,
+            batch_id: batch_id.as_slice().try_into().expect("Invalid batch id"),
+            timestamp: (current_timestamp / 1000) as u64,
+            request_indices: request_indices.iter().map(|&index| index as u64).collect(),
+        };
 
-        // Prove the batch and get the aggregated proof.
+        // Prove the batch and get the aggregated proof. A batch too large for one run is proven as
+        // a tree of runs, each level merging the compressed proofs of the level below it. Runs
+        // below the top one cover the proofs of one program, and the top run merges the runs of
+        // every program. The top run is wrapped in Plonk, which the verifier contract verifies.
         println!("Proving batch");
-        let mut vkeys = Vec::new();
-        let mut public_values = Vec::new();
-        for request in proofs {
-            println!("Proof ID: {:?}", request.proof_id);
-            let proof_deserialized: SP1ProofWithPublicValues =
-                serde_json::from_slice(&request.proof).unwrap();
-            let vk_deserialized: SP1VerifyingKey = serde_json::from_slice(&request.vk).unwrap();
-
-            vkeys.push(vk_deserialized.hash_u32());
-            public_values.push(proof_deserialized.public_values.to_vec());
-
-            let SP1Proof::Compressed(proof) = proof_deserialized.proof else {
-                panic!("Proof is not compressed");
+        let prove_run = |proofs: &[ProofRequest],
+                         subtrees: &[SP1ProofWithPublicValues],
+                         header: Option<&BatchHeader>| {
+            let stdin = aggregation_stdin(
+                proofs,
+                subtrees,
+                hash_function,
+                &aggregation_vk,
+                allowlist.as_ref(),
+                header,
+            );
+            let prover = client.prove(&aggregation_pk, stdin);
+            let prover = match header {
+                Some(_) => prover.plonk(),
+                None => prover.compressed(),
             };
-            stdin.write_proof(*proof, vk_deserialized.vk);
-        }
-        stdin.write::<Vec<[u32; 8]>>(&vkeys);
-        stdin.write::<Vec<Vec<u8>>>(&public_values);
+            prover.run().map_err(|e| e.to_string())
+        };
+        let prove_batch = || {
+            if proofs.len() <= LEAF_RUN_SIZE {
+                return prove_run(&proofs, &[], Some(&header));
+            }
+            let vkey_hashes = vkey_hashes.chunks(32).collect::<Vec<_>>();
+            let mut subtrees = Vec::new();
+            let mut rest = proofs.as_slice();
+            for program in vkey_hashes.chunk_by(|a, b| a == b) {
+                let (program_proofs, next) = rest.split_at(program.len());
+                rest = next;
+                let mut program_subtrees = program_proofs
+                    .chunks(LEAF_RUN_SIZE)
+                    .map(|chunk| prove_run(chunk, &[], None))
+                    .collect::<Result<Vec<_>, _>>()?;
+                while program_subtrees.len() > MERGE_RUN_SIZE {
+                    println!("Merging {} subtrees", program_subtrees.len());
+                    program_subtrees = program_subtrees
+                        .chunks(MERGE_RUN_SIZE)
+                        .map(|chunk| prove_run(&[], chunk, None))
+                        .collect::<Result<_, _>>()?;
+                }
+                subtrees.extend(program_subtrees);
+            }
+            prove_run(&[], &subtrees, Some(&header))
+        };
+        // Every proof of the batch verified on its own, so a run that fails to prove is most likely
+        // a failure of the prover rather than of the batch, which goes back to pending to be
+        // proven again.
+        let aggregated_proof = match prove_batch() {
+            Ok(aggregated_proof) => aggregated_proof,
+            Err(e) => {
+                println!("Proving failed: {}", e);
+                network_client.complete_batch(lease).await?;
+                continue;
+            }
+        };
 
-        let aggregated_proof = client
-            .prove(&aggregation_pk, stdin)
-            .run()
-            .expect("Proving failed");
-
-        // Verify the aggregated proof
+        // Verify the aggregated proof, which the service verifies again before relaying it. Proving
+        // the batch again would give a proof that doesn't verify either, so its proofs fail.
         println!("Verifying aggregated proof");
+        if let Err(e) = client.verify(&aggregated_proof, &aggregation_vk) {
+            println!("Aggregated proof does not verify: {}", e);
+            network_client
+                .mark_proofs_failed(fail_proofs(
+                    &lease,
+                    &proofs,
+                    &format!("aggregated proof does not verify: {}", e),
+                ))
+                .await?;
+            network_client.complete_batch(lease).await?;
+            continue;
+        }
         let aggregated_proof_bytes = bincode::serialize(&aggregated_proof).unwrap();
-        let response = network_client
+        // The batch goes back to pending if the service fails to relay it, unless it was relayed.
+        let response = match network_client
             .verify_aggregation_proof(VerifyAggregationProofRequest {
                 proof: aggregated_proof_bytes,
                 batch_id: batch_id.clone(),
+                worker_id: lease.worker_id.clone(),
             })
             .await
-            .expect("Failed to verify aggregation proof")
-            .into_inner();
+        {
+            Ok(response) => response.into_inner(),
+            Err(status) => {
+                println!("Failed to relay aggregated proof: {}", status);
+                network_client.complete_batch(lease).await?;
+                continue;
+            }
+        };
+        if !response.verified {
+            println!(
+                "Aggregated proof was rejected ({:?}): {}",
+                response.rejection(),
+                response.rejection_reason
+            );
+            // A proof that doesn't extend the current accumulator only lost the race with another
+            // batch, so the batch goes back to pending to be proven again. A new proof of the batch
+            // would be rejected for any other reason as well, so its proofs fail.
+            if response.rejection() != AggregationProofRejection::StaleAccumulator {
+                network_client
+                    .mark_proofs_failed(fail_proofs(
+                        &lease,
+                        &proofs,
+                        &format!(
+                            "aggregated proof was rejected: {}",
+                            response.rejection_reason
+                        ),
+                    ))
+                    .await?;
+            }
+            network_client.complete_batch(lease).await?;
+            continue;
+        }
         println!("tx: {:?}", response.tx_hash);
-        // println!("Response: {:?}", response);
-        // client
-        //     .verify(&aggregated_proof, &aggregation_vk)
-        //     .expect("Proof verification failed");
-
-        // Update the status of all the proofs in the batch to verified.
-        println!("Updating batch status to verified");
-        network_client
-            .update_batch_status(UpdateBatchStatusRequest {
-                batch_id,
-                status: AggregationStatus::Verified as i32,
-            })
-            .await?;
+
+        // The service recorded the relay, which aggregated the proofs of the batch.
+        network_client.complete_batch(lease).await?;
     }
 }
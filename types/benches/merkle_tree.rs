@@ -0,0 +1,49 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use types::merkle_tree::{hash_leaf, HashFunction, MerkleTree, TreeVersion};
+
+const SIZES: [usize; 4] = [1_000, 10_000, 100_000, 1_000_000];
+
+fn leaves(n: usize) -> Vec<[u8; 32]> {
+    (0..n as u64)
+        .map(|i| hash_leaf(&[0; 32], &i.to_be_bytes()))
+        .collect()
+}
+
+fn build(c: &mut Criterion) {
+    let mut group = c.benchmark_group("build");
+    group.sample_size(10);
+    for n in SIZES {
+        let leaves = leaves(n);
+        group.throughput(Throughput::Elements(n as u64));
+        for hash_function in [HashFunction::Keccak256, HashFunction::Sha256] {
+            group.bench_with_input(
+                BenchmarkId::new(format!("{hash_function:?}"), n),
+                &leaves,
+                |b, leaves| {
+                    b.iter(|| {
+                        MerkleTree::with_hash_function(
+                            leaves.clone(),
+                            TreeVersion::LATEST,
+                            hash_function,
+                        )
+                    })
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+fn generate_proof(c: &mut Criterion) {
+    let mut group = c.benchmark_group("generate_proof");
+    for n in SIZES {
+        let tree = MerkleTree::new(leaves(n));
+        group.bench_with_input(BenchmarkId::from_parameter(n), &tree, |b, tree| {
+            b.iter(|| tree.generate_proof_at(n / 2))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, build, generate_proof);
+criterion_main!(benches);
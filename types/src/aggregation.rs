@@ -9,7 +9,9 @@ pub struct GetAggregatedDataRequest {
 #[derive(serde::Serialize, serde::Deserialize)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct GetAggregatedDataResponse {
-    /// The merkle proof for the given account
+    /// The merkle proof for the given account. For batches with a subtree per program, this is
+    /// `subtree_proof` followed by `program_proof`, which are verified separately: the path of the
+    /// program starts from its node, which hashes `0x02`, the vkey hash and `subtree_root`
     #[prost(bytes = "vec", repeated, tag = "1")]
     pub proof: ::prost::alloc::vec::Vec<::prost::alloc::vec::Vec<u8>>,
     /// Status of the call
@@ -24,6 +26,92 @@ pub struct GetAggregatedDataResponse {
     /// The contract address of the proof
     #[prost(bytes = "vec", tag = "5")]
     pub contract_address: ::prost::alloc::vec::Vec<u8>,
+    /// The version of the merkle tree format the proof was generated for
+    #[prost(uint32, tag = "6")]
+    pub tree_version: u32,
+    /// The merkle proof in its stable binary encoding, which can be verified without the tree. For
+    /// batches with a subtree per program, this is the proof against `subtree_root`
+    #[prost(bytes = "vec", tag = "7")]
+    pub encoded_proof: ::prost::alloc::vec::Vec<u8>,
+    /// The hash function of the nodes of the merkle tree: 0 for Keccak-256, 1 for SHA-256
+    #[prost(uint32, tag = "8")]
+    pub hash_function: u32,
+    /// The accumulator root the proof verifies against
+    #[prost(bytes = "vec", tag = "9")]
+    pub root: ::prost::alloc::vec::Vec<u8>,
+    /// The identity of the submitter bound into the leaf, or empty if it is unbound
+    #[prost(bytes = "vec", tag = "10")]
+    pub submitter: ::prost::alloc::vec::Vec<u8>,
+    /// The path of the leaf through the subtree of its program, which `proof` starts with. Empty
+    /// for batches aggregated before each program had a subtree of its own
+    #[prost(bytes = "vec", repeated, tag = "11")]
+    pub subtree_proof: ::prost::alloc::vec::Vec<::prost::alloc::vec::Vec<u8>>,
+    /// The rest of `proof`: the path from the node of the program to `root`
+    #[prost(bytes = "vec", repeated, tag = "12")]
+    pub program_proof: ::prost::alloc::vec::Vec<::prost::alloc::vec::Vec<u8>>,
+    /// The root of the subtree of the program, which only commits to proofs of that program
+    #[prost(bytes = "vec", tag = "13")]
+    pub subtree_root: ::prost::alloc::vec::Vec<u8>,
+}
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct GetAggregatedMultiproofRequest {
+    /// The proof ids to get the merkle multiproof for
+    #[prost(bytes = "vec", repeated, tag = "1")]
+    pub proof_ids: ::prost::alloc::vec::Vec<::prost::alloc::vec::Vec<u8>>,
+}
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct GetAggregatedMultiproofResponse {
+    /// The leaves of the given proofs, in tree order
+    #[prost(bytes = "vec", repeated, tag = "1")]
+    pub leaves: ::prost::alloc::vec::Vec<::prost::alloc::vec::Vec<u8>>,
+    /// The indices of the leaves in the merkle tree
+    #[prost(uint64, repeated, tag = "2")]
+    pub indices: ::prost::alloc::vec::Vec<u64>,
+    /// The number of leaves in the merkle tree
+    #[prost(uint64, tag = "3")]
+    pub leaf_count: u64,
+    /// The sibling nodes that can't be computed from the leaves
+    #[prost(bytes = "vec", repeated, tag = "4")]
+    pub proof: ::prost::alloc::vec::Vec<::prost::alloc::vec::Vec<u8>>,
+    /// For each hash, whether its second operand is computed from the leaves
+    #[prost(bool, repeated, tag = "5")]
+    pub proof_flags: ::prost::alloc::vec::Vec<bool>,
+    /// Status of the call
+    #[prost(enumeration = "AggregationStatusResponse", tag = "6")]
+    pub status: i32,
+    /// The tx hash of the proofs
+    #[prost(bytes = "vec", tag = "7")]
+    pub tx_hash: ::prost::alloc::vec::Vec<u8>,
+    /// The chain id of the proofs
+    #[prost(uint64, tag = "8")]
+    pub chain_id: u64,
+    /// The contract address of the proofs
+    #[prost(bytes = "vec", tag = "9")]
+    pub contract_address: ::prost::alloc::vec::Vec<u8>,
+    /// The version of the merkle tree format the multiproof was generated for
+    #[prost(uint32, tag = "10")]
+    pub tree_version: u32,
+    /// The hash function of the nodes of the merkle tree: 0 for Keccak-256, 1 for SHA-256
+    #[prost(uint32, tag = "11")]
+    pub hash_function: u32,
+    /// For batches with a subtree per program, the proofs must be of one program and the
+    /// multiproof is against the root of its subtree, whose leaves `indices` and `leaf_count`
+    /// refer to. Empty for batches aggregated as a single tree
+    #[prost(bytes = "vec", tag = "12")]
+    pub subtree_root: ::prost::alloc::vec::Vec<u8>,
+    /// The path from the node of the program, which hashes `0x02`, the vkey hash and
+    /// `subtree_root`, to the root of the batch
+    #[prost(bytes = "vec", repeated, tag = "13")]
+    pub program_proof: ::prost::alloc::vec::Vec<::prost::alloc::vec::Vec<u8>>,
+    /// The path from the root of the batch to `root`. On chain, `verifyMultiProof` takes
+    /// `program_proof` followed by `accumulator_proof`
+    #[prost(bytes = "vec", repeated, tag = "14")]
+    pub accumulator_proof: ::prost::alloc::vec::Vec<::prost::alloc::vec::Vec<u8>>,
+    /// The accumulator root the multiproof verifies against
+    #[prost(bytes = "vec", tag = "15")]
+    pub root: ::prost::alloc::vec::Vec<u8>,
 }
 #[derive(serde::Serialize, serde::Deserialize)]
 #[derive(Clone, PartialEq, ::prost::Message)]
@@ -33,11 +121,14 @@ pub struct GetAggregationStatusRequest {
     pub proof_id: ::prost::alloc::vec::Vec<u8>,
 }
 #[derive(serde::Serialize, serde::Deserialize)]
-#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct GetAggregationStatusResponse {
     /// The aggregation status of the proof
     #[prost(enumeration = "AggregationStatus", tag = "1")]
     pub status: i32,
+    /// Why the proof was not aggregated, if it failed
+    #[prost(string, tag = "2")]
+    pub failure_reason: ::prost::alloc::string::String,
 }
 #[derive(serde::Serialize, serde::Deserialize)]
 #[derive(Clone, PartialEq, ::prost::Message)]
@@ -48,26 +139,70 @@ pub struct AggregateProofRequest {
     /// The corresponding vk
     #[prost(bytes = "vec", tag = "2")]
     pub vk: ::prost::alloc::vec::Vec<u8>,
+    /// The tenant submitting the proof, which must own the program if it is registered to one
+    #[prost(string, tag = "3")]
+    pub tenant: ::prost::alloc::string::String,
+    /// What the leaf of the proof commits to besides the vkey hash of its program
+    #[prost(enumeration = "LeafFormat", tag = "4")]
+    pub leaf_format: i32,
+    /// The identity of the submitter bound into the leaf, such as an EVM address or a public key,
+    /// or empty to leave the leaf unbound
+    #[prost(bytes = "vec", tag = "5")]
+    pub submitter: ::prost::alloc::vec::Vec<u8>,
+    /// A key the proof id is derived from together with the tenant, so that retries of the request
+    /// map to it. The tenant is not authenticated, so another client using the same tenant and key
+    /// gets the same proof id. If empty, the proof id is derived from the vkey hash, the public
+    /// values, the proof, the leaf format and the submitter
+    #[prost(bytes = "vec", tag = "6")]
+    pub idempotency_key: ::prost::alloc::vec::Vec<u8>,
 }
 #[derive(serde::Serialize, serde::Deserialize)]
-#[derive(Clone, Copy, PartialEq, ::prost::Message)]
-pub struct GetBatchRequest {
-    /// The unix timestamp to get all proof requests created after
-    #[prost(uint64, optional, tag = "1")]
-    pub created_after: ::core::option::Option<u64>,
-    /// The number of proofs to return (default is 32)
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ClaimBatchRequest {
+    /// The identifier of the worker claiming the batch, or empty for the server to assign one
+    #[prost(string, tag = "1")]
+    pub worker_id: ::prost::alloc::string::String,
+    /// The number of proofs to claim (default is 32)
     #[prost(uint64, optional, tag = "2")]
     pub batch_size: ::core::option::Option<u64>,
+    /// How long the worker holds the batch, in seconds (default is 2 hours)
+    #[prost(uint64, optional, tag = "3")]
+    pub lease_seconds: ::core::option::Option<u64>,
 }
 #[derive(serde::Serialize, serde::Deserialize)]
 #[derive(Clone, PartialEq, ::prost::Message)]
-pub struct GetBatchResponse {
-    /// The list of proof requests
-    #[prost(message, repeated, tag = "1")]
+pub struct ClaimBatchResponse {
+    /// The identifier of the batch
+    #[prost(bytes = "vec", tag = "1")]
+    pub batch_id: ::prost::alloc::vec::Vec<u8>,
+    /// The list of proof requests, which no other worker is given while the lease holds. Empty if
+    /// there are no pending proofs, in which case no batch is claimed
+    #[prost(message, repeated, tag = "2")]
     pub proofs: ::prost::alloc::vec::Vec<ProofRequest>,
+    /// The identifier of the worker holding the lease
+    #[prost(string, tag = "3")]
+    pub worker_id: ::prost::alloc::string::String,
+    /// The unix timestamp in milliseconds when the lease expires. A batch that is neither
+    /// completed nor relayed by then is released, and its proofs go back to pending
+    #[prost(uint64, tag = "4")]
+    pub lease_expires_at: u64,
+}
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct CompleteBatchRequest {
     /// The identifier of the batch
-    #[prost(bytes = "vec", tag = "2")]
+    #[prost(bytes = "vec", tag = "1")]
     pub batch_id: ::prost::alloc::vec::Vec<u8>,
+    /// The identifier of the worker holding the lease
+    #[prost(string, tag = "2")]
+    pub worker_id: ::prost::alloc::string::String,
+}
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct CompleteBatchResponse {
+    /// Indicates if the lease was completed
+    #[prost(bool, tag = "1")]
+    pub success: bool,
 }
 #[derive(serde::Serialize, serde::Deserialize)]
 #[derive(Clone, PartialEq, ::prost::Message)]
@@ -85,16 +220,25 @@ pub struct GetVkeyAndPublicValuesResponse {
     /// The public values for the given proof
     #[prost(bytes = "vec", tag = "2")]
     pub public_values: ::prost::alloc::vec::Vec<u8>,
+    /// What the leaf of the proof commits to besides the vkey hash of its program
+    #[prost(enumeration = "LeafFormat", tag = "3")]
+    pub leaf_format: i32,
 }
 #[derive(serde::Serialize, serde::Deserialize)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct ProcessBatchRequest {
-    /// The list of proof requests to process
+    /// The list of proof requests to process, which must be pending requests claimed for the batch
     #[prost(message, repeated, tag = "1")]
     pub proofs: ::prost::alloc::vec::Vec<ProofRequest>,
     /// The identifier of the batch
     #[prost(bytes = "vec", tag = "2")]
     pub batch_id: ::prost::alloc::vec::Vec<u8>,
+    /// How to handle requests with the same leaf as an earlier request of the batch
+    #[prost(enumeration = "DuplicateLeafPolicy", tag = "3")]
+    pub duplicate_policy: i32,
+    /// The identifier of the worker holding the lease of the batch
+    #[prost(string, tag = "4")]
+    pub worker_id: ::prost::alloc::string::String,
 }
 #[derive(serde::Serialize, serde::Deserialize)]
 #[derive(Clone, PartialEq, ::prost::Message)]
@@ -102,37 +246,40 @@ pub struct ProcessBatchResponse {
     /// The leaves of the merkle tree generated from the batch
     #[prost(bytes = "vec", tag = "1")]
     pub leaves: ::prost::alloc::vec::Vec<u8>,
+    /// The proof ids of the proofs to aggregate, one per leaf and in the same order
+    #[prost(bytes = "vec", repeated, tag = "2")]
+    pub proof_ids: ::prost::alloc::vec::Vec<::prost::alloc::vec::Vec<u8>>,
+    /// The concatenated vkey hashes of the programs of the leaves, in the same order. Leaves are
+    /// grouped by program in ascending order of vkey hash
+    #[prost(bytes = "vec", tag = "3")]
+    pub vkey_hashes: ::prost::alloc::vec::Vec<u8>,
 }
 #[derive(serde::Serialize, serde::Deserialize)]
 #[derive(Clone, PartialEq, ::prost::Message)]
-pub struct WriteMerkleTreeRequest {
-    /// The merkle tree to write
+pub struct FailedProof {
+    /// The identifier of the proof
     #[prost(bytes = "vec", tag = "1")]
-    pub tree: ::prost::alloc::vec::Vec<u8>,
-    /// The identifier of the batch
-    #[prost(bytes = "vec", tag = "2")]
-    pub batch_id: ::prost::alloc::vec::Vec<u8>,
-}
-#[derive(serde::Serialize, serde::Deserialize)]
-#[derive(Clone, Copy, PartialEq, ::prost::Message)]
-pub struct WriteMerkleTreeResponse {
-    /// Indicates if the write was successful
-    #[prost(bool, tag = "1")]
-    pub success: bool,
+    pub proof_id: ::prost::alloc::vec::Vec<u8>,
+    /// Why the proof can't be aggregated
+    #[prost(string, tag = "2")]
+    pub reason: ::prost::alloc::string::String,
 }
 #[derive(serde::Serialize, serde::Deserialize)]
 #[derive(Clone, PartialEq, ::prost::Message)]
-pub struct UpdateBatchStatusRequest {
+pub struct MarkProofsFailedRequest {
+    /// The proofs to mark as failed, which must be pending or processing requests claimed for the batch
+    #[prost(message, repeated, tag = "1")]
+    pub proofs: ::prost::alloc::vec::Vec<FailedProof>,
     /// The identifier of the batch
-    #[prost(bytes = "vec", tag = "1")]
+    #[prost(bytes = "vec", tag = "2")]
     pub batch_id: ::prost::alloc::vec::Vec<u8>,
-    /// The status of the batch
-    #[prost(enumeration = "AggregationStatus", tag = "2")]
-    pub status: i32,
+    /// The identifier of the worker holding the lease of the batch
+    #[prost(string, tag = "3")]
+    pub worker_id: ::prost::alloc::string::String,
 }
 #[derive(serde::Serialize, serde::Deserialize)]
 #[derive(Clone, Copy, PartialEq, ::prost::Message)]
-pub struct UpdateBatchStatusResponse {
+pub struct MarkProofsFailedResponse {
     /// Indicates if the update was successful
     #[prost(bool, tag = "1")]
     pub success: bool,
@@ -146,16 +293,84 @@ pub struct VerifyAggregationProofRequest {
     /// The batch id of the proof
     #[prost(bytes = "vec", tag = "2")]
     pub batch_id: ::prost::alloc::vec::Vec<u8>,
+    /// The identifier of the worker holding the lease of the batch
+    #[prost(string, tag = "3")]
+    pub worker_id: ::prost::alloc::string::String,
 }
 #[derive(serde::Serialize, serde::Deserialize)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct VerifyAggregationProofResponse {
-    /// Indicates if the proof was verified
+    /// Indicates if the proof was verified and relayed
     #[prost(bool, tag = "1")]
     pub verified: bool,
     /// The tx hash of the proof
     #[prost(bytes = "vec", tag = "2")]
     pub tx_hash: ::prost::alloc::vec::Vec<u8>,
+    /// Why the proof was rejected without being relayed, if it was
+    #[prost(enumeration = "AggregationProofRejection", tag = "3")]
+    pub rejection: i32,
+    /// A description of the rejection
+    #[prost(string, tag = "4")]
+    pub rejection_reason: ::prost::alloc::string::String,
+}
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct GetAccumulatorRequest {}
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct GetAccumulatorResponse {
+    /// The number of batches in the accumulator
+    #[prost(uint64, tag = "1")]
+    pub leaf_count: u64,
+    /// The peaks of the accumulator, from the highest to the lowest
+    #[prost(bytes = "vec", repeated, tag = "2")]
+    pub peaks: ::prost::alloc::vec::Vec<::prost::alloc::vec::Vec<u8>>,
+    /// The root of the accumulator, which the verifier contract holds
+    #[prost(bytes = "vec", tag = "3")]
+    pub root: ::prost::alloc::vec::Vec<u8>,
+}
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct GetProofIdProofRequest {
+    /// The proof id to prove the state of
+    #[prost(bytes = "vec", tag = "1")]
+    pub proof_id: ::prost::alloc::vec::Vec<u8>,
+}
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct GetProofIdProofResponse {
+    /// The root of the sparse merkle tree of proof ids
+    #[prost(bytes = "vec", tag = "1")]
+    pub root: ::prost::alloc::vec::Vec<u8>,
+    /// The state of the proof id in the tree
+    #[prost(enumeration = "ProofIdState", tag = "2")]
+    pub state: i32,
+    /// The batch the proof id was aggregated in, empty if it was never aggregated
+    #[prost(bytes = "vec", tag = "3")]
+    pub batch_id: ::prost::alloc::vec::Vec<u8>,
+    /// Bit i, most significant first, is set if the sibling at depth i is not empty
+    #[prost(bytes = "vec", tag = "4")]
+    pub bitmap: ::prost::alloc::vec::Vec<u8>,
+    /// The siblings that are not empty, from the root down to the leaf
+    #[prost(bytes = "vec", repeated, tag = "5")]
+    pub siblings: ::prost::alloc::vec::Vec<::prost::alloc::vec::Vec<u8>>,
+    /// The hash function of the nodes of the tree: 0 for Keccak-256, 1 for SHA-256
+    #[prost(uint32, tag = "6")]
+    pub hash_function: u32,
+}
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RevokeProofRequest {
+    /// The proof id to revoke
+    #[prost(bytes = "vec", tag = "1")]
+    pub proof_id: ::prost::alloc::vec::Vec<u8>,
+}
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct RevokeProofResponse {
+    /// Indicates if the proof was revoked
+    #[prost(bool, tag = "1")]
+    pub success: bool,
 }
 #[derive(serde::Serialize, serde::Deserialize)]
 #[derive(sqlx::FromRow)]
@@ -179,6 +394,12 @@ pub struct ProofRequest {
     /// The unix timestamp of when the request was created
     #[prost(int64, tag = "6")]
     pub created_at: i64,
+    /// What the leaf of the proof commits to besides the vkey hash of its program
+    #[prost(enumeration = "LeafFormat", tag = "7")]
+    pub leaf_format: i32,
+    /// The identity of the submitter bound into the leaf, or empty if it is unbound
+    #[prost(bytes = "vec", tag = "8")]
+    pub submitter: ::prost::alloc::vec::Vec<u8>,
 }
 #[derive(serde::Serialize, serde::Deserialize)]
 #[derive(Clone, PartialEq, ::prost::Message)]
@@ -186,6 +407,66 @@ pub struct AggregateProofResponse {
     /// The identifier of the proof
     #[prost(bytes = "vec", tag = "1")]
     pub proof_id: ::prost::alloc::vec::Vec<u8>,
+    /// The aggregation status of the proof
+    #[prost(enumeration = "AggregationStatusResponse", tag = "2")]
+    pub status: i32,
+    /// Whether the request was submitted before, in which case nothing was created
+    #[prost(bool, tag = "3")]
+    pub existing: bool,
+}
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RegisterProgramRequest {
+    /// The vk of the program, serialized like the vk of an aggregation request
+    #[prost(bytes = "vec", tag = "1")]
+    pub vk: ::prost::alloc::vec::Vec<u8>,
+    /// The tenant owning the program, or empty if any tenant may submit its proofs
+    #[prost(string, tag = "2")]
+    pub tenant: ::prost::alloc::string::String,
+    /// The maximum size of the public values of its proofs in bytes, or 0 for no limit
+    #[prost(uint64, tag = "3")]
+    pub max_public_values_size: u64,
+    /// The fee charged for aggregating one of its proofs
+    #[prost(uint64, tag = "4")]
+    pub fee: u64,
+}
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RegisterProgramResponse {
+    /// The vkey hash of the program, which identifies it in the allowlist
+    #[prost(bytes = "vec", tag = "1")]
+    pub vkey_hash: ::prost::alloc::vec::Vec<u8>,
+}
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RetireProgramRequest {
+    /// The vkey hash of the program to retire
+    #[prost(bytes = "vec", tag = "1")]
+    pub vkey_hash: ::prost::alloc::vec::Vec<u8>,
+}
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct RetireProgramResponse {
+    /// Whether the program was registered and not already retired
+    #[prost(bool, tag = "1")]
+    pub retired: bool,
+}
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct GetProgramAllowlistRequest {}
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct GetProgramAllowlistResponse {
+    /// Whether aggregation is restricted to registered programs, which it is once a program has
+    /// been registered
+    #[prost(bool, tag = "1")]
+    pub enforced: bool,
+    /// The vkey hashes of the registered programs that have not been retired, sorted
+    #[prost(bytes = "vec", repeated, tag = "2")]
+    pub vkey_hashes: ::prost::alloc::vec::Vec<::prost::alloc::vec::Vec<u8>>,
+    /// The root of the allowlist, which the aggregation program commits
+    #[prost(bytes = "vec", tag = "3")]
+    pub root: ::prost::alloc::vec::Vec<u8>,
 }
 #[derive(serde::Serialize, serde::Deserialize)]
 #[derive(sqlx::Type)]
@@ -197,10 +478,13 @@ pub enum AggregationStatus {
     Pending = 1,
     /// The proof has been aggregated
     Aggregated = 2,
-    /// The proof has been verified
+    /// The proof has been verified. Only set by earlier versions of the service, after the batch
+    /// was aggregated
     Verified = 3,
     /// The proof verification failed
     Failed = 4,
+    /// The proof has a leaf in a batch whose aggregation proof has not been relayed yet
+    Processing = 5,
 }
 impl AggregationStatus {
     /// String value of the enum field names used in the ProtoBuf definition.
@@ -214,6 +498,7 @@ impl AggregationStatus {
             Self::Aggregated => "AGGREGATED",
             Self::Verified => "VERIFIED",
             Self::Failed => "FAILED",
+            Self::Processing => "PROCESSING",
         }
     }
     /// Creates an enum from field names used in the ProtoBuf definition.
@@ -224,6 +509,7 @@ impl AggregationStatus {
             "AGGREGATED" => Some(Self::Aggregated),
             "VERIFIED" => Some(Self::Verified),
             "FAILED" => Some(Self::Failed),
+            "PROCESSING" => Some(Self::Processing),
             _ => None,
         }
     }
@@ -241,6 +527,8 @@ pub enum AggregationStatusResponse {
     AggregationComplete = 3,
     /// Proof was found and aggregation was verified
     AggregationVerified = 4,
+    /// Proof was found but was not aggregated
+    AggregationFailed = 5,
 }
 impl AggregationStatusResponse {
     /// String value of the enum field names used in the ProtoBuf definition.
@@ -254,6 +542,7 @@ impl AggregationStatusResponse {
             Self::AggregationPending => "AGGREGATION_PENDING",
             Self::AggregationComplete => "AGGREGATION_COMPLETE",
             Self::AggregationVerified => "AGGREGATION_VERIFIED",
+            Self::AggregationFailed => "AGGREGATION_FAILED",
         }
     }
     /// Creates an enum from field names used in the ProtoBuf definition.
@@ -264,6 +553,225 @@ impl AggregationStatusResponse {
             "AGGREGATION_PENDING" => Some(Self::AggregationPending),
             "AGGREGATION_COMPLETE" => Some(Self::AggregationComplete),
             "AGGREGATION_VERIFIED" => Some(Self::AggregationVerified),
+            "AGGREGATION_FAILED" => Some(Self::AggregationFailed),
+            _ => None,
+        }
+    }
+}
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum DuplicateLeafPolicy {
+    /// Every request gets its own leaf, even if an earlier request has the same leaf
+    KeepDuplicates = 0,
+    /// Requests with the same leaf as an earlier request share its leaf
+    Deduplicate = 1,
+    /// Requests with the same leaf as an earlier request are marked as failed
+    RejectDuplicates = 2,
+}
+impl DuplicateLeafPolicy {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            Self::KeepDuplicates => "KEEP_DUPLICATES",
+            Self::Deduplicate => "DEDUPLICATE",
+            Self::RejectDuplicates => "REJECT_DUPLICATES",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "KEEP_DUPLICATES" => Some(Self::KeepDuplicates),
+            "DEDUPLICATE" => Some(Self::Deduplicate),
+            "REJECT_DUPLICATES" => Some(Self::RejectDuplicates),
+            _ => None,
+        }
+    }
+}
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum LeafFormat {
+    /// The leaf commits to the public values, sha256(vkey_hash || public_values)
+    Full = 0,
+    /// The leaf commits to the digest of the public values,
+    /// sha256(DIGEST_LEAF_TAG || vkey_hash || sha256(public_values))
+    Digest = 1,
+}
+impl LeafFormat {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            Self::Full => "LEAF_FORMAT_FULL",
+            Self::Digest => "LEAF_FORMAT_DIGEST",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "LEAF_FORMAT_FULL" => Some(Self::Full),
+            "LEAF_FORMAT_DIGEST" => Some(Self::Digest),
+            _ => None,
+        }
+    }
+}
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum ProofIdState {
+    /// The proof id was never aggregated, so it has no value in the tree
+    Absent = 0,
+    /// The proof id was aggregated in a batch
+    Aggregated = 1,
+    /// The proof id was aggregated in a batch and revoked afterwards
+    Revoked = 2,
+}
+impl ProofIdState {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            Self::Absent => "PROOF_ID_STATE_ABSENT",
+            Self::Aggregated => "PROOF_ID_STATE_AGGREGATED",
+            Self::Revoked => "PROOF_ID_STATE_REVOKED",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "PROOF_ID_STATE_ABSENT" => Some(Self::Absent),
+            "PROOF_ID_STATE_AGGREGATED" => Some(Self::Aggregated),
+            "PROOF_ID_STATE_REVOKED" => Some(Self::Revoked),
+            _ => None,
+        }
+    }
+}
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum SubmissionRejection {
+    /// The request was not rejected
+    None = 0,
+    /// The leaf format is unknown
+    UnknownLeafFormat = 1,
+    /// The submitter identity is too large
+    SubmitterTooLarge = 2,
+    /// The proof or the vk is too large
+    TooLarge = 3,
+    /// The proof is not a bincode-encoded SP1ProofWithPublicValues
+    InvalidProofEncoding = 4,
+    /// The vk is not a bincode-encoded SP1VerifyingKey
+    InvalidVkEncoding = 5,
+    /// The proof is not a compressed proof, which the aggregation program verifies
+    UnsupportedProofType = 6,
+    /// The public values are larger than the program allows
+    PublicValuesTooLarge = 7,
+    /// The proof does not verify with the vk
+    InvalidProof = 8,
+    /// The idempotency key is too large
+    IdempotencyKeyTooLarge = 9,
+}
+impl SubmissionRejection {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            Self::None => "SUBMISSION_REJECTION_NONE",
+            Self::UnknownLeafFormat => "SUBMISSION_REJECTION_UNKNOWN_LEAF_FORMAT",
+            Self::SubmitterTooLarge => "SUBMISSION_REJECTION_SUBMITTER_TOO_LARGE",
+            Self::TooLarge => "SUBMISSION_REJECTION_TOO_LARGE",
+            Self::InvalidProofEncoding => "SUBMISSION_REJECTION_INVALID_PROOF_ENCODING",
+            Self::InvalidVkEncoding => "SUBMISSION_REJECTION_INVALID_VK_ENCODING",
+            Self::UnsupportedProofType => "SUBMISSION_REJECTION_UNSUPPORTED_PROOF_TYPE",
+            Self::PublicValuesTooLarge => "SUBMISSION_REJECTION_PUBLIC_VALUES_TOO_LARGE",
+            Self::InvalidProof => "SUBMISSION_REJECTION_INVALID_PROOF",
+            Self::IdempotencyKeyTooLarge => {
+                "SUBMISSION_REJECTION_IDEMPOTENCY_KEY_TOO_LARGE"
+            }
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "SUBMISSION_REJECTION_NONE" => Some(Self::None),
+            "SUBMISSION_REJECTION_UNKNOWN_LEAF_FORMAT" => Some(Self::UnknownLeafFormat),
+            "SUBMISSION_REJECTION_SUBMITTER_TOO_LARGE" => Some(Self::SubmitterTooLarge),
+            "SUBMISSION_REJECTION_TOO_LARGE" => Some(Self::TooLarge),
+            "SUBMISSION_REJECTION_INVALID_PROOF_ENCODING" => {
+                Some(Self::InvalidProofEncoding)
+            }
+            "SUBMISSION_REJECTION_INVALID_VK_ENCODING" => Some(Self::InvalidVkEncoding),
+            "SUBMISSION_REJECTION_UNSUPPORTED_PROOF_TYPE" => {
+                Some(Self::UnsupportedProofType)
+            }
+            "SUBMISSION_REJECTION_PUBLIC_VALUES_TOO_LARGE" => {
+                Some(Self::PublicValuesTooLarge)
+            }
+            "SUBMISSION_REJECTION_INVALID_PROOF" => Some(Self::InvalidProof),
+            "SUBMISSION_REJECTION_IDEMPOTENCY_KEY_TOO_LARGE" => {
+                Some(Self::IdempotencyKeyTooLarge)
+            }
+            _ => None,
+        }
+    }
+}
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum AggregationProofRejection {
+    /// The proof was not rejected
+    None = 0,
+    /// The proof or its public values can't be decoded
+    InvalidEncoding = 1,
+    /// The proof is not a Plonk or Groth16 proof, which the verifier contract requires
+    UnsupportedProofType = 2,
+    /// The public values are for another batch, or commit to another root than its tree
+    WrongBatch = 3,
+    /// The public values don't append the batch to the accumulator the contract holds
+    StaleAccumulator = 4,
+    /// The proof does not verify with the vkey of the aggregation program
+    InvalidProof = 5,
+}
+impl AggregationProofRejection {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            Self::None => "AGGREGATION_PROOF_REJECTION_NONE",
+            Self::InvalidEncoding => "AGGREGATION_PROOF_REJECTION_INVALID_ENCODING",
+            Self::UnsupportedProofType => {
+                "AGGREGATION_PROOF_REJECTION_UNSUPPORTED_PROOF_TYPE"
+            }
+            Self::WrongBatch => "AGGREGATION_PROOF_REJECTION_WRONG_BATCH",
+            Self::StaleAccumulator => "AGGREGATION_PROOF_REJECTION_STALE_ACCUMULATOR",
+            Self::InvalidProof => "AGGREGATION_PROOF_REJECTION_INVALID_PROOF",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "AGGREGATION_PROOF_REJECTION_NONE" => Some(Self::None),
+            "AGGREGATION_PROOF_REJECTION_INVALID_ENCODING" => Some(Self::InvalidEncoding),
+            "AGGREGATION_PROOF_REJECTION_UNSUPPORTED_PROOF_TYPE" => {
+                Some(Self::UnsupportedProofType)
+            }
+            "AGGREGATION_PROOF_REJECTION_WRONG_BATCH" => Some(Self::WrongBatch),
+            "AGGREGATION_PROOF_REJECTION_STALE_ACCUMULATOR" => {
+                Some(Self::StaleAccumulator)
+            }
+            "AGGREGATION_PROOF_REJECTION_INVALID_PROOF" => Some(Self::InvalidProof),
             _ => None,
         }
     }
@@ -359,7 +867,8 @@ pub mod aggregation_service_client {
             self.inner = self.inner.max_encoding_message_size(limit);
             self
         }
-        /// Get the merkle proof for a given proof
+        /// Get the merkle proof for a given proof, which fails with FAILED_PRECONDITION until its batch
+        /// has been relayed
         pub async fn get_aggregated_data(
             &mut self,
             request: impl tonic::IntoRequest<super::GetAggregatedDataRequest>,
@@ -389,6 +898,36 @@ pub mod aggregation_service_client {
                 );
             self.inner.unary(req, path, codec).await
         }
+        /// Get a single merkle multiproof for several proofs aggregated in the same relayed batch
+        pub async fn get_aggregated_multiproof(
+            &mut self,
+            request: impl tonic::IntoRequest<super::GetAggregatedMultiproofRequest>,
+        ) -> std::result::Result<
+            tonic::Response<super::GetAggregatedMultiproofResponse>,
+            tonic::Status,
+        > {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::unknown(
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/aggregation.AggregationService/GetAggregatedMultiproof",
+            );
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(
+                    GrpcMethod::new(
+                        "aggregation.AggregationService",
+                        "GetAggregatedMultiproof",
+                    ),
+                );
+            self.inner.unary(req, path, codec).await
+        }
         /// Get the aggregation status of a given proof
         pub async fn get_aggregation_status(
             &mut self,
@@ -419,7 +958,8 @@ pub mod aggregation_service_client {
                 );
             self.inner.unary(req, path, codec).await
         }
-        /// Creates an aggregation request
+        /// Creates an aggregation request, or returns the request it retries. Invalid requests fail
+        /// with INVALID_ARGUMENT and their SubmissionRejection in the `rejection-reason` metadata
         pub async fn aggregate_proof(
             &mut self,
             request: impl tonic::IntoRequest<super::AggregateProofRequest>,
@@ -446,12 +986,13 @@ pub mod aggregation_service_client {
                 );
             self.inner.unary(req, path, codec).await
         }
-        /// Get a batch of proofs that have not yet been aggregated
-        pub async fn get_batch(
+        /// Claim a batch of proofs that have not yet been aggregated, leased to one worker until it is
+        /// completed or its lease expires
+        pub async fn claim_batch(
             &mut self,
-            request: impl tonic::IntoRequest<super::GetBatchRequest>,
+            request: impl tonic::IntoRequest<super::ClaimBatchRequest>,
         ) -> std::result::Result<
-            tonic::Response<super::GetBatchResponse>,
+            tonic::Response<super::ClaimBatchResponse>,
             tonic::Status,
         > {
             self.inner
@@ -464,19 +1005,19 @@ pub mod aggregation_service_client {
                 })?;
             let codec = tonic::codec::ProstCodec::default();
             let path = http::uri::PathAndQuery::from_static(
-                "/aggregation.AggregationService/GetBatch",
+                "/aggregation.AggregationService/ClaimBatch",
             );
             let mut req = request.into_request();
             req.extensions_mut()
-                .insert(GrpcMethod::new("aggregation.AggregationService", "GetBatch"));
+                .insert(GrpcMethod::new("aggregation.AggregationService", "ClaimBatch"));
             self.inner.unary(req, path, codec).await
         }
-        /// Get the corresponding leaf for a given proof
-        pub async fn get_vkey_and_public_values(
+        /// Complete the lease of a claimed batch, once it has been aggregated or given up on
+        pub async fn complete_batch(
             &mut self,
-            request: impl tonic::IntoRequest<super::GetVkeyAndPublicValuesRequest>,
+            request: impl tonic::IntoRequest<super::CompleteBatchRequest>,
         ) -> std::result::Result<
-            tonic::Response<super::GetVkeyAndPublicValuesResponse>,
+            tonic::Response<super::CompleteBatchResponse>,
             tonic::Status,
         > {
             self.inner
@@ -489,24 +1030,21 @@ pub mod aggregation_service_client {
                 })?;
             let codec = tonic::codec::ProstCodec::default();
             let path = http::uri::PathAndQuery::from_static(
-                "/aggregation.AggregationService/GetVkeyAndPublicValues",
+                "/aggregation.AggregationService/CompleteBatch",
             );
             let mut req = request.into_request();
             req.extensions_mut()
                 .insert(
-                    GrpcMethod::new(
-                        "aggregation.AggregationService",
-                        "GetVkeyAndPublicValues",
-                    ),
+                    GrpcMethod::new("aggregation.AggregationService", "CompleteBatch"),
                 );
             self.inner.unary(req, path, codec).await
         }
-        /// Write a merkle tree to the database
-        pub async fn write_merkle_tree(
+        /// Get the corresponding leaf for a given proof
+        pub async fn get_vkey_and_public_values(
             &mut self,
-            request: impl tonic::IntoRequest<super::WriteMerkleTreeRequest>,
+            request: impl tonic::IntoRequest<super::GetVkeyAndPublicValuesRequest>,
         ) -> std::result::Result<
-            tonic::Response<super::WriteMerkleTreeResponse>,
+            tonic::Response<super::GetVkeyAndPublicValuesResponse>,
             tonic::Status,
         > {
             self.inner
@@ -519,16 +1057,19 @@ pub mod aggregation_service_client {
                 })?;
             let codec = tonic::codec::ProstCodec::default();
             let path = http::uri::PathAndQuery::from_static(
-                "/aggregation.AggregationService/WriteMerkleTree",
+                "/aggregation.AggregationService/GetVkeyAndPublicValues",
             );
             let mut req = request.into_request();
             req.extensions_mut()
                 .insert(
-                    GrpcMethod::new("aggregation.AggregationService", "WriteMerkleTree"),
+                    GrpcMethod::new(
+                        "aggregation.AggregationService",
+                        "GetVkeyAndPublicValues",
+                    ),
                 );
             self.inner.unary(req, path, codec).await
         }
-        /// Process a batch of proofs
+        /// Process a batch of proofs, and build and store the merkle tree of the batch
         pub async fn process_batch(
             &mut self,
             request: impl tonic::IntoRequest<super::ProcessBatchRequest>,
@@ -555,12 +1096,13 @@ pub mod aggregation_service_client {
                 );
             self.inner.unary(req, path, codec).await
         }
-        /// Update status of batch of proofs
-        pub async fn update_batch_status(
+        /// Mark proofs of a batch that can't be aggregated as failed, so that batches are aggregated
+        /// without them, which only the worker holding its lease can do
+        pub async fn mark_proofs_failed(
             &mut self,
-            request: impl tonic::IntoRequest<super::UpdateBatchStatusRequest>,
+            request: impl tonic::IntoRequest<super::MarkProofsFailedRequest>,
         ) -> std::result::Result<
-            tonic::Response<super::UpdateBatchStatusResponse>,
+            tonic::Response<super::MarkProofsFailedResponse>,
             tonic::Status,
         > {
             self.inner
@@ -573,19 +1115,17 @@ pub mod aggregation_service_client {
                 })?;
             let codec = tonic::codec::ProstCodec::default();
             let path = http::uri::PathAndQuery::from_static(
-                "/aggregation.AggregationService/UpdateBatchStatus",
+                "/aggregation.AggregationService/MarkProofsFailed",
             );
             let mut req = request.into_request();
             req.extensions_mut()
                 .insert(
-                    GrpcMethod::new(
-                        "aggregation.AggregationService",
-                        "UpdateBatchStatus",
-                    ),
+                    GrpcMethod::new("aggregation.AggregationService", "MarkProofsFailed"),
                 );
             self.inner.unary(req, path, codec).await
         }
-        /// Verify aggregation proof and update requests with tx hash, contract address, and chain id
+        /// Verify aggregation proof off-chain, relay it, and update requests with tx hash, contract
+        /// address, and chain id
         pub async fn verify_aggregation_proof(
             &mut self,
             request: impl tonic::IntoRequest<super::VerifyAggregationProofRequest>,
@@ -615,8 +1155,173 @@ pub mod aggregation_service_client {
                 );
             self.inner.unary(req, path, codec).await
         }
-    }
-}
+        /// Get the accumulator of the batches relayed so far, which the next batch extends
+        pub async fn get_accumulator(
+            &mut self,
+            request: impl tonic::IntoRequest<super::GetAccumulatorRequest>,
+        ) -> std::result::Result<
+            tonic::Response<super::GetAccumulatorResponse>,
+            tonic::Status,
+        > {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::unknown(
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/aggregation.AggregationService/GetAccumulator",
+            );
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(
+                    GrpcMethod::new("aggregation.AggregationService", "GetAccumulator"),
+                );
+            self.inner.unary(req, path, codec).await
+        }
+        /// Get a proof that a proof id was aggregated, revoked, or never aggregated
+        pub async fn get_proof_id_proof(
+            &mut self,
+            request: impl tonic::IntoRequest<super::GetProofIdProofRequest>,
+        ) -> std::result::Result<
+            tonic::Response<super::GetProofIdProofResponse>,
+            tonic::Status,
+        > {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::unknown(
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/aggregation.AggregationService/GetProofIdProof",
+            );
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(
+                    GrpcMethod::new("aggregation.AggregationService", "GetProofIdProof"),
+                );
+            self.inner.unary(req, path, codec).await
+        }
+        /// Revoke an aggregated proof (admin only)
+        pub async fn revoke_proof(
+            &mut self,
+            request: impl tonic::IntoRequest<super::RevokeProofRequest>,
+        ) -> std::result::Result<
+            tonic::Response<super::RevokeProofResponse>,
+            tonic::Status,
+        > {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::unknown(
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/aggregation.AggregationService/RevokeProof",
+            );
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(
+                    GrpcMethod::new("aggregation.AggregationService", "RevokeProof"),
+                );
+            self.inner.unary(req, path, codec).await
+        }
+        /// Register a program whose proofs may be aggregated, or update its policy (admin only)
+        pub async fn register_program(
+            &mut self,
+            request: impl tonic::IntoRequest<super::RegisterProgramRequest>,
+        ) -> std::result::Result<
+            tonic::Response<super::RegisterProgramResponse>,
+            tonic::Status,
+        > {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::unknown(
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/aggregation.AggregationService/RegisterProgram",
+            );
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(
+                    GrpcMethod::new("aggregation.AggregationService", "RegisterProgram"),
+                );
+            self.inner.unary(req, path, codec).await
+        }
+        /// Retire a registered program, so that its proofs are no longer accepted (admin only)
+        pub async fn retire_program(
+            &mut self,
+            request: impl tonic::IntoRequest<super::RetireProgramRequest>,
+        ) -> std::result::Result<
+            tonic::Response<super::RetireProgramResponse>,
+            tonic::Status,
+        > {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::unknown(
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/aggregation.AggregationService/RetireProgram",
+            );
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(
+                    GrpcMethod::new("aggregation.AggregationService", "RetireProgram"),
+                );
+            self.inner.unary(req, path, codec).await
+        }
+        /// Get the allowlist of the programs whose proofs may be aggregated
+        pub async fn get_program_allowlist(
+            &mut self,
+            request: impl tonic::IntoRequest<super::GetProgramAllowlistRequest>,
+        ) -> std::result::Result<
+            tonic::Response<super::GetProgramAllowlistResponse>,
+            tonic::Status,
+        > {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::unknown(
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/aggregation.AggregationService/GetProgramAllowlist",
+            );
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(
+                    GrpcMethod::new(
+                        "aggregation.AggregationService",
+                        "GetProgramAllowlist",
+                    ),
+                );
+            self.inner.unary(req, path, codec).await
+        }
+    }
+}
 /// Generated server implementations.
 pub mod aggregation_service_server {
     #![allow(
@@ -630,7 +1335,8 @@ pub mod aggregation_service_server {
     /// Generated trait containing gRPC methods that should be implemented for use with AggregationServiceServer.
     #[async_trait]
     pub trait AggregationService: std::marker::Send + std::marker::Sync + 'static {
-        /// Get the merkle proof for a given proof
+        /// Get the merkle proof for a given proof, which fails with FAILED_PRECONDITION until its batch
+        /// has been relayed
         async fn get_aggregated_data(
             &self,
             request: tonic::Request<super::GetAggregatedDataRequest>,
@@ -638,6 +1344,14 @@ pub mod aggregation_service_server {
             tonic::Response<super::GetAggregatedDataResponse>,
             tonic::Status,
         >;
+        /// Get a single merkle multiproof for several proofs aggregated in the same relayed batch
+        async fn get_aggregated_multiproof(
+            &self,
+            request: tonic::Request<super::GetAggregatedMultiproofRequest>,
+        ) -> std::result::Result<
+            tonic::Response<super::GetAggregatedMultiproofResponse>,
+            tonic::Status,
+        >;
         /// Get the aggregation status of a given proof
         async fn get_aggregation_status(
             &self,
@@ -646,7 +1360,8 @@ pub mod aggregation_service_server {
             tonic::Response<super::GetAggregationStatusResponse>,
             tonic::Status,
         >;
-        /// Creates an aggregation request
+        /// Creates an aggregation request, or returns the request it retries. Invalid requests fail
+        /// with INVALID_ARGUMENT and their SubmissionRejection in the `rejection-reason` metadata
         async fn aggregate_proof(
             &self,
             request: tonic::Request<super::AggregateProofRequest>,
@@ -654,31 +1369,32 @@ pub mod aggregation_service_server {
             tonic::Response<super::AggregateProofResponse>,
             tonic::Status,
         >;
-        /// Get a batch of proofs that have not yet been aggregated
-        async fn get_batch(
+        /// Claim a batch of proofs that have not yet been aggregated, leased to one worker until it is
+        /// completed or its lease expires
+        async fn claim_batch(
             &self,
-            request: tonic::Request<super::GetBatchRequest>,
+            request: tonic::Request<super::ClaimBatchRequest>,
         ) -> std::result::Result<
-            tonic::Response<super::GetBatchResponse>,
+            tonic::Response<super::ClaimBatchResponse>,
             tonic::Status,
         >;
-        /// Get the corresponding leaf for a given proof
-        async fn get_vkey_and_public_values(
+        /// Complete the lease of a claimed batch, once it has been aggregated or given up on
+        async fn complete_batch(
             &self,
-            request: tonic::Request<super::GetVkeyAndPublicValuesRequest>,
+            request: tonic::Request<super::CompleteBatchRequest>,
         ) -> std::result::Result<
-            tonic::Response<super::GetVkeyAndPublicValuesResponse>,
+            tonic::Response<super::CompleteBatchResponse>,
             tonic::Status,
         >;
-        /// Write a merkle tree to the database
-        async fn write_merkle_tree(
+        /// Get the corresponding leaf for a given proof
+        async fn get_vkey_and_public_values(
             &self,
-            request: tonic::Request<super::WriteMerkleTreeRequest>,
+            request: tonic::Request<super::GetVkeyAndPublicValuesRequest>,
         ) -> std::result::Result<
-            tonic::Response<super::WriteMerkleTreeResponse>,
+            tonic::Response<super::GetVkeyAndPublicValuesResponse>,
             tonic::Status,
         >;
-        /// Process a batch of proofs
+        /// Process a batch of proofs, and build and store the merkle tree of the batch
         async fn process_batch(
             &self,
             request: tonic::Request<super::ProcessBatchRequest>,
@@ -686,15 +1402,17 @@ pub mod aggregation_service_server {
             tonic::Response<super::ProcessBatchResponse>,
             tonic::Status,
         >;
-        /// Update status of batch of proofs
-        async fn update_batch_status(
+        /// Mark proofs of a batch that can't be aggregated as failed, so that batches are aggregated
+        /// without them, which only the worker holding its lease can do
+        async fn mark_proofs_failed(
             &self,
-            request: tonic::Request<super::UpdateBatchStatusRequest>,
+            request: tonic::Request<super::MarkProofsFailedRequest>,
         ) -> std::result::Result<
-            tonic::Response<super::UpdateBatchStatusResponse>,
+            tonic::Response<super::MarkProofsFailedResponse>,
             tonic::Status,
         >;
-        /// Verify aggregation proof and update requests with tx hash, contract address, and chain id
+        /// Verify aggregation proof off-chain, relay it, and update requests with tx hash, contract
+        /// address, and chain id
         async fn verify_aggregation_proof(
             &self,
             request: tonic::Request<super::VerifyAggregationProofRequest>,
@@ -702,6 +1420,54 @@ pub mod aggregation_service_server {
             tonic::Response<super::VerifyAggregationProofResponse>,
             tonic::Status,
         >;
+        /// Get the accumulator of the batches relayed so far, which the next batch extends
+        async fn get_accumulator(
+            &self,
+            request: tonic::Request<super::GetAccumulatorRequest>,
+        ) -> std::result::Result<
+            tonic::Response<super::GetAccumulatorResponse>,
+            tonic::Status,
+        >;
+        /// Get a proof that a proof id was aggregated, revoked, or never aggregated
+        async fn get_proof_id_proof(
+            &self,
+            request: tonic::Request<super::GetProofIdProofRequest>,
+        ) -> std::result::Result<
+            tonic::Response<super::GetProofIdProofResponse>,
+            tonic::Status,
+        >;
+        /// Revoke an aggregated proof (admin only)
+        async fn revoke_proof(
+            &self,
+            request: tonic::Request<super::RevokeProofRequest>,
+        ) -> std::result::Result<
+            tonic::Response<super::RevokeProofResponse>,
+            tonic::Status,
+        >;
+        /// Register a program whose proofs may be aggregated, or update its policy (admin only)
+        async fn register_program(
+            &self,
+            request: tonic::Request<super::RegisterProgramRequest>,
+        ) -> std::result::Result<
+            tonic::Response<super::RegisterProgramResponse>,
+            tonic::Status,
+        >;
+        /// Retire a registered program, so that its proofs are no longer accepted (admin only)
+        async fn retire_program(
+            &self,
+            request: tonic::Request<super::RetireProgramRequest>,
+        ) -> std::result::Result<
+            tonic::Response<super::RetireProgramResponse>,
+            tonic::Status,
+        >;
+        /// Get the allowlist of the programs whose proofs may be aggregated
+        async fn get_program_allowlist(
+            &self,
+            request: tonic::Request<super::GetProgramAllowlistRequest>,
+        ) -> std::result::Result<
+            tonic::Response<super::GetProgramAllowlistResponse>,
+            tonic::Status,
+        >;
     }
     #[derive(Debug)]
     pub struct AggregationServiceServer<T> {
@@ -828,6 +1594,57 @@ pub mod aggregation_service_server {
                     };
                     Box::pin(fut)
                 }
+                "/aggregation.AggregationService/GetAggregatedMultiproof" => {
+                    #[allow(non_camel_case_types)]
+                    struct GetAggregatedMultiproofSvc<T: AggregationService>(pub Arc<T>);
+                    impl<
+                        T: AggregationService,
+                    > tonic::server::UnaryService<super::GetAggregatedMultiproofRequest>
+                    for GetAggregatedMultiproofSvc<T> {
+                        type Response = super::GetAggregatedMultiproofResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<
+                                super::GetAggregatedMultiproofRequest,
+                            >,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as AggregationService>::get_aggregated_multiproof(
+                                        &inner,
+                                        request,
+                                    )
+                                    .await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let method = GetAggregatedMultiproofSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
                 "/aggregation.AggregationService/GetAggregationStatus" => {
                     #[allow(non_camel_case_types)]
                     struct GetAggregationStatusSvc<T: AggregationService>(pub Arc<T>);
@@ -923,25 +1740,26 @@ pub mod aggregation_service_server {
                     };
                     Box::pin(fut)
                 }
-                "/aggregation.AggregationService/GetBatch" => {
+                "/aggregation.AggregationService/ClaimBatch" => {
                     #[allow(non_camel_case_types)]
-                    struct GetBatchSvc<T: AggregationService>(pub Arc<T>);
+                    struct ClaimBatchSvc<T: AggregationService>(pub Arc<T>);
                     impl<
                         T: AggregationService,
-                    > tonic::server::UnaryService<super::GetBatchRequest>
-                    for GetBatchSvc<T> {
-                        type Response = super::GetBatchResponse;
+                    > tonic::server::UnaryService<super::ClaimBatchRequest>
+                    for ClaimBatchSvc<T> {
+                        type Response = super::ClaimBatchResponse;
                         type Future = BoxFuture<
                             tonic::Response<Self::Response>,
                             tonic::Status,
                         >;
                         fn call(
                             &mut self,
-                            request: tonic::Request<super::GetBatchRequest>,
+                            request: tonic::Request<super::ClaimBatchRequest>,
                         ) -> Self::Future {
                             let inner = Arc::clone(&self.0);
                             let fut = async move {
-                                <T as AggregationService>::get_batch(&inner, request).await
+                                <T as AggregationService>::claim_batch(&inner, request)
+                                    .await
                             };
                             Box::pin(fut)
                         }
@@ -952,7 +1770,7 @@ pub mod aggregation_service_server {
                     let max_encoding_message_size = self.max_encoding_message_size;
                     let inner = self.inner.clone();
                     let fut = async move {
-                        let method = GetBatchSvc(inner);
+                        let method = ClaimBatchSvc(inner);
                         let codec = tonic::codec::ProstCodec::default();
                         let mut grpc = tonic::server::Grpc::new(codec)
                             .apply_compression_config(
@@ -968,28 +1786,25 @@ pub mod aggregation_service_server {
                     };
                     Box::pin(fut)
                 }
-                "/aggregation.AggregationService/GetVkeyAndPublicValues" => {
+                "/aggregation.AggregationService/CompleteBatch" => {
                     #[allow(non_camel_case_types)]
-                    struct GetVkeyAndPublicValuesSvc<T: AggregationService>(pub Arc<T>);
+                    struct CompleteBatchSvc<T: AggregationService>(pub Arc<T>);
                     impl<
                         T: AggregationService,
-                    > tonic::server::UnaryService<super::GetVkeyAndPublicValuesRequest>
-                    for GetVkeyAndPublicValuesSvc<T> {
-                        type Response = super::GetVkeyAndPublicValuesResponse;
+                    > tonic::server::UnaryService<super::CompleteBatchRequest>
+                    for CompleteBatchSvc<T> {
+                        type Response = super::CompleteBatchResponse;
                         type Future = BoxFuture<
                             tonic::Response<Self::Response>,
                             tonic::Status,
                         >;
                         fn call(
                             &mut self,
-                            request: tonic::Request<super::GetVkeyAndPublicValuesRequest>,
+                            request: tonic::Request<super::CompleteBatchRequest>,
                         ) -> Self::Future {
                             let inner = Arc::clone(&self.0);
                             let fut = async move {
-                                <T as AggregationService>::get_vkey_and_public_values(
-                                        &inner,
-                                        request,
-                                    )
+                                <T as AggregationService>::complete_batch(&inner, request)
                                     .await
                             };
                             Box::pin(fut)
@@ -1001,7 +1816,7 @@ pub mod aggregation_service_server {
                     let max_encoding_message_size = self.max_encoding_message_size;
                     let inner = self.inner.clone();
                     let fut = async move {
-                        let method = GetVkeyAndPublicValuesSvc(inner);
+                        let method = CompleteBatchSvc(inner);
                         let codec = tonic::codec::ProstCodec::default();
                         let mut grpc = tonic::server::Grpc::new(codec)
                             .apply_compression_config(
@@ -1017,25 +1832,25 @@ pub mod aggregation_service_server {
                     };
                     Box::pin(fut)
                 }
-                "/aggregation.AggregationService/WriteMerkleTree" => {
+                "/aggregation.AggregationService/GetVkeyAndPublicValues" => {
                     #[allow(non_camel_case_types)]
-                    struct WriteMerkleTreeSvc<T: AggregationService>(pub Arc<T>);
+                    struct GetVkeyAndPublicValuesSvc<T: AggregationService>(pub Arc<T>);
                     impl<
                         T: AggregationService,
-                    > tonic::server::UnaryService<super::WriteMerkleTreeRequest>
-                    for WriteMerkleTreeSvc<T> {
-                        type Response = super::WriteMerkleTreeResponse;
+                    > tonic::server::UnaryService<super::GetVkeyAndPublicValuesRequest>
+                    for GetVkeyAndPublicValuesSvc<T> {
+                        type Response = super::GetVkeyAndPublicValuesResponse;
                         type Future = BoxFuture<
                             tonic::Response<Self::Response>,
                             tonic::Status,
                         >;
                         fn call(
                             &mut self,
-                            request: tonic::Request<super::WriteMerkleTreeRequest>,
+                            request: tonic::Request<super::GetVkeyAndPublicValuesRequest>,
                         ) -> Self::Future {
                             let inner = Arc::clone(&self.0);
                             let fut = async move {
-                                <T as AggregationService>::write_merkle_tree(
+                                <T as AggregationService>::get_vkey_and_public_values(
                                         &inner,
                                         request,
                                     )
@@ -1050,7 +1865,7 @@ pub mod aggregation_service_server {
                     let max_encoding_message_size = self.max_encoding_message_size;
                     let inner = self.inner.clone();
                     let fut = async move {
-                        let method = WriteMerkleTreeSvc(inner);
+                        let method = GetVkeyAndPublicValuesSvc(inner);
                         let codec = tonic::codec::ProstCodec::default();
                         let mut grpc = tonic::server::Grpc::new(codec)
                             .apply_compression_config(
@@ -1112,25 +1927,25 @@ pub mod aggregation_service_server {
                     };
                     Box::pin(fut)
                 }
-                "/aggregation.AggregationService/UpdateBatchStatus" => {
+                "/aggregation.AggregationService/MarkProofsFailed" => {
                     #[allow(non_camel_case_types)]
-                    struct UpdateBatchStatusSvc<T: AggregationService>(pub Arc<T>);
+                    struct MarkProofsFailedSvc<T: AggregationService>(pub Arc<T>);
                     impl<
                         T: AggregationService,
-                    > tonic::server::UnaryService<super::UpdateBatchStatusRequest>
-                    for UpdateBatchStatusSvc<T> {
-                        type Response = super::UpdateBatchStatusResponse;
+                    > tonic::server::UnaryService<super::MarkProofsFailedRequest>
+                    for MarkProofsFailedSvc<T> {
+                        type Response = super::MarkProofsFailedResponse;
                         type Future = BoxFuture<
                             tonic::Response<Self::Response>,
                             tonic::Status,
                         >;
                         fn call(
                             &mut self,
-                            request: tonic::Request<super::UpdateBatchStatusRequest>,
+                            request: tonic::Request<super::MarkProofsFailedRequest>,
                         ) -> Self::Future {
                             let inner = Arc::clone(&self.0);
                             let fut = async move {
-                                <T as AggregationService>::update_batch_status(
+                                <T as AggregationService>::mark_proofs_failed(
                                         &inner,
                                         request,
                                     )
@@ -1145,7 +1960,7 @@ pub mod aggregation_service_server {
                     let max_encoding_message_size = self.max_encoding_message_size;
                     let inner = self.inner.clone();
                     let fut = async move {
-                        let method = UpdateBatchStatusSvc(inner);
+                        let method = MarkProofsFailedSvc(inner);
                         let codec = tonic::codec::ProstCodec::default();
                         let mut grpc = tonic::server::Grpc::new(codec)
                             .apply_compression_config(
@@ -1210,6 +2025,288 @@ pub mod aggregation_service_server {
                     };
                     Box::pin(fut)
                 }
+                "/aggregation.AggregationService/GetAccumulator" => {
+                    #[allow(non_camel_case_types)]
+                    struct GetAccumulatorSvc<T: AggregationService>(pub Arc<T>);
+                    impl<
+                        T: AggregationService,
+                    > tonic::server::UnaryService<super::GetAccumulatorRequest>
+                    for GetAccumulatorSvc<T> {
+                        type Response = super::GetAccumulatorResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::GetAccumulatorRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as AggregationService>::get_accumulator(&inner, request)
+                                    .await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let method = GetAccumulatorSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/aggregation.AggregationService/GetProofIdProof" => {
+                    #[allow(non_camel_case_types)]
+                    struct GetProofIdProofSvc<T: AggregationService>(pub Arc<T>);
+                    impl<
+                        T: AggregationService,
+                    > tonic::server::UnaryService<super::GetProofIdProofRequest>
+                    for GetProofIdProofSvc<T> {
+                        type Response = super::GetProofIdProofResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::GetProofIdProofRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as AggregationService>::get_proof_id_proof(
+                                        &inner,
+                                        request,
+                                    )
+                                    .await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let method = GetProofIdProofSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/aggregation.AggregationService/RevokeProof" => {
+                    #[allow(non_camel_case_types)]
+                    struct RevokeProofSvc<T: AggregationService>(pub Arc<T>);
+                    impl<
+                        T: AggregationService,
+                    > tonic::server::UnaryService<super::RevokeProofRequest>
+                    for RevokeProofSvc<T> {
+                        type Response = super::RevokeProofResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::RevokeProofRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as AggregationService>::revoke_proof(&inner, request)
+                                    .await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let method = RevokeProofSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/aggregation.AggregationService/RegisterProgram" => {
+                    #[allow(non_camel_case_types)]
+                    struct RegisterProgramSvc<T: AggregationService>(pub Arc<T>);
+                    impl<
+                        T: AggregationService,
+                    > tonic::server::UnaryService<super::RegisterProgramRequest>
+                    for RegisterProgramSvc<T> {
+                        type Response = super::RegisterProgramResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::RegisterProgramRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as AggregationService>::register_program(&inner, request)
+                                    .await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let method = RegisterProgramSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/aggregation.AggregationService/RetireProgram" => {
+                    #[allow(non_camel_case_types)]
+                    struct RetireProgramSvc<T: AggregationService>(pub Arc<T>);
+                    impl<
+                        T: AggregationService,
+                    > tonic::server::UnaryService<super::RetireProgramRequest>
+                    for RetireProgramSvc<T> {
+                        type Response = super::RetireProgramResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::RetireProgramRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as AggregationService>::retire_program(&inner, request)
+                                    .await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let method = RetireProgramSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/aggregation.AggregationService/GetProgramAllowlist" => {
+                    #[allow(non_camel_case_types)]
+                    struct GetProgramAllowlistSvc<T: AggregationService>(pub Arc<T>);
+                    impl<
+                        T: AggregationService,
+                    > tonic::server::UnaryService<super::GetProgramAllowlistRequest>
+                    for GetProgramAllowlistSvc<T> {
+                        type Response = super::GetProgramAllowlistResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::GetProgramAllowlistRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as AggregationService>::get_program_allowlist(
+                                        &inner,
+                                        request,
+                                    )
+                                    .await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let method = GetProgramAllowlistSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
                 _ => {
                     Box::pin(async move {
                         let mut response = http::Response::new(empty_body());
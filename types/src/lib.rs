@@ -1,2 +1,6 @@
 pub mod aggregation;
 pub mod merkle_tree;
+pub mod mmr;
+pub mod proof_id;
+pub mod public_values;
+pub mod sparse_merkle_tree;
@@ -1,119 +1,1074 @@
-use sha2::{Digest, Sha256};
-use std::collections::HashMap;
-use tiny_keccak::{Hasher, Keccak};
+use std::{collections::HashMap, fmt};
 
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::aggregation::DuplicateLeafPolicy;
+use aggregation_merkle::subtree::{hash_program_node, process_program_proof, program_root};
+
+pub use aggregation_merkle::{
+    allowlist, bind_submitter, compute_root, hash_digest_leaf, hash_leaf, merge_roots,
+    positional_path, process_multiproof, process_positional_proof, process_proof, subtree,
+    words_to_bytes_le, HashFunction, Keccak256, LeafFormat, MerkleHasher, MultiProof, NodeOrdering,
+    PositionalProof, Sha256, TreeVersion, UnknownHashFunction, DIGEST_LEAF_TAG, SUBMITTER_LEAF_TAG,
+};
+
+/// The fewest nodes a thread hashes at a time when building a tree, so that small levels are not
+/// split across threads.
+const PARALLEL_MIN_LEN: usize = 1024;
+
+/// The environment variable that selects the [`HashFunction`] of a deployment.
+pub const HASH_FUNCTION_ENV: &str = "MERKLE_HASH_FUNCTION";
+
+/// Read the [`HashFunction`] of this deployment from `MERKLE_HASH_FUNCTION`, defaulting to
+/// Keccak-256 if it is unset. Fails if it names an unknown hash function.
+pub fn hash_function_from_env() -> Result<HashFunction, UnknownHashFunction> {
+    match std::env::var(HASH_FUNCTION_ENV) {
+        Ok(name) => name.parse(),
+        Err(_) => Ok(HashFunction::default()),
+    }
+}
+
+impl From<crate::aggregation::LeafFormat> for LeafFormat {
+    fn from(format: crate::aggregation::LeafFormat) -> Self {
+        match format {
+            crate::aggregation::LeafFormat::Full => Self::Full,
+            crate::aggregation::LeafFormat::Digest => Self::Digest,
+        }
+    }
+}
+
+impl From<LeafFormat> for crate::aggregation::LeafFormat {
+    fn from(format: LeafFormat) -> Self {
+        match format {
+            LeafFormat::Full => Self::Full,
+            LeafFormat::Digest => Self::Digest,
+        }
+    }
+}
+
+/// An error from generating, decoding or verifying a proof.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MerkleError {
+    /// The leaf is not in the tree.
+    LeafNotFound,
+    /// The index is not less than the number of leaves of the tree.
+    IndexOutOfBounds { index: usize, leaf_count: usize },
+    /// The tree, or the set of leaves to prove, is empty.
+    EmptyTree,
+    /// The proof has the wrong number of siblings for its leaf count.
+    MalformedProof,
+    /// The proof is for a tree with another version, node ordering or hash function.
+    HashSchemeMismatch,
+    /// The proof does not prove its leaf against the expected root.
+    RootMismatch,
+    /// The bytes are not an encoding of a proof.
+    InvalidEncoding,
+    /// An encoded tree is truncated or does not match its checksum.
+    CorruptedTree,
+    /// The leaves of a batch are not grouped by program in ascending order of vkey hash.
+    UnsortedPrograms,
+}
+
+impl fmt::Display for MerkleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::LeafNotFound => f.write_str("leaf not found in the merkle tree"),
+            Self::IndexOutOfBounds { index, leaf_count } => write!(
+                f,
+                "leaf index {index} is out of bounds for a tree of {leaf_count} leaves"
+            ),
+            Self::EmptyTree => f.write_str("no leaves to prove"),
+            Self::MalformedProof => f.write_str("malformed merkle proof"),
+            Self::HashSchemeMismatch => {
+                f.write_str("merkle proof and tree use different hash schemes")
+            }
+            Self::RootMismatch => f.write_str("merkle proof does not match the root"),
+            Self::InvalidEncoding => f.write_str("invalid merkle proof encoding"),
+            Self::CorruptedTree => f.write_str("corrupted merkle tree encoding"),
+            Self::UnsortedPrograms => {
+                f.write_str("leaves are not grouped by program in ascending vkey hash order")
+            }
+        }
+    }
+}
+
+impl std::error::Error for MerkleError {}
+
+/// A proof that a leaf is in a tree, which can be checked without the tree with [`verify`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct MerkleProof {
-    pub proof: Vec<[u8; 32]>,
+    /// The proven leaf.
+    pub leaf: [u8; 32],
+    /// The index of the leaf in the tree.
+    pub index: u64,
+    /// The number of leaves in the tree.
+    pub leaf_count: u64,
+    /// The siblings on the path from the leaf to the root.
+    pub siblings: Vec<[u8; 32]>,
+    /// The root of the tree.
+    pub root: [u8; 32],
+    /// The format of the tree.
+    pub version: TreeVersion,
+    /// How the children of the internal nodes of the tree are ordered.
+    pub ordering: NodeOrdering,
+    /// The hash function of the internal nodes of the tree.
+    #[serde(default)]
+    pub hash_function: HashFunction,
 }
+
+impl MerkleProof {
+    /// The version of the binary encoding produced by [`MerkleProof::to_bytes`].
+    pub const ENCODING_VERSION: u8 = 2;
+
+    /// The length of the `leaf`, `root`, `index`, `leaf_count` and `sibling_count` fields.
+    const FIELDS_LEN: usize = 32 + 32 + 8 + 8 + 4;
+
+    /// Encode the proof as `encoding_version || version || ordering || hash_function || leaf ||
+    /// root || index || leaf_count || sibling_count || siblings`, where the first four fields are
+    /// one byte each, `index` and `leaf_count` are big-endian `u64`s and `sibling_count` is a
+    /// big-endian `u32`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(4 + Self::FIELDS_LEN + 32 * self.siblings.len());
+        bytes.push(Self::ENCODING_VERSION);
+        bytes.push(self.version as u8);
+        bytes.push(self.ordering as u8);
+        bytes.push(self.hash_function as u8);
+        bytes.extend_from_slice(&self.leaf);
+        bytes.extend_from_slice(&self.root);
+        bytes.extend_from_slice(&self.index.to_be_bytes());
+        bytes.extend_from_slice(&self.leaf_count.to_be_bytes());
+        bytes.extend_from_slice(&(self.siblings.len() as u32).to_be_bytes());
+        for sibling in &self.siblings {
+            bytes.extend_from_slice(sibling);
+        }
+        bytes
+    }
+
+    /// Decode a proof encoded with [`MerkleProof::to_bytes`]. Proofs in the first encoding, which
+    /// had no hash function byte, are Keccak-256 proofs.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, MerkleError> {
+        let (version, ordering, hash_function, rest) = match bytes {
+            [1, version, ordering, rest @ ..] => {
+                (*version, *ordering, HashFunction::Keccak256, rest)
+            }
+            [2, version, ordering, hash_function, rest @ ..] => (
+                *version,
+                *ordering,
+                HashFunction::try_from(*hash_function).or(Err(MerkleError::InvalidEncoding))?,
+                rest,
+            ),
+            _ => return Err(MerkleError::InvalidEncoding),
+        };
+        if rest.len() < Self::FIELDS_LEN {
+            return Err(MerkleError::InvalidEncoding);
+        }
+        let (fields, siblings) = rest.split_at(Self::FIELDS_LEN);
+        let sibling_count = u32::from_be_bytes(fields[80..84].try_into().unwrap()) as usize;
+        if siblings.len() != 32 * sibling_count {
+            return Err(MerkleError::InvalidEncoding);
+        }
+        Ok(Self {
+            leaf: fields[0..32].try_into().unwrap(),
+            root: fields[32..64].try_into().unwrap(),
+            index: u64::from_be_bytes(fields[64..72].try_into().unwrap()),
+            leaf_count: u64::from_be_bytes(fields[72..80].try_into().unwrap()),
+            siblings: siblings
+                .chunks(32)
+                .map(|sibling| sibling.try_into().unwrap())
+                .collect(),
+            version: TreeVersion::try_from(version).or(Err(MerkleError::InvalidEncoding))?,
+            ordering: NodeOrdering::try_from(ordering).or(Err(MerkleError::InvalidEncoding))?,
+            hash_function,
+        })
+    }
+
+    /// Compute the root implied by the leaf and its siblings.
+    pub fn compute_root(&self) -> Result<[u8; 32], MerkleError> {
+        match self.ordering {
+            NodeOrdering::Sorted => Ok(process_proof(
+                self.version,
+                &self.hash_function,
+                self.leaf,
+                &self.siblings,
+            )),
+            NodeOrdering::Positional => process_positional_proof(
+                self.version,
+                &self.hash_function,
+                self.leaf,
+                &PositionalProof {
+                    index: self.index,
+                    leaf_count: self.leaf_count,
+                    siblings: self.siblings.clone(),
+                    is_left: positional_path(self.index, self.leaf_count),
+                },
+            )
+            .ok_or(MerkleError::MalformedProof),
+        }
+    }
+}
+
+/// Check that `proof` proves the inclusion of its leaf in the tree with the given `root`.
+pub fn verify(proof: &MerkleProof, root: [u8; 32]) -> Result<(), MerkleError> {
+    if proof.root != root || proof.compute_root()? != root {
+        return Err(MerkleError::RootMismatch);
+    }
+    Ok(())
+}
+
 pub struct MerkleTree {
     pub leaves: Vec<[u8; 32]>,
     pub tree: Vec<[u8; 32]>,
     pub root: [u8; 32],
-    leaf_indices: HashMap<[u8; 32], usize>,
+    pub version: TreeVersion,
+    pub ordering: NodeOrdering,
+    pub hash_function: HashFunction,
+    leaf_indices: HashMap<[u8; 32], Vec<usize>>,
 }
 
 impl MerkleTree {
+    /// Build a tree over `leaves` in the latest [`TreeVersion`].
     pub fn new(leaves: Vec<[u8; 32]>) -> Self {
+        Self::with_version(leaves, TreeVersion::LATEST)
+    }
+
+    /// Build a tree over `leaves` in the given [`TreeVersion`], e.g. to serve proofs for a batch
+    /// aggregated with an older version.
+    pub fn with_version(leaves: Vec<[u8; 32]>, version: TreeVersion) -> Self {
+        Self::with_hash_function(leaves, version, HashFunction::default())
+    }
+
+    /// Build a tree over `leaves` in the given [`TreeVersion`] whose nodes are hashed with
+    /// `hash_function`.
+    pub fn with_hash_function(
+        leaves: Vec<[u8; 32]>,
+        version: TreeVersion,
+        hash_function: HashFunction,
+    ) -> Self {
+        Self::build(leaves, version, NodeOrdering::Sorted, hash_function)
+    }
+
+    /// Build a tree over `leaves` in the latest [`TreeVersion`], hashed in positional order so that
+    /// [`MerkleTree::generate_positional_proof`] binds the index of each leaf.
+    pub fn positional(leaves: Vec<[u8; 32]>) -> Self {
+        Self::build(
+            leaves,
+            TreeVersion::LATEST,
+            NodeOrdering::Positional,
+            HashFunction::default(),
+        )
+    }
+
+    fn build(
+        leaves: Vec<[u8; 32]>,
+        version: TreeVersion,
+        ordering: NodeOrdering,
+        hash_function: HashFunction,
+    ) -> Self {
         // if leaves is empty, return empty tree
         if leaves.is_empty() {
             return Self {
                 leaves: Vec::new(),
                 tree: Vec::new(),
                 root: [0; 32],
+                version,
+                ordering,
+                hash_function,
                 leaf_indices: HashMap::new(),
             };
         }
 
-        let mut tree = Vec::new();
-        let mut current_layer = leaves.clone();
-        let mut leaf_indices = HashMap::new();
-        for (i, leaf) in leaves.iter().enumerate() {
-            leaf_indices.insert(*leaf, i);
+        // All levels live in one buffer, leaves first and the root last, and each level is
+        // hashed in place from the one below it.
+        let mut level_sizes = vec![leaves.len()];
+        while let Some(&size @ 2..) = level_sizes.last() {
+            level_sizes.push((size + 1) / 2);
         }
+        let mut tree = vec![[0u8; 32]; level_sizes.iter().sum()];
+        tree[..leaves.len()]
+            .par_iter_mut()
+            .zip(leaves.par_iter())
+            .with_min_len(PARALLEL_MIN_LEN)
+            .for_each(|(node, leaf)| *node = version.hash_leaf_node(&hash_function, leaf));
 
-        while current_layer.len() > 1 {
-            let mut next_layer = Vec::new();
-            for chunk in current_layer.chunks(2) {
-                if chunk.len() == 1 {
-                    next_layer.push(chunk[0]); // Add the leaf on its own
-                } else {
-                    let left = chunk[0];
-                    let right = chunk[1];
-                    let hash: [u8; 32] = if left > right {
-                        let mut hasher = Keccak::v256();
-                        let mut output = [0u8; 32];
-                        hasher.update(&[right, left].concat());
-                        hasher.finalize(&mut output);
-                        output
-                    } else {
-                        let mut hasher = Keccak::v256();
-                        let mut output = [0u8; 32];
-                        hasher.update(&[left, right].concat());
-                        hasher.finalize(&mut output);
-                        output
-                    };
-                    next_layer.push(hash);
-                }
-            }
-            tree.extend(current_layer);
-            current_layer = next_layer;
+        let mut level_start = 0;
+        for window in level_sizes.windows(2) {
+            let (lower, upper) = tree.split_at_mut(level_start + window[0]);
+            upper[..window[1]]
+                .par_iter_mut()
+                .zip(lower[level_start..].par_chunks(2))
+                .with_min_len(PARALLEL_MIN_LEN)
+                .for_each(|(node, children)| {
+                    *node = match children {
+                        [left, right] => {
+                            ordering.hash_children(version, &hash_function, left, right)
+                        }
+                        // An odd node is promoted to the next level unchanged.
+                        [promoted] => *promoted,
+                        _ => unreachable!(),
+                    }
+                });
+            level_start += window[0];
+        }
+
+        let mut leaf_indices: HashMap<[u8; 32], Vec<usize>> = HashMap::new();
+        for (i, leaf) in leaves.iter().enumerate() {
+            leaf_indices.entry(*leaf).or_default().push(i);
         }
-        // Add the root to the tree
-        tree.extend(current_layer);
         let root = *tree.last().unwrap();
         Self {
             leaves,
             tree,
             root,
+            version,
+            ordering,
+            hash_function,
             leaf_indices,
         }
     }
 
-    pub fn generate_proof(&self, leaf: [u8; 32]) -> Option<Vec<[u8; 32]>> {
-        // Find the index of the leaf in the leaves vector
-        let index = match self.leaf_indices.get(&leaf) {
-            Some(&idx) => idx,
-            None => return None, // Return None if leaf is not found
-        };
+    /// The indices of every occurrence of `leaf`, in ascending order.
+    pub fn indices_of(&self, leaf: [u8; 32]) -> &[usize] {
+        self.leaf_indices.get(&leaf).map_or(&[], Vec::as_slice)
+    }
+
+    /// The index of the first occurrence of `leaf`.
+    fn position(&self, leaf: [u8; 32]) -> Result<usize, MerkleError> {
+        if self.leaves.is_empty() {
+            return Err(MerkleError::EmptyTree);
+        }
+        self.indices_of(leaf)
+            .first()
+            .copied()
+            .ok_or(MerkleError::LeafNotFound)
+    }
+
+    fn check_index(&self, index: usize) -> Result<(), MerkleError> {
+        check_index(index, self.leaves.len())
+    }
+
+    /// Generate a proof for the first occurrence of `leaf`. Use [`MerkleTree::generate_proof_at`]
+    /// to prove a specific occurrence of a duplicate leaf.
+    pub fn generate_proof(&self, leaf: [u8; 32]) -> Result<MerkleProof, MerkleError> {
+        self.generate_proof_at(self.position(leaf)?)
+    }
+
+    /// Generate a proof for the leaf at `index`.
+    pub fn generate_proof_at(&self, index: usize) -> Result<MerkleProof, MerkleError> {
+        self.check_index(index)?;
+        Ok(MerkleProof {
+            leaf: self.leaves[index],
+            index: index as u64,
+            leaf_count: self.leaves.len() as u64,
+            siblings: self.siblings(index),
+            root: self.root,
+            version: self.version,
+            ordering: self.ordering,
+            hash_function: self.hash_function,
+        })
+    }
+
+    /// The siblings on the path from the leaf at `index` to the root.
+    fn siblings(&self, index: usize) -> Vec<[u8; 32]> {
+        path_siblings(self.leaves.len(), index, |node| self.tree[node])
+    }
+
+    /// Check that `proof` was generated by a tree with the hash scheme and the root of this one.
+    pub fn verify_proof(&self, proof: &MerkleProof) -> Result<(), MerkleError> {
+        if (proof.version, proof.ordering, proof.hash_function)
+            != (self.version, self.ordering, self.hash_function)
+        {
+            return Err(MerkleError::HashSchemeMismatch);
+        }
+        verify(proof, self.root)
+    }
+
+    /// Generate a proof that also carries the index of `leaf` and the side of each node on its
+    /// path. Only trees built with [`MerkleTree::positional`] commit to the index.
+    pub fn generate_positional_proof(
+        &self,
+        leaf: [u8; 32],
+    ) -> Result<PositionalProof, MerkleError> {
+        self.generate_positional_proof_at(self.position(leaf)?)
+    }
+
+    /// Generate a positional proof for the leaf at `index`.
+    pub fn generate_positional_proof_at(
+        &self,
+        index: usize,
+    ) -> Result<PositionalProof, MerkleError> {
+        self.check_index(index)?;
+        let siblings = self.siblings(index);
+        Ok(PositionalProof {
+            index: index as u64,
+            leaf_count: self.leaves.len() as u64,
+            siblings,
+            is_left: positional_path(index as u64, self.leaves.len() as u64),
+        })
+    }
+
+    pub fn verify_positional_proof(
+        &self,
+        proof: &PositionalProof,
+        leaf: [u8; 32],
+    ) -> Result<(), MerkleError> {
+        if self.ordering != NodeOrdering::Positional {
+            return Err(MerkleError::HashSchemeMismatch);
+        }
+        let root = process_positional_proof(self.version, &self.hash_function, leaf, proof)
+            .ok_or(MerkleError::MalformedProof)?;
+        if root != self.root {
+            return Err(MerkleError::RootMismatch);
+        }
+        Ok(())
+    }
+
+    /// Generate a single proof for the first occurrence of each of `leaves`.
+    pub fn generate_multiproof(&self, leaves: &[[u8; 32]]) -> Result<MultiProof, MerkleError> {
+        let indices = leaves
+            .iter()
+            .map(|leaf| self.position(*leaf))
+            .collect::<Result<Vec<usize>, MerkleError>>()?;
+        self.generate_multiproof_at(&indices)
+    }
+
+    /// Generate a single proof for the leaves at `indices`.
+    pub fn generate_multiproof_at(&self, indices: &[usize]) -> Result<MultiProof, MerkleError> {
+        for &index in indices {
+            self.check_index(index)?;
+        }
+        let mut indices = indices.to_vec();
+        indices.sort_unstable();
+        indices.dedup();
+        if indices.is_empty() {
+            return Err(MerkleError::EmptyTree);
+        }
 
-        // Generate the proof
         let mut proof = Vec::new();
-        let mut current_index = index;
+        let mut proof_flags = Vec::new();
+        let mut known = indices.clone();
         let mut level_start = 0;
         let mut level_size = self.leaves.len();
-
         while level_size > 1 {
-            let pair_index = if current_index % 2 == 0 {
-                current_index + 1
-            } else {
-                current_index - 1
-            };
-
-            if pair_index < level_size {
-                proof.push(self.tree[level_start + pair_index]);
+            let mut next = Vec::with_capacity(known.len());
+            let mut i = 0;
+            while i < known.len() {
+                let index = known[i];
+                if index == level_size - 1 && level_size % 2 == 1 {
+                    next.push(index / 2);
+                    i += 1;
+                    continue;
+                }
+                let sibling = index ^ 1;
+                if known.get(i + 1) == Some(&sibling) {
+                    proof_flags.push(true);
+                    i += 2;
+                } else {
+                    proof_flags.push(false);
+                    proof.push(self.tree[level_start + sibling]);
+                    i += 1;
+                }
+                next.push(index / 2);
             }
-
-            current_index /= 2;
+            known = next;
             level_start += level_size;
-            level_size = (level_size + 1) / 2;
+            level_size = level_size.div_ceil(2);
+        }
+
+        Ok(MultiProof {
+            leaf_count: self.leaves.len() as u64,
+            indices: indices.iter().map(|&index| index as u64).collect(),
+            leaves: indices.iter().map(|&index| self.leaves[index]).collect(),
+            proof,
+            proof_flags,
+        })
+    }
+
+    pub fn verify_multiproof(&self, proof: &MultiProof) -> Result<(), MerkleError> {
+        if self.ordering != NodeOrdering::Sorted {
+            return Err(MerkleError::HashSchemeMismatch);
+        }
+        let root = process_multiproof(self.version, &self.hash_function, proof)
+            .ok_or(MerkleError::MalformedProof)?;
+        if root != self.root {
+            return Err(MerkleError::RootMismatch);
+        }
+        Ok(())
+    }
+
+    /// The version of the binary encoding produced by [`MerkleTree::to_bytes`].
+    pub const ENCODING_VERSION: u8 = 1;
+
+    /// The length of the header: the encoding version, `version`, `ordering` and `hash_function`
+    /// bytes, the leaf count and the root.
+    const HEADER_LEN: usize = 4 + 8 + 32;
+
+    /// Encode the tree as `encoding_version || version || ordering || hash_function ||
+    /// leaf_count || root || leaves || tree || checksum`, where the first four fields are one
+    /// byte each, `leaf_count` is a big-endian `u64` and `checksum` is the SHA-256 hash of
+    /// everything before it. Every node is stored, so proofs can be read from the encoding with
+    /// [`EncodedMerkleTree`] without hashing the tree again.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes =
+            Vec::with_capacity(Self::HEADER_LEN + 32 * (self.leaves.len() + self.tree.len() + 1));
+        bytes.push(Self::ENCODING_VERSION);
+        bytes.push(self.version as u8);
+        bytes.push(self.ordering as u8);
+        bytes.push(self.hash_function as u8);
+        bytes.extend_from_slice(&(self.leaves.len() as u64).to_be_bytes());
+        bytes.extend_from_slice(&self.root);
+        for node in self.leaves.iter().chain(&self.tree) {
+            bytes.extend_from_slice(node);
+        }
+        let checksum = Sha256.hash(&[&bytes]);
+        bytes.extend_from_slice(&checksum);
+        bytes
+    }
+
+    /// Decode a tree encoded with [`MerkleTree::to_bytes`].
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, MerkleError> {
+        let encoded = EncodedMerkleTree::decode(bytes)?;
+        let leaves: Vec<[u8; 32]> = (0..encoded.leaf_count).map(|i| encoded.leaf(i)).collect();
+        let mut leaf_indices: HashMap<[u8; 32], Vec<usize>> = HashMap::new();
+        for (i, leaf) in leaves.iter().enumerate() {
+            leaf_indices.entry(*leaf).or_default().push(i);
+        }
+        Ok(Self {
+            leaves,
+            tree: encoded
+                .nodes
+                .chunks(32)
+                .map(|node| node.try_into().unwrap())
+                .collect(),
+            root: encoded.root,
+            version: encoded.version,
+            ordering: encoded.ordering,
+            hash_function: encoded.hash_function,
+            leaf_indices,
+        })
+    }
+}
+
+/// A tree encoded with [`MerkleTree::to_bytes`], read in place.
+#[derive(Debug, Clone, Copy)]
+pub struct EncodedMerkleTree<'a> {
+    pub root: [u8; 32],
+    pub version: TreeVersion,
+    pub ordering: NodeOrdering,
+    pub hash_function: HashFunction,
+    pub leaf_count: usize,
+    leaves: &'a [u8],
+    nodes: &'a [u8],
+}
+
+impl<'a> EncodedMerkleTree<'a> {
+    /// Check the header, the length and the checksum of an encoded tree.
+    pub fn decode(bytes: &'a [u8]) -> Result<Self, MerkleError> {
+        Self::parse(bytes).ok_or(MerkleError::CorruptedTree)
+    }
+
+    fn parse(bytes: &'a [u8]) -> Option<Self> {
+        let (body, checksum) = bytes.split_at(bytes.len().checked_sub(32)?);
+        if body.len() < MerkleTree::HEADER_LEN || Sha256.hash(&[body]) != checksum {
+            return None;
         }
+        let [MerkleTree::ENCODING_VERSION, version, ordering, hash_function, rest @ ..] = body
+        else {
+            return None;
+        };
+        let leaf_count = usize::try_from(u64::from_be_bytes(rest[..8].try_into().unwrap())).ok()?;
+        let nodes = &rest[40..];
+        if leaf_count > nodes.len() / 32 {
+            return None;
+        }
+        let (leaves, nodes) = nodes.split_at(32 * leaf_count);
+        if nodes.len() != 32 * node_count(leaf_count) {
+            return None;
+        }
+        Some(Self {
+            root: rest[8..40].try_into().unwrap(),
+            version: TreeVersion::try_from(*version).ok()?,
+            ordering: NodeOrdering::try_from(*ordering).ok()?,
+            hash_function: HashFunction::try_from(*hash_function).ok()?,
+            leaf_count,
+            leaves,
+            nodes,
+        })
+    }
+
+    /// The leaf at `index`, which must be less than the leaf count.
+    fn leaf(&self, index: usize) -> [u8; 32] {
+        self.leaves[32 * index..32 * (index + 1)]
+            .try_into()
+            .unwrap()
+    }
 
-        Some(proof)
+    /// The index of the first occurrence of `leaf`.
+    pub fn position(&self, leaf: [u8; 32]) -> Result<usize, MerkleError> {
+        if self.leaf_count == 0 {
+            return Err(MerkleError::EmptyTree);
+        }
+        self.leaves
+            .chunks(32)
+            .position(|node| node == leaf)
+            .ok_or(MerkleError::LeafNotFound)
+    }
+
+    /// Generate a proof for the leaf at `index` from the stored nodes.
+    pub fn generate_proof_at(&self, index: usize) -> Result<MerkleProof, MerkleError> {
+        check_index(index, self.leaf_count)?;
+        Ok(MerkleProof {
+            leaf: self.leaf(index),
+            index: index as u64,
+            leaf_count: self.leaf_count as u64,
+            siblings: path_siblings(self.leaf_count, index, |node| {
+                self.nodes[32 * node..32 * (node + 1)].try_into().unwrap()
+            }),
+            root: self.root,
+            version: self.version,
+            ordering: self.ordering,
+            hash_function: self.hash_function,
+        })
     }
+}
+
+/// The tree of a batch with one subtree per program: a [`MerkleTree`] over the leaves of each
+/// program, and a top tree over the program nodes, see [`program_root`].
+pub struct ProgramTree {
+    /// The vkey hash and the subtree of each program, in ascending order of vkey hash.
+    pub programs: Vec<([u8; 32], MerkleTree)>,
+    pub root: [u8; 32],
+    pub version: TreeVersion,
+    pub hash_function: HashFunction,
+}
 
-    pub fn verify_proof(&self, proof: Vec<[u8; 32]>, leaf: [u8; 32]) -> bool {
-        let mut current_hash = leaf;
-        for sibling in proof {
-            let mut hasher = Keccak::v256();
-            let mut output = [0u8; 32];
-            if current_hash < sibling {
-                hasher.update(&[current_hash, sibling].concat());
-            } else {
-                hasher.update(&[sibling, current_hash].concat());
+impl ProgramTree {
+    /// Build the tree of a batch from the vkey hash and the leaf of each of its proofs, which
+    /// must be grouped by program in ascending order of vkey hash. Leaves keep their order within
+    /// their program, so the index of a leaf in the batch is its index in `leaves`.
+    pub fn new(
+        leaves: &[([u8; 32], [u8; 32])],
+        version: TreeVersion,
+        hash_function: HashFunction,
+    ) -> Result<Self, MerkleError> {
+        let programs = leaves
+            .chunk_by(|a, b| a.0 == b.0)
+            .map(|program| {
+                let leaves = program.iter().map(|(_, leaf)| *leaf).collect();
+                (
+                    program[0].0,
+                    MerkleTree::with_hash_function(leaves, version, hash_function),
+                )
+            })
+            .collect::<Vec<_>>();
+        if !programs.windows(2).all(|pair| pair[0].0 < pair[1].0) {
+            return Err(MerkleError::UnsortedPrograms);
+        }
+        let root = program_root(
+            version,
+            &hash_function,
+            &programs
+                .iter()
+                .map(|(vkey_hash, tree)| (*vkey_hash, tree.root))
+                .collect::<Vec<_>>(),
+        );
+        Ok(Self {
+            programs,
+            root,
+            version,
+            hash_function,
+        })
+    }
+
+    /// The number of leaves of every program together.
+    pub fn leaf_count(&self) -> usize {
+        self.programs
+            .iter()
+            .map(|(_, tree)| tree.leaves.len())
+            .sum()
+    }
+
+    /// The version of the binary encoding produced by [`ProgramTree::to_bytes`], which comes
+    /// after the versions of [`MerkleTree::ENCODING_VERSION`] so that the first byte tells the two
+    /// encodings apart.
+    pub const ENCODING_VERSION: u8 = 2;
+
+    /// Encode the tree as `encoding_version || version || hash_function || program_count || root
+    /// || programs`, where the first three fields are one byte each, `program_count` is a
+    /// big-endian `u32` and each program is `vkey_hash || tree_len || tree`, with `tree` the
+    /// [`MerkleTree::to_bytes`] encoding of its subtree and `tree_len` its big-endian `u64`
+    /// length. Each subtree carries its own checksum, and the root is checked against the vkey
+    /// hashes and subtree roots when decoding.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![
+            Self::ENCODING_VERSION,
+            self.version as u8,
+            self.hash_function as u8,
+        ];
+        bytes.extend_from_slice(&(self.programs.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&self.root);
+        for (vkey_hash, tree) in &self.programs {
+            let tree = tree.to_bytes();
+            bytes.extend_from_slice(vkey_hash);
+            bytes.extend_from_slice(&(tree.len() as u64).to_be_bytes());
+            bytes.extend_from_slice(&tree);
+        }
+        bytes
+    }
+
+    /// Decode a tree encoded with [`ProgramTree::to_bytes`].
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, MerkleError> {
+        let encoded = EncodedProgramTree::decode(bytes)?;
+        Ok(Self {
+            programs: encoded
+                .programs
+                .iter()
+                .map(|(vkey_hash, tree)| Ok((*vkey_hash, MerkleTree::from_bytes(tree.bytes)?)))
+                .collect::<Result<_, MerkleError>>()?,
+            root: encoded.root,
+            version: encoded.version,
+            hash_function: encoded.hash_function,
+        })
+    }
+
+    /// Whether `bytes` start like an encoding of a [`ProgramTree`] rather than of a
+    /// [`MerkleTree`].
+    pub fn is_encoding(bytes: &[u8]) -> bool {
+        bytes.first() == Some(&Self::ENCODING_VERSION)
+    }
+
+    /// Generate a proof for the leaf at `index` in the batch.
+    pub fn generate_proof_at(&self, index: usize) -> Result<ProgramProof, MerkleError> {
+        let (program, index_in_program) = program_of(
+            self.programs.iter().map(|(_, tree)| tree.leaves.len()),
+            index,
+            self.leaf_count(),
+        )?;
+        let subtree_proof = self.programs[program]
+            .1
+            .generate_proof_at(index_in_program)?;
+        Ok(program_proof(
+            self.version,
+            self.hash_function,
+            &self
+                .programs
+                .iter()
+                .map(|(vkey_hash, tree)| (*vkey_hash, tree.root))
+                .collect::<Vec<_>>(),
+            program,
+            subtree_proof,
+            index,
+        ))
+    }
+}
+
+/// A tree encoded with [`ProgramTree::to_bytes`], read in place.
+#[derive(Debug, Clone)]
+pub struct EncodedProgramTree<'a> {
+    pub root: [u8; 32],
+    pub version: TreeVersion,
+    pub hash_function: HashFunction,
+    pub leaf_count: usize,
+    programs: Vec<([u8; 32], EncodedSubtree<'a>)>,
+}
+
+/// The subtree of a program in an [`EncodedProgramTree`], with the bytes it was decoded from.
+#[derive(Debug, Clone, Copy)]
+struct EncodedSubtree<'a> {
+    tree: EncodedMerkleTree<'a>,
+    bytes: &'a [u8],
+}
+
+impl<'a> EncodedProgramTree<'a> {
+    /// The length of the header: the encoding version, `version` and `hash_function` bytes, the
+    /// program count and the root.
+    const HEADER_LEN: usize = 3 + 4 + 32;
+
+    /// Check the header, the subtrees and the root of an encoded tree.
+    pub fn decode(bytes: &'a [u8]) -> Result<Self, MerkleError> {
+        Self::parse(bytes).ok_or(MerkleError::CorruptedTree)
+    }
+
+    fn parse(bytes: &'a [u8]) -> Option<Self> {
+        if bytes.len() < Self::HEADER_LEN {
+            return None;
+        }
+        let [ProgramTree::ENCODING_VERSION, version, hash_function, ..] = bytes else {
+            return None;
+        };
+        let version = TreeVersion::try_from(*version).ok()?;
+        let hash_function = HashFunction::try_from(*hash_function).ok()?;
+        let program_count = u32::from_be_bytes(bytes[3..7].try_into().unwrap());
+        let root: [u8; 32] = bytes[7..39].try_into().unwrap();
+
+        let mut rest = &bytes[Self::HEADER_LEN..];
+        let mut programs = Vec::new();
+        for _ in 0..program_count {
+            if rest.len() < 40 {
+                return None;
+            }
+            let vkey_hash: [u8; 32] = rest[..32].try_into().unwrap();
+            let tree_len = usize::try_from(u64::from_be_bytes(rest[32..40].try_into().unwrap()))
+                .ok()
+                .filter(|tree_len| *tree_len <= rest.len() - 40)?;
+            let (tree_bytes, next) = rest[40..].split_at(tree_len);
+            let tree = EncodedMerkleTree::decode(tree_bytes).ok()?;
+            if (tree.version, tree.hash_function) != (version, hash_function) {
+                return None;
+            }
+            programs.push((
+                vkey_hash,
+                EncodedSubtree {
+                    tree,
+                    bytes: tree_bytes,
+                },
+            ));
+            rest = next;
+        }
+        let subtree_roots = programs
+            .iter()
+            .map(|(vkey_hash, subtree)| (*vkey_hash, subtree.tree.root))
+            .collect::<Vec<_>>();
+        if !rest.is_empty() || program_root(version, &hash_function, &subtree_roots) != root {
+            return None;
+        }
+        Some(Self {
+            root,
+            version,
+            hash_function,
+            leaf_count: programs
+                .iter()
+                .map(|(_, subtree)| subtree.tree.leaf_count)
+                .sum(),
+            programs,
+        })
+    }
+
+    /// The index in the batch of the first occurrence of `leaf`.
+    pub fn position(&self, leaf: [u8; 32]) -> Result<usize, MerkleError> {
+        if self.leaf_count == 0 {
+            return Err(MerkleError::EmptyTree);
+        }
+        let mut offset = 0;
+        for (_, subtree) in &self.programs {
+            if let Ok(index) = subtree.tree.position(leaf) {
+                return Ok(offset + index);
             }
-            hasher.finalize(&mut output);
-            current_hash = output;
+            offset += subtree.tree.leaf_count;
+        }
+        Err(MerkleError::LeafNotFound)
+    }
+
+    /// Generate a proof for the leaf at `index` in the batch from the stored nodes.
+    pub fn generate_proof_at(&self, index: usize) -> Result<ProgramProof, MerkleError> {
+        let (program, index_in_program) = program_of(
+            self.programs
+                .iter()
+                .map(|(_, subtree)| subtree.tree.leaf_count),
+            index,
+            self.leaf_count,
+        )?;
+        let subtree_proof = self.programs[program]
+            .1
+            .tree
+            .generate_proof_at(index_in_program)?;
+        Ok(program_proof(
+            self.version,
+            self.hash_function,
+            &self
+                .programs
+                .iter()
+                .map(|(vkey_hash, subtree)| (*vkey_hash, subtree.tree.root))
+                .collect::<Vec<_>>(),
+            program,
+            subtree_proof,
+            index,
+        ))
+    }
+
+    /// Decode the subtree of the program with `vkey_hash`, e.g. to generate a multiproof against
+    /// its root, or `None` if the batch has no proof of that program.
+    pub fn subtree(&self, vkey_hash: [u8; 32]) -> Option<Result<MerkleTree, MerkleError>> {
+        self.programs
+            .iter()
+            .find(|(program, _)| *program == vkey_hash)
+            .map(|(_, subtree)| MerkleTree::from_bytes(subtree.bytes))
+    }
+}
+
+/// A proof that a leaf is in the tree of a batch: a proof against the subtree of its program,
+/// followed by the path of the node of the program through the top tree, which can be checked
+/// without the tree with [`verify_program_proof`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProgramProof {
+    /// The proof of the leaf against the root of the subtree of its program, which only commits
+    /// to the proofs of that program.
+    pub subtree_proof: MerkleProof,
+    /// The vkey hash of the program of the leaf.
+    pub vkey_hash: [u8; 32],
+    /// The siblings on the path from the node of the program, see [`hash_program_node`], to
+    /// `root`.
+    pub program_siblings: Vec<[u8; 32]>,
+    /// The index of the leaf in the batch.
+    pub index: u64,
+    /// The root of the batch.
+    pub root: [u8; 32],
+}
+
+impl ProgramProof {
+    /// Compute the root implied by the leaf, its path through the subtree of its program and the
+    /// path of the program.
+    pub fn compute_root(&self) -> Result<[u8; 32], MerkleError> {
+        Ok(process_program_proof(
+            self.subtree_proof.version,
+            &self.subtree_proof.hash_function,
+            &self.vkey_hash,
+            &self.subtree_proof.compute_root()?,
+            &self.program_siblings,
+        ))
+    }
+}
+
+/// Check that `proof` proves the inclusion of its leaf in the batch with the given `root`.
+pub fn verify_program_proof(proof: &ProgramProof, root: [u8; 32]) -> Result<(), MerkleError> {
+    if proof.root != root || proof.compute_root()? != root {
+        return Err(MerkleError::RootMismatch);
+    }
+    Ok(())
+}
+
+/// The program of the leaf at `index` in a batch whose programs hold `leaf_counts` leaves, and the
+/// index of the leaf in the subtree of that program.
+fn program_of(
+    leaf_counts: impl Iterator<Item = usize>,
+    index: usize,
+    leaf_count: usize,
+) -> Result<(usize, usize), MerkleError> {
+    check_index(index, leaf_count)?;
+    let mut offset = 0;
+    for (program, count) in leaf_counts.enumerate() {
+        if index < offset + count {
+            return Ok((program, index - offset));
+        }
+        offset += count;
+    }
+    unreachable!("the index was checked against the leaf count")
+}
+
+/// Extend the proof of a leaf against the subtree of the program at `program` into a proof against
+/// the root of the batch with the given subtree roots.
+fn program_proof(
+    version: TreeVersion,
+    hash_function: HashFunction,
+    programs: &[([u8; 32], [u8; 32])],
+    program: usize,
+    subtree_proof: MerkleProof,
+    index: usize,
+) -> ProgramProof {
+    // Lay out the top tree like the levels of a [`MerkleTree`], with the program nodes first.
+    let mut level: Vec<[u8; 32]> = programs
+        .iter()
+        .map(|(vkey_hash, subtree_root)| {
+            hash_program_node(version, &hash_function, vkey_hash, subtree_root)
+        })
+        .collect();
+    let mut nodes = level.clone();
+    while level.len() > 1 {
+        level = aggregation_merkle::next_layer(version, &hash_function, &level);
+        nodes.extend_from_slice(&level);
+    }
+
+    ProgramProof {
+        subtree_proof,
+        vkey_hash: programs[program].0,
+        program_siblings: path_siblings(programs.len(), program, |node| nodes[node]),
+        index: index as u64,
+        root: *level.last().unwrap(),
+    }
+}
+
+fn check_index(index: usize, leaf_count: usize) -> Result<(), MerkleError> {
+    match leaf_count {
+        0 => Err(MerkleError::EmptyTree),
+        _ if index >= leaf_count => Err(MerkleError::IndexOutOfBounds { index, leaf_count }),
+        _ => Ok(()),
+    }
+}
+
+/// The number of nodes of a tree with `leaf_count` leaves, counting the leaf nodes and the root.
+fn node_count(leaf_count: usize) -> usize {
+    let mut count = leaf_count;
+    let mut level_size = leaf_count;
+    while level_size > 1 {
+        level_size = (level_size + 1) / 2;
+        count += level_size;
+    }
+    count
+}
+
+/// The siblings on the path from the leaf at `index` to the root of a tree with `leaf_count`
+/// leaves, where `node` returns the node at a position of the layout of [`MerkleTree::tree`].
+fn path_siblings(
+    leaf_count: usize,
+    index: usize,
+    node: impl Fn(usize) -> [u8; 32],
+) -> Vec<[u8; 32]> {
+    let mut proof = Vec::new();
+    let mut current_index = index;
+    let mut level_start = 0;
+    let mut level_size = leaf_count;
+
+    while level_size > 1 {
+        let pair_index = if current_index % 2 == 0 {
+            current_index + 1
+        } else {
+            current_index - 1
+        };
+
+        if pair_index < level_size {
+            proof.push(node(level_start + pair_index));
         }
-        current_hash == self.root
+
+        current_index /= 2;
+        level_start += level_size;
+        level_size = (level_size + 1) / 2;
+    }
+
+    proof
+}
+
+/// The leaves of a batch after applying a [`DuplicateLeafPolicy`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchLeaves {
+    /// The leaves of the tree.
+    pub leaves: Vec<[u8; 32]>,
+    /// For each request of the batch, the index of its leaf in the tree, or `None` if the request
+    /// was rejected as a duplicate.
+    pub indices: Vec<Option<usize>>,
+    /// For each leaf of the tree, the index of the request whose proof is aggregated for it.
+    pub sources: Vec<usize>,
+}
+
+/// Lay out the leaves of the requests of a batch, in request order, according to `policy`.
+pub fn assign_leaves(requests: &[[u8; 32]], policy: DuplicateLeafPolicy) -> BatchLeaves {
+    let mut batch = BatchLeaves {
+        leaves: Vec::with_capacity(requests.len()),
+        indices: Vec::with_capacity(requests.len()),
+        sources: Vec::with_capacity(requests.len()),
+    };
+    let mut first_indices = HashMap::new();
+    for (i, leaf) in requests.iter().enumerate() {
+        let index = match (policy, first_indices.get(leaf)) {
+            (DuplicateLeafPolicy::Deduplicate, Some(&index)) => Some(index),
+            (DuplicateLeafPolicy::RejectDuplicates, Some(_)) => None,
+            _ => {
+                first_indices.entry(*leaf).or_insert(batch.leaves.len());
+                batch.leaves.push(*leaf);
+                batch.sources.push(i);
+                Some(batch.leaves.len() - 1)
+            }
+        };
+        batch.indices.push(index);
     }
+    batch
 }
@@ -0,0 +1,75 @@
+pub use aggregation_merkle::mmr::{mmr_proof, MerkleMountainRange};
+
+use crate::merkle_tree::{
+    MerkleError, MerkleHasher, MerkleProof, NodeOrdering, ProgramProof, TreeVersion,
+};
+
+/// Extend a proof against the root of the batch at `batch_index` of the accumulator over
+/// `batch_roots` into a proof against the root of the accumulator.
+pub fn accumulator_proof(
+    proof: MerkleProof,
+    batch_roots: &[[u8; 32]],
+    batch_index: usize,
+) -> Result<MerkleProof, MerkleError> {
+    if proof.ordering != NodeOrdering::Sorted {
+        return Err(MerkleError::HashSchemeMismatch);
+    }
+    let (mmr_siblings, root) = accumulator_siblings(
+        proof.version,
+        &proof.hash_function,
+        proof.root,
+        batch_roots,
+        batch_index,
+    )?;
+    Ok(MerkleProof {
+        siblings: [proof.siblings, mmr_siblings].concat(),
+        root,
+        ..proof
+    })
+}
+
+/// Extend the path of the program of a proof against the root of the batch at `batch_index` of
+/// the accumulator over `batch_roots` into a path to the root of the accumulator.
+pub fn accumulator_program_proof(
+    proof: ProgramProof,
+    batch_roots: &[[u8; 32]],
+    batch_index: usize,
+) -> Result<ProgramProof, MerkleError> {
+    let (mmr_siblings, root) = accumulator_siblings(
+        proof.subtree_proof.version,
+        &proof.subtree_proof.hash_function,
+        proof.root,
+        batch_roots,
+        batch_index,
+    )?;
+    Ok(ProgramProof {
+        program_siblings: [proof.program_siblings, mmr_siblings].concat(),
+        root,
+        ..proof
+    })
+}
+
+/// The siblings of `batch_root`, the root of the batch at `batch_index` of the accumulator over
+/// `batch_roots`, up to the root of the accumulator, and that root. Any proof against the batch
+/// root, such as a multiproof, continues with them.
+pub fn accumulator_siblings<H: MerkleHasher + ?Sized>(
+    version: TreeVersion,
+    hasher: &H,
+    batch_root: [u8; 32],
+    batch_roots: &[[u8; 32]],
+    batch_index: usize,
+) -> Result<(Vec<[u8; 32]>, [u8; 32]), MerkleError> {
+    let expected_root = batch_roots
+        .get(batch_index)
+        .ok_or(MerkleError::IndexOutOfBounds {
+            index: batch_index,
+            leaf_count: batch_roots.len(),
+        })?;
+    if *expected_root != batch_root {
+        return Err(MerkleError::RootMismatch);
+    }
+    let mmr_siblings =
+        mmr_proof(version, hasher, batch_roots, batch_index).expect("batch index is in bounds");
+    let root = MerkleMountainRange::from_leaves(version, hasher, batch_roots).root(version, hasher);
+    Ok((mmr_siblings, root))
+}
@@ -0,0 +1,48 @@
+//! Proof ids derived from the request, so that retrying a request is idempotent.
+
+use crate::merkle_tree::{HashFunction, LeafFormat, MerkleHasher};
+
+/// The tag the preimage of a proof id derived from the content of a request starts with.
+pub const CONTENT_PROOF_ID_TAG: [u8; 32] = *b"aggregation-service/proof-id/002";
+
+/// The tag the preimage of a proof id derived from an idempotency key starts with.
+pub const KEYED_PROOF_ID_TAG: [u8; 32] = *b"aggregation-service/proof-key/01";
+
+/// The proof id of a request for a proof of the program with the given vkey hash and public
+/// values, whose encoding is `proof`, aggregated in `leaf_format` and bound to `submitter`:
+/// `sha256(CONTENT_PROOF_ID_TAG || vkey_hash || sha256(public_values) || sha256(proof) ||
+/// leaf_format || sha256(submitter))`, with the leaf format as one byte. Requests for the same
+/// proof with another leaf or submitter get proof ids of their own.
+pub fn content_proof_id(
+    vkey_hash: &[u8; 32],
+    public_values: &[u8],
+    proof: &[u8],
+    leaf_format: LeafFormat,
+    submitter: &[u8],
+) -> [u8; 32] {
+    let sha256 = HashFunction::Sha256;
+    sha256.hash(&[
+        CONTENT_PROOF_ID_TAG.as_slice(),
+        vkey_hash.as_slice(),
+        sha256.hash(&[public_values]).as_slice(),
+        sha256.hash(&[proof]).as_slice(),
+        &[leaf_format as u8],
+        sha256.hash(&[submitter]).as_slice(),
+    ])
+}
+
+/// The proof id of a request a tenant submits with an idempotency key:
+/// `sha256(KEYED_PROOF_ID_TAG || len(tenant) || tenant || idempotency_key)`, with the length as
+/// four big-endian bytes so that the keys of different tenants can't collide.
+///
+/// The service doesn't authenticate the tenant of a request, so the tenant only keeps the keys of
+/// honest clients apart: anyone who knows the tenant and key of a request can submit another
+/// request under its proof id, which is rejected, or retry it and learn its status.
+pub fn keyed_proof_id(tenant: &str, idempotency_key: &[u8]) -> [u8; 32] {
+    HashFunction::Sha256.hash(&[
+        KEYED_PROOF_ID_TAG.as_slice(),
+        (tenant.len() as u32).to_be_bytes().as_slice(),
+        tenant.as_bytes(),
+        idempotency_key,
+    ])
+}
@@ -0,0 +1 @@
+pub use aggregation_merkle::public_values::AggregationPublicValues;
@@ -0,0 +1,371 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use aggregation_merkle::{LEAF_PREFIX, NODE_PREFIX};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    aggregation::{GetProofIdProofResponse, ProofIdState},
+    merkle_tree::{HashFunction, MerkleError, MerkleHasher},
+};
+
+/// The number of levels of the tree, one for each bit of a key.
+pub const DEPTH: usize = 256;
+
+/// The hash of an empty subtree at any level.
+pub const EMPTY: [u8; 32] = [0; 32];
+
+/// A sparse Merkle tree keyed by proof id, which proves both that a proof id has a value and
+/// that it has none.
+///
+/// Leaves are `hash(0x00 || key || value)` and sit at the position given by the bits of their key,
+/// most significant first. Internal nodes are `hash(0x01 || left || right)`, except that a node
+/// whose children are both empty is empty, so the tree only hashes the paths of its keys.
+#[derive(Debug, Clone, Default)]
+pub struct SparseMerkleTree {
+    pub hash_function: HashFunction,
+    leaves: BTreeMap<[u8; 32], [u8; 32]>,
+}
+
+/// A proof of the value of a key in a [`SparseMerkleTree`], or of its absence.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SparseMerkleProof {
+    /// The proven key.
+    pub key: [u8; 32],
+    /// The value of the key, or `None` for a proof of absence.
+    pub value: Option<[u8; 32]>,
+    /// Bit `i`, most significant first, says whether the sibling at depth `i` is not empty.
+    pub bitmap: [u8; 32],
+    /// The siblings that are not empty, from the root down to the leaf.
+    pub siblings: Vec<[u8; 32]>,
+    /// The hash function of the tree.
+    pub hash_function: HashFunction,
+}
+
+/// The position of a node of a [`SparseMerkleTree`]: its depth, and the first `depth` bits of the
+/// keys under it followed by zeroes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NodePosition {
+    pub depth: usize,
+    pub path: [u8; 32],
+}
+
+/// The nodes of a [`SparseMerkleTree`] stored elsewhere, e.g. in a database, that setting the
+/// values of some keys reads and writes, so that the tree can be updated without loading it whole.
+///
+/// Load the nodes at [`SparseMerklePatch::positions`] of the keys to update, update the keys, then
+/// store the [`SparseMerklePatch::changes`].
+#[derive(Debug, Clone)]
+pub struct SparseMerklePatch {
+    pub hash_function: HashFunction,
+    nodes: BTreeMap<NodePosition, [u8; 32]>,
+    changed: BTreeSet<NodePosition>,
+}
+
+fn bit(bytes: &[u8; 32], index: usize) -> bool {
+    bytes[index / 8] & (0x80 >> (index % 8)) != 0
+}
+
+fn hash_leaf_node(hash_function: HashFunction, key: &[u8; 32], value: &[u8; 32]) -> [u8; 32] {
+    hash_function.hash(&[&[LEAF_PREFIX], key, value])
+}
+
+fn hash_node(hash_function: HashFunction, left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    if *left == EMPTY && *right == EMPTY {
+        EMPTY
+    } else {
+        hash_function.hash(&[&[NODE_PREFIX], left, right])
+    }
+}
+
+impl SparseMerkleTree {
+    pub fn new(hash_function: HashFunction) -> Self {
+        Self {
+            hash_function,
+            leaves: BTreeMap::new(),
+        }
+    }
+
+    /// Set the value of `key`.
+    pub fn insert(&mut self, key: [u8; 32], value: [u8; 32]) {
+        self.leaves.insert(key, value);
+    }
+
+    /// Remove `key` from the tree, returning its value.
+    pub fn remove(&mut self, key: &[u8; 32]) -> Option<[u8; 32]> {
+        self.leaves.remove(key)
+    }
+
+    pub fn get(&self, key: &[u8; 32]) -> Option<[u8; 32]> {
+        self.leaves.get(key).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.leaves.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leaves.is_empty()
+    }
+
+    /// The root of the tree. The root of an empty tree is all zeroes.
+    pub fn root(&self) -> [u8; 32] {
+        let leaves = self.leaves.iter().collect::<Vec<_>>();
+        self.subtree_root(&leaves, 0)
+    }
+
+    /// The root of the subtree at `depth` holding `leaves`, which are sorted by key.
+    fn subtree_root(&self, leaves: &[(&[u8; 32], &[u8; 32])], depth: usize) -> [u8; 32] {
+        match leaves {
+            [] => EMPTY,
+            [(key, value)] if depth == DEPTH => hash_leaf_node(self.hash_function, key, value),
+            _ => {
+                let (left, right) =
+                    leaves.split_at(leaves.partition_point(|(key, _)| !bit(key, depth)));
+                hash_node(
+                    self.hash_function,
+                    &self.subtree_root(left, depth + 1),
+                    &self.subtree_root(right, depth + 1),
+                )
+            }
+        }
+    }
+
+    /// Generate a proof of the value of `key`, or of its absence if it is not in the tree.
+    pub fn generate_proof(&self, key: [u8; 32]) -> SparseMerkleProof {
+        let mut leaves = &self.leaves.iter().collect::<Vec<_>>()[..];
+        let mut bitmap = [0u8; 32];
+        let mut siblings = Vec::new();
+        for depth in 0..DEPTH {
+            let (left, right) =
+                leaves.split_at(leaves.partition_point(|(key, _)| !bit(key, depth)));
+            let (path, other) = if bit(&key, depth) {
+                (right, left)
+            } else {
+                (left, right)
+            };
+            let sibling = self.subtree_root(other, depth + 1);
+            if sibling != EMPTY {
+                bitmap[depth / 8] |= 0x80 >> (depth % 8);
+                siblings.push(sibling);
+            }
+            leaves = path;
+        }
+        SparseMerkleProof {
+            key,
+            value: self.get(&key),
+            bitmap,
+            siblings,
+            hash_function: self.hash_function,
+        }
+    }
+}
+
+impl NodePosition {
+    /// The root of the tree.
+    pub const ROOT: Self = Self {
+        depth: 0,
+        path: [0; 32],
+    };
+
+    /// The position of the node at `depth` on the path of `key`.
+    pub fn new(key: &[u8; 32], depth: usize) -> Self {
+        let mut path = [0u8; 32];
+        for (index, byte) in path.iter_mut().enumerate().take(depth.div_ceil(8)) {
+            let bits = (depth - index * 8).min(8);
+            *byte = key[index] & (0xff00u16 >> bits) as u8;
+        }
+        Self { depth, path }
+    }
+
+    /// The position of the node sharing the parent of this one, which must not be the root.
+    pub fn sibling(&self) -> Self {
+        let mut path = self.path;
+        let index = self.depth - 1;
+        path[index / 8] ^= 0x80 >> (index % 8);
+        Self {
+            depth: self.depth,
+            path,
+        }
+    }
+
+    /// The positions of the siblings on the path of `key`, from the root down to the leaf, which
+    /// is the order of the siblings of a [`SparseMerkleProof`].
+    pub fn siblings(key: &[u8; 32]) -> Vec<Self> {
+        (1..=DEPTH)
+            .map(|depth| Self::new(key, depth).sibling())
+            .collect()
+    }
+}
+
+impl SparseMerklePatch {
+    /// The positions of the nodes to load to update `keys`.
+    pub fn positions(keys: &[[u8; 32]]) -> Vec<NodePosition> {
+        keys.iter()
+            .flat_map(NodePosition::siblings)
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect()
+    }
+
+    /// Start a patch from the stored nodes at the [`SparseMerklePatch::positions`] of the keys to
+    /// update. Positions without a stored node are empty.
+    pub fn new(
+        hash_function: HashFunction,
+        nodes: impl IntoIterator<Item = (NodePosition, [u8; 32])>,
+    ) -> Self {
+        Self {
+            hash_function,
+            nodes: nodes.into_iter().collect(),
+            changed: BTreeSet::new(),
+        }
+    }
+
+    fn node(&self, position: &NodePosition) -> [u8; 32] {
+        self.nodes.get(position).copied().unwrap_or(EMPTY)
+    }
+
+    /// Set the value of `key`, or remove it if `value` is `None`. The nodes at the positions of
+    /// `key` must have been loaded.
+    pub fn update(&mut self, key: [u8; 32], value: Option<[u8; 32]>) {
+        let siblings = NodePosition::siblings(&key)
+            .iter()
+            .map(|position| self.node(position))
+            .collect();
+        let path = SparseMerkleProof::from_siblings(key, value, siblings, self.hash_function)
+            .path()
+            .expect("the bitmap matches the siblings");
+        for (height, node) in path.into_iter().enumerate() {
+            let position = NodePosition::new(&key, DEPTH - height);
+            self.nodes.insert(position, node);
+            self.changed.insert(position);
+        }
+    }
+
+    /// The root of the tree after the updates.
+    pub fn root(&self) -> [u8; 32] {
+        self.node(&NodePosition::ROOT)
+    }
+
+    /// The nodes the updates changed, which are [`EMPTY`] if they are no longer stored.
+    pub fn changes(&self) -> impl Iterator<Item = (NodePosition, [u8; 32])> + '_ {
+        self.changed
+            .iter()
+            .map(|position| (*position, self.node(position)))
+    }
+}
+
+impl SparseMerkleProof {
+    /// Build the proof of `key` from every sibling on its path from the root down, empty or not,
+    /// e.g. as loaded from the [`NodePosition::siblings`] of a stored tree.
+    pub fn from_siblings(
+        key: [u8; 32],
+        value: Option<[u8; 32]>,
+        siblings: Vec<[u8; 32]>,
+        hash_function: HashFunction,
+    ) -> Self {
+        let mut bitmap = [0u8; 32];
+        for (depth, sibling) in siblings.iter().enumerate() {
+            if *sibling != EMPTY {
+                bitmap[depth / 8] |= 0x80 >> (depth % 8);
+            }
+        }
+        Self {
+            key,
+            value,
+            bitmap,
+            siblings: siblings
+                .into_iter()
+                .filter(|sibling| *sibling != EMPTY)
+                .collect(),
+            hash_function,
+        }
+    }
+
+    /// Compute the root implied by the proof. The proof is malformed if the bitmap and the
+    /// siblings disagree.
+    pub fn compute_root(&self) -> Result<[u8; 32], MerkleError> {
+        Ok(*self.path()?.last().expect("the path ends at the root"))
+    }
+
+    /// Compute the nodes on the path of the key implied by the proof, from the leaf up to the
+    /// root.
+    pub fn path(&self) -> Result<Vec<[u8; 32]>, MerkleError> {
+        let sibling_count = self
+            .bitmap
+            .iter()
+            .map(|byte| byte.count_ones())
+            .sum::<u32>();
+        if sibling_count as usize != self.siblings.len() {
+            return Err(MerkleError::MalformedProof);
+        }
+        let mut node = match self.value {
+            Some(value) => hash_leaf_node(self.hash_function, &self.key, &value),
+            None => EMPTY,
+        };
+        let mut path = vec![node];
+        let mut siblings = self.siblings.iter().rev();
+        for depth in (0..DEPTH).rev() {
+            let sibling = if bit(&self.bitmap, depth) {
+                *siblings.next().expect("one sibling per set bit")
+            } else {
+                EMPTY
+            };
+            node = if bit(&self.key, depth) {
+                hash_node(self.hash_function, &sibling, &node)
+            } else {
+                hash_node(self.hash_function, &node, &sibling)
+            };
+            path.push(node);
+        }
+        Ok(path)
+    }
+
+    /// Rebuild the proof for `proof_id` returned by the `GetProofIdProof` RPC.
+    pub fn from_response(
+        proof_id: [u8; 32],
+        response: &GetProofIdProofResponse,
+    ) -> Result<Self, MerkleError> {
+        let hash_function = u8::try_from(response.hash_function)
+            .ok()
+            .and_then(|hash_function| HashFunction::try_from(hash_function).ok())
+            .ok_or(MerkleError::InvalidEncoding)?;
+        let value = match response.state() {
+            ProofIdState::Absent => None,
+            state => Some(proof_id_value(hash_function, state, &response.batch_id)),
+        };
+        Ok(Self {
+            key: proof_id,
+            value,
+            bitmap: response
+                .bitmap
+                .as_slice()
+                .try_into()
+                .or(Err(MerkleError::InvalidEncoding))?,
+            siblings: response
+                .siblings
+                .iter()
+                .map(|sibling| sibling.as_slice().try_into())
+                .collect::<Result<Vec<[u8; 32]>, _>>()
+                .or(Err(MerkleError::InvalidEncoding))?,
+            hash_function,
+        })
+    }
+}
+
+/// Check that `proof` proves the value, or the absence, of its key in the tree with the given
+/// `root`.
+pub fn verify_sparse(proof: &SparseMerkleProof, root: [u8; 32]) -> Result<(), MerkleError> {
+    if proof.compute_root()? != root {
+        return Err(MerkleError::RootMismatch);
+    }
+    Ok(())
+}
+
+/// The value of a proof id in the tree of proof ids: `hash(state || batch_id)`.
+pub fn proof_id_value(
+    hash_function: HashFunction,
+    state: ProofIdState,
+    batch_id: &[u8],
+) -> [u8; 32] {
+    hash_function.hash(&[&[state as u8], batch_id])
+}
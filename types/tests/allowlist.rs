@@ -0,0 +1,26 @@
+use types::merkle_tree::{allowlist::Allowlist, HashFunction, TreeVersion};
+
+#[test]
+fn test_allowlist() {
+    let allowlist = Allowlist::new(vec![[3; 32], [1; 32], [2; 32], [1; 32]]);
+    assert_eq!(allowlist.vkeys(), &[[1; 32], [2; 32], [3; 32]]);
+    assert!(allowlist.contains(&[2; 32]));
+    assert!(!allowlist.contains(&[4; 32]));
+
+    // The root does not depend on the order the programs were registered in.
+    for hash_function in [HashFunction::Keccak256, HashFunction::Sha256] {
+        assert_eq!(
+            allowlist.root(TreeVersion::LATEST, &hash_function),
+            Allowlist::new(vec![[2; 32], [3; 32], [1; 32]])
+                .root(TreeVersion::LATEST, &hash_function)
+        );
+        assert_ne!(
+            allowlist.root(TreeVersion::LATEST, &hash_function),
+            Allowlist::new(vec![[1; 32], [2; 32]]).root(TreeVersion::LATEST, &hash_function)
+        );
+        assert_eq!(
+            Allowlist::default().root(TreeVersion::LATEST, &hash_function),
+            [0; 32]
+        );
+    }
+}
@@ -0,0 +1,55 @@
+use sha2::{Digest, Sha256};
+use types::{
+    aggregation,
+    merkle_tree::{bind_submitter, hash_digest_leaf, hash_leaf, LeafFormat},
+};
+
+/// The leaf `SP1AggregationVerifier.digestLeaf` computes for the same inputs.
+const DIGEST_LEAF: &str = "10ba3b57768d56e6ce540c6acdc55f55a9ef8b587a6eb82c13cbeef40cdd838c";
+
+/// The leaf `SP1AggregationVerifier.submitterLeaf` computes when binding `DIGEST_LEAF` to the
+/// address `0x1111111111111111111111111111111111111111`.
+const SUBMITTER_LEAF: &str = "994ad3d7300fa6c0f7f633af7fdde99d16a072925aca4a9dc45f4d5615b163a6";
+
+#[test]
+fn test_digest_leaf() {
+    let vkey_hash = [1; 32];
+    let public_values = b"hello";
+    let digest: [u8; 32] = Sha256::digest(public_values).into();
+
+    let leaf = hash_digest_leaf(&vkey_hash, &digest);
+    assert_eq!(hex::encode(leaf), DIGEST_LEAF);
+    assert_eq!(LeafFormat::Digest.hash_leaf(&vkey_hash, public_values), leaf);
+    assert_eq!(
+        LeafFormat::Full.hash_leaf(&vkey_hash, public_values),
+        hash_leaf(&vkey_hash, public_values)
+    );
+
+    // A digest leaf can't be presented as a full leaf over the digest.
+    assert_ne!(hash_leaf(&vkey_hash, &digest), leaf);
+}
+
+#[test]
+fn test_leaf_format_conversions() {
+    for format in [LeafFormat::Full, LeafFormat::Digest] {
+        assert_eq!(LeafFormat::try_from(format as u8), Ok(format));
+        assert_eq!(
+            LeafFormat::from(aggregation::LeafFormat::from(format)),
+            format
+        );
+        assert_eq!(aggregation::LeafFormat::from(format) as u8, format as u8);
+    }
+    assert_eq!(LeafFormat::try_from(2), Err(2));
+}
+
+#[test]
+fn test_bind_submitter() {
+    let leaf: [u8; 32] = hex::decode(DIGEST_LEAF).unwrap().try_into().unwrap();
+    let submitter = [0x11; 20];
+    let bound = bind_submitter(&leaf, &submitter);
+    assert_eq!(hex::encode(bound), SUBMITTER_LEAF);
+    assert_eq!(bind_submitter(&leaf, &[]), leaf);
+
+    // Identical statements of different submitters get different leaves.
+    assert_ne!(bind_submitter(&leaf, &[0x22; 20]), bound);
+}
@@ -0,0 +1,468 @@
+//! Checks that the guest, the host, `SP1AggregationVerifier.verifyMerkleProof`,
+//! `SP1AggregationVerifier.verifySubtreeRoot` and `SP1AggregationVerifier.verifyMultiProof` agree on the vectors in
+//! `contracts/src/fixtures/merkle-fixture.json`, which the contract tests also use.
+
+use serde::Deserialize;
+use sha2::Digest;
+use tiny_keccak::{Hasher, Keccak};
+use types::merkle_tree::{
+    compute_root, hash_leaf, process_multiproof,
+    subtree::{hash_program_node, program_root},
+    verify_program_proof, HashFunction, Keccak256, MerkleTree, MultiProof, ProgramTree, Sha256,
+    TreeVersion,
+};
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MerkleFixture {
+    vkeys: Vec<String>,
+    public_values: Vec<String>,
+    leaves: Vec<String>,
+    root: String,
+    subtree_proofs: Vec<Vec<String>>,
+    program_proofs: Vec<Vec<String>>,
+    multiproofs: Vec<MultiproofFixture>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MultiproofFixture {
+    vkey: String,
+    leaves: Vec<String>,
+    indices: Vec<u64>,
+    leaf_count: u64,
+    proof: Vec<String>,
+    proof_flags: Vec<bool>,
+    program_proof: Vec<String>,
+    root: String,
+}
+
+fn from_hex(s: &str) -> Vec<u8> {
+    hex::decode(s.trim_start_matches("0x")).unwrap()
+}
+
+fn from_hex_32(s: &str) -> [u8; 32] {
+    from_hex(s).try_into().unwrap()
+}
+
+fn load_fixture() -> MerkleFixture {
+    let path = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/../contracts/src/fixtures/merkle-fixture.json"
+    );
+    serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap()
+}
+
+/// A line-by-line port of `SP1AggregationVerifier.verifyMerkleProof`, for a contract deployed with
+/// the given `merkleHashFunction`.
+fn solidity_verify_merkle_proof(
+    hash_function: u8,
+    program_vkey: [u8; 32],
+    leaf: [u8; 32],
+    subtree_proof: &[[u8; 32]],
+    program_proof: &[[u8; 32]],
+    root: [u8; 32],
+) -> bool {
+    let subtree_root = solidity_process_proof(hash_function, leaf, subtree_proof);
+    solidity_process_program_proof(
+        hash_function,
+        program_vkey,
+        subtree_root,
+        program_proof,
+        root,
+    )
+}
+
+/// The node the loop of `SP1AggregationVerifier.verifyMerkleProof` computes from a leaf and its
+/// path through the subtree of its program.
+fn solidity_process_proof(hash_function: u8, leaf: [u8; 32], proof: &[[u8; 32]]) -> [u8; 32] {
+    let hash_node = |chunks: &[&[u8]]| match hash_function {
+        0 => keccak256(chunks),
+        _ => sha256(chunks),
+    };
+    let mut computed_hash = hash_node(&[&[0x00], &leaf]);
+    for sibling in proof {
+        if computed_hash < *sibling {
+            computed_hash = hash_node(&[&[0x01], &computed_hash, sibling]);
+        } else {
+            computed_hash = hash_node(&[&[0x01], sibling, &computed_hash]);
+        }
+    }
+    computed_hash
+}
+
+/// A line-by-line port of `SP1AggregationVerifier.processProgramProof`, checked against `root`
+/// instead of the known roots of the contract.
+fn solidity_process_program_proof(
+    hash_function: u8,
+    program_vkey: [u8; 32],
+    subtree_root: [u8; 32],
+    program_proof: &[[u8; 32]],
+    root: [u8; 32],
+) -> bool {
+    let hash_node = |chunks: &[&[u8]]| match hash_function {
+        0 => keccak256(chunks),
+        _ => sha256(chunks),
+    };
+    let mut computed_hash = hash_node(&[&[0x02], &program_vkey, &subtree_root]);
+    for sibling in program_proof {
+        if computed_hash < *sibling {
+            computed_hash = hash_node(&[&[0x01], &computed_hash, sibling]);
+        } else {
+            computed_hash = hash_node(&[&[0x01], sibling, &computed_hash]);
+        }
+    }
+    computed_hash == root
+}
+
+/// A line-by-line port of `SP1AggregationVerifier.hashPair` for a Keccak-256 contract.
+fn solidity_hash_pair(a: [u8; 32], b: [u8; 32]) -> [u8; 32] {
+    if a < b {
+        keccak256(&[&[0x01], &a, &b])
+    } else {
+        keccak256(&[&[0x01], &b, &a])
+    }
+}
+
+/// A line-by-line port of `SP1AggregationVerifier.processMultiProof` for a Keccak-256 contract,
+/// with `None` where the contract reverts.
+fn solidity_process_multiproof(multi_proof: &MultiProof) -> Option<[u8; 32]> {
+    let mut count = multi_proof.leaves.len();
+    if count == 0 || count != multi_proof.indices.len() {
+        return None;
+    }
+    let mut nodes = vec![[0u8; 32]; count];
+    let mut positions = vec![0u64; count];
+    for i in 0..count {
+        if i != 0 && multi_proof.indices[i - 1] >= multi_proof.indices[i] {
+            return None;
+        }
+        nodes[i] = keccak256(&[&[0x00], &multi_proof.leaves[i]]);
+        positions[i] = multi_proof.indices[i];
+    }
+    if positions[count - 1] >= multi_proof.leaf_count {
+        return None;
+    }
+
+    let (mut proof_pos, mut flag_pos) = (0, 0);
+    let mut level_size = multi_proof.leaf_count;
+    while level_size > 1 {
+        let mut next = 0;
+        let mut i = 0;
+        while i < count {
+            let position = positions[i];
+            let mut node = nodes[i];
+            if position == level_size - 1 && level_size % 2 == 1 {
+                i += 1;
+            } else {
+                let sibling_is_known = i + 1 < count && positions[i + 1] == position ^ 1;
+                if flag_pos >= multi_proof.proof_flags.len()
+                    || multi_proof.proof_flags[flag_pos] != sibling_is_known
+                {
+                    return None;
+                }
+                flag_pos += 1;
+                let sibling = if sibling_is_known {
+                    i += 2;
+                    nodes[i - 1]
+                } else {
+                    if proof_pos >= multi_proof.proof.len() {
+                        return None;
+                    }
+                    proof_pos += 1;
+                    i += 1;
+                    multi_proof.proof[proof_pos - 1]
+                };
+                node = solidity_hash_pair(node, sibling);
+            }
+            nodes[next] = node;
+            positions[next] = position / 2;
+            next += 1;
+        }
+        count = next;
+        level_size = (level_size + 1) / 2;
+    }
+    if proof_pos != multi_proof.proof.len() || flag_pos != multi_proof.proof_flags.len() {
+        return None;
+    }
+    Some(nodes[0])
+}
+
+/// A line-by-line port of OpenZeppelin's `MerkleProof.processMultiProof`.
+fn openzeppelin_process_multiproof(
+    leaves: &[[u8; 32]],
+    proof: &[[u8; 32]],
+    proof_flags: &[bool],
+) -> [u8; 32] {
+    assert_eq!(leaves.len() + proof.len(), proof_flags.len() + 1);
+    let mut hashes = Vec::with_capacity(proof_flags.len());
+    let (mut leaf_pos, mut hash_pos, mut proof_pos) = (0, 0, 0);
+    let mut next = |hashes: &Vec<[u8; 32]>| {
+        if leaf_pos < leaves.len() {
+            leaf_pos += 1;
+            leaves[leaf_pos - 1]
+        } else {
+            hash_pos += 1;
+            hashes[hash_pos - 1]
+        }
+    };
+    for &flag in proof_flags {
+        let a = next(&hashes);
+        let b = if flag {
+            next(&hashes)
+        } else {
+            proof_pos += 1;
+            proof[proof_pos - 1]
+        };
+        let hash = if a < b {
+            keccak256(&[&a, &b])
+        } else {
+            keccak256(&[&b, &a])
+        };
+        hashes.push(hash);
+    }
+    match (hashes.last(), leaves.first()) {
+        (Some(hash), _) => *hash,
+        (None, Some(leaf)) => *leaf,
+        (None, None) => proof[0],
+    }
+}
+
+fn keccak256(chunks: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = Keccak::v256();
+    let mut output = [0u8; 32];
+    for chunk in chunks {
+        hasher.update(chunk);
+    }
+    hasher.finalize(&mut output);
+    output
+}
+
+fn sha256(chunks: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = sha2::Sha256::new();
+    for chunk in chunks {
+        hasher.update(chunk);
+    }
+    hasher.finalize().into()
+}
+
+#[test]
+fn test_fixture_leaves_match_guest_and_host() {
+    let fixture = load_fixture();
+    for ((vkey, public_values), leaf) in fixture
+        .vkeys
+        .iter()
+        .zip(fixture.public_values.iter())
+        .zip(fixture.leaves.iter())
+    {
+        let leaf_hash = hash_leaf(&from_hex_32(vkey), &from_hex(public_values));
+        assert_eq!(leaf_hash, from_hex_32(leaf));
+    }
+}
+
+/// The leaves of the fixture with the vkeys of their programs.
+fn fixture_program_leaves(fixture: &MerkleFixture) -> Vec<([u8; 32], [u8; 32])> {
+    fixture
+        .vkeys
+        .iter()
+        .zip(fixture.leaves.iter())
+        .map(|(vkey, leaf)| (from_hex_32(vkey), from_hex_32(leaf)))
+        .collect()
+}
+
+#[test]
+fn test_fixture_root_matches_guest_and_host() {
+    let fixture = load_fixture();
+    let leaves = fixture_program_leaves(&fixture);
+    let root = from_hex_32(&fixture.root);
+
+    // The guest computes the root of the subtree of each program, then the top tree over them.
+    let mut subtree_roots: Vec<([u8; 32], [u8; 32])> = Vec::new();
+    for group in leaves.chunk_by(|a, b| a.0 == b.0) {
+        let group_leaves: Vec<[u8; 32]> = group.iter().map(|(_, leaf)| *leaf).collect();
+        subtree_roots.push((
+            group[0].0,
+            compute_root(TreeVersion::LATEST, &Keccak256, &group_leaves),
+        ));
+    }
+    assert!(subtree_roots.len() > 1);
+    assert_eq!(
+        program_root(TreeVersion::LATEST, &Keccak256, &subtree_roots),
+        root
+    );
+    assert_eq!(
+        ProgramTree::new(&leaves, TreeVersion::LATEST, HashFunction::Keccak256)
+            .unwrap()
+            .root,
+        root
+    );
+}
+
+#[test]
+fn test_fixture_proofs_match_host_and_contract() {
+    let fixture = load_fixture();
+    let leaves = fixture_program_leaves(&fixture);
+    let root = from_hex_32(&fixture.root);
+    let tree = ProgramTree::new(&leaves, TreeVersion::LATEST, HashFunction::Keccak256).unwrap();
+
+    for (index, (vkey, leaf)) in leaves.iter().enumerate() {
+        let subtree_proof: Vec<[u8; 32]> = fixture.subtree_proofs[index]
+            .iter()
+            .map(|p| from_hex_32(p))
+            .collect();
+        let program_proof: Vec<[u8; 32]> = fixture.program_proofs[index]
+            .iter()
+            .map(|p| from_hex_32(p))
+            .collect();
+        let proof = tree.generate_proof_at(index).unwrap();
+        assert_eq!(proof.subtree_proof.siblings, subtree_proof);
+        assert_eq!(proof.program_siblings, program_proof);
+        assert!(verify_program_proof(&proof, root).is_ok());
+        assert!(solidity_verify_merkle_proof(
+            0,
+            *vkey,
+            *leaf,
+            &subtree_proof,
+            &program_proof,
+            root
+        ));
+
+        // The path of another program doesn't prove the subtree root.
+        let (other_vkey, _) = leaves.iter().find(|(other, _)| other != vkey).unwrap();
+        assert!(!solidity_verify_merkle_proof(
+            0,
+            *other_vkey,
+            *leaf,
+            &subtree_proof,
+            &program_proof,
+            root
+        ));
+    }
+}
+
+#[test]
+fn test_guest_and_host_roots_agree() {
+    for hash_function in [HashFunction::Keccak256, HashFunction::Sha256] {
+        for n in 0..=17u8 {
+            let leaves: Vec<[u8; 32]> = (0..n).map(|i| hash_leaf(&[i; 32], &[i, n])).collect();
+            let tree =
+                MerkleTree::with_hash_function(leaves.clone(), TreeVersion::LATEST, hash_function);
+            assert_eq!(
+                compute_root(TreeVersion::LATEST, &hash_function, &leaves),
+                tree.root
+            );
+            for leaf in &leaves {
+                let proof = tree.generate_proof(*leaf).unwrap();
+                assert_eq!(
+                    solidity_process_proof(hash_function as u8, *leaf, &proof.siblings),
+                    tree.root
+                );
+            }
+
+            // The contract hashes the node of a program as the guest does.
+            let vkey = [n; 32];
+            assert!(solidity_process_program_proof(
+                hash_function as u8,
+                vkey,
+                tree.root,
+                &[],
+                hash_program_node(TreeVersion::LATEST, &hash_function, &vkey, &tree.root)
+            ));
+        }
+    }
+}
+
+#[test]
+fn test_hashers_match_reference_implementations() {
+    let leaves: Vec<[u8; 32]> = (0..2u8).map(|i| hash_leaf(&[i; 32], &[i])).collect();
+    let (a, b) = (
+        keccak256(&[&[0x00], &leaves[0]]),
+        keccak256(&[&[0x00], &leaves[1]]),
+    );
+    let (a, b) = if a < b { (a, b) } else { (b, a) };
+    assert_eq!(
+        compute_root(TreeVersion::LATEST, &Keccak256, &leaves),
+        keccak256(&[&[0x01], &a, &b])
+    );
+
+    let (a, b) = (
+        sha256(&[&[0x00], &leaves[0]]),
+        sha256(&[&[0x00], &leaves[1]]),
+    );
+    let (a, b) = if a < b { (a, b) } else { (b, a) };
+    assert_eq!(
+        compute_root(TreeVersion::LATEST, &Sha256, &leaves),
+        sha256(&[&[0x01], &a, &b])
+    );
+}
+
+#[test]
+fn test_multiproofs_match_openzeppelin() {
+    // OpenZeppelin trees have no promoted nodes, so V0 multiproofs over complete trees must verify
+    // with `multiProofVerify` as is.
+    for n in [1u8, 2, 4, 8] {
+        let leaves: Vec<[u8; 32]> = (0..n).map(|i| hash_leaf(&[i; 32], &[i, n])).collect();
+        let tree = MerkleTree::with_version(leaves.clone(), TreeVersion::V0);
+        for mask in 1u32..(1 << n) {
+            let subset: Vec<[u8; 32]> = (0..n as usize)
+                .filter(|i| mask & (1 << i) != 0)
+                .map(|i| leaves[i])
+                .collect();
+            let proof = tree.generate_multiproof(&subset).unwrap();
+            assert_eq!(
+                openzeppelin_process_multiproof(&proof.leaves, &proof.proof, &proof.proof_flags),
+                tree.root
+            );
+        }
+    }
+}
+
+#[test]
+fn test_fixture_multiproofs_match_host_and_contract() {
+    let fixture = load_fixture();
+    assert!(!fixture.multiproofs.is_empty());
+    for multiproof in &fixture.multiproofs {
+        let proof = MultiProof {
+            leaf_count: multiproof.leaf_count,
+            indices: multiproof.indices.clone(),
+            leaves: multiproof.leaves.iter().map(|l| from_hex_32(l)).collect(),
+            proof: multiproof.proof.iter().map(|p| from_hex_32(p)).collect(),
+            proof_flags: multiproof.proof_flags.clone(),
+        };
+        let subtree_root = process_multiproof(TreeVersion::LATEST, &Keccak256, &proof).unwrap();
+        assert_eq!(solidity_process_multiproof(&proof), Some(subtree_root));
+
+        // `verifySubtreeRoot` takes the path of the program node, which tags the vkey and the
+        // subtree root.
+        let vkey = from_hex_32(&multiproof.vkey);
+        let program_node = keccak256(&[&[0x02], &vkey, &subtree_root]);
+        let root = multiproof
+            .program_proof
+            .iter()
+            .fold(program_node, |node, sibling| {
+                solidity_hash_pair(node, from_hex_32(sibling))
+            });
+        assert_eq!(root, from_hex_32(&multiproof.root));
+    }
+}
+
+#[test]
+fn test_multiproofs_match_contract() {
+    for n in 1..=9u8 {
+        let leaves: Vec<[u8; 32]> = (0..n).map(|i| hash_leaf(&[i; 32], &[i, n])).collect();
+        let tree =
+            MerkleTree::with_hash_function(leaves, TreeVersion::LATEST, HashFunction::Keccak256);
+        for mask in 1u32..(1 << n) {
+            let indices: Vec<usize> = (0..n as usize).filter(|i| mask & (1 << i) != 0).collect();
+            let proof = tree.generate_multiproof_at(&indices).unwrap();
+            assert_eq!(solidity_process_multiproof(&proof), Some(tree.root));
+
+            // Each flag must say whether the sibling is computed from the proven leaves.
+            for flag in 0..proof.proof_flags.len() {
+                let mut tampered = proof.clone();
+                tampered.proof_flags[flag] = !tampered.proof_flags[flag];
+                assert_eq!(solidity_process_multiproof(&tampered), None);
+            }
+        }
+    }
+}
@@ -0,0 +1,483 @@
+use types::{
+    aggregation::DuplicateLeafPolicy,
+    merkle_tree::{
+        assign_leaves, compute_root, hash_leaf, positional_path, verify, EncodedMerkleTree,
+        HashFunction, MerkleError, MerkleProof, MerkleTree, NodeOrdering, TreeVersion,
+    },
+};
+
+fn leaves(n: u8) -> Vec<[u8; 32]> {
+    (0..n).map(|i| hash_leaf(&[i; 32], &[i])).collect()
+}
+
+#[test]
+fn test_internal_node_is_not_a_leaf() {
+    let leaves = leaves(4);
+
+    // In a V0 tree, the parent of the first two leaves verifies as a leaf with a shorter proof.
+    let tree = MerkleTree::with_version(leaves.clone(), TreeVersion::V0);
+    let proof = tree.generate_proof(leaves[0]).unwrap();
+    let forged = MerkleProof {
+        leaf: TreeVersion::V0.hash_pair(&tree.hash_function, &leaves[0], &leaves[1]),
+        siblings: proof.siblings[1..].to_vec(),
+        ..proof
+    };
+    assert!(tree.verify_proof(&forged).is_ok());
+
+    // Leaf nodes and internal nodes are domain separated in the latest version.
+    let tree = MerkleTree::new(leaves.clone());
+    let proof = tree.generate_proof(leaves[0]).unwrap();
+    let forged = MerkleProof {
+        leaf: tree.version.hash_pair(
+            &tree.hash_function,
+            &tree.version.hash_leaf_node(&tree.hash_function, &leaves[0]),
+            &tree.version.hash_leaf_node(&tree.hash_function, &leaves[1]),
+        ),
+        siblings: proof.siblings[1..].to_vec(),
+        ..proof.clone()
+    };
+    assert!(tree.verify_proof(&proof).is_ok());
+    assert_eq!(tree.verify_proof(&forged), Err(MerkleError::RootMismatch));
+}
+
+#[test]
+fn test_large_tree_matches_compute_root() {
+    // Sizes around the point where levels are split across threads, with odd levels.
+    for n in [1023, 1024, 2049, 5001] {
+        let leaves: Vec<[u8; 32]> = (0..n as u64)
+            .map(|i| hash_leaf(&[0; 32], &i.to_be_bytes()))
+            .collect();
+        let tree = MerkleTree::new(leaves.clone());
+        assert_eq!(
+            tree.root,
+            compute_root(tree.version, &tree.hash_function, &leaves)
+        );
+        for index in [0, n / 2, n - 1] {
+            assert!(tree
+                .verify_proof(&tree.generate_proof_at(index).unwrap())
+                .is_ok());
+        }
+    }
+}
+
+#[test]
+fn test_versions_have_distinct_roots() {
+    let leaves = leaves(5);
+    let v0 = MerkleTree::with_version(leaves.clone(), TreeVersion::V0);
+    let v1 = MerkleTree::with_version(leaves.clone(), TreeVersion::V1);
+    assert_ne!(v0.root, v1.root);
+
+    // Proofs only verify against a tree of the version they were generated for.
+    for leaf in &leaves {
+        let proof = v0.generate_proof(*leaf).unwrap();
+        assert!(v0.verify_proof(&proof).is_ok());
+        assert_eq!(
+            v1.verify_proof(&proof),
+            Err(MerkleError::HashSchemeMismatch)
+        );
+        assert_eq!(
+            v1.verify_proof(&MerkleProof {
+                version: TreeVersion::V1,
+                root: v1.root,
+                ..proof
+            }),
+            Err(MerkleError::RootMismatch)
+        );
+    }
+}
+
+#[test]
+fn test_tree_version_round_trip() {
+    for version in [TreeVersion::V0, TreeVersion::V1] {
+        assert_eq!(TreeVersion::try_from(version as u8), Ok(version));
+    }
+    assert_eq!(TreeVersion::try_from(2), Err(2));
+}
+
+#[test]
+fn test_positional_proofs() {
+    for n in 1..=17 {
+        let leaves = leaves(n);
+        let tree = MerkleTree::positional(leaves.clone());
+        for (i, leaf) in leaves.iter().enumerate() {
+            let proof = tree.generate_positional_proof(*leaf).unwrap();
+            assert_eq!(proof.index, i as u64);
+            assert_eq!(proof.siblings, tree.generate_proof(*leaf).unwrap().siblings);
+            assert!(tree.verify_positional_proof(&proof, *leaf).is_ok());
+        }
+    }
+}
+
+#[test]
+fn test_positional_proof_binds_index() {
+    let leaves = leaves(6);
+    let tree = MerkleTree::positional(leaves.clone());
+    let proof = tree.generate_positional_proof(leaves[2]).unwrap();
+
+    // Claiming another index changes the expected path.
+    let mut wrong_index = proof.clone();
+    wrong_index.index = 3;
+    assert!(tree
+        .verify_positional_proof(&wrong_index, leaves[2])
+        .is_err());
+
+    // Claiming another index along with its path changes the root.
+    wrong_index.is_left = positional_path(3, 6);
+    assert!(tree
+        .verify_positional_proof(&wrong_index, leaves[2])
+        .is_err());
+
+    // Swapping the sides of the path changes the root.
+    let mut wrong_sides = proof.clone();
+    wrong_sides.is_left[0] = !wrong_sides.is_left[0];
+    assert!(tree
+        .verify_positional_proof(&wrong_sides, leaves[2])
+        .is_err());
+
+    // Sorted trees don't commit to positions.
+    let sorted = MerkleTree::new(leaves.clone());
+    let proof = sorted.generate_positional_proof(leaves[2]).unwrap();
+    assert_eq!(
+        sorted.verify_positional_proof(&proof, leaves[2]),
+        Err(MerkleError::HashSchemeMismatch)
+    );
+}
+
+#[test]
+fn test_multiproofs() {
+    for n in 1..=10u8 {
+        let leaves = leaves(n);
+        let tree = MerkleTree::new(leaves.clone());
+        // Every non-empty subset of the leaves.
+        for mask in 1u32..(1 << n) {
+            let subset: Vec<[u8; 32]> = (0..n as usize)
+                .filter(|i| mask & (1 << i) != 0)
+                .map(|i| leaves[i])
+                .collect();
+            let proof = tree.generate_multiproof(&subset).unwrap();
+            assert_eq!(proof.leaves, subset);
+            assert!(tree.verify_multiproof(&proof).is_ok());
+        }
+    }
+}
+
+#[test]
+fn test_multiproof_is_compact() {
+    let leaves = leaves(8);
+    let tree = MerkleTree::new(leaves.clone());
+
+    // Siblings that are proven leaves or computed from them are not part of the proof.
+    let proof = tree.generate_multiproof(&leaves[0..4]).unwrap();
+    assert_eq!(proof.proof, vec![tree.tree[8 + 4 + 1]]);
+    assert_eq!(proof.proof_flags, vec![true, true, true, false]);
+
+    // Proving a leaf twice is the same as proving it once.
+    let proof = tree
+        .generate_multiproof(&[leaves[5], leaves[1], leaves[5]])
+        .unwrap();
+    assert_eq!(proof.indices, vec![1, 5]);
+    assert!(tree.verify_multiproof(&proof).is_ok());
+}
+
+#[test]
+fn test_invalid_multiproofs() {
+    let leaves = leaves(7);
+    let tree = MerkleTree::new(leaves.clone());
+    let proof = tree
+        .generate_multiproof(&[leaves[0], leaves[3], leaves[6]])
+        .unwrap();
+    assert!(tree.verify_multiproof(&proof).is_ok());
+
+    assert_eq!(tree.generate_multiproof(&[]), Err(MerkleError::EmptyTree));
+    assert_eq!(
+        tree.generate_multiproof(&[[0; 32]]),
+        Err(MerkleError::LeafNotFound)
+    );
+    assert_eq!(
+        tree.generate_multiproof_at(&[0, 7]),
+        Err(MerkleError::IndexOutOfBounds {
+            index: 7,
+            leaf_count: 7
+        })
+    );
+
+    let mut wrong_leaf = proof.clone();
+    wrong_leaf.leaves[1] = leaves[2];
+    assert!(tree.verify_multiproof(&wrong_leaf).is_err());
+
+    let mut wrong_flags = proof.clone();
+    wrong_flags.proof_flags[0] = !wrong_flags.proof_flags[0];
+    assert!(tree.verify_multiproof(&wrong_flags).is_err());
+
+    let mut extra_node = proof.clone();
+    extra_node.proof.push([0; 32]);
+    assert!(tree.verify_multiproof(&extra_node).is_err());
+
+    let mut unsorted = proof.clone();
+    unsorted.indices.swap(0, 1);
+    unsorted.leaves.swap(0, 1);
+    assert!(tree.verify_multiproof(&unsorted).is_err());
+
+    let mut out_of_range = proof.clone();
+    out_of_range.indices[2] = 7;
+    assert!(tree.verify_multiproof(&out_of_range).is_err());
+}
+
+#[test]
+fn test_duplicate_leaves() {
+    let leaves = leaves(4);
+    let duplicated = vec![leaves[0], leaves[1], leaves[0], leaves[2], leaves[0]];
+    let tree = MerkleTree::positional(duplicated.clone());
+    assert_eq!(tree.indices_of(leaves[0]), &[0, 2, 4]);
+    assert_eq!(tree.indices_of(leaves[3]), &[] as &[usize]);
+
+    // Every occurrence has its own proof.
+    for (index, leaf) in duplicated.iter().enumerate() {
+        let proof = tree.generate_positional_proof_at(index).unwrap();
+        assert_eq!(proof.index, index as u64);
+        assert!(tree.verify_positional_proof(&proof, *leaf).is_ok());
+    }
+    assert_eq!(
+        tree.generate_positional_proof(leaves[0]).unwrap().index,
+        0,
+        "proofs by leaf are for the first occurrence"
+    );
+    assert_eq!(
+        tree.generate_proof_at(duplicated.len()),
+        Err(MerkleError::IndexOutOfBounds {
+            index: 5,
+            leaf_count: 5
+        })
+    );
+    assert_eq!(
+        MerkleTree::new(vec![]).generate_proof(leaves[0]),
+        Err(MerkleError::EmptyTree)
+    );
+    assert_eq!(
+        tree.generate_proof(leaves[3]),
+        Err(MerkleError::LeafNotFound)
+    );
+
+    let proof = tree.generate_multiproof_at(&[2, 4]).unwrap();
+    assert_eq!(proof.indices, vec![2, 4]);
+    assert_eq!(proof.leaves, vec![leaves[0], leaves[0]]);
+}
+
+#[test]
+fn test_assign_leaves_keeps_duplicates() {
+    let leaves = leaves(2);
+    let requests = [leaves[0], leaves[1], leaves[0]];
+    let batch = assign_leaves(&requests, DuplicateLeafPolicy::KeepDuplicates);
+    assert_eq!(batch.leaves, requests.to_vec());
+    assert_eq!(batch.indices, vec![Some(0), Some(1), Some(2)]);
+    assert_eq!(batch.sources, vec![0, 1, 2]);
+}
+
+#[test]
+fn test_assign_leaves_deduplicates() {
+    let leaves = leaves(3);
+    let requests = [leaves[0], leaves[1], leaves[0], leaves[2], leaves[1]];
+    let batch = assign_leaves(&requests, DuplicateLeafPolicy::Deduplicate);
+    assert_eq!(batch.leaves, leaves);
+    assert_eq!(
+        batch.indices,
+        vec![Some(0), Some(1), Some(0), Some(2), Some(1)]
+    );
+    assert_eq!(batch.sources, vec![0, 1, 3]);
+
+    // Every request can be proven against the deduplicated tree.
+    let tree = MerkleTree::new(batch.leaves.clone());
+    for (leaf, index) in requests.iter().zip(batch.indices.iter()) {
+        let proof = tree.generate_proof_at(index.unwrap()).unwrap();
+        assert_eq!(proof.leaf, *leaf);
+        assert!(tree.verify_proof(&proof).is_ok());
+    }
+}
+
+#[test]
+fn test_assign_leaves_rejects_duplicates() {
+    let leaves = leaves(3);
+    let requests = [leaves[0], leaves[1], leaves[0], leaves[2], leaves[1]];
+    let batch = assign_leaves(&requests, DuplicateLeafPolicy::RejectDuplicates);
+    assert_eq!(batch.leaves, leaves);
+    assert_eq!(batch.indices, vec![Some(0), Some(1), None, Some(2), None]);
+    assert_eq!(batch.sources, vec![0, 1, 3]);
+}
+
+#[test]
+fn test_stateless_verification() {
+    let leaves = leaves(7);
+    for tree in [
+        MerkleTree::new(leaves.clone()),
+        MerkleTree::positional(leaves.clone()),
+    ] {
+        for index in 0..leaves.len() {
+            let proof = tree.generate_proof_at(index).unwrap();
+            assert!(verify(&proof, tree.root).is_ok());
+            assert_eq!(verify(&proof, [0; 32]), Err(MerkleError::RootMismatch));
+
+            // The claimed root must match the root the proof is checked against.
+            assert_eq!(
+                verify(
+                    &MerkleProof {
+                        root: [0; 32],
+                        ..proof.clone()
+                    },
+                    tree.root
+                ),
+                Err(MerkleError::RootMismatch)
+            );
+        }
+    }
+
+    // A positional proof doesn't verify for another index.
+    let tree = MerkleTree::positional(leaves.clone());
+    let proof = tree.generate_proof_at(2).unwrap();
+    assert_eq!(
+        verify(
+            &MerkleProof {
+                index: 3,
+                ..proof.clone()
+            },
+            tree.root
+        ),
+        Err(MerkleError::RootMismatch)
+    );
+    assert_eq!(
+        verify(
+            &MerkleProof {
+                ordering: NodeOrdering::Sorted,
+                ..proof
+            },
+            tree.root
+        ),
+        Err(MerkleError::RootMismatch)
+    );
+}
+
+#[test]
+fn test_merkle_proof_encoding() {
+    let leaves = leaves(5);
+    let tree = MerkleTree::positional(leaves.clone());
+    let proof = tree.generate_proof_at(4).unwrap();
+    let bytes = proof.to_bytes();
+    assert_eq!(bytes.len(), 88 + 32 * proof.siblings.len());
+    assert_eq!(bytes[0], MerkleProof::ENCODING_VERSION);
+    assert_eq!(MerkleProof::from_bytes(&bytes), Ok(proof.clone()));
+
+    // Truncated, padded and unknown encodings are rejected.
+    assert!(MerkleProof::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    assert!(MerkleProof::from_bytes(&[bytes.as_slice(), &[0]].concat()).is_err());
+    let mut unknown_version = bytes.clone();
+    unknown_version[1] = 0xff;
+    assert!(MerkleProof::from_bytes(&unknown_version).is_err());
+
+    // The serde representation round trips as well.
+    let json = serde_json::to_string(&proof).unwrap();
+    assert_eq!(serde_json::from_str::<MerkleProof>(&json).unwrap(), proof);
+}
+
+#[test]
+fn test_hash_functions() {
+    let leaves = leaves(6);
+    let keccak = MerkleTree::with_hash_function(
+        leaves.clone(),
+        TreeVersion::LATEST,
+        HashFunction::Keccak256,
+    );
+    let sha256 =
+        MerkleTree::with_hash_function(leaves.clone(), TreeVersion::LATEST, HashFunction::Sha256);
+    assert_eq!(keccak.root, MerkleTree::new(leaves.clone()).root);
+    assert_ne!(keccak.root, sha256.root);
+
+    // A proof carries its hash function, and doesn't verify under another one.
+    for index in 0..leaves.len() {
+        let proof = sha256.generate_proof_at(index).unwrap();
+        assert_eq!(proof.hash_function, HashFunction::Sha256);
+        assert!(verify(&proof, sha256.root).is_ok());
+        let proof = MerkleProof::from_bytes(&proof.to_bytes()).unwrap();
+        assert!(verify(&proof, sha256.root).is_ok());
+        assert_eq!(
+            verify(
+                &MerkleProof {
+                    hash_function: HashFunction::Keccak256,
+                    ..proof
+                },
+                sha256.root
+            ),
+            Err(MerkleError::RootMismatch)
+        );
+    }
+
+    assert_eq!("keccak256".parse(), Ok(HashFunction::Keccak256));
+    assert_eq!("sha256".parse(), Ok(HashFunction::Sha256));
+    assert!("blake3".parse::<HashFunction>().is_err());
+}
+
+#[test]
+fn test_decode_first_encoding() {
+    // The first encoding had no hash function byte, and only Keccak-256 trees.
+    let tree = MerkleTree::new(leaves(3));
+    let proof = tree.generate_proof_at(1).unwrap();
+    let bytes = proof.to_bytes();
+    let first_encoding = [&[1], &bytes[1..3], &bytes[4..]].concat();
+    assert_eq!(MerkleProof::from_bytes(&first_encoding), Ok(proof));
+}
+
+#[test]
+fn test_tree_encoding_round_trip() {
+    for n in [0, 1, 2, 7, 16] {
+        for tree in [
+            MerkleTree::with_hash_function(leaves(n), TreeVersion::V0, HashFunction::Keccak256),
+            MerkleTree::with_hash_function(leaves(n), TreeVersion::LATEST, HashFunction::Sha256),
+            MerkleTree::positional(leaves(n)),
+        ] {
+            let bytes = tree.to_bytes();
+            assert_eq!(bytes.len(), 44 + 32 * (n as usize + tree.tree.len() + 1));
+            let decoded = MerkleTree::from_bytes(&bytes).unwrap();
+            assert_eq!(decoded.leaves, tree.leaves);
+            assert_eq!(decoded.tree, tree.tree);
+            assert_eq!(decoded.root, tree.root);
+            assert_eq!(decoded.version, tree.version);
+            assert_eq!(decoded.ordering, tree.ordering);
+            assert_eq!(decoded.hash_function, tree.hash_function);
+
+            // Proofs read from the encoding match the proofs of the tree.
+            let encoded = EncodedMerkleTree::decode(&bytes).unwrap();
+            assert_eq!(encoded.leaf_count, n as usize);
+            for (index, leaf) in tree.leaves.iter().enumerate() {
+                assert_eq!(encoded.position(*leaf), Ok(index));
+                assert_eq!(
+                    encoded.generate_proof_at(index),
+                    tree.generate_proof_at(index)
+                );
+            }
+            assert!(encoded.generate_proof_at(n as usize).is_err());
+        }
+    }
+}
+
+#[test]
+fn test_corrupted_tree_encoding() {
+    let bytes = MerkleTree::new(leaves(5)).to_bytes();
+
+    // Any flipped bit fails the checksum.
+    for i in [0, 4, 12, 44, bytes.len() / 2, bytes.len() - 1] {
+        let mut corrupted = bytes.clone();
+        corrupted[i] ^= 1;
+        assert_eq!(
+            EncodedMerkleTree::decode(&corrupted).unwrap_err(),
+            MerkleError::CorruptedTree
+        );
+        assert!(MerkleTree::from_bytes(&corrupted).is_err());
+    }
+    assert!(MerkleTree::from_bytes(&bytes[..bytes.len() - 32]).is_err());
+    assert!(MerkleTree::from_bytes(&[]).is_err());
+
+    // The leaf count must match the stored nodes, even with a valid checksum.
+    let mut body = bytes[..bytes.len() - 32].to_vec();
+    body[11] += 1;
+    let checksum = <sha2::Sha256 as sha2::Digest>::digest(&body);
+    body.extend_from_slice(&checksum);
+    assert!(MerkleTree::from_bytes(&body).is_err());
+}
@@ -0,0 +1,157 @@
+use types::{
+    merkle_tree::{
+        hash_leaf, process_multiproof, process_proof, verify, HashFunction, MerkleError,
+        MerkleTree, TreeVersion,
+    },
+    mmr::{accumulator_proof, accumulator_siblings, mmr_proof, MerkleMountainRange},
+};
+
+fn batch_roots(n: u8) -> Vec<[u8; 32]> {
+    (0..n).map(|i| hash_leaf(&[i; 32], &[i, n])).collect()
+}
+
+#[test]
+fn test_append_matches_rebuild() {
+    for hash_function in [HashFunction::Keccak256, HashFunction::Sha256] {
+        let roots = batch_roots(33);
+        let mut mmr = MerkleMountainRange::new();
+        assert_eq!(mmr.root(TreeVersion::LATEST, &hash_function), [0; 32]);
+        for (n, root) in roots.iter().enumerate() {
+            mmr.append(TreeVersion::LATEST, &hash_function, *root);
+            assert!(mmr.is_valid());
+            assert_eq!(mmr.peaks.len(), (n + 1).count_ones() as usize);
+            assert_eq!(
+                mmr,
+                MerkleMountainRange::from_leaves(TreeVersion::LATEST, &hash_function, &roots[..=n])
+            );
+        }
+    }
+}
+
+#[test]
+fn test_single_batch_root_is_the_batch_root() {
+    let roots = batch_roots(1);
+    let mmr =
+        MerkleMountainRange::from_leaves(TreeVersion::LATEST, &HashFunction::Keccak256, &roots);
+    assert_eq!(
+        mmr.root(TreeVersion::LATEST, &HashFunction::Keccak256),
+        roots[0]
+    );
+}
+
+#[test]
+fn test_mmr_proofs() {
+    let version = TreeVersion::LATEST;
+    for hash_function in [HashFunction::Keccak256, HashFunction::Sha256] {
+        for n in 1..=20u8 {
+            let roots = batch_roots(n);
+            let mmr = MerkleMountainRange::from_leaves(version, &hash_function, &roots);
+            let root = mmr.root(version, &hash_function);
+            for (index, batch_root) in roots.iter().enumerate() {
+                let proof = mmr_proof(version, &hash_function, &roots, index).unwrap();
+                let computed = proof.iter().fold(*batch_root, |node, sibling| {
+                    version.hash_pair(&hash_function, &node, sibling)
+                });
+                assert_eq!(computed, root);
+            }
+            assert!(mmr_proof(version, &hash_function, &roots, roots.len()).is_none());
+        }
+    }
+}
+
+#[test]
+fn test_invalid_mmr() {
+    let mut mmr = MerkleMountainRange::from_leaves(
+        TreeVersion::LATEST,
+        &HashFunction::Keccak256,
+        &batch_roots(3),
+    );
+    assert!(mmr.is_valid());
+    mmr.leaf_count = 4;
+    assert!(!mmr.is_valid());
+}
+
+#[test]
+fn test_old_batches_stay_verifiable() {
+    let batches: Vec<MerkleTree> = (1..=7u8)
+        .map(|n| MerkleTree::new((0..n).map(|i| hash_leaf(&[i; 32], &[n])).collect()))
+        .collect();
+    let mut batch_roots = Vec::new();
+    for batch in &batches {
+        batch_roots.push(batch.root);
+        let accumulator_root = MerkleMountainRange::from_leaves(
+            TreeVersion::LATEST,
+            &HashFunction::Keccak256,
+            &batch_roots,
+        )
+        .root(TreeVersion::LATEST, &HashFunction::Keccak256);
+
+        // Every leaf of every batch so far verifies against the latest root.
+        for (batch_index, tree) in batches[..batch_roots.len()].iter().enumerate() {
+            for index in 0..tree.leaves.len() {
+                let proof = tree.generate_proof_at(index).unwrap();
+                let proof = accumulator_proof(proof, &batch_roots, batch_index).unwrap();
+                assert_eq!(proof.root, accumulator_root);
+                assert!(verify(&proof, accumulator_root).is_ok());
+                assert_eq!(
+                    process_proof(
+                        proof.version,
+                        &proof.hash_function,
+                        proof.leaf,
+                        &proof.siblings
+                    ),
+                    accumulator_root
+                );
+            }
+        }
+    }
+
+    // A proof is only extended for the batch it was generated for.
+    let proof = batches[0].generate_proof_at(0).unwrap();
+    assert_eq!(
+        accumulator_proof(proof.clone(), &batch_roots, 1),
+        Err(MerkleError::RootMismatch)
+    );
+    assert_eq!(
+        accumulator_proof(proof, &batch_roots, 7),
+        Err(MerkleError::IndexOutOfBounds {
+            index: 7,
+            leaf_count: 7
+        })
+    );
+}
+
+#[test]
+fn test_multiproofs_extend_through_the_accumulator() {
+    let (version, hash_function) = (TreeVersion::LATEST, HashFunction::Keccak256);
+    let batches: Vec<MerkleTree> = (1..=5u8)
+        .map(|n| MerkleTree::new((0..n).map(|i| hash_leaf(&[i; 32], &[n])).collect()))
+        .collect();
+    let batch_roots: Vec<[u8; 32]> = batches.iter().map(|batch| batch.root).collect();
+    let accumulator_root = MerkleMountainRange::from_leaves(version, &hash_function, &batch_roots)
+        .root(version, &hash_function);
+
+    for (batch_index, batch) in batches.iter().enumerate() {
+        let indices: Vec<usize> = (0..batch.leaves.len()).step_by(2).collect();
+        let multiproof = batch.generate_multiproof_at(&indices).unwrap();
+        let batch_root = process_multiproof(version, &hash_function, &multiproof).unwrap();
+        let (siblings, root) = accumulator_siblings(
+            version,
+            &hash_function,
+            batch_root,
+            &batch_roots,
+            batch_index,
+        )
+        .unwrap();
+        assert_eq!(root, accumulator_root);
+        let computed = siblings.iter().fold(batch_root, |node, sibling| {
+            version.hash_pair(&hash_function, &node, sibling)
+        });
+        assert_eq!(computed, accumulator_root);
+    }
+
+    assert_eq!(
+        accumulator_siblings(version, &hash_function, batch_roots[0], &batch_roots, 1),
+        Err(MerkleError::RootMismatch)
+    );
+}
@@ -0,0 +1,201 @@
+use types::merkle_tree::{
+    hash_leaf, merge_roots,
+    subtree::{hash_program_node, process_program_proof, program_root},
+    verify, verify_program_proof, EncodedProgramTree, HashFunction, MerkleError, MerkleTree,
+    ProgramTree, TreeVersion,
+};
+
+/// The leaves of a batch with `counts[i]` proofs of the program with vkey hash `[i + 1; 32]`.
+fn batch(counts: &[u8]) -> Vec<([u8; 32], [u8; 32])> {
+    counts
+        .iter()
+        .enumerate()
+        .flat_map(|(program, &count)| {
+            let vkey_hash = [program as u8 + 1; 32];
+            (0..count).map(move |i| (vkey_hash, hash_leaf(&vkey_hash, &[i])))
+        })
+        .collect()
+}
+
+#[test]
+fn test_program_tree_proofs() {
+    for hash_function in [HashFunction::Keccak256, HashFunction::Sha256] {
+        for counts in [&[1][..], &[3], &[1, 1], &[5, 2, 7], &[4, 1, 1, 9, 2]] {
+            let leaves = batch(counts);
+            let tree = ProgramTree::new(&leaves, TreeVersion::LATEST, hash_function).unwrap();
+            assert_eq!(tree.leaf_count(), leaves.len());
+            assert_eq!(tree.programs.len(), counts.len());
+
+            let subtree_roots = tree
+                .programs
+                .iter()
+                .map(|(vkey_hash, subtree)| (*vkey_hash, subtree.root))
+                .collect::<Vec<_>>();
+            assert_eq!(
+                program_root(TreeVersion::LATEST, &hash_function, &subtree_roots),
+                tree.root
+            );
+
+            for (index, (vkey_hash, leaf)) in leaves.iter().enumerate() {
+                let proof = tree.generate_proof_at(index).unwrap();
+                assert_eq!(proof.vkey_hash, *vkey_hash);
+                assert_eq!(proof.subtree_proof.leaf, *leaf);
+                assert_eq!(proof.index, index as u64);
+                verify_program_proof(&proof, tree.root).unwrap();
+                assert_eq!(
+                    verify_program_proof(&proof, [0; 32]),
+                    Err(MerkleError::RootMismatch)
+                );
+
+                // The subtree proof only needs the root of the program, whose node the path of
+                // the program proves against the root of the batch.
+                let subtree_root = tree
+                    .programs
+                    .iter()
+                    .find(|(program, _)| program == vkey_hash)
+                    .unwrap()
+                    .1
+                    .root;
+                verify(&proof.subtree_proof, subtree_root).unwrap();
+                let program_node = hash_program_node(
+                    TreeVersion::LATEST,
+                    &hash_function,
+                    vkey_hash,
+                    &subtree_root,
+                );
+                let root = proof
+                    .program_siblings
+                    .iter()
+                    .fold(program_node, |node, sibling| {
+                        TreeVersion::LATEST.hash_pair(&hash_function, &node, sibling)
+                    });
+                assert_eq!(root, tree.root);
+                assert_eq!(
+                    process_program_proof(
+                        TreeVersion::LATEST,
+                        &hash_function,
+                        vkey_hash,
+                        &subtree_root,
+                        &proof.program_siblings
+                    ),
+                    tree.root
+                );
+
+                // The node of a program is tagged, so it differs from the leaf and internal
+                // nodes over the same hashes.
+                assert_ne!(
+                    program_node,
+                    TreeVersion::LATEST.hash_pair(
+                        &hash_function,
+                        &TreeVersion::LATEST.hash_leaf_node(&hash_function, vkey_hash),
+                        &subtree_root
+                    )
+                );
+            }
+            assert_eq!(
+                tree.generate_proof_at(leaves.len()).unwrap_err(),
+                MerkleError::IndexOutOfBounds {
+                    index: leaves.len(),
+                    leaf_count: leaves.len()
+                }
+            );
+        }
+    }
+}
+
+#[test]
+fn test_program_root_from_runs() {
+    // Runs over aligned parts of the leaves of a program merge into its subtree root, as the
+    // runs of the aggregation program do.
+    let hash_function = HashFunction::Sha256;
+    let leaves = batch(&[9, 2, 4]);
+    let tree = ProgramTree::new(&leaves, TreeVersion::LATEST, hash_function).unwrap();
+    let subtree_roots = tree
+        .programs
+        .iter()
+        .map(|(vkey_hash, subtree)| {
+            let run_roots = subtree
+                .leaves
+                .chunks(4)
+                .map(|run| {
+                    MerkleTree::with_hash_function(run.to_vec(), TreeVersion::LATEST, hash_function)
+                        .root
+                })
+                .collect::<Vec<_>>();
+            (
+                *vkey_hash,
+                merge_roots(TreeVersion::LATEST, &hash_function, &run_roots),
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        program_root(TreeVersion::LATEST, &hash_function, &subtree_roots),
+        tree.root
+    );
+    assert_eq!(
+        program_root(TreeVersion::LATEST, &hash_function, &[]),
+        [0; 32]
+    );
+}
+
+#[test]
+fn test_unsorted_programs() {
+    let mut leaves = batch(&[2, 2]);
+    leaves.swap(1, 2);
+    assert_eq!(
+        ProgramTree::new(&leaves, TreeVersion::LATEST, HashFunction::Keccak256).err(),
+        Some(MerkleError::UnsortedPrograms)
+    );
+
+    let mut leaves = batch(&[2, 2]);
+    leaves.reverse();
+    assert_eq!(
+        ProgramTree::new(&leaves, TreeVersion::LATEST, HashFunction::Keccak256).err(),
+        Some(MerkleError::UnsortedPrograms)
+    );
+}
+
+#[test]
+fn test_program_tree_encoding() {
+    let leaves = batch(&[3, 1, 6]);
+    let tree = ProgramTree::new(&leaves, TreeVersion::LATEST, HashFunction::Sha256).unwrap();
+    let bytes = tree.to_bytes();
+    assert!(ProgramTree::is_encoding(&bytes));
+    assert!(!ProgramTree::is_encoding(
+        &MerkleTree::new(vec![[1; 32]]).to_bytes()
+    ));
+
+    let encoded = EncodedProgramTree::decode(&bytes).unwrap();
+    assert_eq!(encoded.root, tree.root);
+    assert_eq!(encoded.leaf_count, leaves.len());
+    let decoded = ProgramTree::from_bytes(&bytes).unwrap();
+    assert_eq!(decoded.root, tree.root);
+    for (index, (vkey_hash, leaf)) in leaves.iter().enumerate() {
+        let proof = tree.generate_proof_at(index).unwrap();
+        assert_eq!(encoded.generate_proof_at(index).unwrap(), proof);
+        assert_eq!(decoded.generate_proof_at(index).unwrap(), proof);
+        assert_eq!(encoded.position(*leaf), Ok(index));
+        let subtree = encoded.subtree(*vkey_hash).unwrap().unwrap();
+        assert!(subtree
+            .indices_of(*leaf)
+            .contains(&proof.subtree_proof.index.try_into().unwrap()));
+    }
+    assert!(encoded.subtree([0; 32]).is_none());
+    assert_eq!(encoded.position([0; 32]), Err(MerkleError::LeafNotFound));
+
+    // Flipping any byte of a subtree breaks its checksum, and flipping the root no longer matches
+    // the subtrees.
+    for offset in [1, 2, 10, bytes.len() / 2, bytes.len() - 1] {
+        let mut corrupted = bytes.clone();
+        corrupted[offset] ^= 1;
+        assert_eq!(
+            EncodedProgramTree::decode(&corrupted).err(),
+            Some(MerkleError::CorruptedTree),
+            "offset {offset}"
+        );
+    }
+    assert!(EncodedProgramTree::decode(&bytes[..bytes.len() - 1]).is_err());
+    let mut trailing = bytes.clone();
+    trailing.push(0);
+    assert!(EncodedProgramTree::decode(&trailing).is_err());
+}
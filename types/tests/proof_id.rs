@@ -0,0 +1,69 @@
+use sha2::{Digest, Sha256};
+use types::merkle_tree::LeafFormat;
+use types::proof_id::{content_proof_id, keyed_proof_id, CONTENT_PROOF_ID_TAG, KEYED_PROOF_ID_TAG};
+
+#[test]
+fn test_content_proof_id() {
+    let vkey_hash = [1; 32];
+    let proof_id = content_proof_id(&vkey_hash, b"hello", b"proof", LeafFormat::Full, b"");
+
+    let mut hasher = Sha256::new();
+    hasher.update(CONTENT_PROOF_ID_TAG);
+    hasher.update(vkey_hash);
+    hasher.update(Sha256::digest(b"hello"));
+    hasher.update(Sha256::digest(b"proof"));
+    hasher.update([0]);
+    hasher.update(Sha256::digest(b""));
+    assert_eq!(proof_id, <[u8; 32]>::from(hasher.finalize()));
+
+    // Each part of the request changes the proof id.
+    let id = |vkey_hash, public_values: &[u8], proof: &[u8], leaf_format, submitter: &[u8]| {
+        content_proof_id(&vkey_hash, public_values, proof, leaf_format, submitter)
+    };
+    assert_ne!(
+        id([2; 32], b"hello", b"proof", LeafFormat::Full, b""),
+        proof_id
+    );
+    assert_ne!(
+        id(vkey_hash, b"hellp", b"proof", LeafFormat::Full, b""),
+        proof_id
+    );
+    assert_ne!(
+        id(vkey_hash, b"hello", b"proog", LeafFormat::Full, b""),
+        proof_id
+    );
+    assert_ne!(
+        id(vkey_hash, b"hello", b"proof", LeafFormat::Digest, b""),
+        proof_id
+    );
+    assert_ne!(
+        id(vkey_hash, b"hello", b"proof", LeafFormat::Full, &[1; 20]),
+        proof_id
+    );
+    // Public values and proof are hashed separately, so bytes can't move from one to the other.
+    assert_ne!(
+        id(vkey_hash, b"hell", b"oproof", LeafFormat::Full, b""),
+        proof_id
+    );
+    // Another submitter of the same proof doesn't get the proof id of the first one.
+    assert_ne!(
+        id(vkey_hash, b"hello", b"proof", LeafFormat::Full, &[1; 20]),
+        id(vkey_hash, b"hello", b"proof", LeafFormat::Full, &[2; 20])
+    );
+}
+
+#[test]
+fn test_keyed_proof_id() {
+    let proof_id = keyed_proof_id("tenant", b"key");
+
+    let mut hasher = Sha256::new();
+    hasher.update(KEYED_PROOF_ID_TAG);
+    hasher.update(6u32.to_be_bytes());
+    hasher.update(b"tenant");
+    hasher.update(b"key");
+    assert_eq!(proof_id, <[u8; 32]>::from(hasher.finalize()));
+
+    // The same key of another tenant is another proof id, even if the bytes run together.
+    assert_ne!(keyed_proof_id("tenan", b"tkey"), proof_id);
+    assert_ne!(keyed_proof_id("tenant", b"kez"), proof_id);
+}
@@ -0,0 +1,61 @@
+use types::public_values::AggregationPublicValues;
+
+fn public_values() -> AggregationPublicValues {
+    AggregationPublicValues {
+        batchId: [1; 32].into(),
+        batchIndex: 2,
+        leafCount: 3,
+        timestamp: 4,
+        batchRoot: [5; 32].into(),
+        previousRoot: [6; 32].into(),
+        newRoot: [7; 32].into(),
+        aggregationVKeyDigest: [8; 32].into(),
+        allowlistRoot: [9; 32].into(),
+        requestIndices: vec![0, 2, 3],
+    }
+}
+
+fn uint(value: u64) -> [u8; 32] {
+    let mut word = [0u8; 32];
+    word[24..].copy_from_slice(&value.to_be_bytes());
+    word
+}
+
+#[test]
+fn test_public_values_round_trip() {
+    let public_values = public_values();
+    let bytes = public_values.to_bytes();
+    assert_eq!(
+        AggregationPublicValues::from_bytes(&bytes),
+        Some(public_values)
+    );
+    assert_eq!(AggregationPublicValues::from_bytes(&bytes[1..]), None);
+}
+
+#[test]
+fn test_public_values_abi_layout() {
+    // `abi.decode(publicValues, (AggregationPublicValues))` reads the offset of the struct, one
+    // word per static field with the integers right-aligned, and the request indices at the end.
+    let bytes = public_values().to_bytes();
+    let words = bytes.chunks(32).collect::<Vec<_>>();
+    assert_eq!(
+        words,
+        [
+            uint(32),
+            [1; 32],
+            uint(2),
+            uint(3),
+            uint(4),
+            [5; 32],
+            [6; 32],
+            [7; 32],
+            [8; 32],
+            [9; 32],
+            uint(10 * 32),
+            uint(3),
+            uint(0),
+            uint(2),
+            uint(3),
+        ]
+    );
+}
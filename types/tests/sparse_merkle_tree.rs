@@ -0,0 +1,299 @@
+use std::collections::BTreeMap;
+
+use types::{
+    aggregation::{GetProofIdProofResponse, ProofIdState},
+    merkle_tree::{HashFunction, MerkleError},
+    sparse_merkle_tree::{
+        proof_id_value, verify_sparse, NodePosition, SparseMerklePatch, SparseMerkleProof,
+        SparseMerkleTree, EMPTY,
+    },
+};
+
+fn keys(n: u8) -> Vec<[u8; 32]> {
+    (0..n)
+        .map(|i| std::array::from_fn(|j| i.wrapping_mul(37).wrapping_add(j as u8)))
+        .collect()
+}
+
+fn tree(n: u8, hash_function: HashFunction) -> SparseMerkleTree {
+    let mut tree = SparseMerkleTree::new(hash_function);
+    for (i, key) in keys(n).into_iter().enumerate() {
+        tree.insert(
+            key,
+            proof_id_value(hash_function, ProofIdState::Aggregated, &[i as u8]),
+        );
+    }
+    tree
+}
+
+#[test]
+fn test_empty_tree() {
+    let tree = SparseMerkleTree::new(HashFunction::Keccak256);
+    assert_eq!(tree.root(), EMPTY);
+    let proof = tree.generate_proof([1; 32]);
+    assert_eq!(proof.value, None);
+    assert!(proof.siblings.is_empty());
+    assert!(verify_sparse(&proof, EMPTY).is_ok());
+}
+
+#[test]
+fn test_inclusion_and_exclusion_proofs() {
+    for hash_function in [HashFunction::Keccak256, HashFunction::Sha256] {
+        let tree = tree(9, hash_function);
+        let root = tree.root();
+        for key in keys(9) {
+            let proof = tree.generate_proof(key);
+            assert_eq!(proof.value, tree.get(&key));
+            assert!(proof.value.is_some());
+            assert!(verify_sparse(&proof, root).is_ok());
+
+            // The proof doesn't verify for another value, or as a proof of absence.
+            assert_eq!(
+                verify_sparse(
+                    &SparseMerkleProof {
+                        value: Some([7; 32]),
+                        ..proof.clone()
+                    },
+                    root
+                ),
+                Err(MerkleError::RootMismatch)
+            );
+            assert_eq!(
+                verify_sparse(
+                    &SparseMerkleProof {
+                        value: None,
+                        ..proof
+                    },
+                    root
+                ),
+                Err(MerkleError::RootMismatch)
+            );
+        }
+
+        for key in keys(12).into_iter().skip(9) {
+            let proof = tree.generate_proof(key);
+            assert_eq!(proof.value, None);
+            assert!(verify_sparse(&proof, root).is_ok());
+
+            // A proof of absence can't claim a value.
+            assert_eq!(
+                verify_sparse(
+                    &SparseMerkleProof {
+                        value: Some(EMPTY),
+                        ..proof
+                    },
+                    root
+                ),
+                Err(MerkleError::RootMismatch)
+            );
+        }
+    }
+}
+
+#[test]
+fn test_root_is_independent_of_insertion_order() {
+    let keys = keys(16);
+    let mut forward = SparseMerkleTree::new(HashFunction::Keccak256);
+    let mut backward = SparseMerkleTree::new(HashFunction::Keccak256);
+    for key in &keys {
+        forward.insert(*key, *key);
+    }
+    for key in keys.iter().rev() {
+        backward.insert(*key, *key);
+    }
+    assert_eq!(forward.root(), backward.root());
+
+    // Removing every key brings the tree back to the empty root.
+    for key in &keys {
+        forward.remove(key);
+    }
+    assert!(forward.is_empty());
+    assert_eq!(forward.root(), EMPTY);
+}
+
+#[test]
+fn test_revocation_changes_the_root() {
+    let hash_function = HashFunction::Keccak256;
+    let mut tree = tree(4, hash_function);
+    let key = keys(4)[2];
+    let aggregated_root = tree.root();
+    let aggregated_proof = tree.generate_proof(key);
+
+    tree.insert(
+        key,
+        proof_id_value(hash_function, ProofIdState::Revoked, &[2]),
+    );
+    let revoked_root = tree.root();
+    assert_ne!(aggregated_root, revoked_root);
+
+    let revoked_proof = tree.generate_proof(key);
+    assert!(verify_sparse(&revoked_proof, revoked_root).is_ok());
+    assert_eq!(
+        verify_sparse(&aggregated_proof, revoked_root),
+        Err(MerkleError::RootMismatch)
+    );
+    assert_eq!(
+        revoked_proof.value,
+        Some(proof_id_value(hash_function, ProofIdState::Revoked, &[2]))
+    );
+}
+
+#[test]
+fn test_malformed_proofs() {
+    let tree = tree(5, HashFunction::Keccak256);
+    let proof = tree.generate_proof(keys(5)[0]);
+
+    // The siblings must match the bitmap.
+    let mut missing_sibling = proof.clone();
+    missing_sibling.siblings.pop();
+    assert_eq!(
+        missing_sibling.compute_root(),
+        Err(MerkleError::MalformedProof)
+    );
+    let mut extra_bit = proof.clone();
+    extra_bit.bitmap[31] ^= 1;
+    assert_eq!(
+        verify_sparse(&extra_bit, tree.root()),
+        Err(MerkleError::MalformedProof)
+    );
+
+    // Keys sharing a long prefix need only the siblings where their paths diverge.
+    let mut close = SparseMerkleTree::new(HashFunction::Keccak256);
+    let mut other = [0u8; 32];
+    other[31] = 1;
+    close.insert([0; 32], [1; 32]);
+    close.insert(other, [1; 32]);
+    let proof = close.generate_proof([0; 32]);
+    assert_eq!(proof.siblings.len(), 1);
+    assert!(verify_sparse(&proof, close.root()).is_ok());
+}
+
+#[test]
+fn test_proof_from_response() {
+    let hash_function = HashFunction::Sha256;
+    let tree = tree(6, hash_function);
+    for (i, key) in keys(8).into_iter().enumerate() {
+        let proof = tree.generate_proof(key);
+        let state = if proof.value.is_some() {
+            ProofIdState::Aggregated
+        } else {
+            ProofIdState::Absent
+        };
+        let response = GetProofIdProofResponse {
+            root: tree.root().to_vec(),
+            state: state as i32,
+            batch_id: if proof.value.is_some() {
+                vec![i as u8]
+            } else {
+                vec![]
+            },
+            bitmap: proof.bitmap.to_vec(),
+            siblings: proof.siblings.iter().map(|node| node.to_vec()).collect(),
+            hash_function: hash_function as u32,
+        };
+        assert_eq!(SparseMerkleProof::from_response(key, &response), Ok(proof));
+
+        let malformed = GetProofIdProofResponse {
+            bitmap: vec![0; 31],
+            ..response
+        };
+        assert_eq!(
+            SparseMerkleProof::from_response(key, &malformed),
+            Err(MerkleError::InvalidEncoding)
+        );
+    }
+}
+
+/// Apply `updates` to a tree stored as its non-empty nodes, and return the new root.
+fn apply(
+    store: &mut BTreeMap<NodePosition, [u8; 32]>,
+    hash_function: HashFunction,
+    updates: &[([u8; 32], Option<[u8; 32]>)],
+) -> [u8; 32] {
+    let keys = updates.iter().map(|(key, _)| *key).collect::<Vec<_>>();
+    let nodes = SparseMerklePatch::positions(&keys)
+        .into_iter()
+        .filter_map(|position| store.get(&position).map(|node| (position, *node)));
+    let mut patch = SparseMerklePatch::new(hash_function, nodes);
+    for (key, value) in updates {
+        patch.update(*key, *value);
+    }
+    for (position, node) in patch.changes() {
+        if node == EMPTY {
+            store.remove(&position);
+        } else {
+            store.insert(position, node);
+        }
+    }
+    patch.root()
+}
+
+#[test]
+fn test_node_positions() {
+    let key = [0xff; 32];
+    assert_eq!(NodePosition::new(&key, 0), NodePosition::ROOT);
+    assert_eq!(NodePosition::new(&key, 3).path[0], 0xe0);
+    assert_eq!(NodePosition::new(&key, 3).path[1..], [0; 31]);
+    assert_eq!(NodePosition::new(&key, 256).path, key);
+
+    // The sibling differs in the last bit of the path only.
+    let sibling = NodePosition::new(&key, 3).sibling();
+    assert_eq!(sibling.depth, 3);
+    assert_eq!(sibling.path[0], 0xc0);
+    let siblings = NodePosition::siblings(&key);
+    assert_eq!(siblings.len(), 256);
+    assert_eq!(siblings[0].path[0], 0x00);
+    assert_eq!(siblings[255].path[31], 0xfe);
+}
+
+#[test]
+fn test_patches_match_the_tree() {
+    for hash_function in [HashFunction::Keccak256, HashFunction::Sha256] {
+        let mut tree = SparseMerkleTree::new(hash_function);
+        let mut store = BTreeMap::new();
+        let keys = keys(12);
+
+        // Keys are added in batches, then some are revoked or removed.
+        for batch in keys.chunks(5) {
+            let updates = batch
+                .iter()
+                .map(|key| {
+                    let value = proof_id_value(hash_function, ProofIdState::Aggregated, &key[..1]);
+                    (*key, Some(value))
+                })
+                .collect::<Vec<_>>();
+            for (key, value) in &updates {
+                tree.insert(*key, value.unwrap());
+            }
+            assert_eq!(apply(&mut store, hash_function, &updates), tree.root());
+        }
+        let revoked = proof_id_value(hash_function, ProofIdState::Revoked, &keys[3][..1]);
+        tree.insert(keys[3], revoked);
+        tree.remove(&keys[7]);
+        assert_eq!(
+            apply(
+                &mut store,
+                hash_function,
+                &[(keys[3], Some(revoked)), (keys[7], None)]
+            ),
+            tree.root()
+        );
+
+        // Proofs built from the stored siblings are the proofs of the tree.
+        for key in self::keys(14) {
+            let siblings = NodePosition::siblings(&key)
+                .iter()
+                .map(|position| store.get(position).copied().unwrap_or(EMPTY))
+                .collect();
+            assert_eq!(
+                SparseMerkleProof::from_siblings(key, tree.get(&key), siblings, hash_function),
+                tree.generate_proof(key)
+            );
+        }
+
+        // Removing every key leaves no node stored.
+        let updates = keys.iter().map(|key| (*key, None)).collect::<Vec<_>>();
+        assert_eq!(apply(&mut store, hash_function, &updates), EMPTY);
+        assert!(store.is_empty());
+    }
+}
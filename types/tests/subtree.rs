@@ -0,0 +1,92 @@
+use types::merkle_tree::{
+    hash_leaf, merge_roots,
+    subtree::{is_aligned, SubtreeCommitment},
+    HashFunction, MerkleTree, TreeVersion,
+};
+
+fn leaves(n: u8) -> Vec<[u8; 32]> {
+    (0..n).map(|i| hash_leaf(&[i; 32], &[i])).collect()
+}
+
+fn commitment(leaf_count: u64) -> SubtreeCommitment {
+    SubtreeCommitment {
+        aggregation_vkey: [7; 32],
+        program_vkey: [8; 32],
+        hash_function: HashFunction::Sha256,
+        leaf_count,
+        root: [leaf_count as u8; 32],
+        allowlist_root: [9; 32],
+    }
+}
+
+#[test]
+fn test_merged_root_matches_flat_tree() {
+    for hash_function in [HashFunction::Keccak256, HashFunction::Sha256] {
+        for (n, run_size) in [(1, 4), (5, 4), (8, 4), (11, 4), (33, 8), (64, 16)] {
+            let leaves = leaves(n);
+            let flat =
+                MerkleTree::with_hash_function(leaves.clone(), TreeVersion::LATEST, hash_function);
+            let runs = leaves
+                .chunks(run_size)
+                .map(|chunk| {
+                    MerkleTree::with_hash_function(
+                        chunk.to_vec(),
+                        TreeVersion::LATEST,
+                        hash_function,
+                    )
+                })
+                .collect::<Vec<_>>();
+            let roots = runs.iter().map(|run| run.root).collect::<Vec<_>>();
+            assert_eq!(
+                merge_roots(TreeVersion::LATEST, &hash_function, &roots),
+                flat.root
+            );
+
+            // The path through the flat tree starts with the path through the run of the leaf.
+            for (index, leaf) in leaves.iter().enumerate() {
+                let run_proof = runs[index / run_size].generate_proof(*leaf).unwrap();
+                let flat_proof = flat.generate_proof(*leaf).unwrap();
+                assert!(flat_proof.siblings.starts_with(&run_proof.siblings));
+            }
+        }
+    }
+    assert_eq!(
+        merge_roots(TreeVersion::LATEST, &HashFunction::Keccak256, &[]),
+        [0; 32]
+    );
+}
+
+#[test]
+fn test_aligned_subtrees() {
+    let aligned = |counts: &[u64]| {
+        is_aligned(
+            &counts
+                .iter()
+                .map(|count| commitment(*count))
+                .collect::<Vec<_>>(),
+        )
+    };
+    assert!(aligned(&[3]));
+    assert!(aligned(&[4, 4, 1]));
+    assert!(aligned(&[4, 4, 4]));
+    assert!(aligned(&[16, 3]));
+
+    assert!(!aligned(&[]));
+    assert!(!aligned(&[0]));
+    assert!(!aligned(&[3, 3]));
+    assert!(!aligned(&[4, 2, 4]));
+    assert!(!aligned(&[4, 5]));
+    assert!(!aligned(&[4, 0]));
+}
+
+#[test]
+fn test_commitment_round_trip() {
+    let commitment = commitment(1 << 40);
+    let bytes = commitment.to_bytes();
+    assert_eq!(SubtreeCommitment::from_bytes(&bytes), Some(commitment));
+    assert_eq!(SubtreeCommitment::from_bytes(&bytes[1..]), None);
+
+    let mut unknown_hash_function = bytes;
+    unknown_hash_function[64] = 0xff;
+    assert_eq!(SubtreeCommitment::from_bytes(&unknown_hash_function), None);
+}